@@ -35,9 +35,12 @@ impl Section {
             SectionType::CustomSection => ContentType::Progbits,
         };
 
-        let flags = match content_type {
-            ContentType::Progbits => Flag::ALLOC,
-            ContentType::Nobits => Flag::ALLOC | Flag::WRITE,
+        let flags = match ty {
+            SectionType::Text => Flag::ALLOC | Flag::EXECINSTR,
+            SectionType::Rodata => Flag::ALLOC,
+            SectionType::Data | SectionType::Bss | SectionType::CustomSection => {
+                Flag::ALLOC | Flag::WRITE
+            }
         };
 
         Self {
@@ -52,6 +55,22 @@ impl Section {
     pub fn tag(&self) -> SectionTag {
         self.tag.clone()
     }
+
+    pub fn id(&self) -> SectionId {
+        self.id
+    }
+
+    pub fn flags(&self) -> &Flag {
+        &self.flags
+    }
+
+    pub fn alignment(&self) -> u32 {
+        self.alignment.value
+    }
+
+    pub fn content_type(&self) -> &ContentType {
+        &self.content_type
+    }
 }
 #[derive(Debug, Default, Clone, Copy, EnumCount, PartialEq, Eq, Hash)]
 pub enum SectionType {
@@ -116,7 +135,7 @@ impl Alignment {
 }
 
 /// Represents the type of a section defined by section control directives for RV32I.
-#[derive(Debug, PartialEq, Eq, Default)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
 pub enum ContentType {
     #[default]
     /// Program data (e.g., .text, .data, .rodata, user-defined .section).
@@ -1,7 +1,7 @@
 use std::ops::{Index, IndexMut};
 
-use isa::operand::{Immediate14, Immediate19};
-use shared::{EnumCount, EnumVariants};
+use isa::operand::{Immediate14, Immediate19, UImmediate5};
+use shared::{EnumCount, EnumVariants, object::RelocationKind};
 use thiserror::Error;
 
 use crate::{
@@ -11,11 +11,109 @@ use crate::{
     token::{self, LiteralIntegerType},
 };
 
-#[derive(Debug, EnumVariants, EnumCount)]
+#[derive(Debug, EnumVariants, EnumCount, PartialEq, Eq, Copy, Clone)]
 pub enum PseudoMnemonic {
-    Nop, //No operation: noop converted into addi x0, x0, 0
-    Mv,  //copies value between register: e.g. mv 15, 17 converted into addi a5, a7 0
-    Li,  // Load immediate
+    Nop,  //No operation: noop converted into addi x0, x0, 0
+    Mv,   //copies value between register: e.g. mv 15, 17 converted into addi a5, a7 0
+    Li,   // Load immediate: li a0, value converted into addi, or lui and addi if it doesn't fit
+    Call, // Call a subroutine: call label converted into jal x1, label
+    Ret,  // Return from a subroutine: ret converted into jalr x0, x1, 0
+    J,    // Jump: j label converted into jal x0, label
+    Jr,   // Jump register: jr x5 converted into jalr x0, x5, 0
+    Csrr, // Read a CSR: csrr a0, cycle converted into csrrs a0, cycle, x0
+    Csrw, // Write a CSR: csrw tvec, a0 converted into csrrw x0, tvec, a0
+    Csrs, // Set CSR bits: csrs status, a0 converted into csrrs x0, status, a0
+    Csrc, // Clear CSR bits: csrc status, a0 converted into csrrc x0, status, a0
+}
+
+impl PseudoMnemonic {
+    /// Expand into the base instructions, only `li` can take a second one
+    pub fn expand(self, operands: Operands) -> (Instruction, Option<Instruction>) {
+        use isa::Register;
+        use isa::instruction::Mnemonic;
+
+        let [first, second, _] = operands.0;
+        let zero = Operand::Imm14(Immediate14::new(0));
+        let (mnemonic, expanded) = match self {
+            PseudoMnemonic::Nop => (
+                Mnemonic::AddI,
+                [
+                    Operand::Register(Register::X0),
+                    Operand::Register(Register::X0),
+                    zero,
+                ],
+            ),
+            PseudoMnemonic::Mv => (Mnemonic::AddI, [first, second, zero]),
+            PseudoMnemonic::Li => return load_immediate(first, second),
+            PseudoMnemonic::Call => (
+                Mnemonic::Jal,
+                [Operand::Register(Register::X1), first, Operand::None],
+            ),
+            PseudoMnemonic::Ret => (
+                Mnemonic::Jalr,
+                [
+                    Operand::Register(Register::X0),
+                    Operand::Register(Register::X1),
+                    zero,
+                ],
+            ),
+            PseudoMnemonic::J => (
+                Mnemonic::Jal,
+                [Operand::Register(Register::X0), first, Operand::None],
+            ),
+            PseudoMnemonic::Jr => (
+                Mnemonic::Jalr,
+                [Operand::Register(Register::X0), first, zero],
+            ),
+            PseudoMnemonic::Csrr => (
+                Mnemonic::Csrrs,
+                [first, second, Operand::Register(Register::X0)],
+            ),
+            PseudoMnemonic::Csrw => (
+                Mnemonic::Csrrw,
+                [Operand::Register(Register::X0), first, second],
+            ),
+            PseudoMnemonic::Csrs => (
+                Mnemonic::Csrrs,
+                [Operand::Register(Register::X0), first, second],
+            ),
+            PseudoMnemonic::Csrc => (
+                Mnemonic::Csrrc,
+                [Operand::Register(Register::X0), first, second],
+            ),
+        };
+
+        (instruction(mnemonic, expanded), None)
+    }
+}
+
+fn instruction(mnemonic: isa::instruction::Mnemonic, operands: [Operand; 3]) -> Instruction {
+    let mut expanded = Operands::new();
+    expanded.memcpy(&operands);
+    Instruction::new(mnemonic, expanded)
+}
+
+/// `li dest, value`: an `addi` if `value` fits in an `Immediate14`, else a `lui` of bits 31..13
+/// and an `addi` of bits 12..0. Symbols always take both, their value isn't known yet
+fn load_immediate(dest: Operand, value: Operand) -> (Instruction, Option<Instruction>) {
+    use isa::instruction::Mnemonic::{AddI, Lui};
+
+    let zero = Operand::Register(isa::Register::X0);
+    let (hi, lo) = match value {
+        Operand::Word(word) => match Immediate14::try_from(word as i32) {
+            Ok(imm) => return (instruction(AddI, [dest, zero, Operand::Imm14(imm)]), None),
+            Err(_) => (
+                Operand::Imm19(Immediate19::new(word as i32 >> 13)),
+                Operand::Imm14(Immediate14::new((word & 0x1FFF) as i32)),
+            ),
+        },
+        Operand::Symbol(name) => (Operand::Hi(name), Operand::Lo(name)),
+        value => return (instruction(AddI, [dest, zero, value]), None),
+    };
+    (
+        instruction(Lui, [dest, hi, Operand::None]),
+        Some(instruction(AddI, [dest, dest, lo])),
+    )
 }
 
 #[derive(Debug)]
@@ -29,6 +127,305 @@ impl Instruction {
     pub fn new(mnemonic: isa::instruction::Mnemonic, operands: Operands) -> Instruction {
         Instruction { mnemonic, operands }
     }
+
+    /// The symbol operand, if any, with the way its value is encoded
+    pub fn symbol(&self) -> Option<(StrId, RelocationKind)> {
+        use isa::instruction::Mnemonic::*;
+
+        self.operands.0.iter().find_map(|operand| match operand {
+            Operand::Hi(name) => Some((*name, RelocationKind::Hi19)),
+            Operand::Lo(name) => Some((*name, RelocationKind::Lo13)),
+            Operand::Symbol(name) => {
+                let kind = match self.mnemonic {
+                    Beq | Bne | Blt | Bge | Bltu | Bgeu => RelocationKind::Branch14,
+                    Jal => RelocationKind::Jump19,
+                    // Like `%hi(symbol)`, the VM shifts the immediate back
+                    Lui => RelocationKind::Hi19,
+                    _ => RelocationKind::Abs14,
+                };
+                Some((*name, kind))
+            }
+            _ => None,
+        })
+    }
+
+    /// Lower into an encodable `isa::Instruction`.\
+    /// `address` is the location of this instruction and `resolve` returns the value of a symbol (the address for labels).
+    /// Label operands of branches are turned into offsets relative to `address`
+    pub fn lower(
+        &self,
+        address: u32,
+        resolve: impl Fn(StrId) -> Option<u32>,
+    ) -> Result<isa::Instruction, OperandError> {
+        use isa::instruction::Mnemonic::*;
+
+        let [first, second, third] = self.operands.0;
+        let ins = match self.mnemonic {
+            Add => isa::Instruction::Add {
+                dest: first.register()?,
+                src1: second.register()?,
+                src2: third.register()?,
+            },
+            Sub => isa::Instruction::Sub {
+                dest: first.register()?,
+                src1: second.register()?,
+                src2: third.register()?,
+            },
+            Mul => isa::Instruction::Mul {
+                dest: first.register()?,
+                src1: second.register()?,
+                src2: third.register()?,
+            },
+            Mulh => isa::Instruction::Mulh {
+                dest: first.register()?,
+                src1: second.register()?,
+                src2: third.register()?,
+            },
+            Mulhu => isa::Instruction::Mulhu {
+                dest: first.register()?,
+                src1: second.register()?,
+                src2: third.register()?,
+            },
+            Mulhsu => isa::Instruction::Mulhsu {
+                dest: first.register()?,
+                src1: second.register()?,
+                src2: third.register()?,
+            },
+            Div => isa::Instruction::Div {
+                dest: first.register()?,
+                src1: second.register()?,
+                src2: third.register()?,
+            },
+            Divu => isa::Instruction::Divu {
+                dest: first.register()?,
+                src1: second.register()?,
+                src2: third.register()?,
+            },
+            Rem => isa::Instruction::Rem {
+                dest: first.register()?,
+                src1: second.register()?,
+                src2: third.register()?,
+            },
+            Remu => isa::Instruction::Remu {
+                dest: first.register()?,
+                src1: second.register()?,
+                src2: third.register()?,
+            },
+            And => isa::Instruction::And {
+                dest: first.register()?,
+                src1: second.register()?,
+                src2: third.register()?,
+            },
+            Or => isa::Instruction::Or {
+                dest: first.register()?,
+                src1: second.register()?,
+                src2: third.register()?,
+            },
+            Xor => isa::Instruction::Xor {
+                dest: first.register()?,
+                src1: second.register()?,
+                src2: third.register()?,
+            },
+            Shl => isa::Instruction::Shl {
+                dest: first.register()?,
+                src: second.register()?,
+                shift: third.register()?,
+            },
+            Shr => isa::Instruction::Shr {
+                dest: first.register()?,
+                src: second.register()?,
+                shift: third.register()?,
+            },
+            ShrA => isa::Instruction::ShrA {
+                dest: first.register()?,
+                src: second.register()?,
+                shift: third.register()?,
+            },
+            AddI => isa::Instruction::AddI {
+                dest: first.register()?,
+                src: second.register()?,
+                value: third.imm14(&resolve)?,
+            },
+            Slt => isa::Instruction::Slt {
+                dest: first.register()?,
+                src1: second.register()?,
+                src2: third.register()?,
+            },
+            Sltu => isa::Instruction::Sltu {
+                dest: first.register()?,
+                src1: second.register()?,
+                src2: third.register()?,
+            },
+            SltI => isa::Instruction::SltI {
+                dest: first.register()?,
+                src: second.register()?,
+                value: third.imm14(&resolve)?,
+            },
+            SltIU => isa::Instruction::SltIU {
+                dest: first.register()?,
+                src: second.register()?,
+                value: third.imm14(&resolve)?,
+            },
+            AndI => isa::Instruction::AndI {
+                dest: first.register()?,
+                src: second.register()?,
+                value: third.imm14(&resolve)?,
+            },
+            OrI => isa::Instruction::OrI {
+                dest: first.register()?,
+                src: second.register()?,
+                value: third.imm14(&resolve)?,
+            },
+            XorI => isa::Instruction::XorI {
+                dest: first.register()?,
+                src: second.register()?,
+                value: third.imm14(&resolve)?,
+            },
+            SllI => isa::Instruction::SllI {
+                dest: first.register()?,
+                src: second.register()?,
+                shift: third.imm14(&resolve)?,
+            },
+            SrlI => isa::Instruction::SrlI {
+                dest: first.register()?,
+                src: second.register()?,
+                shift: third.imm14(&resolve)?,
+            },
+            SraI => isa::Instruction::SraI {
+                dest: first.register()?,
+                src: second.register()?,
+                shift: third.imm14(&resolve)?,
+            },
+            Lui => isa::Instruction::Lui {
+                dest: first.register()?,
+                value: match second {
+                    Operand::Symbol(name) => Operand::Hi(name),
+                    operand => operand,
+                }
+                .imm19(&resolve)?,
+            },
+            // `lw dest, offset(src)`
+            Lw => isa::Instruction::Lw {
+                dest: first.register()?,
+                offset: second.imm14(&resolve)?,
+                src: third.register()?,
+            },
+            // `sw src, offset(dest)`
+            Sw => isa::Instruction::Sw {
+                src: first.register()?,
+                offset: second.imm14(&resolve)?,
+                dest: third.register()?,
+            },
+            Lb => isa::Instruction::Lb {
+                dest: first.register()?,
+                offset: second.imm14(&resolve)?,
+                src: third.register()?,
+            },
+            Lh => isa::Instruction::Lh {
+                dest: first.register()?,
+                offset: second.imm14(&resolve)?,
+                src: third.register()?,
+            },
+            Lbu => isa::Instruction::Lbu {
+                dest: first.register()?,
+                offset: second.imm14(&resolve)?,
+                src: third.register()?,
+            },
+            Lhu => isa::Instruction::Lhu {
+                dest: first.register()?,
+                offset: second.imm14(&resolve)?,
+                src: third.register()?,
+            },
+            Sb => isa::Instruction::Sb {
+                src: first.register()?,
+                offset: second.imm14(&resolve)?,
+                dest: third.register()?,
+            },
+            Sh => isa::Instruction::Sh {
+                src: first.register()?,
+                offset: second.imm14(&resolve)?,
+                dest: third.register()?,
+            },
+            Beq => isa::Instruction::Beq {
+                src1: first.register()?,
+                src2: second.register()?,
+                offset: third.offset14(address, &resolve)?,
+            },
+            Bne => isa::Instruction::Bne {
+                src1: first.register()?,
+                src2: second.register()?,
+                offset: third.offset14(address, &resolve)?,
+            },
+            Blt => isa::Instruction::Blt {
+                src1: first.register()?,
+                src2: second.register()?,
+                offset: third.offset14(address, &resolve)?,
+            },
+            Bge => isa::Instruction::Bge {
+                src1: first.register()?,
+                src2: second.register()?,
+                offset: third.offset14(address, &resolve)?,
+            },
+            Bltu => isa::Instruction::Bltu {
+                src1: first.register()?,
+                src2: second.register()?,
+                offset: third.offset14(address, &resolve)?,
+            },
+            Bgeu => isa::Instruction::Bgeu {
+                src1: first.register()?,
+                src2: second.register()?,
+                offset: third.offset14(address, &resolve)?,
+            },
+            Jal => isa::Instruction::Jal {
+                dest: first.register()?,
+                offset: second.offset19(address, &resolve)?,
+            },
+            Jalr => isa::Instruction::Jalr {
+                dest: first.register()?,
+                src: second.register()?,
+                offset: third.imm14(&resolve)?,
+            },
+            Syscall => isa::Instruction::Syscall {
+                src1: first.register()?,
+                src2: second.register()?,
+                src3: third.register()?,
+            },
+            Ebreak => isa::Instruction::Ebreak,
+            Mret => isa::Instruction::Mret,
+            Csrrw => isa::Instruction::Csrrw {
+                dest: first.register()?,
+                csr: second.csr(&resolve)?,
+                src: third.register()?,
+            },
+            Csrrs => isa::Instruction::Csrrs {
+                dest: first.register()?,
+                csr: second.csr(&resolve)?,
+                src: third.register()?,
+            },
+            Csrrc => isa::Instruction::Csrrc {
+                dest: first.register()?,
+                csr: second.csr(&resolve)?,
+                src: third.register()?,
+            },
+            Csrrwi => isa::Instruction::Csrrwi {
+                dest: first.register()?,
+                csr: second.csr(&resolve)?,
+                value: third.uimm5(&resolve)?,
+            },
+            Csrrsi => isa::Instruction::Csrrsi {
+                dest: first.register()?,
+                csr: second.csr(&resolve)?,
+                value: third.uimm5(&resolve)?,
+            },
+            Csrrci => isa::Instruction::Csrrci {
+                dest: first.register()?,
+                csr: second.csr(&resolve)?,
+                value: third.uimm5(&resolve)?,
+            },
+        };
+
+        Ok(ins)
+    }
 }
 
 #[derive(Debug, Error)]
@@ -37,6 +434,10 @@ pub enum OperandError {
     ImmediateError(#[from] isa::operand::ImmediateValueError),
     #[error(transparent)]
     ParseIntError(#[from] std::num::ParseIntError),
+    #[error("undefined symbol {0:?}")]
+    UndefinedSymbol(StrId),
+    #[error("expected {0}")]
+    InvalidOperand(&'static str),
 }
 
 #[derive(Debug, EnumCount)]
@@ -104,13 +505,96 @@ impl IndexMut<OperandsIndex> for Operands {
 //4 bytes
 pub enum Operand {
     Symbol(StrId),
+    /// `%hi(symbol)`
+    Hi(StrId),
+    /// `%lo(symbol)`
+    Lo(StrId),
     Register(isa::Register),
+    /// A CSR by name, numbered ones are immediates
+    Csr(isa::Csr),
     Imm14(isa::operand::Immediate14),
     Imm19(isa::operand::Immediate19),
+    /// Any 32-bit literal, only `li` takes one
+    Word(u32),
     #[default]
     None,
 }
 
+impl Operand {
+    fn register(self) -> Result<isa::Register, OperandError> {
+        match self {
+            Self::Register(r) => Ok(r),
+            _ => Err(OperandError::InvalidOperand("register")),
+        }
+    }
+
+    /// Resolve the operand into its absolute value
+    fn value(self, resolve: impl Fn(StrId) -> Option<u32>) -> Result<i32, OperandError> {
+        match self {
+            Self::Imm14(imm) => Ok(imm.into()),
+            Self::Imm19(imm) => Ok(imm.into()),
+            Self::Symbol(str_id) => resolve(str_id)
+                .map(|v| v as i32)
+                .ok_or(OperandError::UndefinedSymbol(str_id)),
+            Self::Hi(str_id) => resolve(str_id)
+                .map(|v| v as i32 >> 13)
+                .ok_or(OperandError::UndefinedSymbol(str_id)),
+            Self::Lo(str_id) => resolve(str_id)
+                .map(|v| (v & 0x1FFF) as i32)
+                .ok_or(OperandError::UndefinedSymbol(str_id)),
+            _ => Err(OperandError::InvalidOperand("immediate or symbol")),
+        }
+    }
+
+    fn imm14(self, resolve: impl Fn(StrId) -> Option<u32>) -> Result<Immediate14, OperandError> {
+        Ok(Immediate14::try_from(self.value(resolve)?)?)
+    }
+
+    fn imm19(self, resolve: impl Fn(StrId) -> Option<u32>) -> Result<Immediate19, OperandError> {
+        Ok(Immediate19::try_from(self.value(resolve)?)?)
+    }
+
+    fn uimm5(self, resolve: impl Fn(StrId) -> Option<u32>) -> Result<UImmediate5, OperandError> {
+        Ok(UImmediate5::try_from(self.value(resolve)?)?)
+    }
+
+    fn csr(self, resolve: impl Fn(StrId) -> Option<u32>) -> Result<isa::Csr, OperandError> {
+        match self {
+            Self::Csr(csr) => Ok(csr),
+            _ => isa::Csr::new(self.value(resolve)? as u32)
+                .ok_or(OperandError::InvalidOperand("CSR name or 12-bit number")),
+        }
+    }
+
+    /// PC-relative offset. Labels are resolved relative to `address`, numeric operands are taken as is
+    fn offset(
+        self,
+        address: u32,
+        resolve: impl Fn(StrId) -> Option<u32>,
+    ) -> Result<i32, OperandError> {
+        match self {
+            Self::Symbol(_) => Ok(self.value(resolve)?.wrapping_sub(address as i32)),
+            _ => self.value(resolve),
+        }
+    }
+
+    fn offset14(
+        self,
+        address: u32,
+        resolve: impl Fn(StrId) -> Option<u32>,
+    ) -> Result<Immediate14, OperandError> {
+        Ok(Immediate14::try_from(self.offset(address, resolve)?)?)
+    }
+
+    fn offset19(
+        self,
+        address: u32,
+        resolve: impl Fn(StrId) -> Option<u32>,
+    ) -> Result<Immediate19, OperandError> {
+        Ok(Immediate19::try_from(self.offset(address, resolve)?)?)
+    }
+}
+
 type SourceSlice<'a> = &'a [u8];
 impl<'a> TryFrom<(token::Token, OperandRuleType, SourceSlice<'a>)> for Operand {
     type Error = OperandError;
@@ -123,9 +607,17 @@ impl<'a> TryFrom<(token::Token, OperandRuleType, SourceSlice<'a>)> for Operand {
 
         match (token, rule) {
             (Identifier(token::IdentifierType::Symbol), _) => Ok(Self::Symbol(StrId::default())),
+            (Hi, _) => Ok(Self::Hi(StrId::default())),
+            (Lo, _) => Ok(Self::Lo(StrId::default())),
             // (Label, _) => Ok(Self::Label(lexeme.span().to_owned())),
             (Identifier(token::IdentifierType::Register(r)), _) => Ok(Self::Register(r)),
-            (literal @ (LiteralDecimal | LiteralHex | LiteralBinary), R2I | RIR | RI) => {
+            (literal @ (LiteralDecimal | LiteralHex | LiteralBinary), RW) => {
+                Ok(Self::Word(crate::parser::parse_word(literal, slice)?))
+            }
+            (
+                literal @ (LiteralDecimal | LiteralHex | LiteralBinary),
+                R2I | RIR | RI | R2L | RL | L | RCR | RCI | RC | CR,
+            ) => {
                 //safety unwrap: guaranteed safe
                 let frst_byte = slice[0];
                 let int_ty = LiteralIntegerType::from(literal);
@@ -137,8 +629,7 @@ impl<'a> TryFrom<(token::Token, OperandRuleType, SourceSlice<'a>)> for Operand {
                 let radix = std::str::from_utf8({
                     if LiteralIntegerType::is_signed(frst_byte) {
                         buffer.push(b'-');
-                        buffer.fill(Default::default());
-                        buffer[1..].copy_from_slice(bytes);
+                        buffer.extend_from_slice(bytes);
                         buffer.as_slice()
                     } else {
                         bytes
@@ -148,7 +639,9 @@ impl<'a> TryFrom<(token::Token, OperandRuleType, SourceSlice<'a>)> for Operand {
 
                 let imm = i32::from_str_radix(radix, int_ty.base())?;
                 match rule {
-                    R2I | RIR => Ok(Self::Imm14(Immediate14::try_from(imm)?)),
+                    R2I | RIR | R2L | RCR | RCI | RC | CR => {
+                        Ok(Self::Imm14(Immediate14::try_from(imm)?))
+                    }
                     _ => Ok(Self::Imm19(Immediate19::try_from(imm)?)),
                 }
             }
@@ -156,3 +649,86 @@ impl<'a> TryFrom<(token::Token, OperandRuleType, SourceSlice<'a>)> for Operand {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use isa::{Register, instruction::Mnemonic};
+
+    use super::*;
+
+    #[test]
+    fn t_lower_branch() {
+        let mut loop_label = StrId::default();
+        loop_label.set(1);
+        let resolve = |id: StrId| (id == loop_label).then_some(0x10);
+
+        let mut operands = Operands::new();
+        operands.memcpy(&[
+            Operand::Register(Register::X5),
+            Operand::Register(Register::X0),
+            Operand::Symbol(loop_label),
+        ]);
+        let beq = Instruction::new(Mnemonic::Beq, operands);
+
+        assert_eq!(
+            beq.lower(0x18, resolve).unwrap(),
+            isa::Instruction::Beq {
+                src1: Register::X5,
+                src2: Register::X0,
+                offset: Immediate14::new(-8),
+            }
+        );
+        assert_eq!(
+            beq.lower(0x0, resolve).unwrap(),
+            isa::Instruction::Beq {
+                src1: Register::X5,
+                src2: Register::X0,
+                offset: Immediate14::new(0x10),
+            }
+        );
+        assert!(matches!(
+            beq.lower(0x0, |_| None),
+            Err(OperandError::UndefinedSymbol(id)) if id == loop_label
+        ));
+    }
+
+    #[test]
+    fn t_expand_pseudo() {
+        let mut label = StrId::default();
+        label.set(2);
+        let resolve = |id: StrId| (id == label).then_some(0x20);
+
+        let mut operands = Operands::new();
+        operands.memcpy(&[Operand::Symbol(label)]);
+        let call = PseudoMnemonic::Call.expand(operands).0;
+        assert_eq!(
+            call.lower(0x8, resolve).unwrap(),
+            isa::Instruction::Jal {
+                dest: Register::X1,
+                offset: Immediate19::new(0x18),
+            }
+        );
+
+        let ret = PseudoMnemonic::Ret.expand(Operands::new()).0;
+        assert_eq!(
+            ret.lower(0x0, resolve).unwrap(),
+            isa::Instruction::Jalr {
+                dest: Register::X0,
+                src: Register::X1,
+                offset: Immediate14::new(0),
+            }
+        );
+
+        let mut operands = Operands::new();
+        operands.memcpy(&[Operand::Register(Register::X5)]);
+        let jr = PseudoMnemonic::Jr.expand(operands).0;
+        assert_eq!(
+            jr.lower(0x0, resolve).unwrap(),
+            isa::Instruction::Jalr {
+                dest: Register::X0,
+                src: Register::X5,
+                offset: Immediate14::new(0),
+            }
+        );
+    }
+}
@@ -40,10 +40,20 @@ impl Interner {
         self.vec.push(name);
     }
 
+    /// The id of `name` if it was interned
+    pub fn get(&self, name: &str) -> Option<StrId> {
+        self.map.get(name).copied()
+    }
+
     pub fn lookup(&self, id: StrId) -> &str {
         self.vec[usize::from(id)]
     }
 
+    /// Every string in insertion order, so the index is the `StrId`
+    pub fn strings(&self) -> &[&str] {
+        &self.vec
+    }
+
     ///Generate the next `id`
     pub fn generate_id(&self) -> StrId {
         StrId(self.map.len() as u32)
@@ -25,12 +25,17 @@ pub struct IR {
 
 impl IR {
     pub fn new(cap: usize) -> Self {
+        let mut str_tab = Interner::with_capacity(cap);
+        let mut sections = Sections::new();
+        // Anything before the first section directive goes to `.text`
+        let text = sections.switch(str_tab.intern(".text"), SectionType::Text);
+
         Self {
             nodes: Vec::new(),
-            str_tab: Interner::with_capacity(cap),
-            sections: Sections::new(),
+            str_tab,
+            sections,
             instructions: Instructions::new(),
-            last_section_id: SectionId::default(),
+            last_section_id: text,
         }
     }
     pub fn nodes(&self) -> &[Node] {
@@ -41,13 +46,15 @@ impl IR {
         self.str_tab.intern(name)
     }
 
-    pub fn add_instruction(&mut self, ins: Instruction) {
-        let id = self.instructions.add(ins);
+    /// `offset` is the position of the instruction in the source, kept for error reporting
+    pub fn add_instruction(&mut self, ins: Instruction, offset: usize) {
+        let id = self.instructions.add(ins, offset);
         self.nodes.push(Node::Instruction(id));
     }
 
+    /// `name` without its leading dot
     pub fn add_section(&mut self, name: &str, ty: SectionType) {
-        let str_id = self.str_tab.intern(name);
+        let str_id = self.str_tab.intern(&format!(".{name}"));
         let id = self.sections.switch(str_id, ty);
         self.nodes.push(Node::Section(id));
         self.last_section_id = id;
@@ -57,6 +64,10 @@ impl IR {
         self.nodes.push(node);
     }
 
+    pub fn instructions(&self) -> &Instructions {
+        &self.instructions
+    }
+
     pub fn str_tab(&self) -> &Interner {
         &self.str_tab
     }
@@ -65,6 +76,10 @@ impl IR {
         &mut self.str_tab
     }
 
+    pub fn sections(&self) -> &Sections {
+        &self.sections
+    }
+
     pub fn sections_mut(&mut self) -> &mut Sections {
         &mut self.sections
     }
@@ -93,14 +108,22 @@ pub enum Node {
     Section(SectionId),
     Instruction(InstructionId),
     Label(StrId),
+    /// `.word` value and the byte offset of the directive in the source
+    Word(Word, usize),
     // Global(StrId),
-    // Word(u32),
     // Byte(u8),
     // Half(u16),
     // Align(u32), // New for .align, .p2align, .balign
     // Skip(u32),
 }
 
+/// A 32-bit value of a `.word`
+#[derive(Debug, Clone, Copy)]
+pub enum Word {
+    Value(u32),
+    Symbol(StrId),
+}
+
 use rustc_hash::FxHashMap;
 
 #[derive(Debug)]
@@ -137,27 +160,46 @@ impl Sections {
         &self.vec[usize::from(id)]
     }
 
+    pub fn iter(&self) -> impl Iterator<Item = &Section> {
+        self.vec.iter()
+    }
+
     ///Generate the next `id`
     pub fn generate_id(&self) -> SectionId {
         SectionId::new(self.map.len() as u8)
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct InstructionId(u32);
 
+/// Structure of Arrays
 #[derive(Debug)]
 pub struct Instructions {
     vec: Vec<Instruction>,
+    offsets: Vec<usize>,
 }
 
 impl Instructions {
     fn new() -> Instructions {
-        Instructions { vec: Vec::new() }
+        Instructions {
+            vec: Vec::new(),
+            offsets: Vec::new(),
+        }
     }
 
-    pub fn add(&mut self, value: Instruction) -> InstructionId {
+    pub fn add(&mut self, value: Instruction, offset: usize) -> InstructionId {
         self.vec.push(value);
+        self.offsets.push(offset);
         InstructionId((self.vec.len() - 1) as u32)
     }
+
+    pub fn get(&self, id: InstructionId) -> &Instruction {
+        &self.vec[id.0 as usize]
+    }
+
+    /// Position of the instruction in the source
+    pub fn offset(&self, id: InstructionId) -> usize {
+        self.offsets[id.0 as usize]
+    }
 }
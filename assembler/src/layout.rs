@@ -1,30 +1,513 @@
-use crate::ir::IR;
+use rustc_hash::FxHashMap;
+use shared::object::RelocationKind;
+use thiserror::Error;
 
-pub struct Layout;
+use crate::{
+    asm::section::{ContentType, Flag, SectionId, SectionType},
+    instruction::OperandError,
+    interner::StrId,
+    ir::{IR, Node, Word},
+    script::{LinkerScript, ScriptError, ScriptInput},
+    symbol_table::SymbolTable,
+};
+
+#[derive(Debug, Error)]
+pub enum LayoutError {
+    /// Byte offset in the source of the faulty instruction
+    #[error("{1}")]
+    Operand(usize, OperandError),
+    #[error("Undefined symbol `{1}`")]
+    UndefinedSymbol(usize, String),
+    #[error(transparent)]
+    Script(#[from] ScriptError),
+    /// End of the last section with content
+    #[error(
+        "Flat image would be {0:#x} bytes, more than {max:#x}. Use an object or ELF output",
+        max = Layout::MAX_IMAGE_SIZE
+    )]
+    ImageTooLarge(u32),
+}
+
+impl LayoutError {
+    /// Byte offset in the source where the error occurred, `None` for linker script errors
+    pub fn offset(&self) -> Option<usize> {
+        match self {
+            LayoutError::Operand(offset, _) | LayoutError::UndefinedSymbol(offset, _) => {
+                Some(*offset)
+            }
+            LayoutError::Script(_) | LayoutError::ImageTooLarge(_) => None,
+        }
+    }
+}
+
+/// A field to patch once the address of `symbol` changes or is known
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Relocation {
+    /// Offset of the instruction in its section
+    pub offset: u32,
+    pub symbol: StrId,
+    /// Section defining `symbol`, `None` if it's not defined in this file
+    pub target: Option<SectionId>,
+    pub kind: RelocationKind,
+    pub addend: i32,
+}
+
+/// Encoded content of a section
+#[derive(Debug)]
+pub struct AssembledSection {
+    id: SectionId,
+    ty: SectionType,
+    content_type: ContentType,
+    /// Address of the first byte once every section is placed
+    address: u32,
+    /// Value of the location counter once the section is laid out
+    size: u32,
+    /// Encoded machine code or data. Empty for `Nobits` sections
+    bytes: Vec<u8>,
+    relocations: Vec<Relocation>,
+}
+
+impl AssembledSection {
+    pub fn id(&self) -> SectionId {
+        self.id
+    }
+
+    pub fn address(&self) -> u32 {
+        self.address
+    }
+
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn relocations(&self) -> &[Relocation] {
+        &self.relocations
+    }
+}
+
+/// Placement order of the sections in the image. Code first, then read only data, then data and
+/// finally the uninitialized data
+const fn rank(ty: SectionType) -> u8 {
+    match ty {
+        SectionType::Text => 0,
+        SectionType::Rodata => 1,
+        SectionType::Data => 2,
+        SectionType::CustomSection => 3,
+        SectionType::Bss => 4,
+    }
+}
+
+/// Every section of the program with its encoded bytes, indexed by `SectionId`
+pub struct Layout {
+    sections: Vec<AssembledSection>,
+    /// Absolute symbols assigned by the linker script
+    symbols: Vec<(String, u32)>,
+}
 
 impl Layout {
-    pub fn new(ir: IR) -> Layout {
-        Layout
-    }
-
-    // pub fn resolve(&mut self) -> Result<(), ParsingError> {
-    // self.ir.push(Node::Label(Symbol::new(
-    //     str_id,
-    //     Visibility::Local,
-    //     None,
-    //     SymbolType::Label,
-    // )));
-    //     while let Some(token) = self.eat() {
-    //         self.walk(*token)?;
-    //     }
-
-    //     Ok(())
-    // }
-}
-
-// struct AssembledSection {
-//     bytes: Vec<u8>,              // Encoded machine code or data
-//     offsets: Vec<(u32, usize)>,  // Offset and index into original nodes
-//     symbols: Vec<Symbol>,        // Labels defined in this section
-//     relocations: Vec<(u32, String)>, // Relocation info for unresolved symbols
-// }
+    /// Lay the sections one after the other starting at address 0, or where `script` puts them.
+    /// Label values are written into `symtab` as offsets from the start of their section.\
+    /// `constants` are absolute symbols, looked up when no label has the name. The symbols
+    /// assigned by `script` are added to them.\
+    /// Every use of a label that would change if its section moved gets a relocation. When
+    /// `relocatable` is set undefined symbols are allowed and get one too, otherwise they are an error
+    pub fn new(
+        ir: &IR,
+        symtab: &mut SymbolTable,
+        constants: &FxHashMap<StrId, u32>,
+        script: Option<&LinkerScript>,
+        relocatable: bool,
+    ) -> Result<Layout, LayoutError> {
+        let mut sections: Vec<_> = ir
+            .sections()
+            .iter()
+            .map(|section| AssembledSection {
+                id: section.id(),
+                ty: section.tag().ty(),
+                content_type: *section.content_type(),
+                address: 0,
+                size: 0,
+                bytes: Vec::new(),
+                relocations: Vec::new(),
+            })
+            .collect();
+
+        // First pass: run the location counter of every section and assign the labels
+        let mut active = SectionId::default();
+        for node in ir.nodes() {
+            let location_counter = &mut sections[usize::from(active)].size;
+            match node {
+                Node::Section(id) => active = *id,
+                Node::Label(name) => symtab.set_value(active, *name, *location_counter),
+                Node::Instruction(_) | Node::Word(..) => *location_counter += 4,
+                Node::String(string) => *location_counter += string.len() as u32,
+            }
+        }
+
+        // Place the sections
+        let mut order: Vec<_> = (0..sections.len()).collect();
+        order.sort_by_key(|&i| rank(sections[i].ty));
+
+        let mut symbols = Vec::new();
+        match script {
+            Some(script) => {
+                let inputs: Vec<_> = order
+                    .iter()
+                    .map(|&i| {
+                        let section = ir.sections().get(sections[i].id);
+                        ScriptInput {
+                            name: ir.str_tab().lookup(section.tag().strid()),
+                            alignment: section.alignment(),
+                            size: sections[i].size,
+                            writable: section.flags().contains(Flag::WRITE),
+                            executable: section.flags().contains(Flag::EXECINSTR),
+                        }
+                    })
+                    .collect();
+
+                let placed = script.place(&inputs)?;
+                for (&i, address) in order.iter().zip(placed.addresses) {
+                    sections[i].address = address;
+                }
+                symbols = placed.symbols;
+            }
+            None => {
+                let mut address = 0u32;
+                for i in order {
+                    let alignment = ir.sections().get(sections[i].id).alignment();
+                    sections[i].address = address.next_multiple_of(alignment);
+                    address = sections[i].address + sections[i].size;
+                }
+            }
+        }
+
+        // Script symbols are absolute, like the constants they join. Those not interned can't be
+        // used in the program
+        let mut constants = constants.clone();
+        for (name, value) in &symbols {
+            if let Some(name) = ir.str_tab().get(name) {
+                constants.insert(name, *value);
+            }
+        }
+
+        // Second pass: encode
+        let mut active = SectionId::default();
+        for node in ir.nodes() {
+            match node {
+                Node::Section(id) => active = *id,
+                Node::Instruction(id) => {
+                    let instruction = ir.instructions().get(*id);
+                    let section = &sections[usize::from(active)];
+                    let section_offset = section.bytes.len() as u32;
+                    let address = section.address + section_offset;
+
+                    let relocation = instruction.symbol().and_then(|(name, kind)| {
+                        let target = match symtab.lookup(active, name) {
+                            // Moves along with the instruction
+                            Some((target, _)) if kind.is_pc_relative() && target == active => {
+                                return None;
+                            }
+                            Some((target, _)) => Some(target),
+                            None if relocatable && !constants.contains_key(&name) => None,
+                            None => return None,
+                        };
+
+                        Some(Relocation {
+                            offset: section_offset,
+                            symbol: name,
+                            target,
+                            kind,
+                            addend: 0,
+                        })
+                    });
+
+                    let resolve = |name: StrId| {
+                        symtab
+                            .lookup(active, name)
+                            .map(|(id, value)| sections[usize::from(id)].address + value)
+                            .or_else(|| constants.get(&name).copied())
+                            .or_else(|| {
+                                // Placeholder until the symbol is linked: a null offset or value
+                                relocatable.then_some(match relocation {
+                                    Some(relocation) if relocation.kind.is_pc_relative() => address,
+                                    _ => 0,
+                                })
+                            })
+                    };
+
+                    let offset = ir.instructions().offset(*id);
+                    let ins = instruction
+                        .lower(address, resolve)
+                        .map_err(|err| match err {
+                            OperandError::UndefinedSymbol(name) => LayoutError::UndefinedSymbol(
+                                offset,
+                                ir.str_tab().lookup(name).to_owned(),
+                            ),
+                            err => LayoutError::Operand(offset, err),
+                        })?;
+
+                    let section = &mut sections[usize::from(active)];
+                    section
+                        .bytes
+                        .extend_from_slice(&u32::from(&ins).to_le_bytes());
+                    section.relocations.extend(relocation);
+                }
+                Node::Word(Word::Value(value), _) => sections[usize::from(active)]
+                    .bytes
+                    .extend_from_slice(&value.to_le_bytes()),
+                Node::Word(Word::Symbol(name), offset) => {
+                    let name = *name;
+                    // `relocation` is the section defining the symbol when the word needs one
+                    let (value, relocation) =
+                        if let Some((target, value)) = symtab.lookup(active, name) {
+                            (
+                                sections[usize::from(target)].address + value,
+                                Some(Some(target)),
+                            )
+                        } else if let Some(value) = constants.get(&name) {
+                            (*value, None)
+                        } else if relocatable {
+                            (0, Some(None))
+                        } else {
+                            return Err(LayoutError::UndefinedSymbol(
+                                *offset,
+                                ir.str_tab().lookup(name).to_owned(),
+                            ));
+                        };
+
+                    let section = &mut sections[usize::from(active)];
+                    // Addresses of labels move with their section, constants don't
+                    if let Some(target) = relocation {
+                        section.relocations.push(Relocation {
+                            offset: section.bytes.len() as u32,
+                            symbol: name,
+                            target,
+                            kind: RelocationKind::Abs32,
+                            addend: 0,
+                        });
+                    }
+                    section.bytes.extend_from_slice(&value.to_le_bytes());
+                }
+                Node::String(string) => sections[usize::from(active)]
+                    .bytes
+                    .extend_from_slice(string.as_bytes()),
+                Node::Label(_) => {}
+            }
+        }
+
+        Ok(Layout { sections, symbols })
+    }
+
+    pub fn sections(&self) -> &[AssembledSection] {
+        &self.sections
+    }
+
+    /// Symbols assigned by the linker script, with their address
+    pub fn symbols(&self) -> &[(String, u32)] {
+        &self.symbols
+    }
+
+    /// Largest flat image, sections placed far from address 0 need an object or ELF output
+    pub const MAX_IMAGE_SIZE: u32 = 16 * 1024 * 1024;
+
+    /// Flat image of every section with content, loaded at address 0. Gaps are zero filled
+    pub fn image(&self) -> Result<Vec<u8>, LayoutError> {
+        let progbits = || {
+            self.sections
+                .iter()
+                .filter(|section| section.content_type == ContentType::Progbits)
+        };
+
+        let end = progbits()
+            .map(|section| section.address + section.size)
+            .max()
+            .unwrap_or_default();
+        if end > Self::MAX_IMAGE_SIZE {
+            return Err(LayoutError::ImageTooLarge(end));
+        }
+
+        let mut image = vec![0; end as usize];
+        for section in progbits() {
+            let start = section.address as usize;
+            image[start..start + section.bytes.len()].copy_from_slice(&section.bytes);
+        }
+
+        Ok(image)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{lexer::Lexer, parser::Parser};
+
+    #[test]
+    fn t_layout_sections() {
+        let source = br#"
+        .data
+        msg:
+            .ascii "hi!"
+        .text
+        main:
+            addi x10, x0, msg
+            j end
+        .rodata
+        ro:
+            .ascii "ro"
+        .text
+        end:
+            addi x11, x0, ro
+        "#;
+
+        let lexemes = Lexer::new().tokenize(source).unwrap();
+        let mut parsed = Parser::new(source, lexemes).parse().unwrap();
+        let (ir, symtab) = parsed.split_mut();
+        let layout = Layout::new(ir, symtab, &FxHashMap::default(), None, false).unwrap();
+
+        // text: 3 instructions at 0, rodata at 12, data at 14
+        let [text, data, rodata] = layout.sections() else {
+            panic!("expected 3 sections");
+        };
+        assert_eq!((text.address(), text.size()), (0, 12));
+        assert_eq!((rodata.address(), rodata.bytes()), (12, &b"ro"[..]));
+        assert_eq!((data.address(), data.bytes()), (14, &b"hi!"[..]));
+
+        let mut lookup = |name: &str| {
+            let id = ir.alloc_str(name);
+            symtab.lookup(text.id(), id)
+        };
+        assert_eq!(lookup("main"), Some((text.id(), 0)));
+        assert_eq!(lookup("end"), Some((text.id(), 8)));
+        assert_eq!(lookup("msg"), Some((data.id(), 0)));
+
+        let word = |index: usize| {
+            let bytes = &text.bytes()[index * 4..index * 4 + 4];
+            isa::Instruction::try_from(u32::from_le_bytes(bytes.try_into().unwrap())).unwrap()
+        };
+        assert_eq!(
+            word(0),
+            isa::Instruction::AddI {
+                dest: isa::Register::X10,
+                src: isa::Register::X0,
+                value: isa::operand::Immediate14::new(14),
+            }
+        );
+        assert_eq!(
+            word(1),
+            isa::Instruction::Jal {
+                dest: isa::Register::X0,
+                offset: isa::operand::Immediate19::new(4),
+            }
+        );
+        assert_eq!(
+            word(2),
+            isa::Instruction::AddI {
+                dest: isa::Register::X11,
+                src: isa::Register::X0,
+                value: isa::operand::Immediate14::new(12),
+            }
+        );
+
+        let image = layout.image().unwrap();
+        assert_eq!(image.len(), 17);
+        assert_eq!(&image[12..], b"rohi!");
+    }
+
+    #[test]
+    fn t_layout_script() {
+        let script = LinkerScript::parse(
+            r#"
+            ENTRY(reset)
+            MEMORY {
+                ROM (rx) : ORIGIN = 0x100, LENGTH = 0x100
+                RAM (rw) : ORIGIN = 0x1000, LENGTH = 0x1000
+            }
+            SECTIONS {
+                .text : { } > ROM
+                .rodata : ALIGN(16) { } > ROM
+                .data : { } > RAM
+                _stack_top = ORIGIN(RAM) + LENGTH(RAM);
+            }
+            "#,
+        )
+        .unwrap();
+
+        let source = br#"
+        .text
+        reset:
+            lui x2, %hi(_stack_top)
+            addi x2, x2, %lo(_stack_top)
+            addi x10, x0, ro
+        .rodata
+        ro:
+            .ascii "ro"
+        .data
+        counter:
+            .word ro
+        "#;
+        let assembly = crate::Assembler::new()
+            .linker_script(script)
+            .assemble(source)
+            .unwrap();
+
+        let [text, rodata, data] = assembly.sections() else {
+            panic!("expected 3 sections");
+        };
+        assert_eq!(text.address(), 0x100);
+        assert_eq!(rodata.address(), 0x110);
+        assert_eq!(data.address(), 0x1000);
+        assert_eq!(data.bytes(), 0x110u32.to_le_bytes());
+        assert_eq!(assembly.entry(), 0x100);
+
+        let word = |index: usize| {
+            let bytes = &text.bytes()[index * 4..index * 4 + 4];
+            isa::Instruction::try_from(u32::from_le_bytes(bytes.try_into().unwrap())).unwrap()
+        };
+        assert_eq!(
+            word(0),
+            isa::Instruction::Lui {
+                dest: isa::Register::X2,
+                value: isa::operand::Immediate19::new(1),
+            }
+        );
+
+        // The script overflows ROM and errors point to no line
+        let script = LinkerScript::parse(
+            "MEMORY { ROM : ORIGIN = 0, LENGTH = 8 } SECTIONS { .text : { } > ROM }",
+        )
+        .unwrap();
+        let err = crate::Assembler::new()
+            .linker_script(script)
+            .assemble(source)
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err,
+            crate::AssemblerError::LayoutError(LayoutError::Script(ScriptError::RegionOverflow {
+                overflow: 4,
+                ..
+            }))
+        ));
+
+        // Sections far from address 0 only fit an object or ELF output
+        let script = LinkerScript::parse(
+            "MEMORY { RAM : ORIGIN = 0x80000000, LENGTH = 0x1000 } SECTIONS { .text : { } > RAM }",
+        )
+        .unwrap();
+        let assembly = crate::Assembler::new()
+            .linker_script(script)
+            .assemble(b"addi x1, x0, 1")
+            .unwrap();
+        assert!(matches!(
+            assembly.image(),
+            Err(crate::AssemblerError::LayoutError(
+                LayoutError::ImageTooLarge(0x8000_0004)
+            ))
+        ));
+        assert!(!assembly.to_elf().is_empty());
+    }
+}
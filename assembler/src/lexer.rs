@@ -20,7 +20,6 @@ impl Lexer {
         let mut lexemes = Lexemes::new(input.len());
 
         while let Some(sequence) = lex.next() {
-            lex.extras.advance_row();
             let token = sequence.map_err(|e| match e {
                 LexingError::Error => LexingError::UnknownSyntax(
                     String::from_utf8(input.get(lex.span()).unwrap().to_vec()).unwrap(),
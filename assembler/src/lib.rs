@@ -6,15 +6,31 @@ mod interner;
 mod ir;
 mod layout;
 mod lexer;
+mod object;
 mod parser;
+mod script;
+mod source;
 mod symbol_table;
 mod token;
 
-use layout::Layout;
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+};
+
+pub use layout::AssembledSection;
+use layout::{Layout, LayoutError};
 use lexer::Lexer;
-use parser::{Parser, ParsingError};
-// use parser::Parser;
-use symbol_table::SymbolTable;
+use object::ObjectWriter;
+use parser::{ParsedData, Parser, ParsingError};
+use rustc_hash::FxHashMap;
+pub use script::{LinkerScript, ScriptError};
+use shared::{
+    elf,
+    object::{Object, ObjectKind},
+};
+use source::SourceMap;
+pub use source::{Location, SourceError};
 use thiserror::Error;
 use token::LexingError;
 
@@ -24,29 +40,353 @@ pub enum AssemblerError {
     LexerError(#[from] LexingError),
     #[error("Parser error: {0}")]
     ParserErrorError(#[from] ParsingError),
+    #[error("Layout error: {0}")]
+    LayoutError(#[from] LayoutError),
+    #[error(transparent)]
+    SourceError(#[from] SourceError),
+}
+
+impl AssemblerError {
+    /// 1-based line of `source` where the error occurred
+    fn line(&self, source: &[u8]) -> Option<usize> {
+        let line_of = |offset: usize| {
+            source[..offset.min(source.len())]
+                .iter()
+                .filter(|&&byte| byte == b'\n')
+                .count()
+                + 1
+        };
+
+        match self {
+            AssemblerError::LexerError(err) => err.row(),
+            AssemblerError::ParserErrorError(err) => err.offset().map(line_of),
+            AssemblerError::LayoutError(err) => err.offset().map(line_of),
+            AssemblerError::SourceError(_) => None,
+        }
+    }
+}
+
+/// An [`AssemblerError`] with the place in the source files it comes from
+#[derive(Error, Debug)]
+pub struct Diagnostic {
+    pub location: Option<Location>,
+    #[source]
+    pub error: AssemblerError,
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.location {
+            Some(location) => write!(f, "{location}: {}", self.error),
+            None => write!(f, "{}", self.error),
+        }
+    }
+}
+
+/// The assembled program
+pub struct Assembly {
+    parsed: ParsedData,
+    layout: Layout,
+    relocatable: bool,
+    /// Symbol given to `ENTRY` in the linker script
+    entry: Option<String>,
+}
+
+impl Assembly {
+    /// Flat binary, loaded at address 0. Built on each call, sections must end within 16 MiB
+    pub fn image(&self) -> Result<Vec<u8>, AssemblerError> {
+        Ok(self.layout.image()?)
+    }
+
+    /// Every section with its address and content
+    pub fn sections(&self) -> &[AssembledSection] {
+        self.layout.sections()
+    }
+
+    /// Address of the linker script `ENTRY`, else of the `_start` label, or `main` without it.
+    /// 0 if none is defined
+    pub fn entry(&self) -> u32 {
+        let ir = self.parsed.ir();
+        let address = |name: &str| {
+            let label = ir.str_tab().get(name).and_then(|str_id| {
+                let (section, value) = self.parsed.symtab().lookup(Default::default(), str_id)?;
+                Some(self.layout.sections()[usize::from(section)].address() + value)
+            });
+            label.or_else(|| {
+                self.layout
+                    .symbols()
+                    .iter()
+                    .find_map(|(symbol, value)| (symbol == name).then_some(*value))
+            })
+        };
+
+        self.entry
+            .as_deref()
+            .into_iter()
+            .chain(["_start", "main"])
+            .find_map(address)
+            .unwrap_or_default()
+    }
+
+    /// Relocatable if assembled with [`Assembler::relocatable`], executable otherwise
+    fn object(&self) -> Object {
+        let kind = match self.relocatable {
+            true => ObjectKind::Relocatable,
+            false => ObjectKind::Executable,
+        };
+        ObjectWriter::new(self.parsed.ir(), self.parsed.symtab(), &self.layout)
+            .object(kind, self.entry())
+    }
+
+    /// Serialize into a rivet object, see [`shared::object`]
+    pub fn to_object(&self) -> Vec<u8> {
+        self.object().to_bytes()
+    }
+
+    /// Serialize into an ELF32 file, see [`shared::elf`]
+    pub fn to_elf(&self) -> Vec<u8> {
+        elf::write(&self.object())
+    }
+
+    /// One little-endian 32-bit word per line in hex, trailing bytes are zero padded
+    pub fn to_hex(&self) -> Result<String, AssemblerError> {
+        Ok(self
+            .image()?
+            .chunks(4)
+            .map(|chunk| {
+                let mut word = [0; 4];
+                word[..chunk.len()].copy_from_slice(chunk);
+                format!("{:08x}\n", u32::from_le_bytes(word))
+            })
+            .collect())
+    }
 }
 
+#[derive(Default)]
 pub struct Assembler {
-    // lexer: Lexer,
-    // parser: Parser<'a>,
+    include_dirs: Vec<PathBuf>,
+    defines: Vec<(String, u32)>,
+    script: Option<LinkerScript>,
+    relocatable: bool,
 }
 
 impl Assembler {
     pub fn new() -> Assembler {
-        Self {
-            // symbol_table: SymbolTable::new(),
-            // lexer: Lexer::new(),
-        }
+        Self::default()
+    }
+
+    /// Look up `.include`d files in `path` when they aren't next to the including file
+    pub fn include_dir(&mut self, path: impl Into<PathBuf>) -> &mut Self {
+        self.include_dirs.push(path.into());
+        self
     }
 
-    pub fn assemble<'source>(&mut self, source: &'source [u8]) -> Result<(), AssemblerError> {
-        let mut symbol_table = SymbolTable::new();
+    /// Define the absolute symbol `name`. Labels with the same name take precedence
+    pub fn define(&mut self, name: impl Into<String>, value: u32) -> &mut Self {
+        self.defines.push((name.into(), value));
+        self
+    }
+
+    /// Place the sections as described by `script` instead of one after the other from address 0
+    pub fn linker_script(&mut self, script: LinkerScript) -> &mut Self {
+        self.script = Some(script);
+        self
+    }
+
+    /// Allow symbols defined in other files, their uses are left to the linker as relocations
+    pub fn relocatable(&mut self, relocatable: bool) -> &mut Self {
+        self.relocatable = relocatable;
+        self
+    }
+
+    /// Assemble a single source. `.include` is only supported by [`Assembler::assemble_file`]
+    pub fn assemble(&mut self, source: &[u8]) -> Result<Assembly, AssemblerError> {
         let lexemes = Lexer::new().tokenize(source)?;
         let mut parsed_data = Parser::new(source, lexemes).parse()?;
-        // println!("P")
 
-        // let layout = Layout::new(parser.);
+        let (ir, symtab) = parsed_data.split_mut();
+        let constants: FxHashMap<_, _> = self
+            .defines
+            .iter()
+            .map(|(name, value)| (ir.alloc_str(name), *value))
+            .collect();
+
+        let script = self.script.as_ref();
+        let layout = Layout::new(ir, symtab, &constants, script, self.relocatable)?;
+
+        Ok(Assembly {
+            parsed: parsed_data,
+            layout,
+            relocatable: self.relocatable,
+            entry: script.and_then(|script| script.entry().map(str::to_owned)),
+        })
+    }
 
-        Ok(())
+    /// Assemble the file at `path` with its `.include`s. Errors point to the file and line they come from
+    pub fn assemble_file(&mut self, path: impl AsRef<Path>) -> Result<Assembly, Diagnostic> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).map_err(|err| Diagnostic {
+            location: None,
+            error: SourceError::Io(path.display().to_string(), err).into(),
+        })?;
+
+        let map = SourceMap::new(path, &text, &self.include_dirs).map_err(|(location, err)| {
+            Diagnostic {
+                location: Some(location),
+                error: err.into(),
+            }
+        })?;
+
+        let source = map.text().as_bytes();
+        self.assemble(source).map_err(|error| Diagnostic {
+            location: error.line(source).map(|line| map.locate_line(line)),
+            error,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use isa::{Instruction, disassembler::Disassembler};
+    use proptest::{prelude::*, sample};
+
+    use super::*;
+
+    /// Any instruction the `isa` can decode, with random operands in the bits after the opcode
+    fn instruction() -> impl Strategy<Value = Instruction> {
+        let opcodes: Vec<u32> = (0..=u8::MAX as u32)
+            .filter(|&opcode| Instruction::try_from(opcode).is_ok())
+            .collect();
+        (sample::select(opcodes), any::<u32>())
+            .prop_map(|(opcode, operands)| Instruction::try_from(operands << 8 | opcode).unwrap())
+    }
+
+    proptest! {
+        #[test]
+        fn t_display_round_trip(instruction in instruction()) {
+            let text = instruction.to_string();
+            let assembly = Assembler::new().assemble(text.as_bytes());
+            prop_assert!(assembly.is_ok(), "`{text}`: {:?}", assembly.err());
+
+            let image = assembly.unwrap().image().unwrap();
+            prop_assert_eq!(image, u32::from(&instruction).to_le_bytes(), "`{}`", text);
+        }
+    }
+
+    #[test]
+    fn t_csr() {
+        use isa::{Csr, Register, operand::UImmediate5};
+
+        let source = b"
+            csrr a0, cycle
+            csrw scratch, a1
+            csrrci t0, 0x7d0, 3
+        ";
+        let image = Assembler::new().assemble(source).unwrap().image().unwrap();
+        let expected: Vec<u8> = [
+            Instruction::Csrrs {
+                dest: Register::X10,
+                src: Register::X0,
+                csr: Csr::CYCLE,
+            },
+            Instruction::Csrrw {
+                dest: Register::X0,
+                src: Register::X11,
+                csr: Csr::SCRATCH,
+            },
+            Instruction::Csrrci {
+                dest: Register::X5,
+                value: UImmediate5::new(3),
+                csr: Csr::new(0x7d0).unwrap(),
+            },
+        ]
+        .iter()
+        .flat_map(|instruction| u32::from(instruction).to_le_bytes())
+        .collect();
+        assert_eq!(image, expected);
+
+        assert!(Assembler::new().assemble(b"csrr a0, 0x1000").is_err());
+        assert!(Assembler::new().assemble(b"csrrwi a0, cycle, 32").is_err());
+    }
+
+    #[test]
+    fn t_li() {
+        use isa::{
+            Register,
+            operand::{Immediate14, Immediate19},
+        };
+
+        let source = b"
+            li x5, -100
+            li x6, 0x12345678
+            li x7, 0xffff0000
+            li x8, value
+            value:
+        ";
+        let image = Assembler::new().assemble(source).unwrap().image().unwrap();
+        let addi = |dest, src, value| Instruction::AddI {
+            dest,
+            src,
+            value: Immediate14::new(value),
+        };
+        let lui = |dest, value| Instruction::Lui {
+            dest,
+            value: Immediate19::new(value),
+        };
+        let expected: Vec<u8> = [
+            addi(Register::X5, Register::X0, -100),
+            lui(Register::X6, 0x12345678 >> 13),
+            addi(Register::X6, Register::X6, 0x12345678 & 0x1fff),
+            lui(Register::X7, -8),
+            addi(Register::X7, Register::X7, 0),
+            // `value` is at 28, its upper bits are 0
+            lui(Register::X8, 0),
+            addi(Register::X8, Register::X8, 28),
+        ]
+        .iter()
+        .flat_map(|instruction| u32::from(instruction).to_le_bytes())
+        .collect();
+        assert_eq!(image, expected);
+
+        assert!(Assembler::new().assemble(b"li a0, 0x100000000").is_err());
+    }
+
+    #[test]
+    fn t_disassembly_round_trip() {
+        let source = b"
+            _start:
+                addi x10, x0, -200
+                lui x5, 0x3ffff
+                lw x6, -4(x2)
+                sw x6, 8(x8)
+                lbu x7, 3(x5)
+            loop:
+                shra x1, x2, x3
+                sltiu x4, x5, 7
+                mulhsu x31, x30, x29
+                beq x6, x0, loop
+                jal x1, _start
+                jalr x0, x1, 0
+                syscall x0, x0, x0
+                csrrs x10, instret, x0
+                mret
+        ";
+        let image = Assembler::new().assemble(source).unwrap().image().unwrap();
+
+        for abi_names in [false, true] {
+            let mut disassembler = Disassembler::new();
+            disassembler.abi_names(abi_names);
+            let text: String = (0..)
+                .step_by(4)
+                .zip(image.chunks(4))
+                .map(|(address, word)| {
+                    let word = u32::from_le_bytes(word.try_into().unwrap());
+                    disassembler.word(word, address) + "\n"
+                })
+                .collect();
+
+            let reassembled = Assembler::new().assemble(text.as_bytes()).unwrap();
+            assert_eq!(reassembled.image().unwrap(), image, "{text}");
+        }
     }
 }
@@ -1,3 +1,140 @@
-fn main() {
-    println!("Hello, world!");
+use std::{fs, path::PathBuf, process::ExitCode};
+
+use assembler::{Assembler, LinkerScript};
+use clap::{Parser, ValueEnum};
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Format {
+    /// Flat binary, loaded at address 0
+    Bin,
+    /// One 32-bit word per line in hex
+    Hex,
+    /// Rivet relocatable object, may reference symbols of other files
+    Object,
+    /// ELF32 relocatable object (`ET_REL`)
+    Elf,
+    /// ELF32 executable (`ET_EXEC`), every symbol must be defined
+    ElfExec,
+}
+
+/// Assemble a RIVET assembly file
+#[derive(Debug, Parser)]
+#[command(name = "rivet-as", version)]
+struct Args {
+    input: PathBuf,
+    /// Output file, defaults to the input with the format's extension
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    #[arg(short, long, value_enum, default_value_t = Format::Bin)]
+    format: Format,
+    /// Directory searched for `.include`d files
+    #[arg(short = 'I', long = "include", value_name = "DIR")]
+    include_dirs: Vec<PathBuf>,
+    /// Define the symbol `NAME`, `VALUE` defaults to 1
+    #[arg(short = 'D', value_name = "NAME[=VALUE]", value_parser = parse_define)]
+    defines: Vec<(String, u32)>,
+    /// Linker script placing the sections, they follow each other from address 0 without it
+    #[arg(short = 'T', long = "script", value_name = "FILE")]
+    script: Option<PathBuf>,
+}
+
+fn parse_define(define: &str) -> Result<(String, u32), String> {
+    let (name, value) = define.split_once('=').unwrap_or((define, "1"));
+    if name.is_empty() {
+        return Err("missing symbol name".to_owned());
+    }
+
+    let value = value.trim();
+    let (negative, digits) = match value.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, value),
+    };
+    let parsed = if let Some(hex) = digits.strip_prefix("0x") {
+        u32::from_str_radix(hex, 16)
+    } else if let Some(binary) = digits.strip_prefix("0b") {
+        u32::from_str_radix(binary, 2)
+    } else {
+        digits.parse::<u32>()
+    }
+    .map_err(|err| format!("invalid value `{value}`: {err}"))?;
+
+    let parsed = if negative {
+        (parsed as i32).wrapping_neg() as u32
+    } else {
+        parsed
+    };
+
+    Ok((name.to_owned(), parsed))
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+
+    let mut assembler = Assembler::new();
+    assembler.relocatable(matches!(args.format, Format::Object | Format::Elf));
+    for dir in &args.include_dirs {
+        assembler.include_dir(dir);
+    }
+    for (name, value) in &args.defines {
+        assembler.define(name, *value);
+    }
+    if let Some(path) = &args.script {
+        let script = fs::read_to_string(path)
+            .map_err(|err| format!("unable to read `{}`: {err}", path.display()))
+            .and_then(|text| LinkerScript::parse(&text).map_err(|err| err.to_string()));
+        match script {
+            Ok(script) => assembler.linker_script(script),
+            Err(err) => {
+                eprintln!("rivet-as: {err}");
+                return ExitCode::FAILURE;
+            }
+        };
+    }
+
+    let assembly = match assembler.assemble_file(&args.input) {
+        Ok(assembly) => assembly,
+        Err(err) => {
+            eprintln!("{err}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let output = match args.format {
+        Format::Bin => assembly.image().map(|image| ("bin", image)),
+        Format::Hex => assembly.to_hex().map(|hex| ("hex", hex.into_bytes())),
+        Format::Object => Ok(("o", assembly.to_object())),
+        Format::Elf => Ok(("o", assembly.to_elf())),
+        Format::ElfExec => Ok(("elf", assembly.to_elf())),
+    };
+    let (extension, bytes) = match output {
+        Ok(output) => output,
+        Err(err) => {
+            eprintln!("rivet-as: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let output = args
+        .output
+        .unwrap_or_else(|| args.input.with_extension(extension));
+
+    if let Err(err) = fs::write(&output, bytes) {
+        eprintln!("rivet-as: unable to write `{}`: {err}", output.display());
+        return ExitCode::FAILURE;
+    }
+
+    ExitCode::SUCCESS
+}
+
+#[cfg(test)]
+mod test {
+    use super::parse_define;
+
+    #[test]
+    fn t_parse_define() {
+        assert_eq!(parse_define("DEBUG"), Ok(("DEBUG".to_owned(), 1)));
+        assert_eq!(parse_define("BASE=0x100"), Ok(("BASE".to_owned(), 0x100)));
+        assert_eq!(parse_define("N=-1"), Ok(("N".to_owned(), u32::MAX)));
+        assert!(parse_define("=1").is_err());
+        assert!(parse_define("N=abc").is_err());
+    }
 }
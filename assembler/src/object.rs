@@ -0,0 +1,235 @@
+use rustc_hash::FxHashMap;
+use shared::object::{
+    Object, ObjectKind, ObjectSection, ObjectSymbol, Relocation, SectionContent, SymbolSection,
+    SymbolVisibility,
+};
+
+use crate::{
+    asm::section::{ContentType, SectionId},
+    interner::StrId,
+    ir::IR,
+    layout::Layout,
+    symbol_table::{SymbolKind, SymbolTable, Visibility},
+};
+
+/// Builds the rivet object of a laid out program
+pub struct ObjectWriter<'a> {
+    ir: &'a IR,
+    symtab: &'a SymbolTable,
+    layout: &'a Layout,
+}
+
+impl<'a> ObjectWriter<'a> {
+    pub fn new(ir: &'a IR, symtab: &'a SymbolTable, layout: &'a Layout) -> Self {
+        Self { ir, symtab, layout }
+    }
+
+    /// Executables keep no relocation, every symbol is resolved
+    pub fn object(&self, kind: ObjectKind, entry: u32) -> Object {
+        // The interner becomes the string table, `offsets[str_id]` is the position of the string
+        let mut strtab = vec![0];
+        let offsets: Vec<u32> = self
+            .ir
+            .str_tab()
+            .strings()
+            .iter()
+            .map(|string| {
+                let offset = strtab.len() as u32;
+                strtab.extend_from_slice(string.as_bytes());
+                strtab.push(0);
+                offset
+            })
+            .collect();
+        let name = |str_id: StrId| offsets[usize::from(str_id)];
+
+        let sections = self
+            .layout
+            .sections()
+            .iter()
+            .map(|assembled| {
+                let section = self.ir.sections().get(assembled.id());
+                ObjectSection {
+                    name: name(section.tag().strid()),
+                    content: match section.content_type() {
+                        ContentType::Progbits => SectionContent::Progbits,
+                        ContentType::Nobits => SectionContent::Nobits,
+                    },
+                    flags: section.flags().bits(),
+                    alignment: section.alignment(),
+                    address: assembled.address(),
+                    size: assembled.size(),
+                    data: assembled.bytes().to_vec(),
+                }
+            })
+            .collect();
+
+        // Labels first, section by section, then the symbols defined in other files
+        let mut symbols = Vec::new();
+        let mut indices: FxHashMap<(Option<SectionId>, StrId), u32> = FxHashMap::default();
+        let mut locals: Vec<_> = self.symtab.locals().iter().collect();
+        locals.sort_by_key(|(section, _)| usize::from(**section));
+
+        for (section, labels) in locals {
+            for label in labels {
+                indices.insert((Some(*section), label.name()), symbols.len() as u32);
+                symbols.push(ObjectSymbol {
+                    name: name(label.name()),
+                    value: label.value().unwrap_or_default(),
+                    section: SymbolSection::Index(u8::from(*section) as u16),
+                    visibility: match label.visibility() {
+                        Visibility::Local => SymbolVisibility::Local,
+                        Visibility::Global => SymbolVisibility::Global,
+                    },
+                });
+            }
+        }
+
+        // Linker script symbols are absolute. They stay local to relocatable objects, every object
+        // assembled with the same script has them
+        for (script_name, value) in self.layout.symbols() {
+            symbols.push(ObjectSymbol {
+                name: strtab.len() as u32,
+                value: *value,
+                section: SymbolSection::Absolute,
+                visibility: match kind {
+                    ObjectKind::Relocatable => SymbolVisibility::Local,
+                    ObjectKind::Executable => SymbolVisibility::Global,
+                },
+            });
+            strtab.extend_from_slice(script_name.as_bytes());
+            strtab.push(0);
+        }
+
+        let undefined =
+            |symbols: &mut Vec<ObjectSymbol>, indices: &mut FxHashMap<_, u32>, str_id: StrId| {
+                *indices.entry((None, str_id)).or_insert_with(|| {
+                    symbols.push(ObjectSymbol {
+                        name: name(str_id),
+                        value: 0,
+                        section: SymbolSection::Undefined,
+                        visibility: SymbolVisibility::Global,
+                    });
+                    symbols.len() as u32 - 1
+                })
+            };
+
+        for global in self.symtab.globals() {
+            if !global.is_defined() {
+                undefined(&mut symbols, &mut indices, global.name());
+            }
+        }
+
+        let mut relocations = Vec::new();
+        let sections_to_relocate = match kind {
+            ObjectKind::Relocatable => self.layout.sections(),
+            ObjectKind::Executable => &[],
+        };
+        for assembled in sections_to_relocate {
+            for relocation in assembled.relocations() {
+                let symbol = match relocation.target {
+                    Some(target) => indices[&(Some(target), relocation.symbol)],
+                    None => undefined(&mut symbols, &mut indices, relocation.symbol),
+                };
+
+                relocations.push(Relocation {
+                    section: u8::from(assembled.id()) as u16,
+                    offset: relocation.offset,
+                    symbol,
+                    kind: relocation.kind,
+                    addend: relocation.addend,
+                });
+            }
+        }
+
+        Object {
+            kind,
+            entry,
+            sections,
+            symbols,
+            relocations,
+            strtab,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use shared::object::RelocationKind;
+
+    use crate::Assembler;
+
+    use super::*;
+
+    #[test]
+    fn t_object_symbols_and_relocations() {
+        let source = br#"
+        .global main
+        .global puts
+        main:
+            addi x10, x0, msg
+            call puts
+            call local
+        local:
+            ret
+        .data
+        msg:
+            .ascii "hey"
+        "#;
+
+        let mut assembler = Assembler::new();
+        assembler.relocatable(true);
+        let assembly = assembler.assemble(source).unwrap();
+        let object = Object::read(&assembly.to_object()).unwrap();
+
+        let names: Vec<_> = object
+            .sections
+            .iter()
+            .map(|section| object.name(section.name).unwrap())
+            .collect();
+        assert_eq!(names, [".text", ".data"]);
+        assert_eq!(object.sections[1].address, 16);
+        assert_eq!(object.sections[1].data, b"hey");
+
+        let symbol = |name: &str| {
+            object
+                .symbols
+                .iter()
+                .position(|symbol| object.name(symbol.name).unwrap() == name)
+                .unwrap()
+        };
+        assert_eq!(
+            object.symbols[symbol("main")].visibility,
+            SymbolVisibility::Global
+        );
+        assert_eq!(object.symbols[symbol("local")].value, 12);
+        assert_eq!(
+            object.symbols[symbol("msg")].section,
+            SymbolSection::Index(1)
+        );
+        assert_eq!(
+            object.symbols[symbol("puts")].section,
+            SymbolSection::Undefined
+        );
+
+        // `call local` stays in .text and needs no relocation
+        assert_eq!(
+            object.relocations,
+            [
+                Relocation {
+                    section: 0,
+                    offset: 0,
+                    symbol: symbol("msg") as u32,
+                    kind: RelocationKind::Abs14,
+                    addend: 0,
+                },
+                Relocation {
+                    section: 0,
+                    offset: 4,
+                    symbol: symbol("puts") as u32,
+                    kind: RelocationKind::Jump19,
+                    addend: 0,
+                },
+            ]
+        );
+    }
+}
@@ -6,6 +6,7 @@ use shared::EnumCount;
 
 use crate::{
     asm::directive::DirectiveType,
+    instruction::PseudoMnemonic,
     token::{self, IdentifierType, Token},
 };
 
@@ -82,11 +83,20 @@ impl PartialEq<RuleToken> for Token {
         //IMPORTANT: Don't add hidden token here
         match (self, other) {
             (Token::Identifier(IdentifierType::Register(_)), Register)
-            | (Token::Label, Label)
+            // Branch and jump targets are labels or offsets from the instruction
+            | (
+                Token::Identifier(IdentifierType::Symbol)
+                | Token::LiteralDecimal
+                | Token::LiteralHex
+                | Token::LiteralBinary,
+                Label,
+            )
             | (
                 Token::LiteralDecimal
                 | Token::LiteralHex
                 | Token::LiteralBinary
+                | Token::Hi
+                | Token::Lo
                 | Token::Identifier(IdentifierType::Symbol),
                 SymbolOrNumeric,
             )
@@ -123,11 +133,13 @@ impl Display for RuleToken {
             InstructionOrDir => write!(f, "{}|{}", Token::mnemonic(), Token::directive()),
             SymbolOrNumeric => write!(
                 f,
-                "{}|{}|{}|{}",
+                "{}|{}|{}|{}|{}|{}",
                 Token::symbol(),
                 Token::LiteralDecimal,
                 Token::LiteralHex,
-                Token::LiteralBinary
+                Token::LiteralBinary,
+                Token::Hi,
+                Token::Lo
             ),
             Operator => write!(f, "{}|{}", Token::Positive, Token::Negative),
             OperatorOrBreak => write!(f, "{}|{}|{}", Token::Eol, Token::Eof, Operator.to_string()),
@@ -141,10 +153,10 @@ pub struct InstructionRule {
 }
 
 impl InstructionRule {
-    pub fn new(mnemonic: Mnemonic) -> InstructionRule {
+    pub fn new(ty: impl Into<OperandRuleType>) -> InstructionRule {
         InstructionRule {
             sequence: RuleToken::sequence(),
-            ty: OperandRuleType::from(mnemonic),
+            ty: ty.into(),
         }
     }
 
@@ -154,25 +166,25 @@ impl InstructionRule {
 
     pub fn generate_sequence(&mut self) -> &[RuleToken] {
         use RuleToken::*;
-        let last_id: usize = match self.ty {
+        let len: usize = match self.ty {
             OperandRuleType::R3 => {
                 // [Register, Comma, Register, Comma, Register]
                 self.sequence[2] = Register;
                 self.sequence[3] = Comma;
                 self.sequence[4] = Register;
-                4
+                5
             }
             OperandRuleType::R2I => {
                 // [Register, Comma, Register, Comma, SymbolOrNumeric]
                 self.sequence[2] = Register;
                 self.sequence[3] = Comma;
                 self.sequence[4] = SymbolOrNumeric;
-                4
+                5
             }
-            OperandRuleType::RI => {
+            OperandRuleType::RI | OperandRuleType::RW => {
                 // [Register, Comma, SymbolOrNumeric]
                 self.sequence[2] = SymbolOrNumeric;
-                2
+                3
             }
             OperandRuleType::RIR => {
                 // [Register, Comma, SymbolOrNumeric, ParenL, Register, ParenR]
@@ -180,23 +192,60 @@ impl InstructionRule {
                 self.sequence[3] = ParenL;
                 self.sequence[4] = Register;
                 self.sequence[5] = ParenR;
-                5
+                6
             }
             OperandRuleType::R2L => {
                 // [Register, Comma, Register, Comma, Label]
                 self.sequence[2] = Register;
                 self.sequence[3] = Comma;
                 self.sequence[4] = Label;
-                4
+                5
             }
             OperandRuleType::RL => {
                 // [Register, Comma, Label]
                 self.sequence[2] = Label;
-                2
+                3
             }
+            OperandRuleType::R2 => {
+                // [Register, Comma, Register]
+                self.sequence[2] = Register;
+                3
+            }
+            OperandRuleType::R => {
+                // [Register]
+                1
+            }
+            OperandRuleType::L => {
+                // [Label]
+                self.sequence[0] = Label;
+                1
+            }
+            OperandRuleType::RCR => {
+                // [Register, Comma, SymbolOrNumeric, Comma, Register]
+                self.sequence[3] = Comma;
+                self.sequence[4] = Register;
+                5
+            }
+            OperandRuleType::RCI => {
+                // [Register, Comma, SymbolOrNumeric, Comma, SymbolOrNumeric]
+                self.sequence[3] = Comma;
+                self.sequence[4] = SymbolOrNumeric;
+                5
+            }
+            OperandRuleType::RC => {
+                // [Register, Comma, SymbolOrNumeric]
+                3
+            }
+            OperandRuleType::CR => {
+                // [SymbolOrNumeric, Comma, Register]
+                self.sequence[0] = SymbolOrNumeric;
+                self.sequence[2] = Register;
+                3
+            }
+            OperandRuleType::Empty => 0,
         };
 
-        self.sequence.get(0..last_id + 1).unwrap()
+        self.sequence.get(0..len).unwrap()
     }
 }
 
@@ -214,8 +263,26 @@ pub enum OperandRuleType {
     RIR,
     ///Register, Immediate
     RI,
+    ///Register, 32-bit immediate
+    RW,
     ///Register, Label
     RL,
+    ///Register, Register
+    R2,
+    ///Register
+    R,
+    ///Label
+    L,
+    ///Register, Csr, Register
+    RCR,
+    ///Register, Csr, Immediate
+    RCI,
+    ///Register, Csr
+    RC,
+    ///Csr, Register
+    CR,
+    ///No operand
+    Empty,
 }
 
 impl OperandRuleType {
@@ -223,6 +290,15 @@ impl OperandRuleType {
     pub(crate) const fn noises_in_every() -> usize {
         2
     }
+
+    /// Position of the CSR operand, whose names aren't symbols
+    pub(crate) const fn csr_index(&self) -> Option<usize> {
+        match self {
+            Self::RCR | Self::RCI | Self::RC => Some(1),
+            Self::CR => Some(0),
+            _ => None,
+        }
+    }
 }
 
 impl From<InstructionType> for OperandRuleType {
@@ -248,17 +324,69 @@ impl From<Mnemonic> for OperandRuleType {
             Add => Self::R3,
             Sub => Self::R3,
             Mul => Self::R3,
+            Mulh => Self::R3,
+            Mulhu => Self::R3,
+            Mulhsu => Self::R3,
+            Div => Self::R3,
+            Divu => Self::R3,
+            Rem => Self::R3,
+            Remu => Self::R3,
             And => Self::R3,
             Or => Self::R3,
             Xor => Self::R3,
             Shl => Self::R3,
             Shr => Self::R3,
             ShrA => Self::R3,
+            Slt => Self::R3,
+            Sltu => Self::R3,
             AddI => Self::R2I,
+            SltI => Self::R2I,
+            SltIU => Self::R2I,
+            AndI => Self::R2I,
+            OrI => Self::R2I,
+            XorI => Self::R2I,
+            SllI => Self::R2I,
+            SrlI => Self::R2I,
+            SraI => Self::R2I,
             Lui => Self::RI,
             Lw => Self::RIR,
             Sw => Self::RIR,
+            Lb => Self::RIR,
+            Lh => Self::RIR,
+            Lbu => Self::RIR,
+            Lhu => Self::RIR,
+            Sb => Self::RIR,
+            Sh => Self::RIR,
+            Beq => Self::R2L,
+            Bne => Self::R2L,
+            Blt => Self::R2L,
+            Bge => Self::R2L,
+            Bltu => Self::R2L,
+            Bgeu => Self::R2L,
+            Jal => Self::RL,
+            Jalr => Self::R2I,
             Syscall => Self::R3,
+            Ebreak => Self::Empty,
+            Csrrw | Csrrs | Csrrc => Self::RCR,
+            Csrrwi | Csrrsi | Csrrci => Self::RCI,
+            Mret => Self::Empty,
+        }
+    }
+}
+
+impl From<PseudoMnemonic> for OperandRuleType {
+    fn from(value: PseudoMnemonic) -> Self {
+        use PseudoMnemonic::*;
+        match value {
+            Nop => Self::Empty,
+            Mv => Self::R2,
+            Li => Self::RW,
+            Call => Self::L,
+            Ret => Self::Empty,
+            J => Self::L,
+            Jr => Self::R,
+            Csrr => Self::RC,
+            Csrw | Csrs | Csrc => Self::CR,
         }
     }
 }
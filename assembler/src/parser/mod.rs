@@ -1,7 +1,7 @@
 pub mod grammar;
 
 use grammar::{OperandRuleType, RuleToken};
-use shared::{ChunksExt, EnumCount};
+use shared::{ChunksExt, EnumCount, EnumVariants};
 use std::{
     fmt::{Debug, Display},
     ops::Range,
@@ -11,12 +11,12 @@ use thiserror::Error;
 use crate::{
     asm::directive::DirectiveType,
     exprs::Exprs,
-    instruction::{Operand, OperandError, Operands, OperandsIndex},
+    instruction::{Operand, OperandError, Operands, OperandsIndex, PseudoMnemonic},
     interner::StrId,
-    ir::{IR, IRError, Node},
+    ir::{IR, IRError, Node, Word},
     lexer::{Lexeme, Lexemes, LexemesSlice},
     symbol_table::{ConstantSymbol, SymbolError, SymbolTable},
-    token::{self, IdentifierType, Token},
+    token::{self, IdentifierType, LiteralIntegerType, Token},
 };
 
 fn on_invalid_grammar<'a>(found: &Option<String>) -> String {
@@ -49,6 +49,9 @@ pub enum ParsingError {
     IRError(#[from] IRError),
     #[error(" symbol {0}")]
     SymbolError(#[from] SymbolError),
+    /// Byte offset in the source of the token that caused the error
+    #[error("{1}")]
+    Located(usize, Box<ParsingError>),
     //     #[error("Undefined symbol: {0}")]
     //     UndefinedSymbol(String),
 }
@@ -83,12 +86,36 @@ macro_rules! expect_token {
 }
 pub(crate) use expect_token;
 
+impl ParsingError {
+    /// Byte offset in the source where the error occurred
+    pub fn offset(&self) -> Option<usize> {
+        match self {
+            ParsingError::Located(offset, _) => Some(*offset),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct ParsedData {
     symtab: SymbolTable,
     ir: IR,
 }
 
+impl ParsedData {
+    pub fn symtab(&self) -> &SymbolTable {
+        &self.symtab
+    }
+
+    pub fn ir(&self) -> &IR {
+        &self.ir
+    }
+
+    pub fn split_mut(&mut self) -> (&mut IR, &mut SymbolTable) {
+        (&mut self.ir, &mut self.symtab)
+    }
+}
+
 pub struct Parser<'a> {
     lexemes: Lexemes,
     index: usize,
@@ -112,7 +139,12 @@ impl<'a> Parser<'a> {
 
     pub fn parse(mut self) -> Result<ParsedData, ParsingError> {
         while let Some(token) = self.eat() {
-            self.walk(*token)?;
+            if let Err(err) = self.walk(*token) {
+                return Err(ParsingError::Located(
+                    self.current_span().start,
+                    Box::new(err),
+                ));
+            }
         }
 
         // self.ir.print_sections();
@@ -216,6 +248,110 @@ impl<'a> Parser<'a> {
         self.source.get(self.current_span().to_owned()).unwrap()
     }
 
+    /// Check the current line against the operands `rule` and collect its operands
+    fn parse_operands(
+        &mut self,
+        mut rule: grammar::InstructionRule,
+    ) -> Result<Operands, ParsingError> {
+        let mut lexemes = self.peek_line();
+        let rule_sequence = rule.generate_sequence();
+
+        // Syntax analysis
+        if let Some(mismatch) = rule_sequence
+            .iter()
+            .zip(lexemes.by_ref())
+            .find(|(rule_token, lex)| *lex.token() != **rule_token)
+        {
+            let (rule_token, lexeme) = mismatch;
+
+            return Err(ParsingError::UnexpectedToken {
+                expected: *rule_token,
+                found: std::str::from_utf8(self.source.get(lexeme.span().to_owned()).unwrap())
+                    .unwrap()
+                    .to_owned()
+                    .into(),
+            });
+        };
+
+        let seq_len = rule_sequence.len();
+        let rule_residue = seq_len.saturating_sub(lexemes.token_len());
+        // check whether the input is:
+        match (rule_residue > 0, &lexemes.next()) {
+            //too little
+            (true, None) => {
+                let rule_token = rule_sequence[seq_len - rule_residue];
+                return Err(ParsingError::UnexpectedToken {
+                    expected: rule_token,
+                    found: None,
+                });
+            }
+            //too much
+            (false, Some(lexeme)) => {
+                if *lexeme.token() != RuleToken::Break {
+                    return Err(ParsingError::UnexpectedToken {
+                        expected: RuleToken::Break,
+                        found: std::str::from_utf8(
+                            self.source.get(lexeme.span().to_owned()).unwrap(),
+                        )
+                        .unwrap()
+                        .to_owned()
+                        .into(),
+                    });
+                }
+            }
+            //impossible
+            _ => {}
+        };
+        let rule_ty = rule.ty();
+        drop(rule);
+
+        // Value analysis
+        lexemes.reset();
+
+        // Iterate over indices instead of the actual item to bypass the error `cannot borrow as mutable bcs it's also borrowed as immutable`
+        let mut remainder_range = lexemes.range_index().clone();
+        // remove Eol/Eof
+        remainder_range.end -= 1;
+
+        let range_iter = remainder_range.step_by(OperandRuleType::noises_in_every());
+
+        let mut operand_types = [Operand::None; OperandsIndex::VARIANT_COUNT];
+        for (i, op_idx) in range_iter.zip(0..OperandsIndex::VARIANT_COUNT + 1) {
+            let lexeme = self.lexemes.get_unchecked(i);
+            let slice = self.source.get(lexeme.span().to_owned()).unwrap();
+            let token = *lexeme.token();
+
+            let mut operand: Operand = (token, rule_ty, slice).try_into()?;
+            // CSR names aren't symbols
+            let csr = match operand {
+                Operand::Symbol(_) if rule_ty.csr_index() == Some(op_idx) => {
+                    isa::Csr::from_name(std::str::from_utf8(slice).unwrap())
+                }
+                _ => None,
+            };
+            if let Some(csr) = csr {
+                operand = Operand::Csr(csr);
+            }
+            match operand {
+                Operand::Symbol(ref mut str_id) => {
+                    *str_id = self.ir.alloc_str(std::str::from_utf8(slice).unwrap());
+                }
+                // `%hi(symbol)`
+                Operand::Hi(ref mut str_id) | Operand::Lo(ref mut str_id) => {
+                    let name = std::str::from_utf8(&slice[4..slice.len() - 1]).unwrap();
+                    *str_id = self.ir.alloc_str(name.trim());
+                }
+                _ => {}
+            }
+
+            operand_types[op_idx] = operand;
+        }
+
+        let mut operands = Operands::new();
+        operands.memcpy(&operand_types);
+        Ok(operands)
+    }
+
     fn walk(&mut self, token: Token) -> Result<(), ParsingError> {
         match token {
             Token::Directive(dir_type) => {
@@ -250,6 +386,8 @@ impl<'a> Parser<'a> {
                         let box_str = unsafe { std::str::from_boxed_utf8_unchecked(slice.into()) };
 
                         self.ir.push(Node::String(box_str));
+
+                        self.advance();
                     }
                     DirectiveType::Global => {
                         let symbol = expect_token!(
@@ -311,7 +449,34 @@ impl<'a> Parser<'a> {
 
                         self.advance_line();
                     }
-                    DirectiveType::Byte | DirectiveType::Half | DirectiveType::Word => {
+                    DirectiveType::Word => {
+                        // `.word value[, value]*`, every other token is a comma
+                        for chunk in self.peek_line_indices().chunks(2) {
+                            let value = expect_token!(
+                                self.lexemes.get(*chunk.start()),
+                                token::symbol_or_numeric!(),
+                                RuleToken::SymbolOrNumeric
+                            )?;
+                            let offset = value.span().start;
+                            let slice = self.source.get(value.span().to_owned()).unwrap();
+                            let word = match *value.token() {
+                                token::symbol!() => Word::Symbol(
+                                    self.ir.alloc_str(std::str::from_utf8(slice).unwrap()),
+                                ),
+                                literal => Word::Value(parse_word(literal, slice)?),
+                            };
+                            self.ir.push(Node::Word(word, offset));
+
+                            expect_token!(
+                                self.lexemes.get(*chunk.end()),
+                                Token::Comma | token::break_kind!(),
+                                RuleToken::Comma
+                            )?;
+                        }
+
+                        self.advance_line();
+                    }
+                    DirectiveType::Byte | DirectiveType::Half => {
                         return Err(ParsingError::UnimplementedFeature(RuntimeTodo::Dir(
                             dir_type,
                         )));
@@ -342,7 +507,7 @@ impl<'a> Parser<'a> {
                 // syntax analysis
                 expect_token!(
                     self.peek(),
-                    Token::Identifier(IdentifierType::Mnemonic(_))
+                    Token::Identifier(IdentifierType::Mnemonic(_) | IdentifierType::Pseudo(_))
                         | Token::Directive(_)
                         | Token::Eol
                         | Token::Eof,
@@ -357,97 +522,24 @@ impl<'a> Parser<'a> {
                 self.ir.push(Node::Label(str_id));
             }
             Token::Identifier(IdentifierType::Mnemonic(mnemonic)) => {
-                let mut lexemes = self.peek_line();
-                let mut rule = grammar::InstructionRule::new(mnemonic);
-                let rule_sequence = rule.generate_sequence();
-
-                // Syntax analysis
-                if let Some(mismatch) = rule_sequence
-                    .iter()
-                    .zip(lexemes.by_ref())
-                    .find(|(rule_token, lex)| *lex.token() != **rule_token)
-                {
-                    let (rule_token, lexeme) = mismatch;
-
-                    return Err(ParsingError::UnexpectedToken {
-                        expected: *rule_token,
-                        found: std::str::from_utf8(
-                            self.source.get(lexeme.span().to_owned()).unwrap(),
-                        )
-                        .unwrap()
-                        .to_owned()
-                        .into(),
-                    });
-                };
-
-                let seq_len = rule_sequence.len();
-                let rule_residue = seq_len.saturating_sub(lexemes.token_len());
-                // check whether the input is:
-                match (rule_residue > 0, &lexemes.next()) {
-                    //too little
-                    (true, None) => {
-                        let rule_token = rule_sequence[seq_len - rule_residue];
-                        return Err(ParsingError::UnexpectedToken {
-                            expected: rule_token,
-                            found: None,
-                        });
-                    }
-                    //too much
-                    (false, Some(lexeme)) => {
-                        if *lexeme.token() != RuleToken::Break {
-                            return Err(ParsingError::UnexpectedToken {
-                                expected: RuleToken::Break,
-                                found: std::str::from_utf8(
-                                    self.source.get(lexeme.span().to_owned()).unwrap(),
-                                )
-                                .unwrap()
-                                .to_owned()
-                                .into(),
-                            });
-                        }
-                    }
-                    //impossible
-                    _ => {}
-                };
-                let rule_ty = rule.ty();
-                drop(rule);
-
-                // Value analysis
-                lexemes.reset();
-
-                // Iterate over indices instead of the actual item to bypass the error `cannot borrow as mutable bcs it's also borrowed as immutable`
-                let mut remainder_range = lexemes.range_index().clone();
-                // remove Eol/Eof
-                remainder_range.end -= 1;
-
-                let range_iter = remainder_range.step_by(OperandRuleType::noises_in_every());
-
-                let mut operand_types = [Operand::None; OperandsIndex::VARIANT_COUNT];
-                for (i, op_idx) in range_iter.zip(0..OperandsIndex::VARIANT_COUNT + 1) {
-                    let lexeme = self.lexemes.get_unchecked(i);
-                    let slice = self.source.get(lexeme.span().to_owned()).unwrap();
-                    let token = *lexeme.token();
-
-                    let mut operand: Operand = (token, rule_ty, slice).try_into()?;
-                    if let Operand::Symbol(ref mut str_id) = operand {
-                        *str_id = self.ir.alloc_str(std::str::from_utf8(slice).unwrap());
-                    }
-
-                    operand_types[op_idx] = operand;
-                }
-
-                let mut operands = Operands::new();
-                operands.memcpy(&operand_types);
+                let operands = self.parse_operands(grammar::InstructionRule::new(mnemonic))?;
                 let ins = crate::instruction::Instruction::new(mnemonic, operands);
-                println!("Instruction IR: {:?}", ins);
 
-                // // let pseudo = PseudoInstruction
-                self.ir.add_instruction(ins);
+                self.ir.add_instruction(ins, self.current_span().start);
                 self.advance_line();
             }
-            token::break_kind!() => {
-                println!("=== BREAK ===");
+            Token::Identifier(IdentifierType::Pseudo(pseudo)) => {
+                let operands = self.parse_operands(grammar::InstructionRule::new(pseudo))?;
+                let (ins, second) = pseudo.expand(operands);
+
+                let offset = self.current_span().start;
+                self.ir.add_instruction(ins, offset);
+                if let Some(second) = second {
+                    self.ir.add_instruction(second, offset);
+                }
+                self.advance_line();
             }
+            token::break_kind!() => {}
             _ => {
                 return Err(ParsingError::SyntaxError);
             }
@@ -458,10 +550,28 @@ impl<'a> Parser<'a> {
     }
 }
 
+/// A literal integer as a 32-bit word. Negative values are stored in two's complement
+pub(crate) fn parse_word(literal: Token, slice: &[u8]) -> Result<u32, OperandError> {
+    let ty = LiteralIntegerType::from(literal);
+    let digits = &slice[LiteralIntegerType::prefix_len(slice[0], ty as u8)..];
+    let value = i64::from_str_radix(std::str::from_utf8(digits).unwrap(), ty.base())?;
+    let value = if LiteralIntegerType::is_signed(slice[0]) {
+        -value
+    } else {
+        value
+    };
+
+    if value < i32::MIN as i64 || value > u32::MAX as i64 {
+        return Err(OperandError::InvalidOperand("a 32-bit value"));
+    }
+    Ok(value as u32)
+}
+
 #[derive(Debug)]
 pub enum RuntimeTodo {
     // #[errortra]
     Dir(DirectiveType),
+    Pseudo(PseudoMnemonic),
     // #[error("symbol")]
     // Symbol,
 }
@@ -470,7 +580,9 @@ impl Display for RuntimeTodo {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             RuntimeTodo::Dir(directive_type) => Display::fmt(directive_type, f),
-            // RuntimeTodo::Symbol => write!(f, "{}", "symbol"),
+            RuntimeTodo::Pseudo(pseudo) => {
+                write!(f, "{}", PseudoMnemonic::variants()[*pseudo as usize])
+            }
         }
     }
 }
@@ -498,9 +610,39 @@ mod test {
             // eds0110xFF //valid symbol
             // sw x6, -2147483647(x4) //error too small imm value
             add x6, x0, x4
+            div x6, x5, x4
+            remu x6, x5, x4
+            mulhsu x6, x5, x4
+            slt x6, x5, x4
+            sltu x6, x5, x4
+            slti x6, x5, -3
+            sltiu x6, x5, 3
+            andi x6, x5, 0xFF
+            ori x6, x5, 0b1010
+            xori x6, x5, -1
+            slli x6, x5, 2
+            srli x6, x5, 2
+            srai x6, x5, 31
             lw x1, 10(x5)
             sw x1, 111(x5)
+            lb x1, -1(x5)
+            lbu x1, 0(x5)
+            lh x1, 2(x5)
+            lhu x1, 2(x5)
+            sb x1, 3(x5)
+            sh x1, -2(x5)
             lui x1, 0x1212
+        loop:
+            addi x5, x5, -1
+            bne x5, x0, loop
+            bgeu x5, x6, main
+            call func
+            j loop
+        func:
+            jal x1, loop
+            jalr x0, x1, 0
+            jr x5
+            ret
             // 0x1000MP # invalid literal bin
             // 99beto // invalid literal decimal
             // 0b11kl // invalid literal Binary
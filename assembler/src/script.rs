@@ -0,0 +1,860 @@
+//! A subset of the GNU ld linker script language to choose where the sections go
+//!
+//! ```text
+//! ENTRY(_start)
+//! MEMORY {
+//!     ROM (rx) : ORIGIN = 0x0, LENGTH = 16K
+//!     RAM (rw) : ORIGIN = 0x4000, LENGTH = 16K
+//! }
+//! SECTIONS {
+//!     .text : { *(.text) } > ROM
+//!     .rodata : ALIGN(16) { } > ROM
+//!     .data : { *(.data) } > RAM
+//!     .bss : { _bss_start = .; *(.bss) _bss_end = .; } > RAM
+//!     _stack_top = ORIGIN(RAM) + LENGTH(RAM);
+//! }
+//! ```
+//!
+//! `.` is always an absolute address, including inside an output section. An output section
+//! without input patterns takes the section of the same name. Sections the script doesn't mention
+//! go to the first memory region with compatible attributes, or after the last placed section
+
+use rustc_hash::FxHashMap;
+use thiserror::Error;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ScriptError {
+    #[error("Linker script line {line}: {message}")]
+    Syntax { line: usize, message: String },
+    #[error("Memory region `{0}` is defined twice")]
+    DuplicateRegion(String),
+    #[error("Unknown memory region `{0}`")]
+    UnknownRegion(String),
+    #[error("Undefined symbol `{0}` in the linker script")]
+    UndefinedSymbol(String),
+    #[error("The location counter can't move backwards from `{0:#x}` to `{1:#x}`")]
+    Backwards(u32, u32),
+    #[error("Section `{section}` overflows memory region `{region}` by `{overflow}` bytes")]
+    RegionOverflow {
+        section: String,
+        region: String,
+        overflow: u64,
+    },
+    #[error("Sections `{0}` and `{1}` overlap")]
+    Overlap(String, String),
+    #[error("Division by zero in the linker script")]
+    DivisionByZero,
+    #[error("Alignment must not be zero")]
+    ZeroAlignment,
+    #[error("Address overflow in the linker script")]
+    AddressSpace,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operator {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Expr {
+    Number(u32),
+    Symbol(String),
+    /// The location counter `.`
+    Dot,
+    Origin(String),
+    Length(String),
+    /// `ALIGN(alignment)` aligns `.`, `ALIGN(value, alignment)` aligns `value`
+    Align(Option<Box<Expr>>, Box<Expr>),
+    Binary(Operator, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Command {
+    /// `symbol = expr;` or `. = expr;`, `None` is the location counter
+    Assign(Option<String>, Expr),
+    /// `*(.name .other*)` in an output section
+    Input(Vec<String>),
+    Section(OutputSection),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct OutputSection {
+    name: String,
+    address: Option<Expr>,
+    align: Option<Expr>,
+    body: Vec<Command>,
+    region: Option<String>,
+}
+
+/// Which sections a region accepts, from its `(rwx)` attributes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Attributes {
+    read_only: bool,
+    writable: bool,
+    executable: bool,
+    /// Any allocated section, `a`
+    any: bool,
+}
+
+impl Attributes {
+    fn matches(&self, section: &ScriptInput) -> bool {
+        self.any
+            || (self.executable && section.executable)
+            || (self.writable && section.writable)
+            || (self.read_only && !section.writable)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct MemoryRegion {
+    name: String,
+    /// `None` without `(attributes)`, the region only takes sections placed there explicitly
+    attributes: Option<Attributes>,
+    origin: u32,
+    length: u32,
+}
+
+/// A parsed linker script, see the [module](self) documentation for the syntax
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LinkerScript {
+    entry: Option<String>,
+    regions: Vec<MemoryRegion>,
+    commands: Vec<Command>,
+}
+
+/// A section to place, in the default placement order
+#[derive(Debug, Clone)]
+pub struct ScriptInput<'a> {
+    pub name: &'a str,
+    pub alignment: u32,
+    pub size: u32,
+    pub writable: bool,
+    pub executable: bool,
+}
+
+/// Result of running a linker script over the sections of a program
+#[derive(Debug, Default)]
+pub struct ScriptLayout {
+    /// Address of every input, in the same order
+    pub addresses: Vec<u32>,
+    /// Symbols assigned by the script, in assignment order
+    pub symbols: Vec<(String, u32)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Name(String),
+    Number(u32),
+    Punct(char),
+}
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    position: usize,
+}
+
+impl Parser {
+    fn new(text: &str) -> Result<Parser, ScriptError> {
+        let mut tokens = Vec::new();
+        let mut line = 1;
+        let mut chars = text.char_indices().peekable();
+
+        while let Some((start, c)) = chars.next() {
+            match c {
+                '\n' => line += 1,
+                c if c.is_whitespace() => {}
+                '/' if text[start..].starts_with("/*") => {
+                    let Some(end) = text[start + 2..].find("*/") else {
+                        return Err(syntax(line, "unterminated comment"));
+                    };
+                    let comment = &text[start..start + 2 + end + 2];
+                    line += comment.matches('\n').count();
+                    while chars.next_if(|(i, _)| *i < start + comment.len()).is_some() {}
+                }
+                '0'..='9' => {
+                    let mut end = start + 1;
+                    while let Some((i, c)) = chars.next_if(|(_, c)| c.is_ascii_alphanumeric()) {
+                        end = i + c.len_utf8();
+                    }
+                    tokens.push((Token::Number(parse_number(&text[start..end], line)?), line));
+                }
+                c if c.is_ascii_alphabetic() || c == '_' || c == '.' => {
+                    let mut end = start + 1;
+                    while let Some((i, c)) = chars.next_if(|(_, c)| {
+                        c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '*' | '$')
+                    }) {
+                        end = i + c.len_utf8();
+                    }
+                    tokens.push((Token::Name(text[start..end].to_owned()), line));
+                }
+                '{' | '}' | '(' | ')' | ':' | ',' | ';' | '=' | '>' | '+' | '-' | '*' | '/' => {
+                    tokens.push((Token::Punct(c), line))
+                }
+                c => return Err(syntax(line, &format!("unexpected character `{c}`"))),
+            }
+        }
+
+        Ok(Parser {
+            tokens,
+            position: 0,
+        })
+    }
+
+    fn line(&self) -> usize {
+        self.tokens
+            .get(self.position)
+            .or(self.tokens.last())
+            .map_or(1, |(_, line)| *line)
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position).map(|(token, _)| token)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self
+            .tokens
+            .get(self.position)
+            .map(|(token, _)| token.clone());
+        self.position += 1;
+        token
+    }
+
+    fn error(&self, message: &str) -> ScriptError {
+        syntax(self.line(), message)
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        let found = self.peek() == Some(&Token::Punct(c));
+        if found {
+            self.position += 1;
+        }
+        found
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), ScriptError> {
+        match self.eat(c) {
+            true => Ok(()),
+            false => Err(self.error(&format!("expected `{c}`"))),
+        }
+    }
+
+    fn name(&mut self) -> Result<String, ScriptError> {
+        match self.next() {
+            Some(Token::Name(name)) => Ok(name),
+            _ => {
+                self.position -= 1;
+                Err(self.error("expected a name"))
+            }
+        }
+    }
+
+    fn script(&mut self) -> Result<LinkerScript, ScriptError> {
+        let mut script = LinkerScript::default();
+
+        while let Some(token) = self.next() {
+            match token {
+                Token::Name(name) if name == "ENTRY" => {
+                    self.expect('(')?;
+                    script.entry = Some(self.name()?);
+                    self.expect(')')?;
+                }
+                Token::Name(name) if name == "MEMORY" => {
+                    self.expect('{')?;
+                    while !self.eat('}') {
+                        let region = self.region()?;
+                        if script.regions.iter().any(|other| other.name == region.name) {
+                            return Err(ScriptError::DuplicateRegion(region.name));
+                        }
+                        script.regions.push(region);
+                    }
+                }
+                Token::Name(name) if name == "SECTIONS" => {
+                    self.expect('{')?;
+                    while !self.eat('}') {
+                        let command = self.command(false)?;
+                        script.commands.push(command);
+                    }
+                }
+                _ => {
+                    self.position -= 1;
+                    return Err(self.error("expected `ENTRY`, `MEMORY` or `SECTIONS`"));
+                }
+            }
+        }
+
+        Ok(script)
+    }
+
+    /// `NAME [(attributes)] : ORIGIN = expr, LENGTH = expr`
+    fn region(&mut self) -> Result<MemoryRegion, ScriptError> {
+        let name = self.name()?;
+        let mut attributes = None;
+
+        if self.eat('(') {
+            let mut set = Attributes::default();
+            for letter in self.name()?.chars() {
+                match letter.to_ascii_lowercase() {
+                    'r' => set.read_only = true,
+                    'w' => set.writable = true,
+                    'x' => set.executable = true,
+                    'a' => set.any = true,
+                    // Every section is initialized and loaded
+                    'i' | 'l' => {}
+                    _ => return Err(self.error(&format!("unknown attribute `{letter}`"))),
+                }
+            }
+            attributes = Some(set);
+            self.expect(')')?;
+        }
+
+        self.expect(':')?;
+        let field = |parser: &mut Parser, names: &[&str]| -> Result<u32, ScriptError> {
+            let keyword = parser.name()?;
+            if !names.contains(&keyword.as_str()) {
+                return Err(parser.error(&format!("expected `{}`", names[0])));
+            }
+            parser.expect('=')?;
+            parser.expression()?.evaluate(&Context::default())
+        };
+        let origin = field(self, &["ORIGIN", "org", "o"])?;
+        self.eat(',');
+        let length = field(self, &["LENGTH", "len", "l"])?;
+
+        Ok(MemoryRegion {
+            name,
+            attributes,
+            origin,
+            length,
+        })
+    }
+
+    /// An assignment, an output section or, in an output section, input patterns
+    fn command(&mut self, in_section: bool) -> Result<Command, ScriptError> {
+        if in_section && self.eat('*') {
+            self.expect('(')?;
+            let mut patterns = Vec::new();
+            while !self.eat(')') {
+                patterns.push(self.name()?);
+            }
+            return Ok(Command::Input(patterns));
+        }
+
+        let name = self.name()?;
+        if self.eat('=') {
+            let target = (name != ".").then_some(name);
+            let value = self.expression()?;
+            self.expect(';')?;
+            return Ok(Command::Assign(target, value));
+        }
+        if in_section {
+            return Err(self.error("expected an assignment or `*(...)`"));
+        }
+
+        let address = match self.peek() {
+            Some(Token::Punct(':')) => None,
+            _ => Some(self.expression()?),
+        };
+        self.expect(':')?;
+
+        let align = match self.peek() {
+            Some(Token::Name(keyword)) if keyword == "ALIGN" => {
+                self.position += 1;
+                self.expect('(')?;
+                let align = self.expression()?;
+                self.expect(')')?;
+                Some(align)
+            }
+            _ => None,
+        };
+
+        self.expect('{')?;
+        let mut body = Vec::new();
+        while !self.eat('}') {
+            body.push(self.command(true)?);
+        }
+
+        let region = match self.eat('>') {
+            true => Some(self.name()?),
+            false => None,
+        };
+        self.eat(';');
+
+        Ok(Command::Section(OutputSection {
+            name,
+            address,
+            align,
+            body,
+            region,
+        }))
+    }
+
+    fn expression(&mut self) -> Result<Expr, ScriptError> {
+        let mut left = self.term()?;
+        loop {
+            let operator = match self.peek() {
+                Some(Token::Punct('+')) => Operator::Add,
+                Some(Token::Punct('-')) => Operator::Sub,
+                _ => return Ok(left),
+            };
+            self.position += 1;
+            left = Expr::Binary(operator, Box::new(left), Box::new(self.term()?));
+        }
+    }
+
+    fn term(&mut self) -> Result<Expr, ScriptError> {
+        let mut left = self.factor()?;
+        loop {
+            let operator = match self.peek() {
+                Some(Token::Punct('*')) => Operator::Mul,
+                Some(Token::Punct('/')) => Operator::Div,
+                _ => return Ok(left),
+            };
+            self.position += 1;
+            left = Expr::Binary(operator, Box::new(left), Box::new(self.factor()?));
+        }
+    }
+
+    fn factor(&mut self) -> Result<Expr, ScriptError> {
+        match self.next() {
+            Some(Token::Number(value)) => Ok(Expr::Number(value)),
+            Some(Token::Punct('(')) => {
+                let expr = self.expression()?;
+                self.expect(')')?;
+                Ok(expr)
+            }
+            Some(Token::Name(name)) if name == "." => Ok(Expr::Dot),
+            Some(Token::Name(name)) if matches!(name.as_str(), "ORIGIN" | "LENGTH" | "ALIGN") => {
+                self.expect('(')?;
+                let expr = match name.as_str() {
+                    "ORIGIN" => Expr::Origin(self.name()?),
+                    "LENGTH" => Expr::Length(self.name()?),
+                    _ => {
+                        let first = self.expression()?;
+                        match self.eat(',') {
+                            true => {
+                                Expr::Align(Some(Box::new(first)), Box::new(self.expression()?))
+                            }
+                            false => Expr::Align(None, Box::new(first)),
+                        }
+                    }
+                };
+                self.expect(')')?;
+                Ok(expr)
+            }
+            Some(Token::Name(name)) => Ok(Expr::Symbol(name)),
+            _ => {
+                self.position -= 1;
+                Err(self.error("expected an expression"))
+            }
+        }
+    }
+}
+
+fn syntax(line: usize, message: &str) -> ScriptError {
+    ScriptError::Syntax {
+        line,
+        message: message.to_owned(),
+    }
+}
+
+/// Decimal, `0x` hexadecimal or `0b` binary, with an optional `K` or `M` multiplier
+fn parse_number(literal: &str, line: usize) -> Result<u32, ScriptError> {
+    let (digits, multiplier) = match literal.as_bytes().last() {
+        Some(b'K' | b'k') => (&literal[..literal.len() - 1], 1024),
+        Some(b'M' | b'm') => (&literal[..literal.len() - 1], 1024 * 1024),
+        _ => (literal, 1),
+    };
+
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        u32::from_str_radix(hex, 16)
+    } else if let Some(binary) = digits.strip_prefix("0b") {
+        u32::from_str_radix(binary, 2)
+    } else {
+        digits.parse::<u32>()
+    };
+
+    value
+        .ok()
+        .and_then(|value| value.checked_mul(multiplier))
+        .ok_or_else(|| syntax(line, &format!("invalid number `{literal}`")))
+}
+
+/// What expressions can refer to
+#[derive(Default)]
+struct Context<'a> {
+    dot: u32,
+    regions: &'a [MemoryRegion],
+    symbols: FxHashMap<String, u32>,
+}
+
+impl Context<'_> {
+    fn region(&self, name: &str) -> Result<&MemoryRegion, ScriptError> {
+        self.regions
+            .iter()
+            .find(|region| region.name == name)
+            .ok_or_else(|| ScriptError::UnknownRegion(name.to_owned()))
+    }
+}
+
+fn align(value: u32, alignment: u32) -> Result<u32, ScriptError> {
+    if alignment == 0 {
+        return Err(ScriptError::ZeroAlignment);
+    }
+    value
+        .checked_next_multiple_of(alignment)
+        .ok_or(ScriptError::AddressSpace)
+}
+
+impl Expr {
+    fn evaluate(&self, context: &Context) -> Result<u32, ScriptError> {
+        match self {
+            Expr::Number(value) => Ok(*value),
+            Expr::Symbol(name) => context
+                .symbols
+                .get(name)
+                .copied()
+                .ok_or_else(|| ScriptError::UndefinedSymbol(name.clone())),
+            Expr::Dot => Ok(context.dot),
+            Expr::Origin(region) => Ok(context.region(region)?.origin),
+            Expr::Length(region) => Ok(context.region(region)?.length),
+            Expr::Align(value, alignment) => {
+                let value = match value {
+                    Some(value) => value.evaluate(context)?,
+                    None => context.dot,
+                };
+                align(value, alignment.evaluate(context)?)
+            }
+            Expr::Binary(operator, left, right) => {
+                let (left, right) = (left.evaluate(context)?, right.evaluate(context)?);
+                match operator {
+                    Operator::Add => left.checked_add(right).ok_or(ScriptError::AddressSpace),
+                    Operator::Sub => left.checked_sub(right).ok_or(ScriptError::AddressSpace),
+                    Operator::Mul => left.checked_mul(right).ok_or(ScriptError::AddressSpace),
+                    Operator::Div => left.checked_div(right).ok_or(ScriptError::DivisionByZero),
+                }
+            }
+        }
+    }
+}
+
+/// `name` matches `pattern` exactly, or starts with it when it ends with `*`
+fn matches(pattern: &str, name: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => name.starts_with(prefix),
+        None => pattern == name,
+    }
+}
+
+/// State while running the `SECTIONS` commands
+struct Placer<'a, 'b> {
+    context: Context<'a>,
+    inputs: &'b [ScriptInput<'b>],
+    addresses: Vec<Option<u32>>,
+    symbols: Vec<(String, u32)>,
+    /// Next free address of every memory region
+    cursors: Vec<u32>,
+}
+
+impl Placer<'_, '_> {
+    fn assign(&mut self, target: &Option<String>, value: &Expr) -> Result<(), ScriptError> {
+        let value = value.evaluate(&self.context)?;
+        match target {
+            None if value < self.context.dot => {
+                return Err(ScriptError::Backwards(self.context.dot, value));
+            }
+            None => self.context.dot = value,
+            Some(name) => {
+                self.context.symbols.insert(name.clone(), value);
+                self.symbols.retain(|(other, _)| other != name);
+                self.symbols.push((name.clone(), value));
+            }
+        }
+        Ok(())
+    }
+
+    fn place(&mut self, input: usize) -> Result<(), ScriptError> {
+        let section = &self.inputs[input];
+        let address = align(self.context.dot, section.alignment.max(1))?;
+        self.addresses[input] = Some(address);
+        self.context.dot = address
+            .checked_add(section.size)
+            .ok_or(ScriptError::AddressSpace)?;
+        Ok(())
+    }
+
+    /// Unplaced inputs matching one of `patterns`, in input order
+    fn matching(&self, patterns: &[String]) -> Vec<usize> {
+        (0..self.inputs.len())
+            .filter(|&i| {
+                self.addresses[i].is_none()
+                    && patterns
+                        .iter()
+                        .any(|pattern| matches(pattern, self.inputs[i].name))
+            })
+            .collect()
+    }
+
+    /// Move `.` to the next free address of `region`
+    fn enter_region(&mut self, region: usize) {
+        self.context.dot = self.cursors[region];
+    }
+
+    /// Check `section` fit in `region` and move its next free address to `.`
+    fn leave_region(&mut self, region: usize, section: &str) -> Result<(), ScriptError> {
+        let memory = &self.context.regions[region];
+        let end = u64::from(memory.origin) + u64::from(memory.length);
+        if u64::from(self.context.dot) > end {
+            return Err(ScriptError::RegionOverflow {
+                section: section.to_owned(),
+                region: memory.name.clone(),
+                overflow: u64::from(self.context.dot) - end,
+            });
+        }
+
+        self.cursors[region] = self.context.dot;
+        Ok(())
+    }
+
+    fn region_index(&self, name: &str) -> Result<usize, ScriptError> {
+        self.context
+            .regions
+            .iter()
+            .position(|region| region.name == name)
+            .ok_or_else(|| ScriptError::UnknownRegion(name.to_owned()))
+    }
+
+    fn section(&mut self, output: &OutputSection) -> Result<(), ScriptError> {
+        let region = output
+            .region
+            .as_deref()
+            .map(|name| self.region_index(name))
+            .transpose()?;
+        if let Some(region) = region {
+            self.enter_region(region);
+        }
+        if let Some(address) = &output.address {
+            self.context.dot = address.evaluate(&self.context)?;
+        }
+        if let Some(alignment) = &output.align {
+            self.context.dot = align(self.context.dot, alignment.evaluate(&self.context)?)?;
+        }
+
+        let has_inputs = output
+            .body
+            .iter()
+            .any(|command| matches!(command, Command::Input(_)));
+        if !has_inputs {
+            for input in self.matching(std::slice::from_ref(&output.name)) {
+                self.place(input)?;
+            }
+        }
+        for command in &output.body {
+            match command {
+                Command::Assign(target, value) => self.assign(target, value)?,
+                Command::Input(patterns) => {
+                    for input in self.matching(patterns) {
+                        self.place(input)?;
+                    }
+                }
+                Command::Section(_) => unreachable!("output sections don't nest"),
+            }
+        }
+
+        if let Some(region) = region {
+            self.leave_region(region, &output.name)?;
+        }
+        Ok(())
+    }
+}
+
+impl LinkerScript {
+    pub fn parse(text: &str) -> Result<LinkerScript, ScriptError> {
+        Parser::new(text)?.script()
+    }
+
+    /// Symbol given to `ENTRY`
+    pub fn entry(&self) -> Option<&str> {
+        self.entry.as_deref()
+    }
+
+    /// Give an address to every input and evaluate the symbol assignments
+    pub fn place(&self, inputs: &[ScriptInput]) -> Result<ScriptLayout, ScriptError> {
+        let mut placer = Placer {
+            context: Context {
+                dot: 0,
+                regions: &self.regions,
+                symbols: FxHashMap::default(),
+            },
+            inputs,
+            addresses: vec![None; inputs.len()],
+            symbols: Vec::new(),
+            cursors: self.regions.iter().map(|region| region.origin).collect(),
+        };
+
+        for command in &self.commands {
+            match command {
+                Command::Assign(target, value) => placer.assign(target, value)?,
+                Command::Section(output) => placer.section(output)?,
+                Command::Input(_) => unreachable!("input patterns are only parsed in sections"),
+            }
+        }
+
+        // Orphans, like ld: the first region that accepts them, or after the last section
+        for (input, section) in inputs.iter().enumerate() {
+            if placer.addresses[input].is_some() {
+                continue;
+            }
+
+            let region = self.regions.iter().position(|region| {
+                region
+                    .attributes
+                    .is_some_and(|attributes| attributes.matches(section))
+            });
+            match region {
+                Some(region) => {
+                    placer.enter_region(region);
+                    placer.place(input)?;
+                    placer.leave_region(region, section.name)?;
+                }
+                None => placer.place(input)?,
+            }
+        }
+
+        let addresses: Vec<u32> = placer.addresses.into_iter().map(Option::unwrap).collect();
+
+        let mut placed: Vec<_> = (0..inputs.len()).filter(|&i| inputs[i].size > 0).collect();
+        placed.sort_by_key(|&i| addresses[i]);
+        for pair in placed.windows(2) {
+            let (first, second) = (pair[0], pair[1]);
+            if u64::from(addresses[first]) + u64::from(inputs[first].size)
+                > u64::from(addresses[second])
+            {
+                return Err(ScriptError::Overlap(
+                    inputs[first].name.to_owned(),
+                    inputs[second].name.to_owned(),
+                ));
+            }
+        }
+
+        Ok(ScriptLayout {
+            addresses,
+            symbols: placer.symbols,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn input(name: &str, size: u32, writable: bool, executable: bool) -> ScriptInput<'_> {
+        ScriptInput {
+            name,
+            alignment: if executable { 4 } else { 1 },
+            size,
+            writable,
+            executable,
+        }
+    }
+
+    const SCRIPT: &str = r#"
+    /* Code in ROM, everything else in RAM */
+    ENTRY(reset)
+    MEMORY {
+        ROM (rx) : ORIGIN = 0x100, LENGTH = 1K
+        RAM (w) : org = 0x8000, len = 0x100
+    }
+    SECTIONS {
+        .text : { *(.text .text.*) } > ROM
+        .rodata : ALIGN(16) { } > ROM
+        .data : { _data_start = .; *(.data) } > RAM
+        . = ALIGN(0x40);
+        _gap = . - _data_start;
+        _stack_top = ORIGIN(RAM) + LENGTH(RAM);
+    }
+    "#;
+
+    #[test]
+    fn t_place_sections() {
+        let script = LinkerScript::parse(SCRIPT).unwrap();
+        assert_eq!(script.entry(), Some("reset"));
+
+        let inputs = [
+            input(".text", 10, false, true),
+            input(".text.boot", 4, false, true),
+            input(".rodata", 3, false, false),
+            input(".data", 5, true, false),
+            // Orphan, goes to the first writable region after `.data`
+            input(".bss", 8, true, false),
+        ];
+        let layout = script.place(&inputs).unwrap();
+        assert_eq!(layout.addresses, [0x100, 0x10c, 0x110, 0x8000, 0x8005]);
+        assert_eq!(
+            layout.symbols,
+            [
+                ("_data_start".to_owned(), 0x8000),
+                ("_gap".to_owned(), 0x40),
+                ("_stack_top".to_owned(), 0x8100)
+            ]
+        );
+    }
+
+    #[test]
+    fn t_script_errors() {
+        let place = |script: &str, size| {
+            LinkerScript::parse(script)
+                .and_then(|script| script.place(&[input(".text", size, false, true)]))
+                .map(|layout| layout.addresses)
+        };
+
+        assert_eq!(place("SECTIONS { .text 0x20 : { } }", 4), Ok(vec![0x20]));
+        assert_eq!(
+            place(
+                "MEMORY { ROM : ORIGIN = 0, LENGTH = 8 } SECTIONS { .text : { } > ROM }",
+                12
+            ),
+            Err(ScriptError::RegionOverflow {
+                section: ".text".to_owned(),
+                region: "ROM".to_owned(),
+                overflow: 4
+            })
+        );
+        assert_eq!(
+            place("SECTIONS { . = 8; . = 4; }", 4),
+            Err(ScriptError::Backwards(8, 4))
+        );
+        assert_eq!(
+            place("SECTIONS { .text : { } > RAM }", 4),
+            Err(ScriptError::UnknownRegion("RAM".to_owned()))
+        );
+        assert_eq!(
+            place("SECTIONS { a = b; }", 4),
+            Err(ScriptError::UndefinedSymbol("b".to_owned()))
+        );
+        assert_eq!(
+            place("SECTIONS {\n .text : {\n x = ; } }", 4),
+            Err(ScriptError::Syntax {
+                line: 3,
+                message: "expected an expression".to_owned()
+            })
+        );
+    }
+
+    #[test]
+    fn t_overlap() {
+        let script = LinkerScript::parse("SECTIONS { .text 0x10 : { } .data 0x12 : { } }").unwrap();
+        assert_eq!(
+            script
+                .place(&[
+                    input(".text", 4, false, true),
+                    input(".data", 4, true, false)
+                ])
+                .unwrap_err(),
+            ScriptError::Overlap(".text".to_owned(), ".data".to_owned())
+        );
+    }
+}
@@ -0,0 +1,227 @@
+use std::{
+    fmt::Display,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum SourceError {
+    #[error("Unable to read `{0}`: {1}")]
+    Io(String, std::io::Error),
+    #[error("Unable to find `{0}` in the include paths")]
+    IncludeNotFound(String),
+    #[error("`{0}` includes itself")]
+    RecursiveInclude(String),
+    #[error("Expected a quoted path after `.include`")]
+    InvalidInclude,
+}
+
+/// A line in one of the source files
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    pub file: PathBuf,
+    pub line: usize,
+}
+
+impl Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.file.display(), self.line)
+    }
+}
+
+/// First line of a file (or of the part of a file following an `.include`) in the expanded source
+#[derive(Debug)]
+struct Segment {
+    line: usize,
+    file: usize,
+    file_line: usize,
+}
+
+/// The source with every `.include "path"` replaced by the content of the file, and the mapping of
+/// its lines back to the original files
+#[derive(Debug)]
+pub struct SourceMap {
+    text: String,
+    files: Vec<PathBuf>,
+    segments: Vec<Segment>,
+    lines: usize,
+}
+
+impl SourceMap {
+    /// Expand `text`. `name` is used in locations and to look up includes relative to it
+    pub fn new(
+        name: &Path,
+        text: &str,
+        include_dirs: &[PathBuf],
+    ) -> Result<SourceMap, (Location, SourceError)> {
+        let mut map = SourceMap {
+            text: String::with_capacity(text.len()),
+            files: Vec::new(),
+            segments: Vec::new(),
+            lines: 0,
+        };
+
+        map.expand(name, text, include_dirs, &mut Vec::new())?;
+        Ok(map)
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Location of the 1-based `line` of the expanded source
+    pub fn locate_line(&self, line: usize) -> Location {
+        let index = self
+            .segments
+            .partition_point(|segment| segment.line <= line)
+            .saturating_sub(1);
+        let segment = &self.segments[index];
+
+        Location {
+            file: self.files[segment.file].clone(),
+            line: segment.file_line + line.saturating_sub(segment.line),
+        }
+    }
+
+    fn push_segment(&mut self, file: usize, file_line: usize) {
+        self.segments.push(Segment {
+            line: self.lines + 1,
+            file,
+            file_line,
+        });
+    }
+
+    fn expand(
+        &mut self,
+        path: &Path,
+        text: &str,
+        include_dirs: &[PathBuf],
+        stack: &mut Vec<PathBuf>,
+    ) -> Result<(), (Location, SourceError)> {
+        let file = self.files.len();
+        self.files.push(path.to_owned());
+        stack.push(path.to_owned());
+        self.push_segment(file, 1);
+
+        for (index, line) in text.lines().enumerate() {
+            let location = || Location {
+                file: path.to_owned(),
+                line: index + 1,
+            };
+
+            let Some(included) = include_path(line).map_err(|err| (location(), err))? else {
+                self.text.push_str(line);
+                self.text.push('\n');
+                self.lines += 1;
+                continue;
+            };
+
+            let resolved = resolve_include(path, included, include_dirs).ok_or_else(|| {
+                (
+                    location(),
+                    SourceError::IncludeNotFound(included.to_owned()),
+                )
+            })?;
+            if stack.contains(&resolved) {
+                return Err((
+                    location(),
+                    SourceError::RecursiveInclude(resolved.display().to_string()),
+                ));
+            }
+
+            let content = fs::read_to_string(&resolved).map_err(|err| {
+                (
+                    location(),
+                    SourceError::Io(resolved.display().to_string(), err),
+                )
+            })?;
+            self.expand(&resolved, &content, include_dirs, stack)?;
+            self.push_segment(file, index + 2);
+        }
+
+        stack.pop();
+        Ok(())
+    }
+}
+
+/// `Some(path)` if `line` is an `.include "path"` directive
+fn include_path(line: &str) -> Result<Option<&str>, SourceError> {
+    let Some(rest) = line.trim_start().strip_prefix(".include") else {
+        return Ok(None);
+    };
+    if !rest.starts_with(char::is_whitespace) {
+        return Ok(None);
+    }
+
+    let rest = rest.trim_start();
+    let path = rest
+        .strip_prefix('"')
+        .and_then(|rest| rest.split_once('"'))
+        .map(|(path, _)| path)
+        .ok_or(SourceError::InvalidInclude)?;
+
+    Ok(Some(path))
+}
+
+fn resolve_include(from: &Path, included: &str, include_dirs: &[PathBuf]) -> Option<PathBuf> {
+    let relative = from.parent().map(|dir| dir.join(included));
+
+    relative
+        .into_iter()
+        .chain(include_dirs.iter().map(|dir| dir.join(included)))
+        .find(|candidate| candidate.is_file())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn t_include() {
+        let dir = std::env::temp_dir().join(format!("rivet-as-include-{}", std::process::id()));
+        let lib = dir.join("lib");
+        fs::create_dir_all(&lib).unwrap();
+        fs::write(lib.join("macros.s"), "addi x1, x0, 1\naddi x2, x0, 2\n").unwrap();
+
+        let source = "main:\n.include \"macros.s\"\nadd x3, x1, x2\n";
+        let map = SourceMap::new(&dir.join("main.s"), source, std::slice::from_ref(&lib)).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(
+            map.text(),
+            "main:\naddi x1, x0, 1\naddi x2, x0, 2\nadd x3, x1, x2\n"
+        );
+        assert_eq!(
+            map.locate_line(1),
+            Location {
+                file: dir.join("main.s"),
+                line: 1
+            }
+        );
+        assert_eq!(
+            map.locate_line(3),
+            Location {
+                file: lib.join("macros.s"),
+                line: 2
+            }
+        );
+        assert_eq!(
+            map.locate_line(4),
+            Location {
+                file: dir.join("main.s"),
+                line: 3
+            }
+        );
+    }
+
+    #[test]
+    fn t_include_not_found() {
+        let err =
+            SourceMap::new(Path::new("main.s"), "nop\n.include \"nope.s\"\n", &[]).unwrap_err();
+
+        assert_eq!(err.0.line, 2);
+        assert!(matches!(err.1, SourceError::IncludeNotFound(_)));
+    }
+}
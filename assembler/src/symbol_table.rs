@@ -115,6 +115,15 @@ impl Symbol {
     pub fn name(&self) -> StrId {
         self.name
     }
+
+    /// Offset from the start of the section once laid out
+    pub fn value(&self) -> Option<u32> {
+        self.value
+    }
+
+    pub fn visibility(&self) -> Visibility {
+        self.vis
+    }
 }
 
 pub type Key = SectionId;
@@ -131,6 +140,13 @@ pub struct GlobalSymbol {
     handle: Option<GlobalHandle>,
 }
 
+impl GlobalSymbol {
+    /// Whether a label in this file defines the symbol
+    pub fn is_defined(&self) -> bool {
+        self.handle.is_some()
+    }
+}
+
 #[derive(Debug)]
 pub struct SymbolName(StrId);
 impl From<StrId> for SymbolName {
@@ -302,16 +318,33 @@ impl SymbolTable {
             .find(|global| global.handle.is_none() && global.name == name)
     }
 
-    // pub fn get(&self, name: StrId) -> i32 {
-    //     // TODO: Label take precedence over constant
-    //     //   // Prefer local symbol in current section, then global
-    //     //     if let Some(local) = symbols.iter().find(|s| s.name == *name && s.section == current_section && s.visibility == SymbolVisibility::Local) {
-    //     //         Some(local.offset)
-    //     //     } else if let Some(global) = symbols.iter().find(|s| s.name == *name && s.visibility == SymbolVisibility::Global) {
-    //     //         Some(global.offset)
-    //     //     } else {
-    //     //         None // Unresolved, needs relocation
-    //     //     }
-    //     1
-    // }
+    /// Set the value of the label `name` defined in `section`
+    pub fn set_value(&mut self, section: Key, name: StrId, value: u32) {
+        if let Some(symbol) = self
+            .locals
+            .get_mut(&section)
+            .and_then(|locals| locals.iter_mut().find(|symbol| symbol.name == name))
+        {
+            symbol.value = Some(value);
+        }
+    }
+
+    /// Section and value of the label `name`. Labels of `section` take precedence over the other sections
+    pub fn lookup(&self, section: Key, name: StrId) -> Option<(Key, u32)> {
+        let find = |key: &Key, locals: &Vec<Symbol>| {
+            locals
+                .iter()
+                .find(|symbol| symbol.name == name)
+                .and_then(|symbol| Some((*key, symbol.value?)))
+        };
+
+        self.locals
+            .get_key_value(&section)
+            .and_then(|(key, locals)| find(key, locals))
+            .or_else(|| {
+                self.locals
+                    .iter()
+                    .find_map(|(key, locals)| find(key, locals))
+            })
+    }
 }
@@ -1,7 +1,7 @@
 use shared::{EnumCount, EnumVariants};
 use thiserror::Error;
 
-use crate::asm::directive::DirectiveType;
+use crate::{asm::directive::DirectiveType, instruction::PseudoMnemonic};
 
 use super::Token;
 
@@ -32,10 +32,6 @@ pub struct State {
 }
 
 impl State {
-    pub fn advance_row(&mut self) {
-        self.cell.row += 1;
-    }
-
     pub fn set_last_token(&mut self, token: Token) {
         self.last_token = token;
     }
@@ -55,13 +51,26 @@ pub enum LexingError {
     InvalidSuffix(String, usize),
     #[error("Invalid Ascii Character at {0}")]
     NonAsciiCharacter(usize),
-    #[error("Unknown syntax {0} at row {0}")]
+    #[error("Unknown syntax {0} at row {1}")]
     UnknownSyntax(String, usize),
     #[default]
     #[error("")]
     Error,
 }
 
+impl LexingError {
+    /// Line where the error occurred
+    pub fn row(&self) -> Option<usize> {
+        match self {
+            LexingError::UnknownDirective(_, row)
+            | LexingError::InvalidSuffix(_, row)
+            | LexingError::NonAsciiCharacter(row)
+            | LexingError::UnknownSyntax(_, row) => Some(*row),
+            LexingError::Error => None,
+        }
+    }
+}
+
 // pub(super) fn on_block_comment(lex: &mut logos::Lexer<Token>) -> logos::Skip {
 //     lex.extras.in_block_comments = !lex.extras.in_block_comments;
 //     logos::Skip
@@ -76,6 +85,7 @@ pub(super) fn on_newline(lex: &mut logos::Lexer<Token>) {
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum IdentifierType {
     Mnemonic(isa::instruction::Mnemonic),
+    Pseudo(PseudoMnemonic),
     Register(isa::Register),
     Symbol,
 }
@@ -86,10 +96,10 @@ impl IdentifierType {
         isa::instruction::Mnemonic::variants()
     }
 
-    // #[inline(always)]
-    // fn pseudo_mnemonics<'a>() -> [&'a str; crate::instruction::PseudoMnemonic::VARIANT_COUNT] {
-    //     crate::instruction::PseudoMnemonic::variants()
-    // }
+    #[inline(always)]
+    fn pseudo_mnemonics<'a>() -> [&'a str; PseudoMnemonic::VARIANT_COUNT] {
+        PseudoMnemonic::variants()
+    }
 
     #[inline(always)]
     fn registers<'a>() -> [&'a str; isa::Register::VARIANT_COUNT] {
@@ -106,6 +116,16 @@ impl From<&[u8]> for IdentifierType {
             );
         };
 
+        if let Some(i) = Self::pseudo_mnemonics()
+            .iter()
+            .position(|v| v.as_bytes() == value)
+        {
+            return Self::Pseudo(
+                // Safety: guaranteed to be safe because fieldless enum and `i` is an actual index from the selected variant.
+                unsafe { std::mem::transmute::<u8, PseudoMnemonic>(i as u8) },
+            );
+        };
+
         if let Some(i) = Self::registers().iter().position(|v| v.as_bytes() == value) {
             return Self::Register(
                 // Safety: guaranteed to be safe because fieldless enum and `i` is an actual index from the selected variant.
@@ -113,6 +133,13 @@ impl From<&[u8]> for IdentifierType {
             );
         };
 
+        if let Some(register) = std::str::from_utf8(value)
+            .ok()
+            .and_then(isa::Register::from_abi_name)
+        {
+            return Self::Register(register);
+        }
+
         Self::Symbol
     }
 }
@@ -16,10 +16,10 @@ use crate::asm::directive::DirectiveType;
 #[logos(extras = State)]
 #[logos(error = LexingError)]
 pub enum Token {
-    #[regex(r#"[a-zA-Z_]\w+"#, on_ident)]
+    #[regex(r#"[a-zA-Z_]\w*"#, on_ident)]
     Identifier(IdentifierType),
 
-    #[regex(r#"[a-zA-Z]\w+:"#)]
+    #[regex(r#"[a-zA-Z_]\w*:"#)]
     // Label(LabelType), //TODO: add numeric label?
     Label,
     #[regex(r#"\.[a-zA-Z]\w+"#, on_directive)]
@@ -34,6 +34,13 @@ pub enum Token {
     #[regex(r#"-?0b[01]+(?:\w+)?"#, on_literal_integer::<{LiteralIntegerType::Binary as u8}>)]
     LiteralBinary,
 
+    /// `%hi(symbol)`, bits 31..13 of the symbol's value
+    #[regex(r#"%hi\([ \t]*[a-zA-Z_]\w*[ \t]*\)"#)]
+    Hi,
+    /// `%lo(symbol)`, bits 12..0 of the symbol's value
+    #[regex(r#"%lo\([ \t]*[a-zA-Z_]\w*[ \t]*\)"#)]
+    Lo,
+
     #[token(b"-")]
     Negative,
     #[token(b"+")]
@@ -71,7 +78,7 @@ impl Display for Token {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let value = match self {
             Token::Identifier(identifier_type) => match identifier_type {
-                IdentifierType::Mnemonic(_) => "instruction",
+                IdentifierType::Mnemonic(_) | IdentifierType::Pseudo(_) => "instruction",
                 IdentifierType::Register(_) => "register",
                 IdentifierType::Symbol => "symbol",
             },
@@ -81,6 +88,8 @@ impl Display for Token {
             Token::LiteralDecimal => "decimal",
             Token::LiteralHex => "hex",
             Token::LiteralBinary => "binary",
+            Token::Hi => "%hi",
+            Token::Lo => "%lo",
             Token::Positive => "+",
             Token::Negative => "-",
             Token::ParenR => ")",
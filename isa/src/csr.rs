@@ -0,0 +1,151 @@
+use std::{
+    fmt::Display,
+    ops::{BitAnd, Shl},
+};
+
+use crate::instruction::Codec;
+
+/// Number of a control and status register, the 12-bit operand of the `csrr*` instructions.
+/// The named ones use the RISC-V machine-mode numbers, other numbers are encodable but the VM
+/// doesn't implement them
+#[derive(Debug, PartialEq, Eq, Default, Clone, Copy, PartialOrd, Ord, Hash)]
+pub struct Csr(u16);
+
+/// Names of the implemented CSRs, with their number
+const NAMES: [(&str, Csr); 16] = [
+    ("status", Csr::STATUS),
+    ("ie", Csr::IE),
+    ("tvec", Csr::TVEC),
+    ("scratch", Csr::SCRATCH),
+    ("epc", Csr::EPC),
+    ("cause", Csr::CAUSE),
+    ("tval", Csr::TVAL),
+    ("ip", Csr::IP),
+    ("timecmp", Csr::TIMECMP),
+    ("timecmph", Csr::TIMECMPH),
+    ("cycle", Csr::CYCLE),
+    ("time", Csr::TIME),
+    ("instret", Csr::INSTRET),
+    ("cycleh", Csr::CYCLEH),
+    ("timeh", Csr::TIMEH),
+    ("instreth", Csr::INSTRETH),
+];
+
+impl Csr {
+    /// Machine status, with the global interrupt enable
+    pub const STATUS: Csr = Csr(0x300);
+    /// Enabled interrupts, one bit per interrupt cause
+    pub const IE: Csr = Csr(0x304);
+    /// Trap and interrupt handler address
+    pub const TVEC: Csr = Csr(0x305);
+    /// Free for the trap handler
+    pub const SCRATCH: Csr = Csr(0x340);
+    /// Address of the instruction that trapped
+    pub const EPC: Csr = Csr(0x341);
+    /// Cause of the last trap
+    pub const CAUSE: Csr = Csr(0x342);
+    /// Bad address or instruction of the last trap
+    pub const TVAL: Csr = Csr(0x343);
+    /// Pending interrupts, one bit per interrupt cause
+    pub const IP: Csr = Csr(0x344);
+    /// Low half of the `cycle` count raising the timer interrupt, in the custom range
+    pub const TIMECMP: Csr = Csr(0x7c0);
+    pub const TIMECMPH: Csr = Csr(0x7c1);
+    /// Low half of the cycle counter
+    pub const CYCLE: Csr = Csr(0xc00);
+    /// Low half of the microseconds since the VM started
+    pub const TIME: Csr = Csr(0xc01);
+    /// Low half of the retired instructions counter
+    pub const INSTRET: Csr = Csr(0xc02);
+    pub const CYCLEH: Csr = Csr(0xc80);
+    pub const TIMEH: Csr = Csr(0xc81);
+    pub const INSTRETH: Csr = Csr(0xc82);
+
+    /// Largest CSR number
+    pub const MAX: u32 = 0xfff;
+
+    /// CSR number `number`, `None` if it doesn't fit in 12 bits
+    pub fn new(number: u32) -> Option<Csr> {
+        (number <= Self::MAX).then_some(Csr(number as u16))
+    }
+
+    pub fn number(self) -> u32 {
+        self.0 as u32
+    }
+
+    /// Assembler name, `None` for CSRs the VM doesn't implement
+    pub fn name(self) -> Option<&'static str> {
+        NAMES
+            .iter()
+            .find(|(_, csr)| *csr == self)
+            .map(|(name, _)| *name)
+    }
+
+    /// CSR named `name` in assembly, e.g. `cycle`
+    pub fn from_name(name: &str) -> Option<Csr> {
+        NAMES
+            .iter()
+            .find(|(csr_name, _)| *csr_name == name)
+            .map(|(_, csr)| *csr)
+    }
+
+    /// Counters, only readable
+    pub fn is_read_only(self) -> bool {
+        // Bits 11:10 set mark a read-only CSR
+        self.0 >> 10 == 0b11
+    }
+}
+
+/// The name, or the hexadecimal number of an unnamed CSR
+impl Display for Csr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.name() {
+            Some(name) => f.write_str(name),
+            None => write!(f, "{:#x}", self.0),
+        }
+    }
+}
+
+impl Codec for Csr {}
+
+impl From<u32> for Csr {
+    fn from(value: u32) -> Self {
+        Csr((value & Self::MAX) as u16)
+    }
+}
+
+impl BitAnd<u32> for &Csr {
+    type Output = u32;
+
+    fn bitand(self, rhs: u32) -> Self::Output {
+        (self.0 as u32) & rhs
+    }
+}
+
+impl Shl<u32> for &Csr {
+    type Output = u32;
+
+    fn shl(self, rhs: u32) -> Self::Output {
+        (self.0 as u32) << rhs
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn t_csr_names() {
+        assert_eq!(Csr::from_name("cycle"), Some(Csr::CYCLE));
+        assert_eq!(Csr::CYCLE.to_string(), "cycle");
+        assert_eq!(Csr::new(0x7d0).unwrap().to_string(), "0x7d0");
+        assert_eq!(Csr::new(0x1000), None);
+        assert_eq!(Csr::from_name("x10"), None);
+
+        assert!(Csr::INSTRETH.is_read_only());
+        assert!(!Csr::SCRATCH.is_read_only());
+
+        let csr = Csr::TVAL;
+        assert_eq!(Csr::decode(csr.encode(0xfff, 18), 18, 0xfff), csr);
+    }
+}
@@ -0,0 +1,370 @@
+use std::fmt::Write;
+
+use crate::{instruction::Instruction, register::Register};
+
+/// Turns instructions back into assembly, in the syntax the assembler parses.
+///
+/// Branch and jump offsets stay numeric so the output assembles to the same words; the target
+/// address, and the symbol it falls in when symbols are known, follow in a comment
+#[derive(Debug, Default)]
+pub struct Disassembler {
+    abi_names: bool,
+    /// Sorted by address, in insertion order for equal addresses
+    symbols: Vec<(u32, String)>,
+}
+
+impl Disassembler {
+    pub fn new() -> Disassembler {
+        Self::default()
+    }
+
+    /// Print `a0` instead of `x10`
+    pub fn abi_names(&mut self, enabled: bool) -> &mut Self {
+        self.abi_names = enabled;
+        self
+    }
+
+    /// Name `address` in annotations. The first symbol added for an address is its label
+    pub fn symbol(&mut self, address: u32, name: impl Into<String>) -> &mut Self {
+        let index = self
+            .symbols
+            .partition_point(|(symbol, _)| *symbol <= address);
+        self.symbols.insert(index, (address, name.into()));
+        self
+    }
+
+    /// Symbol defined exactly at `address`
+    pub fn label(&self, address: u32) -> Option<&str> {
+        let index = self
+            .symbols
+            .partition_point(|(symbol, _)| *symbol < address);
+        self.symbols
+            .get(index)
+            .filter(|(symbol, _)| *symbol == address)
+            .map(|(_, name)| name.as_str())
+    }
+
+    /// `symbol` or `symbol+offset` for the closest symbol at or before `address`
+    pub fn locate(&self, address: u32) -> Option<String> {
+        let index = self
+            .symbols
+            .partition_point(|(symbol, _)| *symbol <= address);
+        let (symbol, _) = self.symbols.get(index.checked_sub(1)?)?;
+        let name = self.label(*symbol)?;
+        Some(match address - symbol {
+            0 => name.to_owned(),
+            offset => format!("{name}+{offset:#x}"),
+        })
+    }
+
+    fn register(&self, register: Register) -> String {
+        match self.abi_names {
+            true => register.abi_name().to_owned(),
+            false => format!("x{}", register as u8),
+        }
+    }
+
+    /// `instruction` located at `address`
+    pub fn instruction(&self, instruction: &Instruction, address: u32) -> String {
+        let mut text = self.text(instruction);
+        if let Some(target) = target(instruction, address) {
+            write!(text, " # {target:#x}").unwrap();
+            if let Some(symbol) = self.locate(target) {
+                write!(text, " <{symbol}>").unwrap();
+            }
+        }
+
+        text
+    }
+
+    /// `instruction` without the annotations that need its address
+    pub(crate) fn text(&self, instruction: &Instruction) -> String {
+        use Instruction::*;
+
+        let r = |register: &Register| self.register(*register);
+        match instruction {
+            Add { dest, src1, src2 }
+            | Sub { dest, src1, src2 }
+            | Mul { dest, src1, src2 }
+            | Mulh { dest, src1, src2 }
+            | Mulhu { dest, src1, src2 }
+            | Mulhsu { dest, src1, src2 }
+            | Div { dest, src1, src2 }
+            | Divu { dest, src1, src2 }
+            | Rem { dest, src1, src2 }
+            | Remu { dest, src1, src2 }
+            | And { dest, src1, src2 }
+            | Or { dest, src1, src2 }
+            | Xor { dest, src1, src2 }
+            | Slt { dest, src1, src2 }
+            | Sltu { dest, src1, src2 } => {
+                format!(
+                    "{} {}, {}, {}",
+                    mnemonic(instruction),
+                    r(dest),
+                    r(src1),
+                    r(src2)
+                )
+            }
+            Shl { dest, src, shift } | Shr { dest, src, shift } | ShrA { dest, src, shift } => {
+                format!(
+                    "{} {}, {}, {}",
+                    mnemonic(instruction),
+                    r(dest),
+                    r(src),
+                    r(shift)
+                )
+            }
+            AddI { dest, src, value }
+            | SltI { dest, src, value }
+            | SltIU { dest, src, value }
+            | AndI { dest, src, value }
+            | OrI { dest, src, value }
+            | XorI { dest, src, value }
+            | SllI {
+                dest,
+                src,
+                shift: value,
+            }
+            | SrlI {
+                dest,
+                src,
+                shift: value,
+            }
+            | SraI {
+                dest,
+                src,
+                shift: value,
+            }
+            | Jalr {
+                dest,
+                src,
+                offset: value,
+            } => format!(
+                "{} {}, {}, {}",
+                mnemonic(instruction),
+                r(dest),
+                r(src),
+                value.value()
+            ),
+            Lui { dest, value } => format!("lui {}, {}", r(dest), value.value()),
+            Lw { dest, src, offset }
+            | Lb { dest, src, offset }
+            | Lh { dest, src, offset }
+            | Lbu { dest, src, offset }
+            | Lhu { dest, src, offset } => format!(
+                "{} {}, {}({})",
+                mnemonic(instruction),
+                r(dest),
+                offset.value(),
+                r(src)
+            ),
+            Sw { src, dest, offset } | Sb { src, dest, offset } | Sh { src, dest, offset } => {
+                format!(
+                    "{} {}, {}({})",
+                    mnemonic(instruction),
+                    r(src),
+                    offset.value(),
+                    r(dest)
+                )
+            }
+            Beq { src1, src2, offset }
+            | Bne { src1, src2, offset }
+            | Blt { src1, src2, offset }
+            | Bge { src1, src2, offset }
+            | Bltu { src1, src2, offset }
+            | Bgeu { src1, src2, offset } => format!(
+                "{} {}, {}, {}",
+                mnemonic(instruction),
+                r(src1),
+                r(src2),
+                offset.value()
+            ),
+            Jal { dest, offset } => format!("jal {}, {}", r(dest), offset.value()),
+            Syscall { src1, src2, src3 } => {
+                format!("syscall {}, {}, {}", r(src1), r(src2), r(src3))
+            }
+            Ebreak => "ebreak".to_owned(),
+            Mret => "mret".to_owned(),
+            Csrrw { dest, src, csr } | Csrrs { dest, src, csr } | Csrrc { dest, src, csr } => {
+                format!("{} {}, {csr}, {}", mnemonic(instruction), r(dest), r(src))
+            }
+            Csrrwi { dest, value, csr }
+            | Csrrsi { dest, value, csr }
+            | Csrrci { dest, value, csr } => format!(
+                "{} {}, {csr}, {}",
+                mnemonic(instruction),
+                r(dest),
+                value.value()
+            ),
+        }
+    }
+
+    /// The instruction encoded by `word` at `address`, or a `.word` directive if it's not one
+    pub fn word(&self, word: u32, address: u32) -> String {
+        match Instruction::try_from(word) {
+            Ok(instruction) => self.instruction(&instruction, address),
+            Err(_) => format!(".word {word:#010x}"),
+        }
+    }
+}
+
+/// Address a branch or `jal` at `address` goes to
+pub fn target(instruction: &Instruction, address: u32) -> Option<u32> {
+    use Instruction::*;
+
+    let offset = match instruction {
+        Beq { offset, .. }
+        | Bne { offset, .. }
+        | Blt { offset, .. }
+        | Bge { offset, .. }
+        | Bltu { offset, .. }
+        | Bgeu { offset, .. } => offset.value(),
+        Jal { offset, .. } => offset.value(),
+        _ => return None,
+    };
+
+    Some(address.wrapping_add_signed(offset))
+}
+
+/// Assembler name of the instruction
+pub fn mnemonic(instruction: &Instruction) -> &'static str {
+    use Instruction::*;
+
+    match instruction {
+        Add { .. } => "add",
+        Sub { .. } => "sub",
+        Mul { .. } => "mul",
+        Mulh { .. } => "mulh",
+        Mulhu { .. } => "mulhu",
+        Mulhsu { .. } => "mulhsu",
+        Div { .. } => "div",
+        Divu { .. } => "divu",
+        Rem { .. } => "rem",
+        Remu { .. } => "remu",
+        And { .. } => "and",
+        Or { .. } => "or",
+        Xor { .. } => "xor",
+        Shl { .. } => "shl",
+        Shr { .. } => "shr",
+        ShrA { .. } => "shra",
+        Slt { .. } => "slt",
+        Sltu { .. } => "sltu",
+        AddI { .. } => "addi",
+        SltI { .. } => "slti",
+        SltIU { .. } => "sltiu",
+        AndI { .. } => "andi",
+        OrI { .. } => "ori",
+        XorI { .. } => "xori",
+        SllI { .. } => "slli",
+        SrlI { .. } => "srli",
+        SraI { .. } => "srai",
+        Lui { .. } => "lui",
+        Lw { .. } => "lw",
+        Sw { .. } => "sw",
+        Lb { .. } => "lb",
+        Lh { .. } => "lh",
+        Lbu { .. } => "lbu",
+        Lhu { .. } => "lhu",
+        Sb { .. } => "sb",
+        Sh { .. } => "sh",
+        Beq { .. } => "beq",
+        Bne { .. } => "bne",
+        Blt { .. } => "blt",
+        Bge { .. } => "bge",
+        Bltu { .. } => "bltu",
+        Bgeu { .. } => "bgeu",
+        Jal { .. } => "jal",
+        Jalr { .. } => "jalr",
+        Syscall { .. } => "syscall",
+        Ebreak => "ebreak",
+        Csrrw { .. } => "csrrw",
+        Csrrs { .. } => "csrrs",
+        Csrrc { .. } => "csrrc",
+        Csrrwi { .. } => "csrrwi",
+        Csrrsi { .. } => "csrrsi",
+        Csrrci { .. } => "csrrci",
+        Mret => "mret",
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::operand::{Immediate14, Immediate19};
+
+    #[test]
+    fn t_disassemble() {
+        let mut disassembler = Disassembler::new();
+        let add = Instruction::Add {
+            dest: Register::X10,
+            src1: Register::X0,
+            src2: Register::X2,
+        };
+        assert_eq!(disassembler.instruction(&add, 0), "add x10, x0, x2");
+
+        disassembler.abi_names(true);
+        assert_eq!(disassembler.instruction(&add, 0), "add a0, zero, sp");
+
+        let lw = Instruction::Lw {
+            dest: Register::X1,
+            src: Register::X8,
+            offset: Immediate14::new(-4),
+        };
+        assert_eq!(disassembler.instruction(&lw, 0), "lw ra, -4(s0)");
+        let sw = Instruction::Sw {
+            src: Register::X1,
+            dest: Register::X2,
+            offset: Immediate14::new(8),
+        };
+        assert_eq!(disassembler.word(u32::from(&sw), 0), "sw ra, 8(sp)");
+        assert_eq!(disassembler.word(0xff, 0), ".word 0x000000ff");
+
+        let bne = Instruction::Bne {
+            src1: Register::X5,
+            src2: Register::X0,
+            offset: Immediate14::new(-8),
+        };
+        assert_eq!(
+            disassembler.instruction(&bne, 0x10),
+            "bne t0, zero, -8 # 0x8"
+        );
+
+        disassembler.symbol(0x0, "_start").symbol(0x4, "loop");
+        disassembler.symbol(0x4, "alias");
+        assert_eq!(
+            disassembler.instruction(&bne, 0x10),
+            "bne t0, zero, -8 # 0x8 <loop+0x4>"
+        );
+        let jal = Instruction::Jal {
+            dest: Register::X1,
+            offset: Immediate19::new(-12),
+        };
+        assert_eq!(
+            disassembler.instruction(&jal, 0x10),
+            "jal ra, -12 # 0x4 <loop>"
+        );
+        assert_eq!(disassembler.label(0x4), Some("loop"));
+        assert_eq!(disassembler.label(0x8), None);
+
+        let csrrw = Instruction::Csrrw {
+            dest: Register::X10,
+            src: Register::X11,
+            csr: crate::Csr::TVEC,
+        };
+        assert_eq!(disassembler.instruction(&csrrw, 0), "csrrw a0, tvec, a1");
+
+        assert_eq!(bne.to_string(), "bne x5, x0, -8");
+        assert_eq!(jal.to_string(), "jal x1, -12");
+    }
+
+    #[test]
+    fn t_abi_names() {
+        assert_eq!(Register::X2.abi_name(), "sp");
+        assert_eq!(Register::X31.abi_name(), "t6");
+        assert_eq!(Register::from_abi_name("s11"), Some(Register::X27));
+        assert_eq!(Register::from_abi_name("fp"), Some(Register::X8));
+        assert_eq!(Register::from_abi_name("zero"), Some(Register::X0));
+        assert_eq!(Register::from_abi_name("x1"), None);
+    }
+}
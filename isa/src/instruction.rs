@@ -1,11 +1,16 @@
+use std::fmt::Display;
+
 use crate::{
-    operand::{Immediate14, Immediate19},
+    csr::Csr,
+    disassembler::Disassembler,
+    operand::{Immediate14, Immediate19, UImmediate5},
     register::Register,
 };
 use shared::{DecodeError, EnumCount, EnumVariants, VMInstruction};
 
-#[derive(Debug, PartialEq, Eq, VMInstruction, EnumCount)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, VMInstruction, EnumCount)]
 // TODO: if fields got re-arranged, make sure to also re-arrange the bits e.g `(..5, 5, 5)`
+// `t_display_round_trip` in the assembler checks the encoding against the parser
 pub enum Instruction {
     // ---Binary Operators---
     #[isa(0x1, 5, 5, 5)]
@@ -26,6 +31,55 @@ pub enum Instruction {
         src1: Register,
         src2: Register,
     },
+    /// Multiply High (signed x signed)
+    #[isa(0x30, 5, 5, 5)]
+    Mulh {
+        dest: Register,
+        src1: Register,
+        src2: Register,
+    },
+    /// Multiply High (unsigned x unsigned)
+    #[isa(0x31, 5, 5, 5)]
+    Mulhu {
+        dest: Register,
+        src1: Register,
+        src2: Register,
+    },
+    /// Multiply High (signed x unsigned)
+    #[isa(0x32, 5, 5, 5)]
+    Mulhsu {
+        dest: Register,
+        src1: Register,
+        src2: Register,
+    },
+    /// Divide (signed)
+    #[isa(0x33, 5, 5, 5)]
+    Div {
+        dest: Register,
+        src1: Register,
+        src2: Register,
+    },
+    /// Divide (unsigned)
+    #[isa(0x34, 5, 5, 5)]
+    Divu {
+        dest: Register,
+        src1: Register,
+        src2: Register,
+    },
+    /// Remainder (signed)
+    #[isa(0x35, 5, 5, 5)]
+    Rem {
+        dest: Register,
+        src1: Register,
+        src2: Register,
+    },
+    /// Remainder (unsigned)
+    #[isa(0x36, 5, 5, 5)]
+    Remu {
+        dest: Register,
+        src1: Register,
+        src2: Register,
+    },
     #[isa(0x4, 5, 5, 5)]
     And {
         dest: Register,
@@ -65,6 +119,20 @@ pub enum Instruction {
         src: Register,
         shift: Register,
     },
+    /// Set if Less Than
+    #[isa(0x16, 5, 5, 5)]
+    Slt {
+        dest: Register,
+        src1: Register,
+        src2: Register,
+    },
+    /// Set if Less Than (Unsigned)
+    #[isa(0x17, 5, 5, 5)]
+    Sltu {
+        dest: Register,
+        src1: Register,
+        src2: Register,
+    },
     // --- Imm ---
     /// Add Immediate
     #[isa(0x13, 5, 5, 14)]
@@ -73,6 +141,62 @@ pub enum Instruction {
         src: Register,
         value: Immediate14,
     },
+    /// Set if Less Than Immediate
+    #[isa(0x18, 5, 5, 14)]
+    SltI {
+        dest: Register,
+        src: Register,
+        value: Immediate14,
+    },
+    /// Set if Less Than Immediate (Unsigned). The immediate is sign-extended before the comparison
+    #[isa(0x19, 5, 5, 14)]
+    SltIU {
+        dest: Register,
+        src: Register,
+        value: Immediate14,
+    },
+    /// And Immediate
+    #[isa(0x1a, 5, 5, 14)]
+    AndI {
+        dest: Register,
+        src: Register,
+        value: Immediate14,
+    },
+    /// Or Immediate
+    #[isa(0x1b, 5, 5, 14)]
+    OrI {
+        dest: Register,
+        src: Register,
+        value: Immediate14,
+    },
+    /// Xor Immediate
+    #[isa(0x1c, 5, 5, 14)]
+    XorI {
+        dest: Register,
+        src: Register,
+        value: Immediate14,
+    },
+    /// Shift Left Immediate. Only the lower 5 bits of the immediate are used
+    #[isa(0x1d, 5, 5, 14)]
+    SllI {
+        dest: Register,
+        src: Register,
+        shift: Immediate14,
+    },
+    /// Shift Right Logical Immediate. Only the lower 5 bits of the immediate are used
+    #[isa(0x1e, 5, 5, 14)]
+    SrlI {
+        dest: Register,
+        src: Register,
+        shift: Immediate14,
+    },
+    /// Shift Right Arith Immediate. Only the lower 5 bits of the immediate are used
+    #[isa(0x1f, 5, 5, 14)]
+    SraI {
+        dest: Register,
+        src: Register,
+        shift: Immediate14,
+    },
     /// Load Upper Immediate.
     #[isa(0x14, 5, 19)]
     Lui { dest: Register, value: Immediate19 },
@@ -90,17 +214,102 @@ pub enum Instruction {
         dest: Register,
         offset: Immediate14,
     },
-    // #[isa(0xe,5,5,5)]
-    // LoadByte {
-    //     dest: Register,
-    //     src: Register,
-    //     offset: u8,
-    // },
-    // #[isa(0xff,5,5,5)]
-    // Jal {
-    //     dest: Register,
-    //     // offset:
-    // },
+    /// Load Byte (sign-extended)
+    #[isa(0xa, 5, 5, 14)]
+    Lb {
+        dest: Register,
+        src: Register,
+        offset: Immediate14,
+    },
+    /// Load Halfword (sign-extended)
+    #[isa(0xb, 5, 5, 14)]
+    Lh {
+        dest: Register,
+        src: Register,
+        offset: Immediate14,
+    },
+    /// Load Byte Unsigned (zero-extended)
+    #[isa(0xe, 5, 5, 14)]
+    Lbu {
+        dest: Register,
+        src: Register,
+        offset: Immediate14,
+    },
+    /// Load Halfword Unsigned (zero-extended)
+    #[isa(0xf, 5, 5, 14)]
+    Lhu {
+        dest: Register,
+        src: Register,
+        offset: Immediate14,
+    },
+    /// Store Byte
+    #[isa(0x10, 5, 5, 14)]
+    Sb {
+        src: Register,
+        dest: Register,
+        offset: Immediate14,
+    },
+    /// Store Halfword
+    #[isa(0x11, 5, 5, 14)]
+    Sh {
+        src: Register,
+        dest: Register,
+        offset: Immediate14,
+    },
+    // --- Branch ---
+    /// Branch if Equal
+    #[isa(0x60, 5, 5, 14)]
+    Beq {
+        src1: Register,
+        src2: Register,
+        offset: Immediate14,
+    },
+    /// Branch if Not Equal
+    #[isa(0x61, 5, 5, 14)]
+    Bne {
+        src1: Register,
+        src2: Register,
+        offset: Immediate14,
+    },
+    /// Branch if Less Than
+    #[isa(0x62, 5, 5, 14)]
+    Blt {
+        src1: Register,
+        src2: Register,
+        offset: Immediate14,
+    },
+    /// Branch if Greater or Equal
+    #[isa(0x63, 5, 5, 14)]
+    Bge {
+        src1: Register,
+        src2: Register,
+        offset: Immediate14,
+    },
+    /// Branch if Less Than (Unsigned)
+    #[isa(0x64, 5, 5, 14)]
+    Bltu {
+        src1: Register,
+        src2: Register,
+        offset: Immediate14,
+    },
+    /// Branch if Greater or Equal (Unsigned)
+    #[isa(0x65, 5, 5, 14)]
+    Bgeu {
+        src1: Register,
+        src2: Register,
+        offset: Immediate14,
+    },
+    // --- Jump ---
+    /// Jump And Link
+    #[isa(0x6f, 5, 19)]
+    Jal { dest: Register, offset: Immediate19 },
+    /// Jump And Link Register
+    #[isa(0x67, 5, 5, 14)]
+    Jalr {
+        dest: Register,
+        src: Register,
+        offset: Immediate14,
+    },
     // TODO: Exit, halt, shutdown
     // pub const SIGHALT: u8 = 0xf;
     #[isa(0x73, 5, 5, 5)]
@@ -109,12 +318,66 @@ pub enum Instruction {
         src2: Register,
         src3: Register,
     },
+    /// Raise a breakpoint trap
+    #[isa(0x74)]
+    Ebreak,
+    // ---CSR, `dest` gets the old value---
+    /// Write `src` to `csr`
+    #[isa(0x75, 5, 5, 12)]
+    Csrrw {
+        dest: Register,
+        src: Register,
+        csr: Csr,
+    },
+    /// Set the bits of `csr` that are set in `src`
+    #[isa(0x76, 5, 5, 12)]
+    Csrrs {
+        dest: Register,
+        src: Register,
+        csr: Csr,
+    },
+    /// Clear the bits of `csr` that are set in `src`
+    #[isa(0x77, 5, 5, 12)]
+    Csrrc {
+        dest: Register,
+        src: Register,
+        csr: Csr,
+    },
+    #[isa(0x78, 5, 5, 12)]
+    Csrrwi {
+        dest: Register,
+        value: UImmediate5,
+        csr: Csr,
+    },
+    #[isa(0x79, 5, 5, 12)]
+    Csrrsi {
+        dest: Register,
+        value: UImmediate5,
+        csr: Csr,
+    },
+    #[isa(0x7a, 5, 5, 12)]
+    Csrrci {
+        dest: Register,
+        value: UImmediate5,
+        csr: Csr,
+    },
+    /// Return from a trap or interrupt handler to `epc`
+    #[isa(0x7b)]
+    Mret,
     // #[isa(0xff,5,5,5)]
     // Syscall { number: u32 },
     // #[isa(0x0,5,5,5)]
     // Halt,
 }
 
+/// Assembler syntax with `x` register names. Branch and jump offsets are printed as is, see
+/// [`Disassembler`] for ABI names and target annotations
+impl Display for Instruction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&Disassembler::new().text(self))
+    }
+}
+
 pub trait Codec {
     fn decode(src: u32, bit_accumulation: u32, bit_mask: u32) -> Self
     where
@@ -185,6 +448,16 @@ mod test {
                 dest: Register::X5,
                 value: Immediate19::new(150),
             },
+            Instruction::Mulhsu {
+                dest: Register::X1,
+                src1: Register::X2,
+                src2: Register::X3,
+            },
+            Instruction::Remu {
+                dest: Register::X31,
+                src1: Register::X30,
+                src2: Register::X29,
+            },
             Instruction::Lui {
                 dest: Register::X5,
                 value: Immediate19::new(150),
@@ -194,6 +467,16 @@ mod test {
                 src: Register::X11,
                 value: Immediate14::new(-31),
             },
+            Instruction::SltIU {
+                dest: Register::X10,
+                src: Register::X11,
+                value: Immediate14::new(-1),
+            },
+            Instruction::SraI {
+                dest: Register::X3,
+                src: Register::X4,
+                shift: Immediate14::new(31),
+            },
             // Instruction::LoadWord {
             //     dest: Register::X7,
             //     src: Register::X28,
@@ -204,6 +487,35 @@ mod test {
             //     src: Register::X28,
             //     offset: Immediate::new(11),
             // },
+            Instruction::Lbu {
+                dest: Register::X7,
+                src: Register::X28,
+                offset: Immediate14::new(-11),
+            },
+            Instruction::Sh {
+                src: Register::X7,
+                dest: Register::X2,
+                offset: Immediate14::new(6),
+            },
+            Instruction::Beq {
+                src1: Register::X5,
+                src2: Register::X6,
+                offset: Immediate14::new(-8),
+            },
+            Instruction::Bgeu {
+                src1: Register::X31,
+                src2: Register::X0,
+                offset: Immediate14::new(0x1FFC),
+            },
+            Instruction::Jal {
+                dest: Register::X1,
+                offset: Immediate19::new(-0x40000),
+            },
+            Instruction::Jalr {
+                dest: Register::X0,
+                src: Register::X1,
+                offset: Immediate14::new(0),
+            },
             Instruction::Syscall {
                 src1: Register::X11,
                 src2: Register::X12,
@@ -1,7 +1,10 @@
+pub mod csr;
+pub mod disassembler;
 pub mod instruction;
 mod memory;
 pub mod operand;
 mod register;
 
+pub use csr::Csr;
 pub use instruction::Instruction;
 pub use register::Register;
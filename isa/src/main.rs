@@ -0,0 +1,398 @@
+use std::{fmt::Write, fs, path::PathBuf, process::ExitCode};
+
+use clap::Parser;
+use isa::disassembler::Disassembler;
+use shared::{
+    elf,
+    object::{
+        Object, ObjectKind, ObjectSection, SECTION_ALLOC, SECTION_EXECINSTR, SectionContent,
+        SymbolSection, SymbolVisibility,
+    },
+};
+
+/// Display the sections of a RIVET binary or object file
+#[derive(Debug, Parser)]
+#[command(name = "rivet-objdump", version)]
+struct Args {
+    /// ELF file, rivet object or flat binary loaded at address 0
+    input: PathBuf,
+    /// Disassemble the executable sections, the default without `-D`, `-s` or `-t`
+    #[arg(short, long)]
+    disassemble: bool,
+    /// Disassemble every section with content
+    #[arg(short = 'D', long)]
+    disassemble_all: bool,
+    /// Hex dump of every section with content
+    #[arg(short = 's', long)]
+    full_contents: bool,
+    /// Print the symbol table
+    #[arg(short = 't', long)]
+    syms: bool,
+    /// Show the relocations under the instructions they patch
+    #[arg(short, long)]
+    reloc: bool,
+    /// Only show the section `NAME`, may be repeated
+    #[arg(short = 'j', long = "section", value_name = "NAME")]
+    sections: Vec<String>,
+    /// Name registers `a0`, `sp`, ... instead of `x10`, `x2`, ...
+    #[arg(long)]
+    abi_names: bool,
+}
+
+/// What to print, independently of the command line
+#[derive(Debug, Default)]
+struct Options {
+    disassemble: bool,
+    disassemble_all: bool,
+    full_contents: bool,
+    syms: bool,
+    reloc: bool,
+    sections: Vec<String>,
+    abi_names: bool,
+}
+
+/// Read `bytes` as an ELF file, a rivet object or a flat binary, with the name of the format
+fn read(bytes: &[u8]) -> Result<(Object, &'static str), String> {
+    if elf::is_elf(bytes) {
+        return elf::read(bytes)
+            .map(|object| (object, "elf32-rivet"))
+            .map_err(|err| err.to_string());
+    }
+    if Object::is_object(bytes) {
+        return Object::read(bytes)
+            .map(|object| (object, "rivet-object"))
+            .map_err(|err| err.to_string());
+    }
+
+    let object = Object {
+        kind: ObjectKind::Executable,
+        entry: 0,
+        sections: vec![ObjectSection {
+            name: 1,
+            content: SectionContent::Progbits,
+            flags: SECTION_ALLOC | SECTION_EXECINSTR,
+            alignment: 4,
+            address: 0,
+            size: bytes.len() as u32,
+            data: bytes.to_vec(),
+        }],
+        symbols: Vec::new(),
+        relocations: Vec::new(),
+        strtab: b"\0.text\0".to_vec(),
+    };
+    Ok((object, "binary"))
+}
+
+fn section_name(object: &Object, section: &ObjectSection) -> String {
+    object.name(section.name).unwrap_or("?").to_owned()
+}
+
+fn symbol_table(object: &Object, out: &mut String) {
+    writeln!(out, "SYMBOL TABLE:").unwrap();
+    for symbol in &object.symbols {
+        let (address, section) = match symbol.section {
+            SymbolSection::Undefined => (0, "*UND*".to_owned()),
+            SymbolSection::Absolute => (symbol.value, "*ABS*".to_owned()),
+            SymbolSection::Index(index) => (
+                object.symbol_address(symbol).unwrap_or(symbol.value),
+                object
+                    .sections
+                    .get(index as usize)
+                    .map_or("?".to_owned(), |section| section_name(object, section)),
+            ),
+        };
+        let visibility = match symbol.visibility {
+            SymbolVisibility::Local => 'l',
+            SymbolVisibility::Global => 'g',
+        };
+        writeln!(
+            out,
+            "{address:08x} {visibility}     {section}\t{}",
+            object.name(symbol.name).unwrap_or("?")
+        )
+        .unwrap();
+    }
+    writeln!(out).unwrap();
+}
+
+fn full_contents(section: &ObjectSection, name: &str, out: &mut String) {
+    writeln!(out, "Contents of section {name}:").unwrap();
+    for (line, chunk) in section.data.chunks(16).enumerate() {
+        let mut hex = String::new();
+        for (index, byte) in chunk.iter().enumerate() {
+            if index > 0 && index % 4 == 0 {
+                hex.push(' ');
+            }
+            write!(hex, "{byte:02x}").unwrap();
+        }
+        let ascii: String = chunk
+            .iter()
+            .map(|&byte| match byte {
+                0x20..=0x7e => byte as char,
+                _ => '.',
+            })
+            .collect();
+        writeln!(
+            out,
+            " {:04x} {hex:<35}  {ascii}",
+            section.address as usize + line * 16
+        )
+        .unwrap();
+    }
+}
+
+fn disassemble(
+    object: &Object,
+    index: usize,
+    disassembler: &Disassembler,
+    options: &Options,
+    out: &mut String,
+) {
+    let section = &object.sections[index];
+    writeln!(
+        out,
+        "\nDisassembly of section {}:",
+        section_name(object, section)
+    )
+    .unwrap();
+
+    for (offset, chunk) in (0..).step_by(4).zip(section.data.chunks(4)) {
+        let address = section.address + offset;
+        if let Some(label) = disassembler.label(address) {
+            writeln!(out, "\n{address:08x} <{label}>:").unwrap();
+        }
+
+        let text = match chunk.try_into() {
+            Ok(word) => {
+                let word = u32::from_le_bytes(word);
+                format!("{word:08x}\t{}", disassembler.word(word, address))
+            }
+            Err(_) => {
+                let bytes: Vec<_> = chunk.iter().map(|byte| format!("{byte:#04x}")).collect();
+                format!("        \t.byte {}", bytes.join(", "))
+            }
+        };
+        writeln!(out, "{address:8x}:\t{text}").unwrap();
+
+        if !options.reloc {
+            continue;
+        }
+        for relocation in object
+            .relocations
+            .iter()
+            .filter(|relocation| relocation.section as usize == index)
+            .filter(|relocation| relocation.offset == offset)
+        {
+            let symbol = object
+                .symbols
+                .get(relocation.symbol as usize)
+                .and_then(|symbol| object.name(symbol.name).ok())
+                .unwrap_or("?");
+            write!(out, "\t\t\t{address:x}: {:?}\t{symbol}", relocation.kind).unwrap();
+            if relocation.addend != 0 {
+                write!(out, "{:+#x}", relocation.addend).unwrap();
+            }
+            writeln!(out).unwrap();
+        }
+    }
+}
+
+/// Everything `options` asks for about `object`
+fn dump(object: &Object, options: &Options) -> String {
+    let mut out = String::new();
+
+    let mut disassembler = Disassembler::new();
+    disassembler.abi_names(options.abi_names);
+    // Globals first so that they label the addresses they share with locals
+    let mut symbols: Vec<_> = object
+        .symbols
+        .iter()
+        .filter(|symbol| matches!(symbol.section, SymbolSection::Index(_)))
+        .collect();
+    symbols.sort_by_key(|symbol| symbol.visibility != SymbolVisibility::Global);
+    for symbol in symbols {
+        if let (Some(address), Ok(name)) = (object.symbol_address(symbol), object.name(symbol.name))
+        {
+            disassembler.symbol(address, name);
+        }
+    }
+
+    if options.syms {
+        symbol_table(object, &mut out);
+    }
+
+    let selected = |section: &ObjectSection| {
+        options.sections.is_empty() || options.sections.contains(&section_name(object, section))
+    };
+    for (index, section) in object.sections.iter().enumerate() {
+        if !selected(section) || section.content != SectionContent::Progbits {
+            continue;
+        }
+
+        if options.full_contents {
+            full_contents(section, &section_name(object, section), &mut out);
+        }
+        let executable = section.flags & SECTION_EXECINSTR != 0;
+        if options.disassemble_all || (options.disassemble && executable) {
+            disassemble(object, index, &disassembler, options, &mut out);
+        }
+    }
+
+    out
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+
+    let bytes = match fs::read(&args.input) {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            eprintln!(
+                "rivet-objdump: unable to read `{}`: {err}",
+                args.input.display()
+            );
+            return ExitCode::FAILURE;
+        }
+    };
+    let (object, format) = match read(&bytes) {
+        Ok(read) => read,
+        Err(err) => {
+            eprintln!("rivet-objdump: {}: {err}", args.input.display());
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let nothing_asked = !(args.disassemble_all || args.full_contents || args.syms);
+    let options = Options {
+        disassemble: args.disassemble || nothing_asked,
+        disassemble_all: args.disassemble_all,
+        full_contents: args.full_contents,
+        syms: args.syms,
+        reloc: args.reloc,
+        sections: args.sections,
+        abi_names: args.abi_names,
+    };
+
+    println!("\n{}:     file format {format}", args.input.display());
+    println!("start address {:#010x}\n", object.entry);
+    print!("{}", dump(&object, &options));
+
+    ExitCode::SUCCESS
+}
+
+#[cfg(test)]
+mod test {
+    use isa::{
+        Instruction, Register,
+        operand::{Immediate14, Immediate19},
+    };
+    use shared::object::{ObjectSymbol, Relocation, RelocationKind, SECTION_WRITE};
+
+    use super::*;
+
+    fn object() -> Object {
+        let instructions = [
+            Instruction::AddI {
+                dest: Register::X10,
+                src: Register::X0,
+                value: Immediate14::new(0x104),
+            },
+            Instruction::Jal {
+                dest: Register::X1,
+                offset: Immediate19::new(-4),
+            },
+        ];
+        let mut text: Vec<u8> = instructions
+            .iter()
+            .flat_map(|instruction| u32::from(instruction).to_le_bytes())
+            .collect();
+        text.push(0xaa);
+
+        let section = |name, flags, address, data: Vec<u8>| ObjectSection {
+            name,
+            content: SectionContent::Progbits,
+            flags,
+            alignment: 4,
+            address,
+            size: data.len() as u32,
+            data,
+        };
+        Object {
+            kind: ObjectKind::Relocatable,
+            entry: 0,
+            sections: vec![
+                section(1, SECTION_ALLOC | SECTION_EXECINSTR, 0x100, text),
+                section(7, SECTION_ALLOC | SECTION_WRITE, 0x110, b"hi".to_vec()),
+            ],
+            symbols: vec![
+                ObjectSymbol {
+                    name: 13,
+                    value: 0,
+                    section: SymbolSection::Index(0),
+                    visibility: SymbolVisibility::Global,
+                },
+                ObjectSymbol {
+                    name: 20,
+                    value: 0,
+                    section: SymbolSection::Index(1),
+                    visibility: SymbolVisibility::Local,
+                },
+            ],
+            relocations: vec![Relocation {
+                section: 0,
+                offset: 0,
+                symbol: 1,
+                kind: RelocationKind::Abs14,
+                addend: 0,
+            }],
+            strtab: b"\0.text\0.data\0_start\0msg\0".to_vec(),
+        }
+    }
+
+    #[test]
+    fn t_disassemble_sections() {
+        let options = Options {
+            disassemble: true,
+            reloc: true,
+            abi_names: true,
+            ..Options::default()
+        };
+        let out = dump(&object(), &options);
+        assert_eq!(
+            out,
+            "\nDisassembly of section .text:\n\
+             \n00000100 <_start>:\n\
+             \x20    100:\t04100a13\taddi a0, zero, 260\n\
+             \t\t\t100: Abs14\tmsg\n\
+             \x20    104:\tffff816f\tjal ra, -4 # 0x100 <_start>\n\
+             \x20    108:\t        \t.byte 0xaa\n"
+        );
+
+        let options = Options {
+            full_contents: true,
+            syms: true,
+            sections: vec![".data".to_owned()],
+            ..Options::default()
+        };
+        let out = dump(&object(), &options);
+        assert!(out.contains("00000100 g     .text\t_start\n"));
+        assert!(out.contains("00000110 l     .data\tmsg\n"));
+        assert!(out.contains("Contents of section .data:\n 0110 6869"));
+        assert!(!out.contains("Contents of section .text"));
+    }
+
+    #[test]
+    fn t_read_formats() {
+        let object = object();
+        let (read_back, format) = read(&object.to_bytes()).unwrap();
+        assert_eq!((read_back, format), (object.clone(), "rivet-object"));
+
+        let (_, format) = read(&elf::write(&object)).unwrap();
+        assert_eq!(format, "elf32-rivet");
+
+        let (binary, format) = read(&[0x13, 0, 0, 0]).unwrap();
+        assert_eq!(format, "binary");
+        assert_eq!(binary.sections[0].address, 0);
+        assert_eq!(binary.name(binary.sections[0].name), Ok(".text"));
+    }
+}
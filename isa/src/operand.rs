@@ -190,6 +190,69 @@ impl<const BIT: u32> Shl<u32> for &Immediate<BIT> {
     }
 }
 
+/// Zero-extended 5-bit immediate, the source operand of `csrrwi`, `csrrsi` and `csrrci`
+#[derive(Debug, PartialEq, Eq, Default, Clone, Copy)]
+pub struct UImmediate5(u8);
+
+impl UImmediate5 {
+    const MAX: u32 = 0x1f;
+
+    pub fn new(value: u32) -> Self {
+        assert!(value <= Self::MAX, "the value does not fit into `5` bit");
+        UImmediate5(value as u8)
+    }
+
+    pub fn value(&self) -> u32 {
+        self.0 as u32
+    }
+}
+
+impl TryFrom<i32> for UImmediate5 {
+    type Error = ImmediateValueError;
+
+    fn try_from(value: i32) -> Result<Self, Self::Error> {
+        match value {
+            ..0 => Err(ImmediateValueError::ParseError(IntError(
+                IntErrorKind::NegOverflow,
+            ))),
+            0..=0x1f => Ok(UImmediate5(value as u8)),
+            _ => Err(ImmediateValueError::ParseError(IntError(
+                IntErrorKind::PosOverflow,
+            ))),
+        }
+    }
+}
+
+impl Codec for UImmediate5 {}
+
+impl From<u32> for UImmediate5 {
+    fn from(value: u32) -> Self {
+        UImmediate5((value & Self::MAX) as u8)
+    }
+}
+
+impl From<UImmediate5> for u32 {
+    fn from(value: UImmediate5) -> Self {
+        value.0 as u32
+    }
+}
+
+impl BitAnd<u32> for &UImmediate5 {
+    type Output = u32;
+
+    fn bitand(self, rhs: u32) -> Self::Output {
+        (self.0 as u32) & rhs
+    }
+}
+
+impl Shl<u32> for &UImmediate5 {
+    type Output = u32;
+
+    fn shl(self, rhs: u32) -> Self::Output {
+        (self.0 as u32) << rhs
+    }
+}
+
 #[cfg(test)]
 mod test_super {
     use super::*;
@@ -55,10 +55,34 @@ pub enum Register {
     X31, //T6
 }
 
+/// Calling convention names of `x0` to `x31`
+const ABI_NAMES: [&str; Register::VARIANT_COUNT] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
 impl Register {
     pub fn fp() -> Register {
         Register::X8
     }
+
+    /// Calling convention name, `a0` for `x10`
+    pub fn abi_name(self) -> &'static str {
+        ABI_NAMES[self as usize]
+    }
+
+    /// Register with the calling convention name `name`. `fp` is an alias of `s0`
+    pub fn from_abi_name(name: &str) -> Option<Register> {
+        if name == "fp" {
+            return Some(Register::fp());
+        }
+
+        ABI_NAMES
+            .iter()
+            .position(|abi_name| *abi_name == name)
+            .map(|index| Register::from(index as u32))
+    }
 }
 
 impl Codec for Register {}
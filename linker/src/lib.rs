@@ -0,0 +1,650 @@
+use std::{collections::HashMap, fs, path::Path};
+
+use shared::object::{
+    Object, ObjectError, ObjectKind, ObjectSection, ObjectSymbol, SECTION_EXECINSTR, SECTION_WRITE,
+    SectionContent, SymbolSection, SymbolVisibility,
+};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum LinkError {
+    #[error("Unable to read `{0}`: {1}")]
+    Io(String, std::io::Error),
+    #[error("{0}: {1}")]
+    Object(String, ObjectError),
+    #[error("{0}: not a relocatable object")]
+    NotRelocatable(String),
+    #[error("Duplicate symbol `{name}`: defined in `{first}` and `{second}`")]
+    DuplicateSymbol {
+        name: String,
+        first: String,
+        second: String,
+    },
+    #[error("{file}: undefined symbol `{name}`")]
+    UndefinedSymbol { file: String, name: String },
+    #[error("Entry symbol `{0}` is not defined")]
+    UndefinedEntry(String),
+    #[error("{file}: relocation against `{symbol}` at `{section}+{offset:#x}`: {error}")]
+    Relocation {
+        file: String,
+        section: String,
+        offset: u32,
+        symbol: String,
+        error: ObjectError,
+    },
+    #[error("The program doesn't fit in the 32-bit address space")]
+    AddressSpace,
+}
+
+struct Input {
+    name: String,
+    object: Object,
+}
+
+impl Input {
+    fn name(&self, offset: u32) -> Result<&str, LinkError> {
+        self.object
+            .name(offset)
+            .map_err(|err| LinkError::Object(self.name.clone(), err))
+    }
+}
+
+/// Sections with the same name, merged in input order
+struct OutputSection {
+    name: String,
+    content: SectionContent,
+    flags: u32,
+    alignment: u32,
+    address: u32,
+    size: u32,
+    data: Vec<u8>,
+}
+
+/// Where an input section ended up
+#[derive(Clone, Copy)]
+struct Placement {
+    /// Index of the output section
+    section: usize,
+    /// Offset of the input section in the output section
+    offset: u32,
+}
+
+/// Placement order of the output sections: code, read only data, data then zero filled data
+fn rank(section: &OutputSection) -> u8 {
+    if section.flags & SECTION_EXECINSTR != 0 {
+        0
+    } else if section.flags & SECTION_WRITE == 0 {
+        1
+    } else if section.content == SectionContent::Progbits {
+        2
+    } else {
+        3
+    }
+}
+
+/// Combines relocatable rivet objects into an executable
+pub struct Linker {
+    inputs: Vec<Input>,
+    base_address: u32,
+    entry: String,
+}
+
+impl Default for Linker {
+    fn default() -> Self {
+        Self {
+            inputs: Vec::new(),
+            base_address: 0,
+            entry: "_start".to_owned(),
+        }
+    }
+}
+
+impl Linker {
+    pub fn new() -> Linker {
+        Self::default()
+    }
+
+    /// Address of the first section, 0 by default
+    pub fn base_address(&mut self, address: u32) -> &mut Self {
+        self.base_address = address;
+        self
+    }
+
+    /// Symbol where the program starts, `_start` by default
+    pub fn entry(&mut self, symbol: impl Into<String>) -> &mut Self {
+        self.entry = symbol.into();
+        self
+    }
+
+    /// Add the object `bytes`. `name` identifies it in errors
+    pub fn add_object(
+        &mut self,
+        name: impl Into<String>,
+        bytes: &[u8],
+    ) -> Result<&mut Self, LinkError> {
+        let name = name.into();
+        let object = Object::read(bytes).map_err(|err| LinkError::Object(name.clone(), err))?;
+        if object.kind != ObjectKind::Relocatable {
+            return Err(LinkError::NotRelocatable(name));
+        }
+
+        self.inputs.push(Input { name, object });
+        Ok(self)
+    }
+
+    pub fn add_file(&mut self, path: impl AsRef<Path>) -> Result<&mut Self, LinkError> {
+        let name = path.as_ref().display().to_string();
+        let bytes = fs::read(path).map_err(|err| LinkError::Io(name.clone(), err))?;
+        self.add_object(name, &bytes)
+    }
+
+    /// Merge the sections of every input, place them from the base address and resolve every
+    /// relocation. The result has no relocation left
+    pub fn link(&self) -> Result<Object, LinkError> {
+        let (mut outputs, placements) = self.merge_sections()?;
+        self.place(&mut outputs)?;
+        let globals = self.globals(&outputs, &placements)?;
+        let addresses: Vec<u32> = outputs.iter().map(|output| output.address).collect();
+
+        // Address of the symbol `index` of the input `file`
+        let resolve = |file: usize, index: u32| -> Result<u32, LinkError> {
+            let input = &self.inputs[file];
+            let symbol = &input.object.symbols[index as usize];
+            match symbol.section {
+                SymbolSection::Index(section) => {
+                    let placement = placements[file][section as usize];
+                    Ok(addresses[placement.section] + placement.offset + symbol.value)
+                }
+                SymbolSection::Absolute => Ok(symbol.value),
+                SymbolSection::Undefined => {
+                    let name = input.name(symbol.name)?;
+                    globals
+                        .get(name)
+                        .map(|(address, _)| *address)
+                        .ok_or_else(|| LinkError::UndefinedSymbol {
+                            file: input.name.clone(),
+                            name: name.to_owned(),
+                        })
+                }
+            }
+        };
+
+        for (file, input) in self.inputs.iter().enumerate() {
+            for relocation in &input.object.relocations {
+                let value =
+                    resolve(file, relocation.symbol)?.wrapping_add_signed(relocation.addend);
+                let placement = placements[file][relocation.section as usize];
+                let output = &mut outputs[placement.section];
+                let offset = placement.offset + relocation.offset;
+
+                let error = |error| -> Result<LinkError, LinkError> {
+                    let symbol = &input.object.symbols[relocation.symbol as usize];
+                    Ok(LinkError::Relocation {
+                        file: input.name.clone(),
+                        section: output_name(input, relocation.section)?,
+                        offset: relocation.offset,
+                        symbol: input.name(symbol.name)?.to_owned(),
+                        error,
+                    })
+                };
+
+                let Some(field) = output.data.get_mut(offset as usize..offset as usize + 4) else {
+                    return Err(error(ObjectError::Truncated(offset as usize + 4))?);
+                };
+                let word = u32::from_le_bytes(field.try_into().unwrap());
+                let patched = match relocation.kind.apply(word, value, output.address + offset) {
+                    Ok(patched) => patched,
+                    Err(err) => return Err(error(err)?),
+                };
+                field.copy_from_slice(&patched.to_le_bytes());
+            }
+        }
+
+        let entry = match globals.get(self.entry.as_str()) {
+            Some((address, _)) => *address,
+            // A `_start` that wasn't made global is fine as long as there's only one
+            None => self.local_entry(&outputs, &placements)?,
+        };
+
+        self.output(outputs, &placements, entry)
+    }
+
+    fn merge_sections(&self) -> Result<(Vec<OutputSection>, Vec<Vec<Placement>>), LinkError> {
+        let mut outputs: Vec<OutputSection> = Vec::new();
+        let mut by_name: HashMap<&str, usize> = HashMap::new();
+        let mut placements = Vec::with_capacity(self.inputs.len());
+
+        for input in &self.inputs {
+            let mut placement = Vec::with_capacity(input.object.sections.len());
+            for section in &input.object.sections {
+                let name = input.name(section.name)?;
+                let index = *by_name.entry(name).or_insert_with(|| {
+                    outputs.push(OutputSection {
+                        name: name.to_owned(),
+                        content: section.content,
+                        flags: section.flags,
+                        alignment: 1,
+                        address: 0,
+                        size: 0,
+                        data: Vec::new(),
+                    });
+                    outputs.len() - 1
+                });
+
+                let output = &mut outputs[index];
+                let alignment = section.alignment.max(1);
+                let offset = output
+                    .size
+                    .checked_next_multiple_of(alignment)
+                    .ok_or(LinkError::AddressSpace)?;
+                output.alignment = output.alignment.max(alignment);
+                output.flags |= section.flags;
+                output.size = offset
+                    .checked_add(section.size)
+                    .ok_or(LinkError::AddressSpace)?;
+                if section.content == SectionContent::Progbits {
+                    output.content = SectionContent::Progbits;
+                    output.data.resize(offset as usize, 0);
+                    output.data.extend_from_slice(&section.data);
+                }
+
+                placement.push(Placement {
+                    section: index,
+                    offset,
+                });
+            }
+            placements.push(placement);
+        }
+
+        // Output sections with content are stored whole, including trailing `Nobits` parts
+        for output in &mut outputs {
+            if output.content == SectionContent::Progbits {
+                output.data.resize(output.size as usize, 0);
+            }
+        }
+
+        // Sort by placement order, so that output indices follow addresses
+        let mut order: Vec<usize> = (0..outputs.len()).collect();
+        order.sort_by_key(|&i| rank(&outputs[i]));
+        let mut new_index = vec![0; outputs.len()];
+        for (position, &index) in order.iter().enumerate() {
+            new_index[index] = position;
+        }
+
+        let mut outputs: Vec<_> = outputs.into_iter().map(Some).collect();
+        let outputs = order
+            .iter()
+            .map(|&index| outputs[index].take().unwrap())
+            .collect();
+        for placement in placements.iter_mut().flatten() {
+            placement.section = new_index[placement.section];
+        }
+
+        Ok((outputs, placements))
+    }
+
+    fn place(&self, outputs: &mut [OutputSection]) -> Result<(), LinkError> {
+        let mut address = self.base_address;
+        for output in outputs {
+            output.address = address
+                .checked_next_multiple_of(output.alignment)
+                .ok_or(LinkError::AddressSpace)?;
+            address = output
+                .address
+                .checked_add(output.size)
+                .ok_or(LinkError::AddressSpace)?;
+        }
+
+        Ok(())
+    }
+
+    /// Address and defining file of every global symbol
+    fn globals(
+        &self,
+        outputs: &[OutputSection],
+        placements: &[Vec<Placement>],
+    ) -> Result<HashMap<&str, (u32, usize)>, LinkError> {
+        let mut globals: HashMap<&str, (u32, usize)> = HashMap::new();
+
+        for (file, input) in self.inputs.iter().enumerate() {
+            for symbol in &input.object.symbols {
+                if symbol.visibility != SymbolVisibility::Global {
+                    continue;
+                }
+                let address = match symbol.section {
+                    SymbolSection::Undefined => continue,
+                    SymbolSection::Absolute => symbol.value,
+                    SymbolSection::Index(section) => {
+                        let placement = placements[file][section as usize];
+                        outputs[placement.section].address + placement.offset + symbol.value
+                    }
+                };
+
+                let name = input.name(symbol.name)?;
+                if let Some((_, first)) = globals.insert(name, (address, file)) {
+                    return Err(LinkError::DuplicateSymbol {
+                        name: name.to_owned(),
+                        first: self.inputs[first].name.clone(),
+                        second: input.name.clone(),
+                    });
+                }
+            }
+        }
+
+        Ok(globals)
+    }
+
+    fn local_entry(
+        &self,
+        outputs: &[OutputSection],
+        placements: &[Vec<Placement>],
+    ) -> Result<u32, LinkError> {
+        let mut found = None;
+        for (file, input) in self.inputs.iter().enumerate() {
+            for symbol in &input.object.symbols {
+                let SymbolSection::Index(section) = symbol.section else {
+                    continue;
+                };
+                if input.name(symbol.name)? != self.entry {
+                    continue;
+                }
+                if found.is_some() {
+                    return Err(LinkError::UndefinedEntry(self.entry.clone()));
+                }
+
+                let placement = placements[file][section as usize];
+                found = Some(outputs[placement.section].address + placement.offset + symbol.value);
+            }
+        }
+
+        found.ok_or_else(|| LinkError::UndefinedEntry(self.entry.clone()))
+    }
+
+    fn output(
+        &self,
+        outputs: Vec<OutputSection>,
+        placements: &[Vec<Placement>],
+        entry: u32,
+    ) -> Result<Object, LinkError> {
+        let mut strtab = vec![0];
+        let mut offsets: HashMap<String, u32> = HashMap::new();
+        let mut add_name = |name: &str| {
+            if let Some(offset) = offsets.get(name) {
+                return *offset;
+            }
+            let offset = strtab.len() as u32;
+            strtab.extend_from_slice(name.as_bytes());
+            strtab.push(0);
+            offsets.insert(name.to_owned(), offset);
+            offset
+        };
+
+        let mut symbols = Vec::new();
+        for (file, input) in self.inputs.iter().enumerate() {
+            for symbol in &input.object.symbols {
+                let (section, value) = match symbol.section {
+                    SymbolSection::Undefined => continue,
+                    SymbolSection::Absolute => (SymbolSection::Absolute, symbol.value),
+                    SymbolSection::Index(section) => {
+                        let placement = placements[file][section as usize];
+                        (
+                            SymbolSection::Index(placement.section as u16),
+                            placement.offset + symbol.value,
+                        )
+                    }
+                };
+
+                symbols.push(ObjectSymbol {
+                    name: add_name(input.name(symbol.name)?),
+                    value,
+                    section,
+                    visibility: symbol.visibility,
+                });
+            }
+        }
+
+        let sections = outputs
+            .into_iter()
+            .map(|output| ObjectSection {
+                name: add_name(&output.name),
+                content: output.content,
+                flags: output.flags,
+                alignment: output.alignment,
+                address: output.address,
+                size: output.size,
+                data: output.data,
+            })
+            .collect();
+
+        Ok(Object {
+            kind: ObjectKind::Executable,
+            entry,
+            sections,
+            symbols,
+            relocations: Vec::new(),
+            strtab,
+        })
+    }
+}
+
+fn output_name(input: &Input, section: u16) -> Result<String, LinkError> {
+    Ok(input
+        .name(input.object.sections[section as usize].name)?
+        .to_owned())
+}
+
+#[cfg(test)]
+mod test {
+    use assembler::Assembler;
+    use shared::elf;
+    use vm::{VM, loader::Loader, memory::MemoryConfiguration};
+
+    use super::*;
+
+    fn assemble(source: &str) -> Vec<u8> {
+        Assembler::new()
+            .relocatable(true)
+            .assemble(source.as_bytes())
+            .unwrap()
+            .to_object()
+    }
+
+    fn run(object: &Object) -> u32 {
+        let mut vm = VM::new(MemoryConfiguration::new(1024 * 1024).unwrap());
+        Loader::from_elf(&elf::write(object))
+            .unwrap()
+            .load(&mut vm)
+            .unwrap();
+        vm.run().unwrap();
+        vm.exit_code().unwrap()
+    }
+
+    const MAIN: &str = r#"
+    .global _start
+    .global sum
+    _start:
+        call sum
+        lui x5, %hi(bias)
+        lw x5, %lo(bias)(x5)
+        add x10, x10, x5
+        addi x20, x0, 0
+        syscall x0, x0, x0
+    .data
+    bias:
+        .word 100
+    "#;
+
+    const LIB: &str = r#"
+    .global sum
+    .global table
+    sum:
+        lui x6, %hi(table)
+        addi x6, x6, %lo(table)
+        lw x7, 0(x6)
+        lw x6, 4(x6)
+        lw x10, 0(x6)
+        add x10, x10, x7
+        ret
+    .data
+    ok:
+        .word 7
+    table:
+        .word 20, ok
+    "#;
+
+    #[test]
+    fn t_link_and_run() {
+        let mut linker = Linker::new();
+        linker
+            .base_address(0x1000)
+            .add_object("main.o", &assemble(MAIN))
+            .unwrap()
+            .add_object("lib.o", &assemble(LIB))
+            .unwrap();
+        let object = linker.link().unwrap();
+
+        let names: Vec<_> = object
+            .sections
+            .iter()
+            .map(|section| object.name(section.name).unwrap())
+            .collect();
+        assert_eq!(names, [".text", ".data"]);
+
+        // main's 6 instructions then lib's 7, the data follows in the same order
+        let [text, data] = &object.sections[..] else {
+            unreachable!()
+        };
+        assert_eq!((text.address, text.size), (0x1000, 13 * 4));
+        assert_eq!((data.address, data.size), (0x1000 + 13 * 4, 4 * 4));
+        assert_eq!(object.entry, 0x1000);
+
+        let table = object
+            .symbols
+            .iter()
+            .find(|symbol| object.name(symbol.name) == Ok("table"))
+            .unwrap();
+        assert_eq!(object.symbol_address(table), Some(data.address + 8));
+        // `.word ok` now holds the address of `ok` in the merged `.data`
+        assert_eq!(&data.data[12..], (data.address + 4).to_le_bytes());
+
+        assert_eq!(run(&object), 100 + 20 + 7);
+    }
+
+    #[test]
+    fn t_entry_symbol() {
+        let mut linker = Linker::new();
+        linker
+            .entry("main")
+            .add_object("main.o", &assemble(".global main\nnop\nmain:\nnop\n"))
+            .unwrap();
+        assert_eq!(linker.link().unwrap().entry, 4);
+
+        linker.entry("start");
+        assert!(matches!(
+            linker.link(),
+            Err(LinkError::UndefinedEntry(name)) if name == "start"
+        ));
+    }
+
+    #[test]
+    fn t_lui_symbol() {
+        let main = r#"
+        .global _start
+        _start:
+            lui x5, answer
+            addi x5, x5, %lo(answer)
+            lw x10, 0(x5)
+            addi x20, x0, 0
+            syscall x0, x0, x0
+        "#;
+        let mut linker = Linker::new();
+        // Far enough for `answer` to have upper bits
+        linker
+            .base_address(0x2_4000)
+            .add_object("main.o", &assemble(main))
+            .unwrap()
+            .add_object(
+                "answer.o",
+                &assemble(".global answer\n.data\nanswer:\n.word 42\n"),
+            )
+            .unwrap();
+        let object = linker.link().unwrap();
+
+        // The `Immediate19` holds the upper bits, not the whole address
+        let lui = u32::from_le_bytes(object.sections[0].data[..4].try_into().unwrap());
+        assert_eq!(lui >> 13, 0x2_4000 >> 13);
+        assert_eq!(run(&object), 42);
+    }
+
+    #[test]
+    fn t_symbol_errors() {
+        let mut linker = Linker::new();
+        linker.add_object("main.o", &assemble(MAIN)).unwrap();
+        assert!(matches!(
+            linker.link(),
+            Err(LinkError::UndefinedSymbol { file, name }) if file == "main.o" && name == "sum"
+        ));
+
+        linker
+            .add_object("lib.o", &assemble(LIB))
+            .unwrap()
+            .add_object("copy.o", &assemble(LIB))
+            .unwrap();
+        assert!(matches!(
+            linker.link(),
+            Err(LinkError::DuplicateSymbol { name, first, second })
+                if name == "sum" && first == "lib.o" && second == "copy.o"
+        ));
+    }
+
+    #[test]
+    fn t_merge_alignment() {
+        let text = |data: Vec<u8>, alignment| ObjectSection {
+            name: 1,
+            content: SectionContent::Progbits,
+            flags: shared::object::SECTION_ALLOC | SECTION_EXECINSTR,
+            alignment,
+            address: 0,
+            size: data.len() as u32,
+            data,
+        };
+        let object = |section| Object {
+            kind: ObjectKind::Relocatable,
+            entry: 0,
+            sections: vec![section],
+            symbols: vec![ObjectSymbol {
+                name: 7,
+                value: 0,
+                section: SymbolSection::Index(0),
+                visibility: SymbolVisibility::Local,
+            }],
+            relocations: Vec::new(),
+            strtab: b"\0.text\0_start\0".to_vec(),
+        };
+
+        // Both files define a local `_start`, the entry is ambiguous
+        let mut linker = Linker::new();
+        linker
+            .add_object("a.o", &object(text(vec![1, 2], 1)).to_bytes())
+            .unwrap()
+            .add_object("b.o", &object(text(vec![3], 8)).to_bytes())
+            .unwrap();
+        assert!(matches!(linker.link(), Err(LinkError::UndefinedEntry(_))));
+
+        let mut linker = Linker::new();
+        linker
+            .base_address(4)
+            .add_object("a.o", &object(text(vec![1, 2], 1)).to_bytes())
+            .unwrap();
+        let mut second = object(text(vec![3], 8));
+        second.symbols.clear();
+        linker.add_object("b.o", &second.to_bytes()).unwrap();
+
+        let linked = linker.link().unwrap();
+        let section = &linked.sections[0];
+        assert_eq!(linked.entry, 8);
+        assert_eq!(section.address, 8);
+        assert_eq!(section.alignment, 8);
+        assert_eq!(section.data, [1, 2, 0, 0, 0, 0, 0, 0, 3]);
+    }
+}
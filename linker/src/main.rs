@@ -0,0 +1,89 @@
+use std::{fs, path::PathBuf, process::ExitCode};
+
+use clap::{Parser, ValueEnum};
+use linker::Linker;
+use shared::elf;
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Format {
+    /// ELF32 executable (`ET_EXEC`)
+    Elf,
+    /// Rivet executable object
+    Object,
+}
+
+/// Link RIVET relocatable objects into an executable
+#[derive(Debug, Parser)]
+#[command(name = "rivet-ld", version)]
+struct Args {
+    /// Rivet relocatable objects, as produced by `rivet-as -f object`
+    #[arg(required = true)]
+    inputs: Vec<PathBuf>,
+    #[arg(short, long, default_value = "a.out")]
+    output: PathBuf,
+    #[arg(short, long, value_enum, default_value_t = Format::Elf)]
+    format: Format,
+    /// Address of the first section
+    #[arg(long, value_name = "ADDRESS", default_value = "0", value_parser = parse_address)]
+    base: u32,
+    /// Symbol where the program starts
+    #[arg(short, long, default_value = "_start")]
+    entry: String,
+}
+
+fn parse_address(address: &str) -> Result<u32, String> {
+    let parsed = match address.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => address.parse::<u32>(),
+    };
+
+    parsed.map_err(|err| format!("invalid address `{address}`: {err}"))
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+
+    let mut linker = Linker::new();
+    linker.base_address(args.base).entry(args.entry);
+    for input in &args.inputs {
+        if let Err(err) = linker.add_file(input) {
+            eprintln!("rivet-ld: {err}");
+            return ExitCode::FAILURE;
+        }
+    }
+
+    let object = match linker.link() {
+        Ok(object) => object,
+        Err(err) => {
+            eprintln!("rivet-ld: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let bytes = match args.format {
+        Format::Elf => elf::write(&object),
+        Format::Object => object.to_bytes(),
+    };
+    if let Err(err) = fs::write(&args.output, bytes) {
+        eprintln!(
+            "rivet-ld: unable to write `{}`: {err}",
+            args.output.display()
+        );
+        return ExitCode::FAILURE;
+    }
+
+    ExitCode::SUCCESS
+}
+
+#[cfg(test)]
+mod test {
+    use super::parse_address;
+
+    #[test]
+    fn t_parse_address() {
+        assert_eq!(parse_address("4096"), Ok(4096));
+        assert_eq!(parse_address("0x1000"), Ok(0x1000));
+        assert!(parse_address("0x").is_err());
+        assert!(parse_address("-1").is_err());
+    }
+}
@@ -0,0 +1,865 @@
+//! ELF32 little-endian view of a rivet [`Object`], so that `readelf` and `objdump` can inspect it.
+//!
+//! Relocatable objects become `ET_REL` and executables `ET_EXEC` with one `PT_LOAD` segment per
+//! allocated section. The section table is the null section, the object's sections in order, one
+//! `.rela<name>` section per section with relocations, then `.symtab`, `.strtab` and `.shstrtab`.
+//!
+//! `sh_addr` keeps the address the section was laid out at, even in relocatable objects, and symbol
+//! values are relative to their section in `ET_REL` and absolute in `ET_EXEC`. Relocation types are
+//! the values of [`RelocationKind`].
+//!
+//! [`read_executable`] is the other direction, for loaders: it only looks at the program headers.
+//! [`read`] goes back to an [`Object`] from the section headers, for tools that want the symbols.
+//!
+//! [`RelocationKind`]: crate::object::RelocationKind
+
+use thiserror::Error;
+
+use crate::object::{
+    Object, ObjectError, ObjectKind, ObjectSection, ObjectSymbol, Relocation, RelocationKind,
+    SECTION_ALLOC, SECTION_EXECINSTR, SECTION_WRITE, SectionContent, SymbolSection,
+    SymbolVisibility,
+};
+
+/// Unofficial machine number, ELF doesn't reserve a range for private use
+pub const EM_RIVET: u16 = 0x5256;
+
+pub const ELF_MAGIC: [u8; 4] = *b"\x7fELF";
+pub const EHDR_SIZE: usize = 52;
+pub const PHDR_SIZE: usize = 32;
+pub const SHDR_SIZE: usize = 40;
+pub const SYM_SIZE: usize = 16;
+pub const RELA_SIZE: usize = 12;
+
+const ELFCLASS32: u8 = 1;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+
+pub const ET_REL: u16 = 1;
+pub const ET_EXEC: u16 = 2;
+
+pub const PT_LOAD: u32 = 1;
+pub const PF_X: u32 = 0x1;
+pub const PF_W: u32 = 0x2;
+pub const PF_R: u32 = 0x4;
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_RELA: u32 = 4;
+const SHT_NOBITS: u32 = 8;
+
+const SHF_INFO_LINK: u32 = 0x40;
+
+const SHN_UNDEF: u16 = 0;
+const SHN_ABS: u16 = 0xFFF1;
+
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+const STT_NOTYPE: u8 = 0;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ElfError {
+    #[error("Not an ELF file")]
+    BadMagic,
+    #[error("Only 32-bit little-endian ELF files are supported")]
+    UnsupportedFormat,
+    #[error("Unsupported machine `{0:#06x}`")]
+    UnsupportedMachine(u16),
+    #[error("Not an executable, ELF type `{0}`")]
+    NotExecutable(u16),
+    #[error("Unsupported ELF type `{0}`")]
+    UnsupportedType(u16),
+    #[error("Invalid section index `{0}`")]
+    InvalidSection(u32),
+    #[error(transparent)]
+    Object(#[from] ObjectError),
+    #[error("Truncated ELF file: expected `{0}` bytes")]
+    Truncated(usize),
+    #[error("Segment at `{0:#010x}` has a file size larger than its memory size")]
+    InvalidSegment(u32),
+}
+
+/// A `PT_LOAD` segment
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub address: u32,
+    /// Size in memory, the bytes after `data` are zero filled
+    pub size: u32,
+    /// `PF_R`, `PF_W` and `PF_X`
+    pub flags: u32,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Executable {
+    pub entry: u32,
+    pub segments: Vec<Segment>,
+}
+
+pub fn is_elf(bytes: &[u8]) -> bool {
+    bytes.starts_with(&ELF_MAGIC)
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Result<u16, ElfError> {
+    bytes
+        .get(offset..offset + 2)
+        .map(|field| u16::from_le_bytes(field.try_into().unwrap()))
+        .ok_or(ElfError::Truncated(offset + 2))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Result<u32, ElfError> {
+    bytes
+        .get(offset..offset + 4)
+        .map(|field| u32::from_le_bytes(field.try_into().unwrap()))
+        .ok_or(ElfError::Truncated(offset + 4))
+}
+
+/// Check the identification and machine of the file and return its type
+fn read_header(bytes: &[u8]) -> Result<u16, ElfError> {
+    if !is_elf(bytes) {
+        return Err(ElfError::BadMagic);
+    }
+    if bytes.len() < EHDR_SIZE {
+        return Err(ElfError::Truncated(EHDR_SIZE));
+    }
+    if bytes[4] != ELFCLASS32 || bytes[5] != ELFDATA2LSB {
+        return Err(ElfError::UnsupportedFormat);
+    }
+
+    let machine = read_u16(bytes, 18)?;
+    if machine != EM_RIVET {
+        return Err(ElfError::UnsupportedMachine(machine));
+    }
+
+    read_u16(bytes, 16)
+}
+
+/// The entry point and loadable segments of an `ET_EXEC` file
+pub fn read_executable(bytes: &[u8]) -> Result<Executable, ElfError> {
+    let ty = read_header(bytes)?;
+    if ty != ET_EXEC {
+        return Err(ElfError::NotExecutable(ty));
+    }
+
+    let entry = read_u32(bytes, 24)?;
+    let phoff = read_u32(bytes, 28)? as usize;
+    let phentsize = read_u16(bytes, 42)? as usize;
+    let phnum = read_u16(bytes, 44)? as usize;
+
+    let mut segments = Vec::new();
+    for index in 0..phnum {
+        let header = phoff + index * phentsize;
+        if read_u32(bytes, header)? != PT_LOAD {
+            continue;
+        }
+
+        let offset = read_u32(bytes, header + 4)? as usize;
+        let address = read_u32(bytes, header + 8)?;
+        let file_size = read_u32(bytes, header + 16)? as usize;
+        let size = read_u32(bytes, header + 20)?;
+        let flags = read_u32(bytes, header + 24)?;
+        if file_size > size as usize {
+            return Err(ElfError::InvalidSegment(address));
+        }
+
+        let data = bytes
+            .get(offset..offset + file_size)
+            .ok_or(ElfError::Truncated(offset + file_size))?
+            .to_vec();
+        segments.push(Segment {
+            address,
+            size,
+            flags,
+            data,
+        });
+    }
+
+    Ok(Executable { entry, segments })
+}
+
+/// A section header, the fields [`read`] needs
+struct SectionHeader {
+    name: u32,
+    ty: u32,
+    flags: u32,
+    address: u32,
+    offset: usize,
+    size: usize,
+    link: u32,
+    info: u32,
+    alignment: u32,
+}
+
+impl SectionHeader {
+    fn contents<'a>(&self, bytes: &'a [u8]) -> Result<&'a [u8], ElfError> {
+        bytes
+            .get(self.offset..self.offset + self.size)
+            .ok_or(ElfError::Truncated(self.offset + self.size))
+    }
+}
+
+/// The sections, symbols and relocations of an `ET_REL` or `ET_EXEC` file. Sections other than
+/// `PROGBITS` and `NOBITS` are left out, and symbol values become relative to their section again
+pub fn read(bytes: &[u8]) -> Result<Object, ElfError> {
+    let kind = match read_header(bytes)? {
+        ET_REL => ObjectKind::Relocatable,
+        ET_EXEC => ObjectKind::Executable,
+        ty => return Err(ElfError::UnsupportedType(ty)),
+    };
+    let entry = read_u32(bytes, 24)?;
+    let shoff = read_u32(bytes, 32)? as usize;
+    let shentsize = read_u16(bytes, 46)? as usize;
+    let shnum = read_u16(bytes, 48)? as usize;
+    let shstrndx = read_u16(bytes, 50)? as usize;
+
+    let headers = (0..shnum)
+        .map(|index| {
+            let header = shoff + index * shentsize;
+            Ok(SectionHeader {
+                name: read_u32(bytes, header)?,
+                ty: read_u32(bytes, header + 4)?,
+                flags: read_u32(bytes, header + 8)?,
+                address: read_u32(bytes, header + 12)?,
+                offset: read_u32(bytes, header + 16)? as usize,
+                size: read_u32(bytes, header + 20)? as usize,
+                link: read_u32(bytes, header + 24)?,
+                info: read_u32(bytes, header + 28)?,
+                alignment: read_u32(bytes, header + 32)?,
+            })
+        })
+        .collect::<Result<Vec<_>, ElfError>>()?;
+    let header = |index: u32| {
+        headers
+            .get(index as usize)
+            .ok_or(ElfError::InvalidSection(index))
+    };
+
+    let shstrtab = header(shstrndx as u32)?.contents(bytes)?;
+    let symtab = headers.iter().find(|header| header.ty == SHT_SYMTAB);
+
+    // The symbol names keep their offsets, the section names follow them
+    let mut strtab = match symtab {
+        Some(symtab) => header(symtab.link)?.contents(bytes)?.to_vec(),
+        None => vec![0],
+    };
+
+    let mut sections = Vec::new();
+    // Object index of every ELF section
+    let mut indices = vec![None; headers.len()];
+    for (index, section) in headers.iter().enumerate() {
+        let content = match section.ty {
+            SHT_PROGBITS => SectionContent::Progbits,
+            SHT_NOBITS => SectionContent::Nobits,
+            _ => continue,
+        };
+
+        let name = shstrtab
+            .get(section.name as usize..)
+            .and_then(|name| name.split(|&byte| byte == 0).next())
+            .ok_or(ObjectError::InvalidString(section.name))?;
+        indices[index] = Some(sections.len() as u16);
+        sections.push(ObjectSection {
+            name: strtab.len() as u32,
+            content,
+            flags: section.flags & (SECTION_WRITE | SECTION_ALLOC | SECTION_EXECINSTR),
+            alignment: section.alignment,
+            address: section.address,
+            size: section.size as u32,
+            data: match content {
+                SectionContent::Progbits => section.contents(bytes)?.to_vec(),
+                SectionContent::Nobits => Vec::new(),
+            },
+        });
+        strtab.extend_from_slice(name);
+        strtab.push(0);
+    }
+    let section_index = |index: u32| {
+        indices
+            .get(index as usize)
+            .copied()
+            .flatten()
+            .ok_or(ElfError::InvalidSection(index))
+    };
+
+    let mut symbols = Vec::new();
+    if let Some(symtab) = symtab {
+        for entry in symtab.contents(bytes)?.chunks_exact(SYM_SIZE).skip(1) {
+            let name = read_u32(entry, 0)?;
+            let value = read_u32(entry, 4)?;
+            let shndx = read_u16(entry, 14)?;
+            let section = match shndx {
+                SHN_UNDEF => SymbolSection::Undefined,
+                SHN_ABS => SymbolSection::Absolute,
+                index => SymbolSection::Index(section_index(index as u32)?),
+            };
+            let value = match section {
+                SymbolSection::Index(index) if kind == ObjectKind::Executable => {
+                    value.wrapping_sub(sections[index as usize].address)
+                }
+                _ => value,
+            };
+
+            symbols.push(ObjectSymbol {
+                name,
+                value,
+                section,
+                visibility: match entry[12] >> 4 {
+                    STB_LOCAL => SymbolVisibility::Local,
+                    _ => SymbolVisibility::Global,
+                },
+            });
+        }
+    }
+
+    let mut relocations = Vec::new();
+    for rela in headers.iter().filter(|header| header.ty == SHT_RELA) {
+        let section = section_index(rela.info)?;
+        for entry in rela.contents(bytes)?.chunks_exact(RELA_SIZE) {
+            let info = read_u32(entry, 4)?;
+            // The null symbol isn't in `symbols`
+            let symbol = (info >> 8)
+                .checked_sub(1)
+                .filter(|&symbol| (symbol as usize) < symbols.len())
+                .ok_or(ObjectError::InvalidSymbol(info >> 8))?;
+
+            relocations.push(Relocation {
+                section,
+                offset: read_u32(entry, 0)?,
+                symbol,
+                kind: RelocationKind::try_from(info as u8)?,
+                addend: read_u32(entry, 8)? as i32,
+            });
+        }
+    }
+
+    Ok(Object {
+        kind,
+        entry,
+        sections,
+        symbols,
+        relocations,
+        strtab,
+    })
+}
+
+/// Serialize `object` as an ELF32 file
+pub fn write(object: &Object) -> Vec<u8> {
+    let executable = object.kind == ObjectKind::Executable;
+
+    // ELF wants the local symbols first, `order[i]` is the object index of the ELF symbol `i + 1`
+    let mut order: Vec<usize> = (0..object.symbols.len()).collect();
+    order.sort_by_key(|&i| object.symbols[i].visibility == SymbolVisibility::Global);
+    let mut elf_index = vec![0; order.len()];
+    for (position, &index) in order.iter().enumerate() {
+        elf_index[index] = position as u32 + 1;
+    }
+    let first_global = order
+        .iter()
+        .position(|&i| object.symbols[i].visibility == SymbolVisibility::Global)
+        .unwrap_or(order.len())
+        + 1;
+
+    // Object sections with relocations, each gets a `.rela` section after the object's ones
+    let relocated: Vec<usize> = (0..object.sections.len())
+        .filter(|&index| {
+            object
+                .relocations
+                .iter()
+                .any(|relocation| relocation.section as usize == index)
+        })
+        .collect();
+    let symtab_index = (object.sections.len() + relocated.len()) as u32 + 1;
+    let strtab_index = symtab_index + 1;
+    let shstrtab_index = symtab_index + 2;
+    let section_count = shstrtab_index as usize + 1;
+
+    // Section names
+    let mut shstrtab = vec![0];
+    let mut add_name = |name: &str| {
+        let offset = shstrtab.len() as u32;
+        shstrtab.extend_from_slice(name.as_bytes());
+        shstrtab.push(0);
+        offset
+    };
+    let names: Vec<u32> = object
+        .sections
+        .iter()
+        .map(|section| add_name(object.name(section.name).unwrap_or_default()))
+        .collect();
+    let rela_names: Vec<u32> = relocated
+        .iter()
+        .map(|&index| {
+            add_name(&format!(
+                ".rela{}",
+                object.name(object.sections[index].name).unwrap_or_default()
+            ))
+        })
+        .collect();
+    let symtab_name = add_name(".symtab");
+    let strtab_name = add_name(".strtab");
+    let shstrtab_name = add_name(".shstrtab");
+
+    // Symbols
+    let mut symtab = vec![0; SYM_SIZE];
+    for &index in &order {
+        let symbol = &object.symbols[index];
+        let (shndx, value) = match symbol.section {
+            SymbolSection::Undefined => (SHN_UNDEF, 0),
+            SymbolSection::Absolute => (SHN_ABS, symbol.value),
+            SymbolSection::Index(section) if executable => (
+                section + 1,
+                object.symbol_address(symbol).unwrap_or(symbol.value),
+            ),
+            SymbolSection::Index(section) => (section + 1, symbol.value),
+        };
+        let bind = match symbol.visibility {
+            SymbolVisibility::Local => STB_LOCAL,
+            SymbolVisibility::Global => STB_GLOBAL,
+        };
+
+        symtab.extend_from_slice(&symbol.name.to_le_bytes());
+        symtab.extend_from_slice(&value.to_le_bytes());
+        symtab.extend_from_slice(&0u32.to_le_bytes());
+        symtab.extend_from_slice(&[bind << 4 | STT_NOTYPE, 0]);
+        symtab.extend_from_slice(&shndx.to_le_bytes());
+    }
+
+    let relas: Vec<Vec<u8>> = relocated
+        .iter()
+        .map(|&index| {
+            let mut bytes = Vec::new();
+            for relocation in object
+                .relocations
+                .iter()
+                .filter(|relocation| relocation.section as usize == index)
+            {
+                let info = elf_index[relocation.symbol as usize] << 8 | relocation.kind as u32;
+                bytes.extend_from_slice(&relocation.offset.to_le_bytes());
+                bytes.extend_from_slice(&info.to_le_bytes());
+                bytes.extend_from_slice(&relocation.addend.to_le_bytes());
+            }
+            bytes
+        })
+        .collect();
+
+    // File layout: headers, section contents, tables, then the section header table
+    let segments: Vec<usize> = if executable {
+        (0..object.sections.len())
+            .filter(|&i| object.sections[i].flags & SECTION_ALLOC != 0)
+            .collect()
+    } else {
+        Vec::new()
+    };
+    let mut offset = EHDR_SIZE + segments.len() * PHDR_SIZE;
+    let mut offsets = Vec::with_capacity(object.sections.len());
+    for section in &object.sections {
+        // Congruent to the address modulo the alignment, as loaders expect for segments
+        offset = offset.next_multiple_of(section.alignment.max(1) as usize);
+        offsets.push(offset);
+        if section.content == SectionContent::Progbits {
+            offset += section.data.len();
+        }
+    }
+    let mut table = |len: usize, alignment: usize| {
+        let start = offset.next_multiple_of(alignment);
+        offset = start + len;
+        start
+    };
+    let rela_offsets: Vec<usize> = relas.iter().map(|rela| table(rela.len(), 4)).collect();
+    let symtab_offset = table(symtab.len(), 4);
+    let strtab_offset = table(object.strtab.len(), 1);
+    let shstrtab_offset = table(shstrtab.len(), 1);
+    let shoff = table(section_count * SHDR_SIZE, 4);
+
+    let mut bytes = Vec::with_capacity(offset);
+    bytes.extend_from_slice(&ELF_MAGIC);
+    bytes.extend_from_slice(&[ELFCLASS32, ELFDATA2LSB, EV_CURRENT]);
+    bytes.resize(16, 0);
+    bytes.extend_from_slice(&(if executable { ET_EXEC } else { ET_REL }).to_le_bytes());
+    bytes.extend_from_slice(&EM_RIVET.to_le_bytes());
+    bytes.extend_from_slice(&(EV_CURRENT as u32).to_le_bytes());
+    bytes.extend_from_slice(&object.entry.to_le_bytes());
+    let phoff = if segments.is_empty() { 0 } else { EHDR_SIZE };
+    for value in [phoff as u32, shoff as u32, 0] {
+        bytes.extend_from_slice(&value.to_le_bytes());
+    }
+    for value in [
+        EHDR_SIZE as u16,
+        PHDR_SIZE as u16,
+        segments.len() as u16,
+        SHDR_SIZE as u16,
+        section_count as u16,
+        shstrtab_index as u16,
+    ] {
+        bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    for &index in &segments {
+        let section = &object.sections[index];
+        let mut flags = PF_R;
+        if section.flags & SECTION_WRITE != 0 {
+            flags |= PF_W;
+        }
+        if section.flags & SECTION_EXECINSTR != 0 {
+            flags |= PF_X;
+        }
+
+        for value in [
+            PT_LOAD,
+            offsets[index] as u32,
+            section.address,
+            section.address,
+            section.data.len() as u32,
+            section.size,
+            flags,
+            section.alignment,
+        ] {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+
+    for (section, &offset) in object.sections.iter().zip(&offsets) {
+        if section.content == SectionContent::Progbits {
+            bytes.resize(offset, 0);
+            bytes.extend_from_slice(&section.data);
+        }
+    }
+    for (rela, &offset) in relas.iter().zip(&rela_offsets) {
+        bytes.resize(offset, 0);
+        bytes.extend_from_slice(rela);
+    }
+    for (table, offset) in [
+        (&symtab, symtab_offset),
+        (&object.strtab, strtab_offset),
+        (&shstrtab, shstrtab_offset),
+    ] {
+        bytes.resize(offset, 0);
+        bytes.extend_from_slice(table);
+    }
+
+    bytes.resize(shoff, 0);
+    let mut header = |name, ty, flags, addr, offset, size, link, info, align, entsize| {
+        for value in [
+            name, ty, flags, addr, offset, size, link, info, align, entsize,
+        ] {
+            bytes.extend_from_slice(&u32::to_le_bytes(value));
+        }
+    };
+    header(0, 0, 0, 0, 0, 0, 0, 0, 0, 0);
+    for ((section, &offset), &name) in object.sections.iter().zip(&offsets).zip(&names) {
+        let ty = match section.content {
+            SectionContent::Progbits => SHT_PROGBITS,
+            SectionContent::Nobits => SHT_NOBITS,
+        };
+        header(
+            name,
+            ty,
+            section.flags,
+            section.address,
+            offset as u32,
+            section.size,
+            0,
+            0,
+            section.alignment,
+            0,
+        );
+    }
+    for ((&index, &offset), (rela, &name)) in relocated
+        .iter()
+        .zip(&rela_offsets)
+        .zip(relas.iter().zip(&rela_names))
+    {
+        header(
+            name,
+            SHT_RELA,
+            SHF_INFO_LINK,
+            0,
+            offset as u32,
+            rela.len() as u32,
+            symtab_index,
+            index as u32 + 1,
+            4,
+            RELA_SIZE as u32,
+        );
+    }
+    header(
+        symtab_name,
+        SHT_SYMTAB,
+        0,
+        0,
+        symtab_offset as u32,
+        symtab.len() as u32,
+        strtab_index,
+        first_global as u32,
+        4,
+        SYM_SIZE as u32,
+    );
+    header(
+        strtab_name,
+        SHT_STRTAB,
+        0,
+        0,
+        strtab_offset as u32,
+        object.strtab.len() as u32,
+        0,
+        0,
+        1,
+        0,
+    );
+    header(
+        shstrtab_name,
+        SHT_STRTAB,
+        0,
+        0,
+        shstrtab_offset as u32,
+        shstrtab.len() as u32,
+        0,
+        0,
+        1,
+        0,
+    );
+
+    bytes
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+    }
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn object(kind: ObjectKind) -> Object {
+        Object {
+            kind,
+            entry: 4,
+            sections: vec![
+                ObjectSection {
+                    name: 1,
+                    content: SectionContent::Progbits,
+                    flags: SECTION_ALLOC | SECTION_EXECINSTR,
+                    alignment: 4,
+                    address: 0,
+                    size: 8,
+                    data: vec![1, 2, 3, 4, 5, 6, 7, 8],
+                },
+                ObjectSection {
+                    name: 7,
+                    content: SectionContent::Nobits,
+                    flags: SECTION_ALLOC | SECTION_WRITE,
+                    alignment: 4,
+                    address: 8,
+                    size: 16,
+                    data: Vec::new(),
+                },
+            ],
+            symbols: vec![
+                ObjectSymbol {
+                    name: 12,
+                    value: 4,
+                    section: SymbolSection::Index(0),
+                    visibility: SymbolVisibility::Global,
+                },
+                ObjectSymbol {
+                    name: 17,
+                    value: 0,
+                    section: SymbolSection::Index(1),
+                    visibility: SymbolVisibility::Local,
+                },
+            ],
+            relocations: vec![Relocation {
+                section: 0,
+                offset: 0,
+                symbol: 1,
+                kind: RelocationKind::Abs14,
+                addend: 0,
+            }],
+            strtab: b"\0.text\0.bss\0main\0buf\0".to_vec(),
+        }
+    }
+
+    /// Offset of the header of section `index` and its name
+    fn section(bytes: &[u8], index: usize) -> (usize, String) {
+        let shoff = u32_at(bytes, 32) as usize;
+        let shstrndx = u16_at(bytes, 50) as usize;
+        let strings = u32_at(bytes, shoff + shstrndx * SHDR_SIZE + 16) as usize;
+
+        let header = shoff + index * SHDR_SIZE;
+        let name = &bytes[strings + u32_at(bytes, header) as usize..];
+        let end = name.iter().position(|&byte| byte == 0).unwrap();
+        (header, String::from_utf8(name[..end].to_vec()).unwrap())
+    }
+
+    #[test]
+    fn t_relocatable() {
+        let bytes = write(&object(ObjectKind::Relocatable));
+
+        assert_eq!(&bytes[..4], ELF_MAGIC);
+        assert_eq!(u16_at(&bytes, 16), ET_REL);
+        assert_eq!(u16_at(&bytes, 18), EM_RIVET);
+        assert_eq!(u16_at(&bytes, 44), 0);
+
+        let names: Vec<_> = (0..u16_at(&bytes, 48) as usize)
+            .map(|index| section(&bytes, index).1)
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "",
+                ".text",
+                ".bss",
+                ".rela.text",
+                ".symtab",
+                ".strtab",
+                ".shstrtab"
+            ]
+        );
+
+        // The local `buf` comes first, so the relocation now points at symbol 1
+        let (text, _) = section(&bytes, 1);
+        let data = u32_at(&bytes, text + 16) as usize;
+        assert_eq!(&bytes[data..data + 8], [1, 2, 3, 4, 5, 6, 7, 8]);
+
+        let (rela, _) = section(&bytes, 3);
+        assert_eq!(u32_at(&bytes, rela + 28), 1);
+        let entries = u32_at(&bytes, rela + 16) as usize;
+        assert_eq!(
+            u32_at(&bytes, entries + 4),
+            1 << 8 | RelocationKind::Abs14 as u32
+        );
+
+        let (symtab, _) = section(&bytes, 4);
+        assert_eq!(u32_at(&bytes, symtab + 28), 2);
+        let symbols = u32_at(&bytes, symtab + 16) as usize;
+        let main = symbols + 2 * SYM_SIZE;
+        assert_eq!(u32_at(&bytes, main), 12);
+        assert_eq!(u32_at(&bytes, main + 4), 4);
+        assert_eq!(bytes[main + 12], STB_GLOBAL << 4);
+        assert_eq!(u16_at(&bytes, main + 14), 1);
+    }
+
+    #[test]
+    fn t_executable() {
+        let mut object = object(ObjectKind::Executable);
+        object.relocations.clear();
+        let bytes = write(&object);
+
+        assert_eq!(u16_at(&bytes, 16), ET_EXEC);
+        assert_eq!(u32_at(&bytes, 24), 4);
+        assert_eq!(u16_at(&bytes, 44), 2);
+
+        let phoff = u32_at(&bytes, 28) as usize;
+        let text = phoff;
+        assert_eq!(u32_at(&bytes, text), PT_LOAD);
+        assert_eq!(u32_at(&bytes, text + 8), 0);
+        assert_eq!(u32_at(&bytes, text + 24), PF_R | PF_X);
+        let data = u32_at(&bytes, text + 4) as usize;
+        assert_eq!(&bytes[data..data + 8], [1, 2, 3, 4, 5, 6, 7, 8]);
+
+        let bss = phoff + PHDR_SIZE;
+        assert_eq!(u32_at(&bytes, bss + 8), 8);
+        assert_eq!(u32_at(&bytes, bss + 16), 0);
+        assert_eq!(u32_at(&bytes, bss + 20), 16);
+        assert_eq!(u32_at(&bytes, bss + 24), PF_R | PF_W);
+
+        // Symbol values are absolute: `buf` is at 8
+        let (symtab, name) = section(&bytes, 3);
+        assert_eq!(name, ".symtab");
+        let symbols = u32_at(&bytes, symtab + 16) as usize;
+        assert_eq!(u32_at(&bytes, symbols + SYM_SIZE + 4), 8);
+    }
+
+    #[test]
+    fn t_read_executable() {
+        let mut object = object(ObjectKind::Executable);
+        object.relocations.clear();
+        let executable = read_executable(&write(&object)).unwrap();
+
+        assert_eq!(executable.entry, 4);
+        assert_eq!(
+            executable.segments,
+            [
+                Segment {
+                    address: 0,
+                    size: 8,
+                    flags: PF_R | PF_X,
+                    data: vec![1, 2, 3, 4, 5, 6, 7, 8],
+                },
+                Segment {
+                    address: 8,
+                    size: 16,
+                    flags: PF_R | PF_W,
+                    data: Vec::new(),
+                },
+            ]
+        );
+
+        let relocatable = write(&self::object(ObjectKind::Relocatable));
+        assert_eq!(
+            read_executable(&relocatable),
+            Err(ElfError::NotExecutable(ET_REL))
+        );
+        assert_eq!(read_executable(b"RVTO"), Err(ElfError::BadMagic));
+    }
+
+    #[test]
+    fn t_read() {
+        for kind in [ObjectKind::Relocatable, ObjectKind::Executable] {
+            let mut original = object(kind);
+            if kind == ObjectKind::Executable {
+                original.relocations.clear();
+            }
+            let read = read(&write(&original)).unwrap();
+
+            assert_eq!(read.kind, kind);
+            assert_eq!(read.entry, original.entry);
+            let names: Vec<_> = read
+                .sections
+                .iter()
+                .map(|section| read.name(section.name).unwrap())
+                .collect();
+            assert_eq!(names, [".text", ".bss"]);
+            assert_eq!(read.sections[0].data, original.sections[0].data);
+            assert_eq!(read.sections[1].size, 16);
+
+            // Locals come first in ELF: `buf`, then `main`
+            let symbols: Vec<_> = read
+                .symbols
+                .iter()
+                .map(|symbol| {
+                    (
+                        read.name(symbol.name).unwrap(),
+                        symbol.value,
+                        symbol.section,
+                    )
+                })
+                .collect();
+            assert_eq!(
+                symbols,
+                [
+                    ("buf", 0, SymbolSection::Index(1)),
+                    ("main", 4, SymbolSection::Index(0))
+                ]
+            );
+            assert_eq!(read.relocations.len(), original.relocations.len());
+        }
+
+        let read = read(&write(&object(ObjectKind::Relocatable))).unwrap();
+        assert_eq!(
+            read.relocations,
+            [Relocation {
+                section: 0,
+                offset: 0,
+                symbol: 0,
+                kind: RelocationKind::Abs14,
+                addend: 0,
+            }]
+        );
+    }
+}
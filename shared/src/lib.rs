@@ -1,5 +1,8 @@
 use std::ops::{Add, Neg, Range, RangeInclusive, Sub};
 
+pub mod elf;
+pub mod object;
+
 pub use macros_derive::{EnumCount, EnumVariants, VMInstruction};
 
 #[derive(Debug, thiserror::Error)]
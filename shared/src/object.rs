@@ -0,0 +1,576 @@
+//! Rivet object format (`.o`), little-endian:
+//!
+//! | Part          | Size                     |
+//! |---------------|--------------------------|
+//! | Header        | 32 bytes                 |
+//! | Section table | 28 bytes per section     |
+//! | Symbol table  | 12 bytes per symbol      |
+//! | Relocations   | 16 bytes per relocation  |
+//! | String table  | `strtab_size` bytes      |
+//! | Contents      | section data, 4-aligned  |
+//!
+//! Header: `magic: [u8; 4]`, `version: u16`, `kind: u16`, `entry: u32`, `section_count: u32`,
+//! `symbol_count: u32`, `relocation_count: u32`, `strtab_size: u32`, `reserved: u32`.
+//!
+//! Section: `name: u32`, `content: u8`, `reserved: [u8; 3]`, `flags: u32`, `alignment: u32`,
+//! `address: u32`, `size: u32`, `offset: u32`. `offset` is the position of the data in the file and is
+//! 0 for `Nobits` sections.
+//!
+//! Symbol: `name: u32`, `value: u32`, `section: u16`, `visibility: u8`, `reserved: u8`. `value` is
+//! relative to the start of the section.
+//!
+//! Relocation: `section: u16`, `kind: u8`, `reserved: u8`, `offset: u32`, `symbol: u32`, `addend: i32`.
+//! The patched field is at `offset` in `section`, and receives `S + A` (`S + A - P` for PC-relative
+//! kinds) where `S` is the address of `symbol`, `A` the addend and `P` the address of the field.
+//! Relocations against defined symbols are already applied for the section addresses in the table.
+//!
+//! Names are offsets in the string table, a sequence of NUL terminated strings starting with an
+//! empty one.
+
+use thiserror::Error;
+
+pub const MAGIC: [u8; 4] = *b"RVTO";
+pub const VERSION: u16 = 1;
+
+pub const HEADER_SIZE: usize = 32;
+pub const SECTION_SIZE: usize = 28;
+pub const SYMBOL_SIZE: usize = 12;
+pub const RELOCATION_SIZE: usize = 16;
+
+/// Section is writable
+pub const SECTION_WRITE: u32 = 0x1;
+/// Section occupies memory during execution
+pub const SECTION_ALLOC: u32 = 0x2;
+/// Section contains executable instructions
+pub const SECTION_EXECINSTR: u32 = 0x4;
+
+const UNDEFINED_SECTION: u16 = 0xFFFF;
+const ABSOLUTE_SECTION: u16 = 0xFFFE;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ObjectError {
+    #[error("Not a rivet object file")]
+    BadMagic,
+    #[error("Unsupported object version `{0}`")]
+    UnsupportedVersion(u16),
+    #[error("Truncated object file: expected `{0}` bytes")]
+    Truncated(usize),
+    #[error("Unknown object kind `{0}`")]
+    UnknownKind(u16),
+    #[error("Unknown section content `{0}`")]
+    UnknownContent(u8),
+    #[error("Unknown relocation kind `{0}`")]
+    UnknownRelocation(u8),
+    #[error("Invalid string table offset `{0}`")]
+    InvalidString(u32),
+    #[error("Invalid section index `{0}`")]
+    InvalidSection(u16),
+    #[error("Invalid symbol index `{0}`")]
+    InvalidSymbol(u32),
+    #[error("Relocation `{0:?}` overflow: `{1}` doesn't fit in the field")]
+    RelocationOverflow(RelocationKind, i32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObjectKind {
+    /// Output of the assembler, may reference undefined symbols
+    Relocatable = 0,
+    /// Every symbol is resolved and the sections are at their final address
+    Executable = 1,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SectionContent {
+    /// Data stored in the file
+    Progbits = 0,
+    /// Zero filled, only the size is stored
+    Nobits = 1,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObjectSection {
+    pub name: u32,
+    pub content: SectionContent,
+    pub flags: u32,
+    pub alignment: u32,
+    pub address: u32,
+    pub size: u32,
+    /// Empty for `Nobits` sections
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolSection {
+    /// Defined in another object
+    Undefined,
+    /// The value doesn't move with any section
+    Absolute,
+    /// Index in the section table
+    Index(u16),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolVisibility {
+    Local = 0,
+    Global = 1,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObjectSymbol {
+    pub name: u32,
+    pub value: u32,
+    pub section: SymbolSection,
+    pub visibility: SymbolVisibility,
+}
+
+/// How a relocation patches its field
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelocationKind {
+    /// PC-relative offset in the `Immediate14` of a branch
+    Branch14 = 1,
+    /// PC-relative offset in the `Immediate19` of a jump
+    Jump19 = 2,
+    /// Absolute value in an `Immediate14`
+    Abs14 = 3,
+    /// Absolute value in an `Immediate19`
+    Abs19 = 4,
+    /// Absolute 32-bit word
+    Abs32 = 5,
+    /// Bits 31..13 of the absolute value in the `Immediate19` of a `Lui`
+    Hi19 = 6,
+    /// Bits 12..0 of the absolute value in an `Immediate14`, completes a [`RelocationKind::Hi19`]
+    Lo13 = 7,
+}
+
+impl RelocationKind {
+    pub fn is_pc_relative(self) -> bool {
+        matches!(self, RelocationKind::Branch14 | RelocationKind::Jump19)
+    }
+
+    /// Patch the instruction or data `word` located at `place` with `value` (`S + A`).
+    /// `Immediate14` fields are at bits 18..31 and `Immediate19` fields at bits 13..31
+    pub fn apply(self, word: u32, value: u32, place: u32) -> Result<u32, ObjectError> {
+        let (field, bits, shift) = match self {
+            RelocationKind::Branch14 => (value.wrapping_sub(place) as i32, 14, 18),
+            RelocationKind::Jump19 => (value.wrapping_sub(place) as i32, 19, 13),
+            RelocationKind::Abs14 => (value as i32, 14, 18),
+            RelocationKind::Abs19 => (value as i32, 19, 13),
+            RelocationKind::Abs32 => return Ok(value),
+            RelocationKind::Hi19 => ((value as i32) >> 13, 19, 13),
+            RelocationKind::Lo13 => ((value & 0x1FFF) as i32, 14, 18),
+        };
+
+        let max = (1 << (bits - 1)) - 1;
+        if field < -(max + 1) || field > max {
+            return Err(ObjectError::RelocationOverflow(self, field));
+        }
+
+        let mask = ((1u32 << bits) - 1) << shift;
+        Ok(word & !mask | ((field as u32) << shift) & mask)
+    }
+}
+
+impl TryFrom<u8> for RelocationKind {
+    type Error = ObjectError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Ok(match value {
+            1 => RelocationKind::Branch14,
+            2 => RelocationKind::Jump19,
+            3 => RelocationKind::Abs14,
+            4 => RelocationKind::Abs19,
+            5 => RelocationKind::Abs32,
+            6 => RelocationKind::Hi19,
+            7 => RelocationKind::Lo13,
+            _ => return Err(ObjectError::UnknownRelocation(value)),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Relocation {
+    /// Section holding the field to patch
+    pub section: u16,
+    /// Offset of the field in the section
+    pub offset: u32,
+    /// Index in the symbol table
+    pub symbol: u32,
+    pub kind: RelocationKind,
+    pub addend: i32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Object {
+    pub kind: ObjectKind,
+    pub entry: u32,
+    pub sections: Vec<ObjectSection>,
+    pub symbols: Vec<ObjectSymbol>,
+    pub relocations: Vec<Relocation>,
+    pub strtab: Vec<u8>,
+}
+
+impl Object {
+    pub fn is_object(bytes: &[u8]) -> bool {
+        bytes.starts_with(&MAGIC)
+    }
+
+    /// The string at `offset` in the string table
+    pub fn name(&self, offset: u32) -> Result<&str, ObjectError> {
+        let tail = self
+            .strtab
+            .get(offset as usize..)
+            .ok_or(ObjectError::InvalidString(offset))?;
+        let end = tail
+            .iter()
+            .position(|&byte| byte == 0)
+            .ok_or(ObjectError::InvalidString(offset))?;
+
+        std::str::from_utf8(&tail[..end]).map_err(|_| ObjectError::InvalidString(offset))
+    }
+
+    /// Address of `symbol` once the sections are placed at their address
+    pub fn symbol_address(&self, symbol: &ObjectSymbol) -> Option<u32> {
+        match symbol.section {
+            SymbolSection::Undefined => None,
+            SymbolSection::Absolute => Some(symbol.value),
+            SymbolSection::Index(index) => self
+                .sections
+                .get(index as usize)
+                .map(|section| section.address.wrapping_add(symbol.value)),
+        }
+    }
+
+    pub fn read(bytes: &[u8]) -> Result<Object, ObjectError> {
+        let mut reader = Reader { bytes, position: 0 };
+
+        if reader.take(4)? != MAGIC {
+            return Err(ObjectError::BadMagic);
+        }
+        let version = reader.u16()?;
+        if version != VERSION {
+            return Err(ObjectError::UnsupportedVersion(version));
+        }
+        let kind = match reader.u16()? {
+            0 => ObjectKind::Relocatable,
+            1 => ObjectKind::Executable,
+            kind => return Err(ObjectError::UnknownKind(kind)),
+        };
+        let entry = reader.u32()?;
+        let section_count = reader.u32()? as usize;
+        let symbol_count = reader.u32()? as usize;
+        let relocation_count = reader.u32()? as usize;
+        let strtab_size = reader.u32()? as usize;
+        reader.u32()?;
+
+        let mut sections = Vec::with_capacity(section_count.min(bytes.len() / SECTION_SIZE));
+        for _ in 0..section_count {
+            let name = reader.u32()?;
+            let content = match reader.u8()? {
+                0 => SectionContent::Progbits,
+                1 => SectionContent::Nobits,
+                content => return Err(ObjectError::UnknownContent(content)),
+            };
+            reader.take(3)?;
+            let flags = reader.u32()?;
+            let alignment = reader.u32()?;
+            let address = reader.u32()?;
+            let size = reader.u32()?;
+            let offset = reader.u32()? as usize;
+
+            let data = match content {
+                SectionContent::Progbits => {
+                    let end = offset + size as usize;
+                    bytes
+                        .get(offset..end)
+                        .ok_or(ObjectError::Truncated(end))?
+                        .to_vec()
+                }
+                SectionContent::Nobits => Vec::new(),
+            };
+
+            sections.push(ObjectSection {
+                name,
+                content,
+                flags,
+                alignment,
+                address,
+                size,
+                data,
+            });
+        }
+
+        let mut symbols = Vec::with_capacity(symbol_count.min(bytes.len() / SYMBOL_SIZE));
+        for _ in 0..symbol_count {
+            let name = reader.u32()?;
+            let value = reader.u32()?;
+            let section = match reader.u16()? {
+                UNDEFINED_SECTION => SymbolSection::Undefined,
+                ABSOLUTE_SECTION => SymbolSection::Absolute,
+                index if (index as usize) < sections.len() => SymbolSection::Index(index),
+                index => return Err(ObjectError::InvalidSection(index)),
+            };
+            let visibility = match reader.u8()? {
+                0 => SymbolVisibility::Local,
+                _ => SymbolVisibility::Global,
+            };
+            reader.u8()?;
+
+            symbols.push(ObjectSymbol {
+                name,
+                value,
+                section,
+                visibility,
+            });
+        }
+
+        let mut relocations =
+            Vec::with_capacity(relocation_count.min(bytes.len() / RELOCATION_SIZE));
+        for _ in 0..relocation_count {
+            let section = reader.u16()?;
+            if section as usize >= sections.len() {
+                return Err(ObjectError::InvalidSection(section));
+            }
+            let kind = RelocationKind::try_from(reader.u8()?)?;
+            reader.u8()?;
+            let offset = reader.u32()?;
+            let symbol = reader.u32()?;
+            if symbol as usize >= symbols.len() {
+                return Err(ObjectError::InvalidSymbol(symbol));
+            }
+            let addend = reader.u32()? as i32;
+
+            relocations.push(Relocation {
+                section,
+                offset,
+                symbol,
+                kind,
+                addend,
+            });
+        }
+
+        let strtab = reader.take(strtab_size)?.to_vec();
+
+        Ok(Object {
+            kind,
+            entry,
+            sections,
+            symbols,
+            relocations,
+            strtab,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let tables = HEADER_SIZE
+            + self.sections.len() * SECTION_SIZE
+            + self.symbols.len() * SYMBOL_SIZE
+            + self.relocations.len() * RELOCATION_SIZE
+            + self.strtab.len();
+
+        // Offset of every section's data
+        let mut offset = tables.next_multiple_of(4);
+        let offsets: Vec<usize> = self
+            .sections
+            .iter()
+            .map(|section| match section.content {
+                SectionContent::Progbits => {
+                    let current = offset;
+                    offset = (offset + section.data.len()).next_multiple_of(4);
+                    current
+                }
+                SectionContent::Nobits => 0,
+            })
+            .collect();
+
+        let mut bytes = Vec::with_capacity(offset);
+        bytes.extend_from_slice(&MAGIC);
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        bytes.extend_from_slice(&(self.kind as u16).to_le_bytes());
+        for value in [
+            self.entry,
+            self.sections.len() as u32,
+            self.symbols.len() as u32,
+            self.relocations.len() as u32,
+            self.strtab.len() as u32,
+            0,
+        ] {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+
+        for (section, offset) in self.sections.iter().zip(&offsets) {
+            bytes.extend_from_slice(&section.name.to_le_bytes());
+            bytes.extend_from_slice(&[section.content as u8, 0, 0, 0]);
+            for value in [
+                section.flags,
+                section.alignment,
+                section.address,
+                section.size,
+                *offset as u32,
+            ] {
+                bytes.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+
+        for symbol in &self.symbols {
+            let section = match symbol.section {
+                SymbolSection::Undefined => UNDEFINED_SECTION,
+                SymbolSection::Absolute => ABSOLUTE_SECTION,
+                SymbolSection::Index(index) => index,
+            };
+            bytes.extend_from_slice(&symbol.name.to_le_bytes());
+            bytes.extend_from_slice(&symbol.value.to_le_bytes());
+            bytes.extend_from_slice(&section.to_le_bytes());
+            bytes.extend_from_slice(&[symbol.visibility as u8, 0]);
+        }
+
+        for relocation in &self.relocations {
+            bytes.extend_from_slice(&relocation.section.to_le_bytes());
+            bytes.extend_from_slice(&[relocation.kind as u8, 0]);
+            bytes.extend_from_slice(&relocation.offset.to_le_bytes());
+            bytes.extend_from_slice(&relocation.symbol.to_le_bytes());
+            bytes.extend_from_slice(&relocation.addend.to_le_bytes());
+        }
+
+        bytes.extend_from_slice(&self.strtab);
+
+        for (section, offset) in self.sections.iter().zip(offsets) {
+            if section.content == SectionContent::Progbits {
+                bytes.resize(offset, 0);
+                bytes.extend_from_slice(&section.data);
+            }
+        }
+
+        bytes
+    }
+}
+
+/// Little-endian cursor over the object bytes
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], ObjectError> {
+        let end = self.position + len;
+        let slice = self
+            .bytes
+            .get(self.position..end)
+            .ok_or(ObjectError::Truncated(end))?;
+        self.position = end;
+        Ok(slice)
+    }
+
+    fn u8(&mut self) -> Result<u8, ObjectError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, ObjectError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, ObjectError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn object() -> Object {
+        Object {
+            kind: ObjectKind::Relocatable,
+            entry: 0,
+            sections: vec![
+                ObjectSection {
+                    name: 1,
+                    content: SectionContent::Progbits,
+                    flags: SECTION_ALLOC | SECTION_EXECINSTR,
+                    alignment: 4,
+                    address: 0,
+                    size: 8,
+                    data: vec![1, 2, 3, 4, 5, 6, 7, 8],
+                },
+                ObjectSection {
+                    name: 7,
+                    content: SectionContent::Nobits,
+                    flags: SECTION_ALLOC | SECTION_WRITE,
+                    alignment: 1,
+                    address: 8,
+                    size: 16,
+                    data: Vec::new(),
+                },
+            ],
+            symbols: vec![
+                ObjectSymbol {
+                    name: 12,
+                    value: 4,
+                    section: SymbolSection::Index(0),
+                    visibility: SymbolVisibility::Global,
+                },
+                ObjectSymbol {
+                    name: 17,
+                    value: 0,
+                    section: SymbolSection::Undefined,
+                    visibility: SymbolVisibility::Global,
+                },
+            ],
+            relocations: vec![Relocation {
+                section: 0,
+                offset: 4,
+                symbol: 1,
+                kind: RelocationKind::Jump19,
+                addend: -4,
+            }],
+            strtab: b"\0.text\0.bss\0main\0puts\0".to_vec(),
+        }
+    }
+
+    #[test]
+    fn t_round_trip() {
+        let object = object();
+        let bytes = object.to_bytes();
+        let read = Object::read(&bytes).unwrap();
+
+        assert_eq!(read, object);
+        assert_eq!(read.name(read.sections[1].name), Ok(".bss"));
+        assert_eq!(read.name(read.symbols[1].name), Ok("puts"));
+        assert_eq!(read.symbol_address(&read.symbols[0]), Some(4));
+    }
+
+    #[test]
+    fn t_apply_relocation() {
+        // `jal x1, 0` at 0x100, opcode and register fields are kept
+        let jal = 0x0000_00EF;
+        assert_eq!(
+            RelocationKind::Jump19.apply(jal, 0xF0, 0x100),
+            Ok((-16i32 as u32) << 13 | jal)
+        );
+
+        let address = 0x1234_5678;
+        let hi = RelocationKind::Hi19.apply(0, address, 0).unwrap() >> 13;
+        let lo = RelocationKind::Lo13.apply(0, address, 0).unwrap() >> 18;
+        assert_eq!((hi << 13) + lo, address);
+
+        assert_eq!(RelocationKind::Abs32.apply(0, address, 0), Ok(address));
+        assert_eq!(
+            RelocationKind::Branch14.apply(0, 0x10_0000, 0),
+            Err(ObjectError::RelocationOverflow(
+                RelocationKind::Branch14,
+                0x10_0000
+            ))
+        );
+    }
+
+    #[test]
+    fn t_invalid_object() {
+        let bytes = object().to_bytes();
+
+        assert_eq!(Object::read(b"ELF!"), Err(ObjectError::BadMagic));
+        assert!(matches!(
+            Object::read(&bytes[..40]),
+            Err(ObjectError::Truncated(_))
+        ));
+    }
+}
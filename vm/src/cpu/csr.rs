@@ -0,0 +1,134 @@
+use std::time::Instant;
+
+use isa::Csr;
+
+use super::TrapState;
+use crate::interrupt::{InterruptController, Timer};
+
+/// Control and status registers, read and written by the `csrr*` instructions
+#[derive(Debug)]
+pub struct Csrs {
+    /// [`Csrs::STATUS_IE`] and [`Csrs::STATUS_PIE`], other bits read back what was written
+    pub status: u32,
+    /// Free for the trap handler, e.g. to stash a register
+    pub scratch: u32,
+    /// `tvec`, `cause`, `tval` and `epc`
+    pub trap: TrapState,
+    /// `ie` and `ip`
+    pub interrupts: InterruptController,
+    /// `timecmp`
+    pub timer: Timer,
+    /// Instructions executed, the ones that trapped included
+    pub cycle: u64,
+    /// Instructions that completed
+    pub instret: u64,
+    /// `time` counts from here
+    started: Instant,
+}
+
+impl Default for Csrs {
+    fn default() -> Self {
+        Self {
+            status: 0,
+            scratch: 0,
+            trap: TrapState::default(),
+            interrupts: InterruptController::default(),
+            timer: Timer::default(),
+            cycle: 0,
+            instret: 0,
+            started: Instant::now(),
+        }
+    }
+}
+
+impl Csrs {
+    /// Interrupts are taken while set
+    pub const STATUS_IE: u32 = 1 << 3;
+    /// `IE` before the trap being handled, restored by `mret`
+    pub const STATUS_PIE: u32 = 1 << 7;
+
+    /// Microseconds since the VM was created or reset
+    pub fn time(&self) -> u64 {
+        self.started.elapsed().as_micros() as u64
+    }
+
+    /// Value of `csr`, `None` if it's not implemented
+    pub fn read(&self, csr: Csr) -> Option<u32> {
+        let value = match csr {
+            Csr::STATUS => self.status,
+            Csr::IE => self.interrupts.enabled,
+            Csr::TVEC => self.trap.vector,
+            Csr::SCRATCH => self.scratch,
+            Csr::EPC => self.trap.pc,
+            Csr::CAUSE => self.trap.cause,
+            Csr::TVAL => self.trap.value,
+            Csr::IP => self.interrupts.pending,
+            Csr::TIMECMP => self.timer.compare as u32,
+            Csr::TIMECMPH => (self.timer.compare >> 32) as u32,
+            Csr::CYCLE => self.cycle as u32,
+            Csr::CYCLEH => (self.cycle >> 32) as u32,
+            Csr::TIME => self.time() as u32,
+            Csr::TIMEH => (self.time() >> 32) as u32,
+            Csr::INSTRET => self.instret as u32,
+            Csr::INSTRETH => (self.instret >> 32) as u32,
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    /// Set `csr` to `value`, `false` if it's not implemented or read-only
+    pub fn write(&mut self, csr: Csr, value: u32) -> bool {
+        let compare = self.timer.compare;
+        let register = match csr {
+            Csr::IP => {
+                self.interrupts.write_pending(value);
+                return true;
+            }
+            Csr::TIMECMP => {
+                self.timer.compare = compare & !0xffff_ffff | value as u64;
+                return true;
+            }
+            Csr::TIMECMPH => {
+                self.timer.compare = compare & 0xffff_ffff | (value as u64) << 32;
+                return true;
+            }
+            Csr::STATUS => &mut self.status,
+            Csr::IE => &mut self.interrupts.enabled,
+            Csr::TVEC => &mut self.trap.vector,
+            Csr::SCRATCH => &mut self.scratch,
+            Csr::EPC => &mut self.trap.pc,
+            Csr::CAUSE => &mut self.trap.cause,
+            Csr::TVAL => &mut self.trap.value,
+            _ => return false,
+        };
+        *register = value;
+        true
+    }
+
+    /// Record a trap or interrupt, with `cause` and `value`, of the instruction at `pc` and
+    /// disable interrupts until `mret`. Returns the handler's address
+    pub fn enter_trap(&mut self, cause: u32, value: u32, pc: u32) -> u32 {
+        self.trap.cause = cause;
+        self.trap.value = value;
+        self.trap.pc = pc;
+        let enabled = self.status & Self::STATUS_IE != 0;
+        self.status &= !(Self::STATUS_IE | Self::STATUS_PIE);
+        if enabled {
+            self.status |= Self::STATUS_PIE;
+        }
+        self.trap.vector
+    }
+
+    /// Restore interrupts as they were before the trap. Returns where to resume
+    pub fn return_from_trap(&mut self) -> u32 {
+        if self.status & Self::STATUS_PIE != 0 {
+            self.status |= Self::STATUS_IE;
+        }
+        self.status |= Self::STATUS_PIE;
+        self.trap.pc
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+}
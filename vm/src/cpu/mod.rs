@@ -1,12 +1,14 @@
+pub mod csr;
 pub mod register;
 
+use csr::Csrs;
 use register::Registers;
 
 #[derive(Default, Debug)]
 pub struct CPU {
     pub registers: Registers,
     pub pc: ProgramCounter,
-    flags: u32,
+    pub csrs: Csrs,
 }
 
 impl CPU {
@@ -14,11 +16,24 @@ impl CPU {
         CPU {
             registers: Default::default(),
             pc: ProgramCounter::new(),
-            flags: 0,
+            csrs: Csrs::default(),
         }
     }
 }
 
+/// Where traps go and what the last one taken was, see [`crate::trap::Trap`]
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrapState {
+    /// Address of the guest's handler, 0 if traps stop the VM
+    pub vector: u32,
+    /// [`crate::trap::Trap::cause`]
+    pub cause: u32,
+    /// [`crate::trap::Trap::value`]
+    pub value: u32,
+    /// Address of the instruction that trapped
+    pub pc: u32,
+}
+
 #[derive(Default, Debug)]
 pub struct ProgramCounter(u32);
 
@@ -32,6 +47,17 @@ impl ProgramCounter {
         self.0 += 4
     }
 
+    /// Move the counter by `offset` bytes relative to the instruction that is currently being executed
+    #[inline(always)]
+    pub fn offset(&mut self, offset: i32) {
+        self.0 = self.0.wrapping_sub(4).wrapping_add_signed(offset);
+    }
+
+    #[inline(always)]
+    pub fn set(&mut self, value: u32) {
+        self.0 = value;
+    }
+
     #[inline(always)]
     pub fn value(&self) -> u32 {
         self.0
@@ -3,7 +3,7 @@ use std::ops::{Index, IndexMut};
 use isa::Register;
 use shared::EnumCount;
 
-#[derive(Default, Debug)]
+#[derive(Default, Debug, Clone)]
 pub struct Registers([u32; Register::VARIANT_COUNT]);
 
 impl Registers {
@@ -11,8 +11,10 @@ impl Registers {
         self.0[register as usize]
     }
 
+    /// Writes to `X0` are discarded, it always reads as zero
     pub fn set(&mut self, register: Register, value: u32) {
         self.0[register as usize] = value;
+        self.0[Register::X0 as usize] = 0;
     }
 
     pub fn reset(&mut self) {
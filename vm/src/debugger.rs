@@ -0,0 +1,657 @@
+use std::{
+    collections::BTreeSet,
+    fmt::Write as _,
+    io::{self, BufRead, Write},
+};
+
+use isa::{Instruction, Register, disassembler::Disassembler};
+use shared::EnumCount;
+use thiserror::Error;
+
+use crate::{
+    VM,
+    memory::{MemoryError, WatchHit, WatchKind},
+    trap::Trap,
+};
+
+const HELP: &str = "\
+break|b LOC          stop before executing the instruction at LOC
+delete|d LOC         remove the breakpoint at LOC
+step|s [N]           execute N instructions, 1 by default
+next|n               execute one instruction, calls run until they return
+continue|c           run until a breakpoint, a watchpoint or the exit
+registers|r          print pc and the registers
+x LOC [LEN]          hex dump LEN bytes from LOC, 64 by default
+watch LOC [LEN]      stop after writes to the LEN bytes from LOC, 4 by default
+rwatch LOC [LEN]     stop after reads
+awatch LOC [LEN]     stop after reads and writes
+unwatch LOC          remove the watchpoints at LOC
+info|i               list breakpoints and watchpoints
+help|h               print this help
+quit|q               leave the debugger
+LOC is a symbol, a decimal or a 0x-prefixed hexadecimal address. An empty line repeats the last command";
+
+#[derive(Debug, Error)]
+pub enum DebuggerError {
+    #[error("Unknown command `{0}`, try `help`")]
+    UnknownCommand(String),
+    #[error("Missing argument: {0}")]
+    MissingArgument(&'static str),
+    #[error("Unexpected argument `{0}`")]
+    UnexpectedArgument(String),
+    #[error("Invalid number `{0}`")]
+    InvalidNumber(String),
+    #[error("Unknown symbol `{0}`")]
+    UnknownSymbol(String),
+    #[error("No breakpoint at `{0:#010x}`")]
+    NoBreakpoint(u32),
+    #[error("No watchpoint at `{0:#010x}`")]
+    NoWatchpoint(u32),
+    #[error("The program has exited")]
+    Exited,
+    #[error("Trap: {0}")]
+    Trap(#[from] Trap),
+    #[error(transparent)]
+    Memory(#[from] MemoryError),
+}
+
+/// Why the program stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// The requested instructions were executed
+    Step,
+    /// `pc` is at a breakpoint
+    Breakpoint(u32),
+    /// The last instruction accessed a watched address
+    Watchpoint(WatchHit),
+    /// The program called the exit syscall with this code
+    Exited(u32),
+}
+
+/// A location, either an address or a symbol
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Location {
+    Address(u32),
+    Symbol(String),
+}
+
+impl Location {
+    fn parse(location: &str) -> Location {
+        match parse_number(location) {
+            Ok(address) => Location::Address(address),
+            Err(_) => Location::Symbol(location.to_owned()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Break(Location),
+    Delete(Location),
+    Step(u32),
+    Next,
+    Continue,
+    Registers,
+    Examine(Location, u32),
+    Watch(Location, u32, WatchKind),
+    Unwatch(Location),
+    Info,
+    Help,
+    Quit,
+}
+
+/// `0x`-prefixed hexadecimal or decimal
+fn parse_number(number: &str) -> Result<u32, DebuggerError> {
+    match number.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => number.parse(),
+    }
+    .map_err(|_| DebuggerError::InvalidNumber(number.to_owned()))
+}
+
+impl Command {
+    pub fn parse(line: &str) -> Result<Command, DebuggerError> {
+        let mut words = line.split_whitespace();
+        let name = words.next().unwrap_or_default();
+        let mut location = || {
+            words
+                .next()
+                .map(Location::parse)
+                .ok_or(DebuggerError::MissingArgument("location"))
+        };
+
+        let command = match name {
+            "break" | "b" => Command::Break(location()?),
+            "delete" | "d" => Command::Delete(location()?),
+            "next" | "n" => Command::Next,
+            "continue" | "c" => Command::Continue,
+            "registers" | "r" => Command::Registers,
+            "unwatch" => Command::Unwatch(location()?),
+            "info" | "i" => Command::Info,
+            "help" | "h" => Command::Help,
+            "quit" | "q" => Command::Quit,
+            "step" | "s" => {
+                let count = words.next().map(parse_number).transpose()?;
+                Command::Step(count.unwrap_or(1))
+            }
+            "x" => {
+                let location = location()?;
+                let len = words.next().map(parse_number).transpose()?;
+                Command::Examine(location, len.unwrap_or(64))
+            }
+            "watch" | "rwatch" | "awatch" => {
+                let kind = match name {
+                    "watch" => WatchKind::Write,
+                    "rwatch" => WatchKind::Read,
+                    _ => WatchKind::Access,
+                };
+                let location = location()?;
+                let len = words.next().map(parse_number).transpose()?;
+                Command::Watch(location, len.unwrap_or(4), kind)
+            }
+            _ => return Err(DebuggerError::UnknownCommand(name.to_owned())),
+        };
+
+        match words.next() {
+            Some(argument) => Err(DebuggerError::UnexpectedArgument(argument.to_owned())),
+            None => Ok(command),
+        }
+    }
+}
+
+/// Runs a [`VM`] instruction by instruction, stopping at breakpoints and watchpoints
+pub struct Debugger {
+    vm: VM,
+    breakpoints: BTreeSet<u32>,
+    symbols: Vec<(u32, String)>,
+    disassembler: Disassembler,
+}
+
+impl Debugger {
+    pub fn new(vm: VM) -> Debugger {
+        let mut disassembler = Disassembler::new();
+        disassembler.abi_names(true);
+
+        Debugger {
+            vm,
+            breakpoints: BTreeSet::new(),
+            symbols: Vec::new(),
+            disassembler,
+        }
+    }
+
+    /// Name `address` in locations and disassembly
+    pub fn symbol(&mut self, address: u32, name: impl Into<String>) -> &mut Self {
+        let name = name.into();
+        self.disassembler.symbol(address, name.as_str());
+        self.symbols.push((address, name));
+        self
+    }
+
+    pub fn vm(&self) -> &VM {
+        &self.vm
+    }
+
+    pub fn vm_mut(&mut self) -> &mut VM {
+        &mut self.vm
+    }
+
+    /// Address of `location`
+    pub fn resolve(&self, location: &Location) -> Result<u32, DebuggerError> {
+        match location {
+            Location::Address(address) => Ok(*address),
+            Location::Symbol(name) => self
+                .symbols
+                .iter()
+                .find_map(|(address, symbol)| (symbol == name).then_some(*address))
+                .ok_or_else(|| DebuggerError::UnknownSymbol(name.clone())),
+        }
+    }
+
+    /// `false` if there already was one at `address`
+    pub fn add_breakpoint(&mut self, address: u32) -> bool {
+        self.breakpoints.insert(address)
+    }
+
+    /// `false` if there was none at `address`
+    pub fn remove_breakpoint(&mut self, address: u32) -> bool {
+        self.breakpoints.remove(&address)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = u32> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    /// Execute one instruction. On a fault `pc` is left at the faulting instruction
+    pub fn step(&mut self) -> Result<StopReason, DebuggerError> {
+        if self.vm.is_halted() {
+            return Err(DebuggerError::Exited);
+        }
+
+        // A hit from outside of the debugger, e.g. the loader
+        self.vm.memory().take_watch_hit();
+        self.vm.step()?;
+
+        if let Some(code) = self.vm.exit_code() {
+            return Ok(StopReason::Exited(code));
+        }
+        Ok(match self.vm.memory().take_watch_hit() {
+            Some(hit) => StopReason::Watchpoint(hit),
+            None => StopReason::Step,
+        })
+    }
+
+    /// Step until `done` or something else stops the program
+    fn run_until(&mut self, done: impl Fn(&VM) -> bool) -> Result<StopReason, DebuggerError> {
+        loop {
+            let stop = self.step()?;
+            if stop != StopReason::Step || done(&self.vm) {
+                return Ok(stop);
+            }
+
+            let pc = self.vm.pc();
+            if self.breakpoints.contains(&pc) {
+                return Ok(StopReason::Breakpoint(pc));
+            }
+        }
+    }
+
+    /// Execute one instruction, or a whole call when it's `jal` or `jalr` that links
+    pub fn step_over(&mut self) -> Result<StopReason, DebuggerError> {
+        let pc = self.vm.pc();
+        let call = match self.instruction(pc) {
+            Some(Instruction::Jal { dest, .. } | Instruction::Jalr { dest, .. }) => {
+                dest != Register::X0
+            }
+            _ => false,
+        };
+        if !call {
+            return self.step();
+        }
+
+        // The stack pointer tells the return from a recursive call apart
+        let sp = self.vm.cpu.registers.get(Register::X2);
+        let return_address = pc.wrapping_add(4);
+        self.run_until(|vm| vm.pc() == return_address && vm.cpu.registers.get(Register::X2) >= sp)
+    }
+
+    /// Run until a breakpoint, a watchpoint or the exit. The breakpoint at `pc` is stepped over
+    pub fn cont(&mut self) -> Result<StopReason, DebuggerError> {
+        self.run_until(|_| false)
+    }
+
+    /// The instruction at `address`, `None` if it's not an instruction
+    fn instruction(&self, address: u32) -> Option<Instruction> {
+        let word = self.vm.memory().inspect(address, 4).ok()?;
+        Instruction::try_from(u32::from_le_bytes(word.try_into().ok()?)).ok()
+    }
+
+    /// `pc`, its symbol and the instruction it points to
+    pub fn location(&self) -> String {
+        let pc = self.vm.pc();
+        let mut text = format!("{pc:#010x}");
+        if let Some(symbol) = self.disassembler.locate(pc) {
+            write!(text, " <{symbol}>").unwrap();
+        }
+        match self.instruction(pc) {
+            Some(instruction) => write!(
+                text,
+                ": {}",
+                self.disassembler.instruction(&instruction, pc)
+            )
+            .unwrap(),
+            None => text.push_str(": <invalid instruction>"),
+        }
+        text
+    }
+
+    /// `pc` and every register by its ABI name
+    pub fn registers(&self) -> String {
+        let mut text = format!("pc   {:#010x}\n", self.vm.pc());
+        for index in 0..Register::VARIANT_COUNT as u32 {
+            let register = Register::from(index);
+            let separator = match index % 4 {
+                3 => "\n",
+                _ => "  ",
+            };
+            write!(
+                text,
+                "{:<4} {:#010x}{separator}",
+                register.abi_name(),
+                self.vm.cpu.registers.get(register)
+            )
+            .unwrap();
+        }
+        text
+    }
+
+    /// 16 bytes per line, with their ASCII on the right
+    pub fn hex_dump(&self, address: u32, len: u32) -> Result<String, MemoryError> {
+        let bytes = self.vm.memory().inspect(address, len)?;
+        let mut text = String::new();
+        for (line, chunk) in (address..).step_by(16).zip(bytes.chunks(16)) {
+            write!(text, "{line:#010x}:").unwrap();
+            for byte in chunk {
+                write!(text, " {byte:02x}").unwrap();
+            }
+            let ascii: String = chunk
+                .iter()
+                .map(|&byte| match byte {
+                    0x20..=0x7e => byte as char,
+                    _ => '.',
+                })
+                .collect();
+            writeln!(
+                text,
+                "{:width$}  |{ascii}|",
+                "",
+                width = (16 - chunk.len()) * 3
+            )
+            .unwrap();
+        }
+        Ok(text)
+    }
+
+    fn describe(&self, stop: StopReason) -> String {
+        match stop {
+            StopReason::Step => self.location(),
+            StopReason::Breakpoint(_) => format!("Breakpoint at {}", self.location()),
+            StopReason::Watchpoint(hit) => format!(
+                "{} of {:#010x} hit the watchpoint at {:#010x}\n{}",
+                hit.access,
+                hit.address,
+                hit.watchpoint.address,
+                self.location()
+            ),
+            StopReason::Exited(code) => format!("Program exited with code {code}"),
+        }
+    }
+
+    /// Run `command`, with what it prints
+    pub fn execute(&mut self, command: &Command) -> Result<String, DebuggerError> {
+        Ok(match command {
+            Command::Break(location) => {
+                let address = self.resolve(location)?;
+                self.add_breakpoint(address);
+                format!("Breakpoint at {address:#010x}")
+            }
+            Command::Delete(location) => {
+                let address = self.resolve(location)?;
+                if !self.remove_breakpoint(address) {
+                    return Err(DebuggerError::NoBreakpoint(address));
+                }
+                format!("Deleted the breakpoint at {address:#010x}")
+            }
+            Command::Step(count) => {
+                let mut stop = StopReason::Step;
+                for _ in 0..*count {
+                    stop = self.step()?;
+                    if stop != StopReason::Step {
+                        break;
+                    }
+                }
+                self.describe(stop)
+            }
+            Command::Next => {
+                let stop = self.step_over()?;
+                self.describe(stop)
+            }
+            Command::Continue => {
+                let stop = self.cont()?;
+                self.describe(stop)
+            }
+            Command::Registers => self.registers().trim_end().to_owned(),
+            Command::Examine(location, len) => {
+                let text = self.hex_dump(self.resolve(location)?, *len)?;
+                text.trim_end().to_owned()
+            }
+            Command::Watch(location, len, kind) => {
+                let address = self.resolve(location)?;
+                self.vm.memory_mut().add_watchpoint(address, *len, *kind);
+                format!("{kind:?} watchpoint at {address:#010x}, {len} bytes")
+            }
+            Command::Unwatch(location) => {
+                let address = self.resolve(location)?;
+                if !self.vm.memory_mut().remove_watchpoint(address) {
+                    return Err(DebuggerError::NoWatchpoint(address));
+                }
+                format!("Deleted the watchpoints at {address:#010x}")
+            }
+            Command::Info => {
+                let mut text = String::new();
+                for address in self.breakpoints() {
+                    writeln!(text, "Breakpoint at {address:#010x}").unwrap();
+                }
+                for watchpoint in self.vm.memory().watchpoints() {
+                    writeln!(
+                        text,
+                        "{:?} watchpoint at {:#010x}, {} bytes",
+                        watchpoint.kind, watchpoint.address, watchpoint.len
+                    )
+                    .unwrap();
+                }
+                match text.is_empty() {
+                    true => "No breakpoints or watchpoints".to_owned(),
+                    false => text.trim_end().to_owned(),
+                }
+            }
+            Command::Help => HELP.to_owned(),
+            Command::Quit => String::new(),
+        })
+    }
+
+    /// Read commands from `input` until `quit` or the end of the input
+    pub fn repl(&mut self, input: impl BufRead, mut output: impl Write) -> io::Result<()> {
+        writeln!(output, "{}", self.location())?;
+        write!(output, "(rdb) ")?;
+        output.flush()?;
+
+        // Only valid commands are repeated
+        let mut last = None;
+        for line in input.lines() {
+            let command = match line?.trim() {
+                "" => last.clone().map(Ok),
+                line => Some(Command::parse(line)),
+            };
+
+            match command {
+                Some(Ok(Command::Quit)) => return Ok(()),
+                Some(Ok(command)) => {
+                    match self.execute(&command) {
+                        Ok(text) => writeln!(output, "{text}")?,
+                        Err(err) => writeln!(output, "error: {err}")?,
+                    }
+                    last = Some(command);
+                }
+                Some(Err(err)) => writeln!(output, "error: {err}")?,
+                None => {}
+            }
+
+            write!(output, "(rdb) ")?;
+            output.flush()?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use isa::operand::{Immediate14, Immediate19};
+
+    use super::*;
+    use crate::{
+        io::BufferIo,
+        memory::{MemoryConfiguration, Segment},
+        syscall::{NUMBER_REGISTER, SyscallNumber},
+    };
+
+    /// Calls `double` on 21 and stores the result on the stack before exiting with it
+    fn program() -> Debugger {
+        let program = [
+            // 0x00: _start
+            Instruction::AddI {
+                dest: Register::X2,
+                src: Register::X2,
+                value: Immediate14::new(-15),
+            },
+            Instruction::AddI {
+                dest: Register::X5,
+                src: Register::X0,
+                value: Immediate14::new(21),
+            },
+            Instruction::Jal {
+                dest: Register::X1,
+                offset: Immediate19::new(16),
+            },
+            Instruction::Sw {
+                src: Register::X10,
+                dest: Register::X2,
+                offset: Immediate14::new(0),
+            },
+            Instruction::AddI {
+                dest: NUMBER_REGISTER,
+                src: Register::X0,
+                value: Immediate14::new(SyscallNumber::Exit as i32),
+            },
+            Instruction::Syscall {
+                src1: Register::X0,
+                src2: Register::X0,
+                src3: Register::X0,
+            },
+            // 0x18: double
+            Instruction::Add {
+                dest: Register::X10,
+                src1: Register::X5,
+                src2: Register::X5,
+            },
+            Instruction::Jalr {
+                dest: Register::X0,
+                src: Register::X1,
+                offset: Immediate14::new(0),
+            },
+        ];
+        let code: Vec<u8> = program
+            .iter()
+            .flat_map(|instruction| u32::from(instruction).to_le_bytes())
+            .collect();
+
+        let mut vm = VM::new(MemoryConfiguration::new(1024 * 1024).unwrap());
+        vm.set_io(BufferIo::new(""));
+        vm.load_segments(&[Segment {
+            address: 0,
+            size: code.len() as u32,
+            data: code,
+            permissions: vec![crate::memory::Permission::R, crate::memory::Permission::X],
+        }])
+        .unwrap();
+
+        let mut debugger = Debugger::new(vm);
+        debugger.symbol(0, "_start").symbol(0x18, "double");
+        debugger
+    }
+
+    #[test]
+    fn t_step_over_and_watch() {
+        let mut debugger = program();
+        debugger.step().unwrap();
+        debugger.step().unwrap();
+        assert_eq!(
+            debugger.location(),
+            "0x00000008 <_start+0x8>: jal ra, 16 # 0x18 <double>"
+        );
+
+        // The breakpoint in the callee stops `next`
+        debugger.add_breakpoint(0x18);
+        assert_eq!(debugger.step_over().unwrap(), StopReason::Breakpoint(0x18));
+        debugger.remove_breakpoint(0x18);
+        assert_eq!(debugger.cont().unwrap(), StopReason::Exited(42));
+        assert!(matches!(debugger.step(), Err(DebuggerError::Exited)));
+
+        let mut debugger = program();
+        debugger.execute(&Command::Step(2)).unwrap();
+        assert_eq!(debugger.step_over().unwrap(), StopReason::Step);
+        assert_eq!(debugger.vm().pc(), 0xc);
+        assert_eq!(debugger.vm_mut().registers().get(Register::X10), 42);
+
+        let sp = debugger.vm_mut().registers().get(Register::X2);
+        debugger
+            .vm_mut()
+            .memory_mut()
+            .add_watchpoint(sp, 4, WatchKind::Write);
+        let StopReason::Watchpoint(hit) = debugger.cont().unwrap() else {
+            panic!("the store didn't hit the watchpoint");
+        };
+        assert_eq!(hit.address, sp);
+        assert_eq!(debugger.vm().pc(), 0x10);
+        assert_eq!(
+            debugger.hex_dump(sp, 4).unwrap(),
+            format!("{sp:#010x}: 2a 00 00 00{:36}  |*...|\n", "")
+        );
+    }
+
+    #[test]
+    fn t_repl() {
+        let mut debugger = program();
+        let script =
+            "b double\nc\nr\nn\n\nwatch 0xffff0\nc\nx 0xffff0 4\ninfo\nd 4\nfoo\nc\nc\nq\n";
+        let mut output = Vec::new();
+        debugger.repl(script.as_bytes(), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+
+        let expected = [
+            "0x00000000 <_start>: addi sp, sp, -15\n(rdb) ",
+            "Breakpoint at 0x00000018\n(rdb) ",
+            "Breakpoint at 0x00000018 <double>: add a0, t0, t0\n",
+            "t0   0x00000015",
+            "0x0000001c <double+0x4>: jalr zero, ra, 0\n",
+            // The empty line repeats `next`
+            "0x0000000c <_start+0xc>: sw a0, 0(sp)\n",
+            "Write watchpoint at 0x000ffff0, 4 bytes\n",
+            "Write of 0x000ffff0 hit the watchpoint at 0x000ffff0\n",
+            "0x000ffff0: 2a 00 00 00",
+            "Breakpoint at 0x00000018\nWrite watchpoint at 0x000ffff0, 4 bytes\n",
+            "error: No breakpoint at `0x00000004`\n",
+            "error: Unknown command `foo`, try `help`\n",
+            "Program exited with code 42\n",
+            "error: The program has exited\n(rdb) ",
+        ];
+        let mut rest = output.as_str();
+        for text in expected {
+            let index = rest
+                .find(text)
+                .unwrap_or_else(|| panic!("`{text}` missing from:\n{rest}"));
+            rest = &rest[index + text.len()..];
+        }
+        assert_eq!(rest, "");
+    }
+
+    #[test]
+    fn t_parse_command() {
+        assert_eq!(
+            Command::parse("b loop").unwrap(),
+            Command::Break(Location::Symbol("loop".to_owned()))
+        );
+        assert_eq!(
+            Command::parse("  x 0x100  ").unwrap(),
+            Command::Examine(Location::Address(0x100), 64)
+        );
+        assert_eq!(
+            Command::parse("awatch 16 2").unwrap(),
+            Command::Watch(Location::Address(16), 2, WatchKind::Access)
+        );
+        assert_eq!(Command::parse("s 3").unwrap(), Command::Step(3));
+        assert!(matches!(
+            Command::parse("b"),
+            Err(DebuggerError::MissingArgument(_))
+        ));
+        assert!(matches!(
+            Command::parse("s 0xz"),
+            Err(DebuggerError::InvalidNumber(_))
+        ));
+        assert!(matches!(
+            Command::parse("c now"),
+            Err(DebuggerError::UnexpectedArgument(_))
+        ));
+    }
+}
@@ -0,0 +1,34 @@
+use super::{Device, DeviceEvent};
+
+/// Ends the run with the exit code written to it, so bare-metal tests can report without syscalls
+#[derive(Debug, Default)]
+pub struct TestFinisher {
+    code: Option<u32>,
+}
+
+impl TestFinisher {
+    /// Where `rivet-vm` maps it
+    pub const BASE: u32 = 0x1000_1000;
+    pub const LEN: u32 = 4;
+}
+
+impl Device for TestFinisher {
+    /// Write-only
+    fn read(&mut self, _offset: u32, _size: u8) -> Option<u32> {
+        None
+    }
+
+    fn write(&mut self, offset: u32, _size: u8, value: u32) -> bool {
+        if offset != 0 {
+            return false;
+        }
+        self.code = Some(value);
+        true
+    }
+
+    fn tick(&mut self) -> DeviceEvent {
+        self.code
+            .take()
+            .map_or(DeviceEvent::None, DeviceEvent::Exit)
+    }
+}
@@ -0,0 +1,249 @@
+//! Memory-mapped I/O. Address ranges mapped to a [`Device`] with [`crate::VM::map_device`] are
+//! consulted before RAM, so guests can do I/O with plain loads and stores
+pub mod finisher;
+pub mod uart;
+
+use std::cell::RefCell;
+
+pub use finisher::TestFinisher;
+pub use uart::Uart;
+
+use crate::memory::MemoryError;
+
+/// What a device asks of the VM after a tick
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceEvent {
+    #[default]
+    None,
+    /// Halt with the exit code
+    Exit(u32),
+}
+
+/// Registers the guest reaches through loads and stores. Accesses are 1, 2 or 4 bytes, aligned on
+/// their size, at an `offset` from the start of the mapping
+pub trait Device {
+    /// `None` if there's nothing to read at `offset`, which faults the guest
+    fn read(&mut self, offset: u32, size: u8) -> Option<u32>;
+
+    /// `false` if there's nothing to write at `offset`, which faults the guest
+    fn write(&mut self, offset: u32, size: u8, value: u32) -> bool;
+
+    /// Called after every instruction
+    fn tick(&mut self) -> DeviceEvent {
+        DeviceEvent::None
+    }
+}
+
+struct Mapping {
+    start: u32,
+    /// Exclusive, a `u64` so a device can end the address space
+    end: u64,
+    /// Reads change device state but only borrow the memory
+    device: RefCell<Box<dyn Device>>,
+}
+
+/// The mapped devices, by address
+#[derive(Default)]
+pub struct DeviceBus {
+    mappings: Vec<Mapping>,
+}
+
+impl DeviceBus {
+    /// Map `len` bytes from `address` to `device`. Mappings can't overlap
+    pub fn map(
+        &mut self,
+        address: u32,
+        len: u32,
+        device: Box<dyn Device>,
+    ) -> Result<(), MemoryError> {
+        let end = address as u64 + len as u64;
+        if len == 0 || end > 1 << 32 {
+            return Err(MemoryError::OutOfBounds(address));
+        }
+        let overlap = self
+            .mappings
+            .iter()
+            .any(|mapping| (address as u64) < mapping.end && (mapping.start as u64) < end);
+        if overlap {
+            return Err(MemoryError::DeviceOverlap(address));
+        }
+
+        self.mappings.push(Mapping {
+            start: address,
+            end,
+            device: RefCell::new(device),
+        });
+        Ok(())
+    }
+
+    /// The mapping `address` falls in, if any
+    fn get(&self, address: u32) -> Option<&Mapping> {
+        self.mappings
+            .iter()
+            .find(|mapping| mapping.start <= address && (address as u64) < mapping.end)
+    }
+
+    /// `None` if `address` isn't mapped, the access then goes to RAM. The value is truncated to
+    /// `size` bytes
+    pub fn read(&self, address: u32, size: u8) -> Option<Result<u32, MemoryError>> {
+        let mapping = self.get(address)?;
+        let value = match address as u64 + size as u64 <= mapping.end {
+            true => mapping
+                .device
+                .borrow_mut()
+                .read(address - mapping.start, size),
+            false => None,
+        };
+        let mask = u32::MAX >> (32 - 8 * size as u32);
+        Some(
+            value
+                .map(|value| value & mask)
+                .ok_or(MemoryError::InvalidAddress(address)),
+        )
+    }
+
+    /// `None` if `address` isn't mapped, the access then goes to RAM
+    pub fn write(&self, address: u32, size: u8, value: u32) -> Option<Result<(), MemoryError>> {
+        let mapping = self.get(address)?;
+        let written = address as u64 + size as u64 <= mapping.end
+            && mapping
+                .device
+                .borrow_mut()
+                .write(address - mapping.start, size, value);
+        Some(match written {
+            true => Ok(()),
+            false => Err(MemoryError::InvalidAddress(address)),
+        })
+    }
+
+    /// Tick every device, the first event wins
+    pub fn tick(&mut self) -> DeviceEvent {
+        self.mappings
+            .iter_mut()
+            .map(|mapping| mapping.device.get_mut().tick())
+            .fold(DeviceEvent::None, |first, event| match first {
+                DeviceEvent::None => event,
+                first => first,
+            })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use isa::{Instruction::*, Register, operand::Immediate14};
+
+    use super::*;
+    use crate::{VM, io::BufferIo, memory::MemoryConfiguration, trap::Trap};
+
+    fn li(dest: Register, value: i32) -> isa::Instruction {
+        AddI {
+            dest,
+            src: Register::X0,
+            value: Immediate14::new(value),
+        }
+    }
+
+    fn load(program: &[isa::Instruction]) -> VM {
+        let mut vm = VM::new(MemoryConfiguration::new(1024 * 1024).unwrap());
+        let bytes: Vec<u8> = program
+            .iter()
+            .flat_map(|instruction| u32::from(instruction).to_le_bytes())
+            .collect();
+        vm.load_program(&bytes).unwrap();
+        vm
+    }
+
+    #[test]
+    fn t_uart_and_finisher() {
+        // x5 = 0x1000_0000, x6 = 0x1000_1000
+        let base = |dest| Lui {
+            dest,
+            value: isa::operand::Immediate19::new(0x1000_0000 >> 13),
+        };
+        let mut vm = load(&[
+            base(Register::X5),
+            base(Register::X6),
+            AddI {
+                dest: Register::X6,
+                src: Register::X6,
+                value: Immediate14::new(0x1000),
+            },
+            // Echo one byte, then "!"
+            Lbu {
+                dest: Register::X10,
+                src: Register::X5,
+                offset: Immediate14::new(0),
+            },
+            Sb {
+                dest: Register::X5,
+                src: Register::X10,
+                offset: Immediate14::new(0),
+            },
+            li(Register::X10, '!' as i32),
+            Sb {
+                dest: Register::X5,
+                src: Register::X10,
+                offset: Immediate14::new(0),
+            },
+            Lbu {
+                dest: Register::X11,
+                src: Register::X5,
+                offset: Immediate14::new(5),
+            },
+            Sw {
+                dest: Register::X6,
+                src: Register::X11,
+                offset: Immediate14::new(0),
+            },
+            Ebreak,
+        ]);
+        let io = BufferIo::new("ab");
+        vm.map_device(Uart::BASE, Uart::LEN, Uart::new(io.clone()))
+            .unwrap();
+        vm.map_device(
+            TestFinisher::BASE,
+            TestFinisher::LEN,
+            TestFinisher::default(),
+        )
+        .unwrap();
+
+        // The finisher stops the VM before the `ebreak`
+        let status = Uart::LSR_DATA_READY | Uart::LSR_TRANSMIT_EMPTY;
+        assert_eq!(vm.run(), Ok(status as u32));
+        assert_eq!(io.captured_stdout(), b"a!");
+
+        let overlap = vm.map_device(Uart::BASE + 4, 1, TestFinisher::default());
+        assert_eq!(overlap, Err(MemoryError::DeviceOverlap(Uart::BASE + 4)));
+
+        // The finisher can't be read nor executed
+        let mut vm = load(&[
+            base(Register::X6),
+            AddI {
+                dest: Register::X6,
+                src: Register::X6,
+                value: Immediate14::new(0x1000),
+            },
+            Lw {
+                dest: Register::X7,
+                src: Register::X6,
+                offset: Immediate14::new(0),
+            },
+        ]);
+        vm.map_device(
+            TestFinisher::BASE,
+            TestFinisher::LEN,
+            TestFinisher::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            vm.run(),
+            Err(Trap::AccessFault {
+                pc: 8,
+                address: TestFinisher::BASE,
+                access: crate::memory::Permission::R
+            })
+        );
+        vm.set_pc(TestFinisher::BASE);
+        assert!(matches!(vm.step(), Err(Trap::AccessFault { .. })));
+    }
+}
@@ -0,0 +1,77 @@
+use std::io::ErrorKind;
+
+use super::Device;
+use crate::io::HostIo;
+
+/// Console with the registers of a 16550 that guest drivers poll: the data register at offset 0
+/// sends the byte written and receives on read, the line status at offset 5. The other registers
+/// accept writes and read as 0, there is no FIFO nor interrupt to configure
+pub struct Uart {
+    io: Box<dyn HostIo>,
+    /// Input ran out, reads of the data register return 0
+    eof: bool,
+}
+
+impl Uart {
+    /// Where `rivet-vm` maps it
+    pub const BASE: u32 = 0x1000_0000;
+    pub const LEN: u32 = 8;
+
+    const DATA: u32 = 0;
+    const LINE_STATUS: u32 = 5;
+
+    /// Set until the input is exhausted. A read of the data register blocks for the next byte
+    pub const LSR_DATA_READY: u8 = 1 << 0;
+    /// Always set, output is written right away
+    pub const LSR_TRANSMIT_EMPTY: u8 = 1 << 5 | 1 << 6;
+
+    /// Receive from `io`'s stdin and send to its stdout
+    pub fn new(io: impl HostIo + 'static) -> Self {
+        Self {
+            io: Box::new(io),
+            eof: false,
+        }
+    }
+
+    fn receive(&mut self) -> u8 {
+        let mut byte = [0];
+        match self.io.stdin().read_exact(&mut byte) {
+            Ok(()) => byte[0],
+            Err(err) => {
+                if err.kind() != ErrorKind::UnexpectedEof {
+                    log::error!("uart: {err}");
+                }
+                self.eof = true;
+                0
+            }
+        }
+    }
+}
+
+impl Device for Uart {
+    fn read(&mut self, offset: u32, _size: u8) -> Option<u32> {
+        let value = match offset {
+            Self::DATA if !self.eof => self.receive(),
+            Self::LINE_STATUS => match self.eof {
+                true => Self::LSR_TRANSMIT_EMPTY,
+                false => Self::LSR_DATA_READY | Self::LSR_TRANSMIT_EMPTY,
+            },
+            _ => 0,
+        };
+        Some(value as u32)
+    }
+
+    fn write(&mut self, offset: u32, _size: u8, value: u32) -> bool {
+        if offset == Self::DATA {
+            let stdout = self.io.stdout();
+            // Losing console output isn't the guest's fault
+            if let Err(err) = stdout
+                .write_all(&[value as u8])
+                .and_then(|_| stdout.flush())
+            {
+                log::error!("uart: {err}");
+            }
+        }
+        true
+    }
+}
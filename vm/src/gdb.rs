@@ -0,0 +1,524 @@
+//! GDB remote serial protocol stub, see <https://sourceware.org/gdb/current/onlinedocs/gdb.html/Remote-Protocol.html>
+//!
+//! The register file is described to GDB as RISC-V RV32: `x0`..`x31` then `pc`. The program
+//! only runs while a `c` or `s` packet is handled, an interrupt (`Ctrl-C`) is only seen between
+//! packets
+
+use std::{
+    fmt::Write as _,
+    io::{self, BufRead, Write},
+};
+
+use isa::Register;
+use shared::EnumCount;
+
+use crate::{
+    debugger::{Debugger, DebuggerError, StopReason},
+    memory::WatchKind,
+    trap::Trap,
+};
+
+/// `SIGTRAP`, reported for breakpoints, watchpoints and steps
+const SIGTRAP: u8 = 5;
+/// `SIGINT`, reported when GDB interrupts the program
+const SIGINT: u8 = 2;
+/// `SIGILL`, reported for instructions that can't be decoded
+const SIGILL: u8 = 4;
+/// `SIGSEGV`, reported for memory faults
+const SIGSEGV: u8 = 11;
+
+/// Number of `pc` in `g`, `p` and `P` packets
+const PC: usize = Register::VARIANT_COUNT;
+
+/// Largest packet GDB may send us
+const PACKET_SIZE: usize = 0x1000;
+
+fn target_xml() -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\"?><!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+         <target version=\"1.0\"><architecture>riscv:rv32</architecture>\
+         <feature name=\"org.gnu.gdb.riscv.cpu\">",
+    );
+    for index in 0..Register::VARIANT_COUNT as u32 {
+        let ty = match index {
+            1 | 3 | 4 => "code_ptr",
+            2 | 8 => "data_ptr",
+            _ => "int",
+        };
+        write!(
+            xml,
+            "<reg name=\"{}\" bitsize=\"32\" type=\"{ty}\"/>",
+            Register::from(index).abi_name()
+        )
+        .unwrap();
+    }
+    xml.push_str("<reg name=\"pc\" bitsize=\"32\" type=\"code_ptr\"/></feature></target>");
+    xml
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, byte| sum.wrapping_add(*byte))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(text.get(index..index + 2)?, 16).ok())
+        .collect()
+}
+
+fn number(text: &str) -> Option<u32> {
+    u32::from_str_radix(text, 16).ok()
+}
+
+/// A little-endian register value
+fn register_value(text: &str) -> Option<u32> {
+    Some(u32::from_le_bytes(unhex(text)?.try_into().ok()?))
+}
+
+/// `addr,len`
+fn range(text: &str) -> Option<(u32, u32)> {
+    let (address, len) = text.split_once(',')?;
+    Some((number(address)?, number(len)?))
+}
+
+/// What to do after a packet
+enum Reply {
+    Packet(String),
+    /// `k` packets have no reply
+    Close,
+    /// `D` is acknowledged before closing
+    CloseAfter(String),
+}
+
+/// Serves GDB for a [`Debugger`], over any byte stream
+pub struct GdbStub {
+    debugger: Debugger,
+    ack: bool,
+}
+
+impl GdbStub {
+    pub fn new(debugger: Debugger) -> GdbStub {
+        GdbStub {
+            debugger,
+            ack: true,
+        }
+    }
+
+    pub fn debugger(&self) -> &Debugger {
+        &self.debugger
+    }
+
+    /// Read `$data#cs` packets, skipping acknowledgements. `None` at the end of the input, `\x03`
+    /// for an interrupt. Corrupted packets are answered with `-` so that GDB sends them again
+    fn read_packet(
+        &mut self,
+        input: &mut impl BufRead,
+        output: &mut impl Write,
+    ) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            let mut byte = [0];
+            if input.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            match byte[0] {
+                b'$' => {}
+                0x03 => return Ok(Some(vec![0x03])),
+                _ => continue,
+            }
+
+            let mut packet = Vec::new();
+            input.read_until(b'#', &mut packet)?;
+            if packet.pop() != Some(b'#') {
+                return Ok(None);
+            }
+            let mut sum = [0; 2];
+            input.read_exact(&mut sum)?;
+
+            let valid = std::str::from_utf8(&sum)
+                .ok()
+                .and_then(|sum| u8::from_str_radix(sum, 16).ok())
+                == Some(checksum(&packet));
+            if valid || !self.ack {
+                return Ok(Some(packet));
+            }
+            output.write_all(b"-")?;
+            output.flush()?;
+        }
+    }
+
+    fn write_packet(&self, output: &mut impl Write, data: &str) -> io::Result<()> {
+        write!(output, "${data}#{:02x}", checksum(data.as_bytes()))?;
+        output.flush()
+    }
+
+    /// Answer packets from `input` on `output` until GDB detaches, kills the program or leaves
+    pub fn serve(&mut self, mut input: impl BufRead, output: impl Write) -> io::Result<()> {
+        // The acknowledgement and the reply go out together
+        let mut output = io::BufWriter::new(output);
+        while let Some(packet) = self.read_packet(&mut input, &mut output)? {
+            if packet == [0x03] {
+                self.write_packet(&mut output, &format!("S{SIGINT:02x}"))?;
+                continue;
+            }
+
+            if self.ack {
+                output.write_all(b"+")?;
+            }
+            let packet = String::from_utf8_lossy(&packet);
+            match self.handle(&packet) {
+                Reply::Packet(reply) => self.write_packet(&mut output, &reply)?,
+                Reply::CloseAfter(reply) => return self.write_packet(&mut output, &reply),
+                Reply::Close => return output.flush(),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn stop_reply(&self, stop: Result<StopReason, DebuggerError>) -> String {
+        match stop {
+            Ok(StopReason::Step | StopReason::Breakpoint(_)) => format!("S{SIGTRAP:02x}"),
+            Ok(StopReason::Watchpoint(hit)) => {
+                let kind = match hit.watchpoint.kind {
+                    WatchKind::Write => "watch",
+                    WatchKind::Read => "rwatch",
+                    WatchKind::Access => "awatch",
+                };
+                format!("T{SIGTRAP:02x}{kind}:{:x};", hit.address)
+            }
+            Ok(StopReason::Exited(code)) => format!("W{:02x}", code as u8),
+            Err(DebuggerError::Exited) => {
+                let code = self.debugger.vm().exit_code().unwrap_or_default();
+                format!("W{:02x}", code as u8)
+            }
+            Err(DebuggerError::Trap(Trap::AccessFault { .. } | Trap::MisalignedAccess { .. })) => {
+                format!("S{SIGSEGV:02x}")
+            }
+            Err(DebuggerError::Trap(Trap::Breakpoint { .. })) => format!("S{SIGTRAP:02x}"),
+            Err(_) => format!("S{SIGILL:02x}"),
+        }
+    }
+
+    fn register(&self, index: usize) -> Option<u32> {
+        match index {
+            PC => Some(self.debugger.vm().pc()),
+            index if index < PC => {
+                Some(self.debugger.vm().cpu.registers[Register::from(index as u32)])
+            }
+            _ => None,
+        }
+    }
+
+    fn set_register(&mut self, index: usize, value: u32) -> bool {
+        let vm = self.debugger.vm_mut();
+        match index {
+            PC => vm.set_pc(value),
+            index if index < PC => vm.registers().set(Register::from(index as u32), value),
+            _ => return false,
+        }
+        true
+    }
+
+    /// `Z`/`z` packets: `type,addr,kind`
+    fn breakpoint(&mut self, insert: bool, arguments: &str) -> Option<&'static str> {
+        let mut arguments = arguments.splitn(3, ',');
+        let ty = arguments.next()?;
+        let address = number(arguments.next()?)?;
+        let len = number(arguments.next()?)?;
+
+        let kind = match ty {
+            // Hardware breakpoints behave like software ones here
+            "0" | "1" => {
+                match insert {
+                    true => self.debugger.add_breakpoint(address),
+                    false => self.debugger.remove_breakpoint(address),
+                };
+                return Some("OK");
+            }
+            "2" => WatchKind::Write,
+            "3" => WatchKind::Read,
+            "4" => WatchKind::Access,
+            _ => return Some(""),
+        };
+
+        let memory = self.debugger.vm_mut().memory_mut();
+        match insert {
+            true => memory.add_watchpoint(address, len, kind),
+            false => {
+                memory.remove_watchpoint(address);
+            }
+        }
+        Some("OK")
+    }
+
+    /// `qXfer:features:read:target.xml:offset,length`
+    fn features(&self, arguments: &str) -> Option<String> {
+        let (offset, len) = range(arguments.strip_prefix("target.xml:")?)?;
+        let xml = target_xml();
+        let start = (offset as usize).min(xml.len());
+        let end = start.saturating_add(len as usize).min(xml.len());
+        let more = match end < xml.len() {
+            true => 'm',
+            false => 'l',
+        };
+        Some(format!("{more}{}", &xml[start..end]))
+    }
+
+    fn handle(&mut self, packet: &str) -> Reply {
+        // The packet comes from the socket, its first character can be more than a byte
+        let command_len = packet.chars().next().map_or(0, char::len_utf8);
+        let (command, arguments) = packet.split_at(command_len);
+        let reply = match command {
+            "?" => Some(format!("S{SIGTRAP:02x}")),
+            "g" => {
+                let mut registers = String::new();
+                for index in 0..=PC {
+                    registers.push_str(&hex(&self.register(index).unwrap().to_le_bytes()));
+                }
+                Some(registers)
+            }
+            "G" => (0..=PC)
+                .map(|index| {
+                    let value = register_value(arguments.get(index * 8..index * 8 + 8)?)?;
+                    Some(self.set_register(index, value))
+                })
+                .collect::<Option<Vec<_>>>()
+                .map(|_| "OK".to_owned()),
+            "p" => number(arguments)
+                .and_then(|index| self.register(index as usize))
+                .map(|value| hex(&value.to_le_bytes())),
+            "P" => arguments.split_once('=').and_then(|(index, value)| {
+                let set = self.set_register(number(index)? as usize, register_value(value)?);
+                set.then(|| "OK".to_owned())
+            }),
+            "m" => range(arguments).map(|(address, len)| {
+                match self.debugger.vm().memory().inspect(address, len) {
+                    Ok(bytes) => hex(bytes),
+                    Err(_) => "E01".to_owned(),
+                }
+            }),
+            "M" => arguments.split_once(':').and_then(|(range_, data)| {
+                let (address, len) = range(range_)?;
+                let bytes = unhex(data).filter(|bytes| bytes.len() == len as usize)?;
+                Some(
+                    match self.debugger.vm_mut().memory_mut().patch(address, &bytes) {
+                        Ok(()) => "OK".to_owned(),
+                        Err(_) => "E01".to_owned(),
+                    },
+                )
+            }),
+            "c" | "s" => {
+                // `c addr` and `s addr` resume at `addr`
+                if let Some(address) = number(arguments) {
+                    self.debugger.vm_mut().set_pc(address);
+                }
+                let stop = match command {
+                    "c" => self.debugger.cont(),
+                    _ => self.debugger.step(),
+                };
+                Some(self.stop_reply(stop))
+            }
+            "Z" | "z" => self
+                .breakpoint(command == "Z", arguments)
+                .map(str::to_owned),
+            "H" => Some("OK".to_owned()),
+            "k" => return Reply::Close,
+            "D" => return Reply::CloseAfter("OK".to_owned()),
+            _ => Some(match packet {
+                "qAttached" => "1".to_owned(),
+                "qC" => "QC1".to_owned(),
+                "QStartNoAckMode" => {
+                    self.ack = false;
+                    "OK".to_owned()
+                }
+                _ if packet.starts_with("qSupported") => {
+                    format!("PacketSize={PACKET_SIZE:x};QStartNoAckMode+;qXfer:features:read+")
+                }
+                _ => match packet.strip_prefix("qXfer:features:read:") {
+                    Some(arguments) => self.features(arguments).unwrap_or("E01".to_owned()),
+                    // Unsupported
+                    None => String::new(),
+                },
+            }),
+        };
+
+        Reply::Packet(reply.unwrap_or("E01".to_owned()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::{BufReader, Read},
+        net::{TcpListener, TcpStream},
+        thread,
+    };
+
+    use isa::{Instruction, operand::Immediate14};
+
+    use super::*;
+    use crate::{
+        VM,
+        io::BufferIo,
+        memory::{MemoryConfiguration, Permission, Segment},
+        syscall::{NUMBER_REGISTER, SyscallNumber},
+    };
+
+    /// Stores 42 on the stack and exits with `a0`
+    fn stub() -> GdbStub {
+        let program = [
+            Instruction::AddI {
+                dest: Register::X2,
+                src: Register::X2,
+                value: Immediate14::new(-15),
+            },
+            Instruction::AddI {
+                dest: Register::X10,
+                src: Register::X0,
+                value: Immediate14::new(42),
+            },
+            Instruction::Sw {
+                src: Register::X10,
+                dest: Register::X2,
+                offset: Immediate14::new(0),
+            },
+            Instruction::AddI {
+                dest: NUMBER_REGISTER,
+                src: Register::X0,
+                value: Immediate14::new(SyscallNumber::Exit as i32),
+            },
+            Instruction::Syscall {
+                src1: Register::X0,
+                src2: Register::X0,
+                src3: Register::X0,
+            },
+        ];
+        let code: Vec<u8> = program
+            .iter()
+            .flat_map(|instruction| u32::from(instruction).to_le_bytes())
+            .collect();
+
+        let mut vm = VM::new(MemoryConfiguration::new(1024 * 1024).unwrap());
+        vm.set_io(BufferIo::new(""));
+        vm.load_segments(&[Segment {
+            address: 0,
+            size: code.len() as u32,
+            data: code,
+            permissions: vec![Permission::R, Permission::X],
+        }])
+        .unwrap();
+        GdbStub::new(Debugger::new(vm))
+    }
+
+    /// A GDB stand-in on the other end of a TCP connection
+    struct Client {
+        stream: BufReader<TcpStream>,
+    }
+
+    impl Client {
+        fn send(&mut self, data: &str) -> String {
+            let packet = format!("${data}#{:02x}", checksum(data.as_bytes()));
+            self.stream.get_mut().write_all(packet.as_bytes()).unwrap();
+
+            let mut ack = [0];
+            self.stream.read_exact(&mut ack).unwrap();
+            assert_eq!(ack, *b"+", "`{data}` wasn't acknowledged");
+            self.reply()
+        }
+
+        fn reply(&mut self) -> String {
+            let mut reply = Vec::new();
+            self.stream.read_until(b'$', &mut reply).unwrap();
+            reply.clear();
+            self.stream.read_until(b'#', &mut reply).unwrap();
+            reply.pop();
+            let mut sum = [0; 2];
+            self.stream.read_exact(&mut sum).unwrap();
+            assert_eq!(
+                u8::from_str_radix(std::str::from_utf8(&sum).unwrap(), 16).unwrap(),
+                checksum(&reply)
+            );
+            String::from_utf8(reply).unwrap()
+        }
+    }
+
+    #[test]
+    fn t_remote_session() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut stub = stub();
+            stub.serve(BufReader::new(stream.try_clone().unwrap()), stream)
+                .unwrap();
+            stub.debugger().vm().exit_code()
+        });
+
+        let mut gdb = Client {
+            stream: BufReader::new(TcpStream::connect(address).unwrap()),
+        };
+        // A corrupted packet is asked again
+        gdb.stream.get_mut().write_all(b"$?#00").unwrap();
+        let mut nack = [0];
+        gdb.stream.read_exact(&mut nack).unwrap();
+        assert_eq!(nack, *b"-");
+
+        assert!(
+            gdb.send("qSupported:swbreak+")
+                .contains("qXfer:features:read+")
+        );
+        assert_eq!(gdb.send("?"), "S05");
+        let xml = gdb.send("qXfer:features:read:target.xml:0,fff");
+        assert!(xml.starts_with("l<?xml") && xml.contains("riscv:rv32"));
+
+        // 33 registers, sp is at the top of memory
+        let registers = gdb.send("g");
+        assert_eq!(registers.len(), 33 * 8);
+        assert_eq!(&registers[2 * 8..3 * 8], "ffff0f00");
+        assert_eq!(gdb.send("p20"), "00000000");
+
+        assert_eq!(gdb.send("Z0,8,4"), "OK");
+        assert_eq!(gdb.send("c"), "S05");
+        assert_eq!(gdb.send("p20"), "08000000");
+        assert_eq!(gdb.send("pa"), "2a000000");
+        assert_eq!(gdb.send("z0,8,4"), "OK");
+
+        // The instruction at 4, `addi a0, zero, 42`
+        let word = u32::from(&Instruction::AddI {
+            dest: Register::X10,
+            src: Register::X0,
+            value: Immediate14::new(42),
+        });
+        assert_eq!(gdb.send("m4,4"), hex(&word.to_le_bytes()));
+        assert_eq!(gdb.send("m100000,4"), "E01");
+        assert_eq!(gdb.send("Mffff4,2:beef"), "OK");
+        assert_eq!(gdb.send("mffff4,2"), "beef");
+        assert_eq!(gdb.send("M100000,1:00"), "E01");
+
+        assert_eq!(gdb.send("Z2,ffff0,4"), "OK");
+        assert_eq!(gdb.send("c"), "T05watch:ffff0;");
+        assert_eq!(gdb.send("mffff0,4"), "2a000000");
+        assert_eq!(gdb.send("s"), "S05");
+        assert_eq!(gdb.send("p20"), "10000000");
+
+        assert_eq!(gdb.send("Pa=07000000"), "OK");
+        assert_eq!(gdb.send("vMustReplyEmpty"), "");
+        assert_eq!(gdb.send("éa"), "");
+        assert_eq!(gdb.send("QStartNoAckMode"), "OK");
+        // No more acknowledgements
+        gdb.stream.get_mut().write_all(b"$c#63").unwrap();
+        assert_eq!(gdb.reply(), "W07");
+        gdb.stream.get_mut().write_all(b"$D#44").unwrap();
+        assert_eq!(gdb.reply(), "OK");
+
+        assert_eq!(server.join().unwrap(), Some(7));
+    }
+}
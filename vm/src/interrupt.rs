@@ -0,0 +1,236 @@
+//! Asynchronous interrupts. Between two instructions the VM takes the highest priority interrupt that
+//! is pending and enabled, when the `IE` bit of `status` is set and the guest has a trap vector. The
+//! handler sees it in `cause` with [`INTERRUPT_CAUSE`] set and returns with `mret`. The guest
+//! reaches the controller through the `ie`, `ip` and `timecmp` CSRs
+use crate::cpu::csr::Csrs;
+
+/// Set in `cause` for interrupts, clear for traps
+pub const INTERRUPT_CAUSE: u32 = 1 << 31;
+
+/// Interrupt sources, numbered like RISC-V's machine interrupts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Interrupt {
+    /// Raised by the host or by the guest writing `ip`
+    Software = 3,
+    /// Raised while `cycle >= timecmp`
+    Timer = 7,
+}
+
+impl Interrupt {
+    /// Highest priority first
+    const ALL: [Interrupt; 2] = [Interrupt::Software, Interrupt::Timer];
+
+    /// Bit in `ie` and `ip`
+    pub fn bit(self) -> u32 {
+        1 << self as u32
+    }
+
+    /// Value of `cause` while handling it
+    pub fn cause(self) -> u32 {
+        INTERRUPT_CAUSE | self as u32
+    }
+}
+
+/// Enabled and pending interrupts, one bit each, see [`Interrupt::bit`]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptController {
+    /// `ie`
+    pub enabled: u32,
+    /// `ip`
+    pub pending: u32,
+}
+
+impl InterruptController {
+    /// Bits of `ip` the guest can write, the timer's follows `timecmp`
+    const WRITABLE: u32 = 1 << Interrupt::Software as u32;
+
+    pub fn raise(&mut self, interrupt: Interrupt) {
+        self.pending |= interrupt.bit();
+    }
+
+    pub fn clear(&mut self, interrupt: Interrupt) {
+        self.pending &= !interrupt.bit();
+    }
+
+    /// Guest write of `ip`, only the writable bits change
+    pub fn write_pending(&mut self, value: u32) {
+        self.pending = self.pending & !Self::WRITABLE | value & Self::WRITABLE;
+    }
+
+    /// The pending and enabled interrupt with the highest priority
+    pub fn next(&self) -> Option<Interrupt> {
+        let ready = self.pending & self.enabled;
+        Interrupt::ALL
+            .into_iter()
+            .find(|interrupt| ready & interrupt.bit() != 0)
+    }
+}
+
+/// Compares `cycle` with `timecmp`. Counting instructions rather than time keeps runs deterministic
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timer {
+    pub compare: u64,
+}
+
+impl Default for Timer {
+    /// Never fires
+    fn default() -> Self {
+        Self { compare: u64::MAX }
+    }
+}
+
+impl Timer {
+    pub fn fires(&self, time: u64) -> bool {
+        time >= self.compare
+    }
+}
+
+/// Update the timer interrupt and return the interrupt to take before the next instruction, if any
+pub fn poll(csrs: &mut Csrs) -> Option<Interrupt> {
+    match csrs.timer.fires(csrs.cycle) {
+        true => csrs.interrupts.raise(Interrupt::Timer),
+        false => csrs.interrupts.clear(Interrupt::Timer),
+    }
+    if csrs.status & Csrs::STATUS_IE == 0 || csrs.trap.vector == 0 {
+        return None;
+    }
+    csrs.interrupts.next()
+}
+
+#[cfg(test)]
+mod test {
+    use isa::{
+        Csr,
+        Instruction::*,
+        Register,
+        operand::{Immediate14, UImmediate5},
+    };
+
+    use super::*;
+    use crate::{VM, memory::MemoryConfiguration};
+
+    fn li(dest: Register, value: i32) -> isa::Instruction {
+        AddI {
+            dest,
+            src: Register::X0,
+            value: Immediate14::new(value),
+        }
+    }
+
+    fn csrw(csr: Csr, src: Register) -> isa::Instruction {
+        Csrrw {
+            dest: Register::X0,
+            src,
+            csr,
+        }
+    }
+
+    fn load(program: &[isa::Instruction]) -> VM {
+        let mut vm = VM::new(MemoryConfiguration::new(1024 * 1024).unwrap());
+        let bytes: Vec<u8> = program
+            .iter()
+            .flat_map(|instruction| u32::from(instruction).to_le_bytes())
+            .collect();
+        vm.load_program(&bytes).unwrap();
+        vm
+    }
+
+    #[test]
+    fn t_timer_interrupt() {
+        let mut vm = load(&[
+            li(Register::X5, 40),
+            csrw(Csr::TVEC, Register::X5),
+            li(Register::X5, 20),
+            csrw(Csr::TIMECMP, Register::X5),
+            csrw(Csr::TIMECMPH, Register::X0),
+            li(Register::X5, Interrupt::Timer.bit() as i32),
+            csrw(Csr::IE, Register::X5),
+            Csrrsi {
+                dest: Register::X0,
+                value: UImmediate5::new(Csrs::STATUS_IE),
+                csr: Csr::STATUS,
+            },
+            // Counts the instructions run before the interrupt
+            AddI {
+                dest: Register::X10,
+                src: Register::X10,
+                value: Immediate14::new(1),
+            },
+            Jal {
+                dest: Register::X0,
+                offset: isa::operand::Immediate19::new(-4),
+            },
+            // Handler: push `timecmp` back and count the interrupts
+            li(Register::X5, -1),
+            csrw(Csr::TIMECMP, Register::X5),
+            csrw(Csr::TIMECMPH, Register::X5),
+            AddI {
+                dest: Register::X11,
+                src: Register::X11,
+                value: Immediate14::new(1),
+            },
+            Mret,
+        ]);
+
+        // `timecmp` is reached before the 21st instruction
+        for _ in 0..21 {
+            vm.step().unwrap();
+        }
+        assert_eq!(vm.pc(), 44);
+        // Interrupts are disabled in the handler, `mret` brings them back
+        assert_eq!(vm.csrs().status & Csrs::STATUS_IE, 0);
+        assert_eq!(vm.csrs().status & Csrs::STATUS_PIE, Csrs::STATUS_PIE);
+        let interrupted = vm.trap_state();
+        assert_eq!(interrupted.cause, Interrupt::Timer.cause());
+        assert_eq!((interrupted.pc, interrupted.value), (32, 0));
+
+        for _ in 0..4 {
+            vm.step().unwrap();
+        }
+        assert_eq!(vm.registers().get(Register::X11), 1);
+        assert_eq!(vm.pc(), interrupted.pc);
+        assert_eq!(vm.csrs().status & Csrs::STATUS_IE, Csrs::STATUS_IE);
+        // The interrupt didn't fire again
+        for _ in 0..10 {
+            vm.step().unwrap();
+        }
+        assert_eq!(vm.registers().get(Register::X11), 1);
+    }
+
+    #[test]
+    fn t_software_interrupt() {
+        let handler = 16;
+        let program = [
+            li(Register::X5, Interrupt::Software.bit() as i32),
+            csrw(Csr::IE, Register::X5),
+            // Raise it from the guest, nothing happens while `IE` is clear
+            csrw(Csr::IP, Register::X5),
+            li(Register::X6, 1),
+            // Handler: acknowledge
+            csrw(Csr::IP, Register::X0),
+            Mret,
+        ];
+        let mut vm = load(&program);
+        vm.set_trap_vector(handler);
+        for _ in 0..4 {
+            vm.step().unwrap();
+        }
+        assert_eq!(vm.pc(), 16);
+        assert_eq!(vm.csrs().read(Csr::IP), Some(Interrupt::Software.bit()));
+
+        let mut vm = load(&program);
+        vm.set_trap_vector(handler);
+        vm.cpu.csrs.status |= Csrs::STATUS_IE;
+        vm.cpu.csrs.interrupts.enabled = Interrupt::Software.bit();
+        vm.raise_interrupt(Interrupt::Software);
+        // Taken before the first instruction, which runs after `mret`
+        vm.step().unwrap();
+        vm.step().unwrap();
+        assert_eq!(vm.pc(), 0);
+        assert_eq!(vm.trap_state().cause, Interrupt::Software.cause());
+        assert_eq!(vm.csrs().read(Csr::IP), Some(0));
+        vm.step().unwrap();
+        assert_eq!(vm.pc(), 4);
+    }
+}
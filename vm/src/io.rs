@@ -0,0 +1,221 @@
+use std::{
+    cell::RefCell,
+    io::{self, Cursor, Read, Write},
+    rc::Rc,
+};
+
+/// Host side of the guest's standard streams
+pub trait HostIo {
+    fn stdin(&mut self) -> &mut dyn Read;
+    fn stdout(&mut self) -> &mut dyn Write;
+    fn stderr(&mut self) -> &mut dyn Write;
+}
+
+/// Forwards the guest streams to the process' own stdin/stdout/stderr
+pub struct StdIo {
+    stdin: io::Stdin,
+    stdout: io::Stdout,
+    stderr: io::Stderr,
+}
+
+impl Default for StdIo {
+    fn default() -> Self {
+        Self {
+            stdin: io::stdin(),
+            stdout: io::stdout(),
+            stderr: io::stderr(),
+        }
+    }
+}
+
+impl HostIo for StdIo {
+    fn stdin(&mut self) -> &mut dyn Read {
+        &mut self.stdin
+    }
+
+    fn stdout(&mut self) -> &mut dyn Write {
+        &mut self.stdout
+    }
+
+    fn stderr(&mut self) -> &mut dyn Write {
+        &mut self.stderr
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+struct Buffers {
+    stdin: Cursor<Vec<u8>>,
+    stdout: SharedBuffer,
+    stderr: SharedBuffer,
+}
+
+#[derive(Debug, Default, Clone)]
+struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// In-memory streams. Clones share stdout and stderr, so a copy can be handed to the VM and the
+/// other one inspected once the program is done
+#[derive(Debug, Default, Clone)]
+pub struct BufferIo {
+    buffers: Buffers,
+}
+
+impl BufferIo {
+    /// Streams with `stdin` as the guest's input
+    pub fn new(stdin: impl Into<Vec<u8>>) -> Self {
+        Self {
+            buffers: Buffers {
+                stdin: Cursor::new(stdin.into()),
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Everything the guest wrote to stdout so far
+    pub fn captured_stdout(&self) -> Vec<u8> {
+        self.buffers.stdout.0.borrow().clone()
+    }
+
+    /// Everything the guest wrote to stderr so far
+    pub fn captured_stderr(&self) -> Vec<u8> {
+        self.buffers.stderr.0.borrow().clone()
+    }
+}
+
+impl HostIo for BufferIo {
+    fn stdin(&mut self) -> &mut dyn Read {
+        &mut self.buffers.stdin
+    }
+
+    fn stdout(&mut self) -> &mut dyn Write {
+        &mut self.buffers.stdout
+    }
+
+    fn stderr(&mut self) -> &mut dyn Write {
+        &mut self.buffers.stderr
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use isa::{
+        Instruction::{self, *},
+        Register,
+        operand::Immediate14,
+    };
+
+    use super::*;
+    use crate::{
+        VM,
+        memory::MemoryConfiguration,
+        syscall::{NUMBER_REGISTER, SyscallNumber},
+    };
+
+    fn li(dest: Register, value: i32) -> Instruction {
+        AddI {
+            dest,
+            src: Register::X0,
+            value: Immediate14::new(value),
+        }
+    }
+
+    fn syscall(number: SyscallNumber) -> [Instruction; 2] {
+        [
+            li(NUMBER_REGISTER, number as i32),
+            Syscall {
+                src1: Register::X0,
+                src2: Register::X0,
+                src3: Register::X0,
+            },
+        ]
+    }
+
+    /// Stores `text` below the stack pointer, then `write(fd, sp - 32, len)`
+    fn print(fd: u32, text: &[u8]) -> Vec<Instruction> {
+        let mut program = Vec::new();
+        for (i, byte) in text.iter().enumerate() {
+            program.push(li(Register::X5, *byte as i32));
+            program.push(Sb {
+                src: Register::X5,
+                dest: Register::X2,
+                offset: Immediate14::new(i as i32 - 32),
+            });
+        }
+        program.extend([
+            li(Register::X10, fd as i32),
+            AddI {
+                dest: Register::X11,
+                src: Register::X2,
+                value: Immediate14::new(-32),
+            },
+            li(Register::X12, text.len() as i32),
+        ]);
+        program.extend(syscall(SyscallNumber::Write));
+        program
+    }
+
+    #[test]
+    fn t_capture_output() {
+        let io = BufferIo::default();
+        let mut vm = VM::new(MemoryConfiguration::new(1024 * 1024).unwrap());
+        vm.set_io(io.clone());
+
+        let mut program = print(1, b"hello\n");
+        program.extend(print(2, b"oops"));
+        program.push(li(Register::X10, 0));
+        program.extend(syscall(SyscallNumber::Exit));
+
+        vm.test_run(&program).unwrap();
+        assert_eq!(io.captured_stdout(), b"hello\n");
+        assert_eq!(io.captured_stderr(), b"oops");
+    }
+
+    #[test]
+    fn t_echo_stdin() {
+        let io = BufferIo::new("ping");
+        let mut vm = VM::new(MemoryConfiguration::new(1024 * 1024).unwrap());
+        vm.set_io(io.clone());
+
+        let mut program = vec![
+            // a0 = read(0, sp - 32, 16)
+            li(Register::X10, 0),
+            AddI {
+                dest: Register::X11,
+                src: Register::X2,
+                value: Immediate14::new(-32),
+            },
+            li(Register::X12, 16),
+        ];
+        program.extend(syscall(SyscallNumber::Read));
+        program.extend([
+            Add {
+                dest: Register::X12,
+                src1: Register::X10,
+                src2: Register::X0,
+            },
+            // write(1, sp - 32, a0)
+            li(Register::X10, 1),
+            AddI {
+                dest: Register::X11,
+                src: Register::X2,
+                value: Immediate14::new(-32),
+            },
+        ]);
+        program.extend(syscall(SyscallNumber::Write));
+        program.push(li(Register::X10, 0));
+        program.extend(syscall(SyscallNumber::Exit));
+
+        vm.test_run(&program).unwrap();
+        assert_eq!(io.captured_stdout(), b"ping");
+    }
+}
@@ -0,0 +1,14 @@
+pub mod cpu;
+pub mod debugger;
+pub mod device;
+pub mod gdb;
+pub mod interrupt;
+pub mod io;
+pub mod loader;
+pub mod memory;
+pub mod syscall;
+pub mod trace;
+pub mod trap;
+pub mod vm;
+
+pub use vm::VM;
@@ -1,19 +1,433 @@
-// use std::io::{BufRead, Cursor, Read, Seek, SeekFrom, Write};
+use std::{fs, path::Path};
 
-// pub struct Loader;
-// impl Loader {
-//     // fn new() -> Loader {
-//     //     Loader{  }
-//     // }
+use shared::{
+    elf::{self, ElfError, PF_W, PF_X},
+    object::{
+        Object, ObjectError, SECTION_EXECINSTR, SECTION_WRITE, SectionContent, SymbolSection,
+    },
+};
+use thiserror::Error;
 
-//     fn write_ten_bytes_at_end<W: Write + Seek>(mut writer: W) -> std::io::Result<()> {
-//         writer.seek(SeekFrom::End(-10))?;
+use crate::{
+    VM,
+    memory::{MemoryError, Permission, Segment},
+};
 
-//         for i in 0..10 {
-//             writer.write(&[i])?;
-//         }
+#[derive(Debug, Error)]
+pub enum LoaderError {
+    #[error("Unable to read the program: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Program size `{0}` is not a multiple of the instruction size")]
+    Misaligned(usize),
+    #[error("The program is empty")]
+    Empty,
+    #[error(transparent)]
+    MemoryError(#[from] MemoryError),
+    #[error(transparent)]
+    ObjectError(#[from] ObjectError),
+    #[error(transparent)]
+    ElfError(#[from] ElfError),
+    #[error("Undefined symbol `{0}`, the object needs to be linked")]
+    UndefinedSymbol(String),
+}
 
-//         // all went well
-//         Ok(())
-//     }
-// }
+/// Read and execute, plus write if `writable`
+fn permissions(writable: bool, executable: bool) -> Vec<Permission> {
+    let mut permissions = vec![Permission::R];
+    if writable {
+        permissions.push(Permission::W);
+    }
+    if executable {
+        permissions.push(Permission::X);
+    }
+    permissions
+}
+
+/// Labels of the sections, `(address, name)`
+fn symbols(object: &Object) -> Vec<(u32, String)> {
+    object
+        .symbols
+        .iter()
+        .filter(|symbol| matches!(symbol.section, SymbolSection::Index(_)))
+        .filter_map(|symbol| {
+            let name = object.name(symbol.name).ok()?;
+            Some((object.symbol_address(symbol)?, name.to_owned()))
+        })
+        .collect()
+}
+
+/// Program segments, each placed at its address in memory
+pub struct Loader {
+    segments: Vec<Segment>,
+    entry: u32,
+    symbols: Vec<(u32, String)>,
+}
+
+impl Loader {
+    /// A flat binary, loaded at address 0 as read only code
+    pub fn from_bytes(image: impl Into<Vec<u8>>) -> Result<Loader, LoaderError> {
+        let image = image.into();
+        if image.is_empty() {
+            return Err(LoaderError::Empty);
+        }
+        if image.len() % std::mem::size_of::<u32>() != 0 {
+            return Err(LoaderError::Misaligned(image.len()));
+        }
+
+        Ok(Loader {
+            segments: vec![Segment {
+                address: 0,
+                size: image.len() as u32,
+                data: image,
+                permissions: permissions(false, true),
+            }],
+            entry: 0,
+            symbols: Vec::new(),
+        })
+    }
+
+    /// Every section of a rivet object at its address. Every symbol must be defined
+    pub fn from_object(bytes: &[u8]) -> Result<Loader, LoaderError> {
+        let object = Object::read(bytes)?;
+
+        if let Some(symbol) = object
+            .symbols
+            .iter()
+            .find(|symbol| symbol.section == SymbolSection::Undefined)
+        {
+            return Err(LoaderError::UndefinedSymbol(
+                object.name(symbol.name)?.to_owned(),
+            ));
+        }
+
+        let symbols = symbols(&object);
+        let segments: Vec<_> = object
+            .sections
+            .into_iter()
+            .map(|section| Segment {
+                address: section.address,
+                size: section.size,
+                permissions: permissions(
+                    section.flags & SECTION_WRITE != 0,
+                    section.flags & SECTION_EXECINSTR != 0,
+                ),
+                // `Nobits` sections are zero filled
+                data: match section.content {
+                    SectionContent::Progbits => section.data,
+                    SectionContent::Nobits => Vec::new(),
+                },
+            })
+            .collect();
+        if segments.iter().all(|segment| segment.size == 0) {
+            return Err(LoaderError::Empty);
+        }
+
+        Ok(Loader {
+            segments,
+            entry: object.entry,
+            symbols,
+        })
+    }
+
+    /// The `PT_LOAD` segments of an ELF executable, with the permissions from their `p_flags`
+    pub fn from_elf(bytes: &[u8]) -> Result<Loader, LoaderError> {
+        let executable = elf::read_executable(bytes)?;
+
+        let segments: Vec<_> = executable
+            .segments
+            .into_iter()
+            .map(|segment| Segment {
+                address: segment.address,
+                size: segment.size,
+                permissions: permissions(segment.flags & PF_W != 0, segment.flags & PF_X != 0),
+                data: segment.data,
+            })
+            .collect();
+        if segments.iter().all(|segment| segment.size == 0) {
+            return Err(LoaderError::Empty);
+        }
+
+        // Stripped or not, the segments are enough to run
+        let symbols = elf::read(bytes)
+            .map(|object| symbols(&object))
+            .unwrap_or_default();
+
+        Ok(Loader {
+            segments,
+            entry: executable.entry,
+            symbols,
+        })
+    }
+
+    /// An ELF executable or a rivet object according to the file's magic, a raw image otherwise
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Loader, LoaderError> {
+        let bytes = fs::read(path)?;
+        if elf::is_elf(&bytes) {
+            return Self::from_elf(&bytes);
+        }
+        if Object::is_object(&bytes) {
+            return Self::from_object(&bytes);
+        }
+
+        Self::from_bytes(bytes)
+    }
+
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    /// Address of the first instruction
+    pub fn entry(&self) -> u32 {
+        self.entry
+    }
+
+    /// `(address, name)` of the labels in the program, empty for flat binaries
+    pub fn symbols(&self) -> &[(u32, String)] {
+        &self.symbols
+    }
+
+    /// Copy the segments into the VM's memory, ready to run from the entry point
+    pub fn load(&self, vm: &mut VM) -> Result<(), LoaderError> {
+        vm.load_segments(&self.segments)?;
+        vm.set_pc(self.entry);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use isa::{Instruction, Register, operand::Immediate14};
+
+    use super::*;
+    use crate::{
+        memory::MemoryConfiguration,
+        syscall::{NUMBER_REGISTER, SyscallNumber},
+    };
+
+    #[test]
+    fn t_load_and_run() {
+        let program = [
+            Instruction::AddI {
+                dest: Register::X10,
+                src: Register::X0,
+                value: Immediate14::new(3),
+            },
+            Instruction::AddI {
+                dest: NUMBER_REGISTER,
+                src: Register::X0,
+                value: Immediate14::new(SyscallNumber::Exit as i32),
+            },
+            Instruction::Syscall {
+                src1: Register::X0,
+                src2: Register::X0,
+                src3: Register::X0,
+            },
+        ];
+        let image: Vec<u8> = program
+            .iter()
+            .flat_map(|instruction| u32::from(instruction).to_ne_bytes())
+            .collect();
+
+        let mut vm = VM::new(MemoryConfiguration::new(1024 * 1024).unwrap());
+        Loader::from_bytes(image).unwrap().load(&mut vm).unwrap();
+        vm.run().unwrap();
+
+        assert_eq!(vm.exit_code(), Some(3));
+    }
+
+    #[test]
+    fn t_load_object() {
+        use shared::object::{
+            ObjectKind, ObjectSection, ObjectSymbol, SECTION_ALLOC, SECTION_EXECINSTR,
+            SymbolVisibility,
+        };
+
+        // Jumps over a garbage word to the entry point, which exits with 5
+        let code: Vec<u8> = [
+            0xFFFF_FFFFu32,
+            u32::from(&Instruction::AddI {
+                dest: Register::X10,
+                src: Register::X0,
+                value: Immediate14::new(5),
+            }),
+            u32::from(&Instruction::AddI {
+                dest: NUMBER_REGISTER,
+                src: Register::X0,
+                value: Immediate14::new(SyscallNumber::Exit as i32),
+            }),
+            u32::from(&Instruction::Syscall {
+                src1: Register::X0,
+                src2: Register::X0,
+                src3: Register::X0,
+            }),
+        ]
+        .iter()
+        .flat_map(|word| word.to_le_bytes())
+        .collect();
+
+        let mut object = Object {
+            kind: ObjectKind::Executable,
+            entry: 4,
+            sections: vec![ObjectSection {
+                name: 1,
+                content: SectionContent::Progbits,
+                flags: SECTION_ALLOC | SECTION_EXECINSTR,
+                alignment: 4,
+                address: 0,
+                size: code.len() as u32,
+                data: code,
+            }],
+            symbols: vec![ObjectSymbol {
+                name: 7,
+                value: 4,
+                section: SymbolSection::Index(0),
+                visibility: SymbolVisibility::Global,
+            }],
+            relocations: Vec::new(),
+            strtab: b"\0.text\0_start\0".to_vec(),
+        };
+
+        let mut vm = VM::new(MemoryConfiguration::new(1024 * 1024).unwrap());
+        let loader = Loader::from_object(&object.to_bytes()).unwrap();
+        assert_eq!(loader.symbols(), [(4, "_start".to_owned())]);
+        assert_eq!(
+            Loader::from_elf(&elf::write(&object)).unwrap().symbols(),
+            loader.symbols()
+        );
+        loader.load(&mut vm).unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.exit_code(), Some(5));
+
+        object.symbols[0].section = SymbolSection::Undefined;
+        assert!(matches!(
+            Loader::from_object(&object.to_bytes()),
+            Err(LoaderError::UndefinedSymbol(name)) if name == "_start"
+        ));
+    }
+
+    #[test]
+    fn t_load_elf() {
+        use shared::object::{ObjectKind, ObjectSection, SECTION_ALLOC};
+
+        let exit = |code: i32| {
+            [
+                Instruction::AddI {
+                    dest: Register::X10,
+                    src: Register::X0,
+                    value: Immediate14::new(code),
+                },
+                Instruction::AddI {
+                    dest: NUMBER_REGISTER,
+                    src: Register::X0,
+                    value: Immediate14::new(SyscallNumber::Exit as i32),
+                },
+                Instruction::Syscall {
+                    src1: Register::X0,
+                    src2: Register::X0,
+                    src3: Register::X0,
+                },
+            ]
+        };
+        // Exits with the byte at the end of .bss plus the one in .data
+        let program = [
+            Instruction::Lbu {
+                dest: Register::X5,
+                src: Register::X0,
+                offset: Immediate14::new(0x1007),
+            },
+            Instruction::Lbu {
+                dest: Register::X6,
+                src: Register::X0,
+                offset: Immediate14::new(0x1000),
+            },
+            Instruction::Add {
+                dest: Register::X10,
+                src1: Register::X5,
+                src2: Register::X6,
+            },
+            Instruction::AddI {
+                dest: NUMBER_REGISTER,
+                src: Register::X0,
+                value: Immediate14::new(SyscallNumber::Exit as i32),
+            },
+            Instruction::Syscall {
+                src1: Register::X0,
+                src2: Register::X0,
+                src3: Register::X0,
+            },
+        ];
+        let words = |instructions: &[Instruction]| -> Vec<u8> {
+            instructions
+                .iter()
+                .flat_map(|instruction| u32::from(instruction).to_le_bytes())
+                .collect()
+        };
+        let mut code = words(&exit(1));
+        code.extend(words(&program));
+
+        let object = Object {
+            kind: ObjectKind::Executable,
+            entry: 12,
+            sections: vec![
+                ObjectSection {
+                    name: 0,
+                    content: SectionContent::Progbits,
+                    flags: SECTION_ALLOC | SECTION_EXECINSTR,
+                    alignment: 4,
+                    address: 0,
+                    size: code.len() as u32,
+                    data: code,
+                },
+                ObjectSection {
+                    name: 0,
+                    content: SectionContent::Progbits,
+                    flags: SECTION_ALLOC | SECTION_WRITE,
+                    alignment: 4,
+                    address: 0x1000,
+                    size: 4,
+                    data: vec![40, 0, 0, 0],
+                },
+                ObjectSection {
+                    name: 0,
+                    content: SectionContent::Nobits,
+                    flags: SECTION_ALLOC | SECTION_WRITE,
+                    alignment: 4,
+                    address: 0x1004,
+                    size: 4,
+                    data: Vec::new(),
+                },
+            ],
+            symbols: Vec::new(),
+            relocations: Vec::new(),
+            strtab: vec![0],
+        };
+        let elf = elf::write(&object);
+
+        let mut vm = VM::new(MemoryConfiguration::new(1024 * 1024).unwrap());
+        // Leftovers in the bss must be cleared by the loader
+        vm.load_program(&[0xFF; 0x1010]).unwrap();
+        let loader = Loader::from_elf(&elf).unwrap();
+        assert_eq!(loader.entry(), 12);
+        assert_eq!(
+            loader.segments()[1].permissions,
+            [Permission::R, Permission::W]
+        );
+
+        loader.load(&mut vm).unwrap();
+        assert_eq!(vm.registers().get(Register::X2), 1024 * 1024 - 1);
+        vm.run().unwrap();
+        assert_eq!(vm.exit_code(), Some(40));
+    }
+
+    #[test]
+    fn t_reject_bad_image() {
+        assert!(matches!(
+            Loader::from_bytes(vec![1, 2, 3]),
+            Err(LoaderError::Misaligned(3))
+        ));
+        assert!(matches!(
+            Loader::from_bytes(vec![]),
+            Err(LoaderError::Empty)
+        ));
+    }
+}
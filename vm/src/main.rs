@@ -1,8 +1,159 @@
-mod cpu;
-mod loader;
-mod memory;
-mod vm;
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter},
+    net::TcpListener,
+    path::PathBuf,
+    process::ExitCode,
+};
 
-fn main() {
-    println!("Hello, world!");
+use clap::{Parser, ValueEnum};
+use vm::{
+    VM,
+    debugger::Debugger,
+    device::{TestFinisher, Uart},
+    gdb::GdbStub,
+    io::StdIo,
+    loader::Loader,
+    memory::MemoryConfiguration,
+    trace::{BinaryTrace, JsonTrace, TextTrace},
+};
+
+/// Run a RIVET program
+///
+/// A console UART is mapped at 0x10000000 and a test finisher, which exits with the code written
+/// to it, at 0x10001000
+#[derive(Debug, Parser)]
+#[command(name = "rivet-vm", version)]
+struct Args {
+    /// ELF executable, rivet object or flat binary loaded at address 0
+    program: PathBuf,
+    /// Memory size, accepts `K`, `M` and `G` suffixes
+    #[arg(long, default_value = "1M", value_parser = parse_size)]
+    memory: u32,
+    /// Stack size, accepts `K`, `M` and `G` suffixes
+    #[arg(long, default_value = "2K", value_parser = parse_size)]
+    stack: u32,
+    /// Start in the debugger, commands are read from stdin
+    #[arg(short, long, conflicts_with = "gdb")]
+    debug: bool,
+    /// Wait for GDB to connect on `ADDRESS`, e.g. `localhost:1234`, and let it drive the program
+    #[arg(long, value_name = "ADDRESS")]
+    gdb: Option<String>,
+    /// Write a record of every executed instruction to `PATH`
+    #[arg(long, value_name = "PATH")]
+    trace: Option<PathBuf>,
+    #[arg(long, value_enum, default_value_t = TraceFormat::Text, requires = "trace")]
+    trace_format: TraceFormat,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum TraceFormat {
+    /// One line per instruction
+    Text,
+    /// One JSON object per line
+    Json,
+    /// Compact little-endian records
+    Binary,
+}
+
+/// `64K` -> 65536. Suffixes are powers of 1024
+fn parse_size(size: &str) -> Result<u32, String> {
+    let size = size.trim();
+    let (digits, multiplier) = match size.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&size[..i], 1 << 10),
+        Some((i, 'm' | 'M')) => (&size[..i], 1 << 20),
+        Some((i, 'g' | 'G')) => (&size[..i], 1 << 30),
+        _ => (size, 1),
+    };
+
+    let value = match digits.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => digits.parse::<u32>(),
+    }
+    .map_err(|err| format!("invalid size `{size}`: {err}"))?;
+
+    value
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("size `{size}` doesn't fit in the 32-bit address space"))
+}
+
+fn run(args: &Args) -> anyhow::Result<u32> {
+    let configuration = MemoryConfiguration::with_stack_size(args.memory, args.stack)?;
+
+    let loader = Loader::from_file(&args.program)
+        .map_err(|err| anyhow::anyhow!("{}: {err}", args.program.display()))?;
+    let mut vm = VM::new(configuration);
+    loader.load(&mut vm)?;
+    vm.map_device(Uart::BASE, Uart::LEN, Uart::new(StdIo::default()))?;
+    vm.map_device(
+        TestFinisher::BASE,
+        TestFinisher::LEN,
+        TestFinisher::default(),
+    )?;
+
+    if let Some(path) = &args.trace {
+        let file = File::create(path)
+            .map(BufWriter::new)
+            .map_err(|err| anyhow::anyhow!("{}: {err}", path.display()))?;
+        match args.trace_format {
+            TraceFormat::Text => vm.set_trace(TextTrace::new(file)),
+            TraceFormat::Json => vm.set_trace(JsonTrace::new(file)),
+            TraceFormat::Binary => vm.set_trace(BinaryTrace::new(file)),
+        }
+    }
+
+    if args.debug || args.gdb.is_some() {
+        let mut debugger = Debugger::new(vm);
+        for (address, name) in loader.symbols() {
+            debugger.symbol(*address, name.as_str());
+        }
+
+        let Some(address) = &args.gdb else {
+            debugger.repl(std::io::stdin().lock(), std::io::stdout())?;
+            return Ok(debugger.vm().exit_code().unwrap_or_default());
+        };
+        let listener = TcpListener::bind(address)?;
+        eprintln!("rivet-vm: waiting for GDB on {}", listener.local_addr()?);
+        let (stream, _) = listener.accept()?;
+        stream.set_nodelay(true)?;
+
+        let mut stub = GdbStub::new(debugger);
+        stub.serve(BufReader::new(stream.try_clone()?), stream)?;
+        return Ok(stub.debugger().vm().exit_code().unwrap_or_default());
+    }
+
+    let result = vm.run();
+    if let Some(mut trace) = vm.take_trace() {
+        trace.flush()?;
+    }
+
+    Ok(result?)
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+
+    match run(&args) {
+        // Like a posix shell, only the low byte of the code survives
+        Ok(code) => ExitCode::from(code as u8),
+        Err(err) => {
+            eprintln!("rivet-vm: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::parse_size;
+
+    #[test]
+    fn t_parse_size() {
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert_eq!(parse_size("64K"), Ok(64 * 1024));
+        assert_eq!(parse_size("1M"), Ok(1024 * 1024));
+        assert_eq!(parse_size("0x100k"), Ok(256 * 1024));
+        assert!(parse_size("4G").is_err());
+        assert!(parse_size("12Q").is_err());
+    }
 }
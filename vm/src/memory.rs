@@ -1,28 +1,31 @@
 use std::{
+    cell::{Cell, RefCell},
     fmt::{Debug, Display},
-    ops::{Index, IndexMut, Range},
+    ops::{Index, Range},
 };
 
 use shared::EnumCount;
 use thiserror::Error;
 
-// use log::debug;
+use log::debug;
+
+use crate::device::{Device, DeviceBus, DeviceEvent};
 
 #[derive(Debug, Error, PartialEq)]
 pub enum MemoryError {
-    #[error("Permission Denied: Unable to `{0}` at address `{1}`")]
+    #[error("Permission Denied: Unable to `{0}` at address `{1:#010x}`")]
     PermissionDenied(Permission, u32),
-    #[error("Unaligned access: Trying to access address `{0}` with `{1}`")]
+    #[error("Unaligned access: Trying to access address `{0:#010x}` with a `{1}` byte alignment")]
     UnalignedAccess(u32, usize),
-    #[error("Invalid address: `{0}`")]
+    #[error("Invalid address: `{0:#010x}`")]
     InvalidAddress(u32),
-    #[error("out of bounds: `{0}`")]
+    #[error("Out of bounds: `{0:#010x}`")]
     OutOfBounds(u32),
-    #[error("Out of memory: maximum capacity is`{0}`")]
+    #[error("Out of memory: maximum capacity is `{0:#010x}`")]
     OutOfMemory(u32),
-    #[error("out of bounds: `{0}`")]
+    #[error("Out of bounds: `{0:#010x}`")]
     AddressTranslation(u32, Box<MemoryError>),
-    #[error("no mapping: `{0}`")]
+    #[error("No mapping: `{0:#010x}`")]
     NoMap(u32),
     #[error("invalid mapping index: `{0}`")]
     InvalidMap(u32, usize),
@@ -32,6 +35,12 @@ pub enum MemoryError {
     InternalMapperWithMessage(u32, String),
     #[error("this memory is read only")]
     ReadOnly,
+    #[error("`{0:#010x}` is already mapped to a device")]
+    DeviceOverlap(u32),
+    #[error("region `{0}` overlaps region `{1}`")]
+    RegionOverlap(String, String),
+    #[error("region `{0}` is laid out by the VM")]
+    ReservedRegion(String),
 }
 
 pub trait ReadWrite<T>
@@ -181,24 +190,93 @@ impl Index<Range<usize>> for LinearMemory {
     }
 }
 
+/// A chunk of the program image placed at `address`, the bytes after `data` up to `size` are zeroed
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    pub address: u32,
+    pub size: u32,
+    pub data: Vec<u8>,
+    pub permissions: Vec<Permission>,
+}
+
+/// Accesses a watchpoint reports
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    /// Reads and writes
+    Access,
+}
+
+impl WatchKind {
+    fn matches(self, permission: Permission) -> bool {
+        matches!(
+            (self, permission),
+            (WatchKind::Access, _)
+                | (WatchKind::Read, Permission::R)
+                | (WatchKind::Write, Permission::W)
+        )
+    }
+}
+
+/// `len` bytes from `address` watched for `kind` accesses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub address: u32,
+    pub len: u32,
+    pub kind: WatchKind,
+}
+
+/// A guest access that touched a watchpoint
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchHit {
+    pub watchpoint: Watchpoint,
+    /// Address of the access, which may start before the watchpoint
+    pub address: u32,
+    /// `R` or `W`
+    pub access: Permission,
+}
+
+/// A guest load or store, logged while [`MemoryManager::record_accesses`] is on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryAccess {
+    pub address: u32,
+    /// 1, 2 or 4 bytes
+    pub size: u8,
+    /// The value loaded or stored, zero-extended
+    pub value: u32,
+    /// `R` or `W`
+    pub access: Permission,
+}
+
 pub struct MemoryManager {
     memory: LinearMemory,
-    regions: Regions,
-    free_memory: u32,
+    map: MemoryMap,
+    /// The configured map, before any program was loaded
+    initial_map: MemoryMap,
+    heap_start: u32,
+    program_break: u32,
+    watchpoints: Vec<Watchpoint>,
+    /// First watchpoint hit since the last [`MemoryManager::take_watch_hit`]. A `Cell` because reads only borrow `self`
+    watch_hit: Cell<Option<WatchHit>>,
+    /// `None` unless accesses are recorded, so that untraced runs only pay for the check
+    accesses: Option<RefCell<Vec<MemoryAccess>>>,
+    /// Consulted before RAM
+    devices: DeviceBus,
 }
 
 impl MemoryManager {
     pub fn new(configuration: &MemoryConfiguration) -> MemoryManager {
-        let mut regions = Regions::default();
-        let stack_start = configuration.allocated_memory - 1;
-        regions[RegionType::Stack].set_bounds(
-            configuration.allocated_memory - configuration.stack_size,
-            stack_start,
-        );
         MemoryManager {
             memory: LinearMemory::new(configuration.allocated_memory),
-            regions,
-            free_memory: configuration.allocated_memory,
+            map: configuration.map.clone(),
+            initial_map: configuration.map.clone(),
+            heap_start: 0,
+            program_break: 0,
+            watchpoints: Vec::new(),
+            watch_hit: Cell::new(None),
+            accesses: None,
+            devices: DeviceBus::default(),
         }
     }
 
@@ -207,53 +285,148 @@ impl MemoryManager {
         self.memory.buffer.capacity()
     }
 
+    /// Last byte of the stack, where `sp` starts
     pub fn stack_start(&self) -> u32 {
-        self.regions[RegionType::Stack].bounds.end()
+        let end = self
+            .map
+            .region(Region::STACK)
+            .map_or(self.memory.size() as u64, Region::end);
+        (end - 1) as u32
+    }
+
+    pub fn memory_map(&self) -> &MemoryMap {
+        &self.map
+    }
+
+    /// `region` must be backed by the memory
+    fn check_backed(&self, region: &Region) -> Result<(), MemoryError> {
+        if region.end() > self.memory.size() as u64 {
+            return Err(MemoryError::OutOfMemory(self.memory.size() as u32));
+        }
+        debug!(
+            "Region {} {:#010x}..{:#010x} {}",
+            region.name,
+            region.base,
+            region.end(),
+            region.permissions
+        );
+        Ok(())
+    }
+
+    /// Add `region`, which must be backed by the memory
+    fn map_region(&mut self, region: Region) -> Result<(), MemoryError> {
+        self.check_backed(&region)?;
+        self.map.insert(region)
+    }
+
+    /// Replace the regions of the previous program with `regions`. The map is left as it was if
+    /// one of them doesn't fit
+    fn map_program(&mut self, regions: &[Region]) -> Result<(), MemoryError> {
+        let mut map = self.map.clone();
+        for name in [Region::CODE, Region::RODATA, Region::DATA, Region::HEAP] {
+            map.remove(name);
+        }
+        for region in regions {
+            self.check_backed(region)?;
+            map.insert(region.clone())?;
+        }
+        self.map = map;
+        Ok(())
     }
 
+    /// Copy a flat binary at address 0, it makes up the `code` region. The heap starts after it
     pub fn load_program(&mut self, program: &[u8]) -> Result<(), MemoryError> {
-        // TODO: grow stack and heap and then stack pointer
-
-        // rounding up to the nearest alignment in case the program length is not aligned. //TODO: Decide to give region alignment or not if the program legnth is not aligned
-        let alignment = 4;
-        let code_end = (program.len() as u32).div_ceil(alignment) * alignment;
-        self.regions[RegionType::Code].set_bounds(0, code_end);
-
-        self.regions[RegionType::Code]
-            .permissions
-            .enable(Permission::W);
-
-        let mut current_address = 0;
-        println!("Program: {:?}", program);
-        // TODO: use chunks_exact
-        // Handle full 4-byte chunks
-        for chunk in program.chunks(4) {
-            if chunk.len() == 4 {
-                println!("Chunk: {:?}", chunk);
-                // Write full 4 bytes
-                let word = u32::from_ne_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
-                self.write::<u32>(current_address, word)?;
-                current_address += 4
+        // Rounded up so that the heap is aligned
+        let code_end = (program.len() as u32).next_multiple_of(4);
+        self.map_program(&[Region::new(
+            Region::CODE,
+            0,
+            code_end,
+            Permissions::new(&[Permission::R, Permission::X]),
+        )])?;
+
+        let buffer = &mut self.memory.buffer[..code_end as usize];
+        buffer[..program.len()].copy_from_slice(program);
+        buffer[program.len()..].fill(0);
+        self.heap_start = code_end;
+        self.program_break = code_end;
+
+        Ok(())
+    }
+
+    /// Copy every segment at its address. Executable segments make up the `code` region, read only
+    /// ones the `rodata` region and the others the `data` region, each region gets the permissions
+    /// of all its segments. The heap starts right after the last segment
+    pub fn load_segments(&mut self, segments: &[Segment]) -> Result<(), MemoryError> {
+        let region = |name| Region::new(name, 0, 0, Permissions::default());
+        let mut code = region(Region::CODE);
+        let mut rodata = region(Region::RODATA);
+        let mut data = region(Region::DATA);
+        let mut end = 0;
+
+        let segments: Vec<_> = segments
+            .iter()
+            .filter(|segment| segment.size != 0)
+            .collect();
+        for segment in &segments {
+            let segment_end = segment
+                .address
+                .checked_add(segment.size)
+                .filter(|_| segment.data.len() <= segment.size as usize)
+                .ok_or(MemoryError::OutOfBounds(segment.address))?;
+
+            let region = if segment.permissions.contains(&Permission::X) {
+                &mut code
+            } else if segment.permissions.contains(&Permission::W) {
+                &mut data
             } else {
-                // TODO: Decide to write/add padding to the bytes or not if the program is not aligned
-                panic!("CHUNK IS NOT 4 BYTES");
-                // Handle the remaining bytes which are less than 4
-                // for &byte in chunk {
-                //     self.write_byte(current_address, byte);
-                // current_address += 1;
-                // }
+                &mut rodata
+            };
+            let base = match region.size {
+                0 => segment.address,
+                _ => region.base.min(segment.address),
+            };
+            let region_end = (region.end() as u32).max(segment_end);
+            region.base = base;
+            region.size = region_end - base;
+            for permission in &segment.permissions {
+                region.permissions.enable(*permission);
             }
+            end = end.max(segment_end);
         }
 
-        self.regions[RegionType::Code]
-            .permissions
-            .disable(Permission::W);
+        // Every segment is in one of the regions, which are all backed once mapped
+        self.map_program(&[code, rodata, data])?;
+        for segment in segments {
+            let start = segment.address as usize;
+            let buffer = &mut self.memory.buffer[start..start + segment.size as usize];
+            buffer[..segment.data.len()].copy_from_slice(&segment.data);
+            buffer[segment.data.len()..].fill(0);
+        }
+        self.heap_start = end.next_multiple_of(4);
+        self.program_break = self.heap_start;
 
-        self.regions[RegionType::Data].set_bounds(code_end, code_end);
-        self.regions[RegionType::Heap].bounds = RegionBounds::new(
-            self.regions[RegionType::Data].bounds.start(),
-            self.regions[RegionType::Data].bounds.end(),
-        );
+        Ok(())
+    }
+
+    /// The first address after the end of the heap
+    pub fn program_break(&self) -> u32 {
+        self.program_break
+    }
+
+    /// Grow or shrink the heap so it ends right before `address`. The heap can't go below its start or run into another region
+    pub fn set_program_break(&mut self, address: u32) -> Result<(), MemoryError> {
+        if address < self.heap_start {
+            return Err(MemoryError::InvalidAddress(address));
+        }
+
+        self.map_region(Region::new(
+            Region::HEAP,
+            self.heap_start,
+            address - self.heap_start,
+            Permissions::new(&[Permission::R, Permission::W]),
+        ))?;
+        self.program_break = address;
 
         Ok(())
     }
@@ -274,47 +447,192 @@ impl MemoryManager {
         size: usize, // 1, 2, or 4 bytes
         permission: Permission,
     ) -> Result<usize, MemoryError> {
-        if vaddr > (self.memory.buffer.capacity() as u32) {
+        if vaddr as usize + size > self.memory.size() {
             return Err(MemoryError::OutOfBounds(vaddr));
         }
 
-        let Some(region) = self.regions.get(vaddr) else {
+        let Some(region) = self.map.get(vaddr) else {
             return Err(MemoryError::InvalidAddress(vaddr));
         };
 
-        if region.allowed_to(permission) {
+        if region.permissions.allows(permission) {
             Ok(vaddr as usize)
         } else {
             Err(MemoryError::PermissionDenied(permission, vaddr))
         }
     }
 
+    /// Report `size` bytes accessed at `address` if they overlap a watchpoint
+    fn watch(&self, address: u32, size: usize, access: Permission) {
+        if self.watchpoints.is_empty() || self.watch_hit.get().is_some() {
+            return;
+        }
+
+        let end = address as u64 + size as u64;
+        let watchpoint = self.watchpoints.iter().find(|watchpoint| {
+            let watch_end = watchpoint.address as u64 + watchpoint.len as u64;
+            watchpoint.kind.matches(access)
+                && (address as u64) < watch_end
+                && (watchpoint.address as u64) < end
+        });
+        if let Some(&watchpoint) = watchpoint {
+            self.watch_hit.set(Some(WatchHit {
+                watchpoint,
+                address,
+                access,
+            }));
+        }
+    }
+
+    /// Log the access of `size` bytes of `value` at `address`, if accesses are recorded
+    fn log(&self, address: u32, size: usize, value: u32, access: Permission) {
+        let Some(accesses) = &self.accesses else {
+            return;
+        };
+
+        accesses.borrow_mut().push(MemoryAccess {
+            address,
+            size: size as u8,
+            value,
+            access,
+        });
+    }
+
+    /// Start or stop logging the guest's loads and stores for [`MemoryManager::take_accesses`]
+    pub fn record_accesses(&mut self, enabled: bool) {
+        self.accesses = enabled.then(RefCell::default);
+    }
+
+    /// The accesses logged since the last call, oldest first
+    pub fn take_accesses(&self) -> Vec<MemoryAccess> {
+        self.accesses
+            .as_ref()
+            .map(|accesses| accesses.take())
+            .unwrap_or_default()
+    }
+
+    /// Report `kind` accesses to the `len` bytes from `address` with [`MemoryManager::take_watch_hit`]
+    pub fn add_watchpoint(&mut self, address: u32, len: u32, kind: WatchKind) {
+        self.watchpoints.push(Watchpoint {
+            address,
+            len: len.max(1),
+            kind,
+        });
+    }
+
+    /// Remove the watchpoints starting at `address`, `false` if there was none
+    pub fn remove_watchpoint(&mut self, address: u32) -> bool {
+        let len = self.watchpoints.len();
+        self.watchpoints
+            .retain(|watchpoint| watchpoint.address != address);
+        self.watchpoints.len() != len
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    /// The first watchpoint hit since the last call
+    pub fn take_watch_hit(&self) -> Option<WatchHit> {
+        self.watch_hit.take()
+    }
+
+    /// `len` bytes from `address` regardless of the regions and their permissions, without
+    /// triggering watchpoints. Meant for debuggers
+    pub fn inspect(&self, address: u32, len: u32) -> Result<&[u8], MemoryError> {
+        let end = address
+            .checked_add(len)
+            .filter(|&end| end as usize <= self.memory.size())
+            .ok_or(MemoryError::OutOfBounds(address.saturating_add(len)))?;
+        Ok(&self.memory[address as usize..end as usize])
+    }
+
+    /// Overwrite memory at `address` with `bytes` regardless of the regions and their permissions,
+    /// without triggering watchpoints. Meant for debuggers
+    pub fn patch(&mut self, address: u32, bytes: &[u8]) -> Result<(), MemoryError> {
+        let end = address
+            .checked_add(bytes.len() as u32)
+            .filter(|&end| end as usize <= self.memory.size())
+            .ok_or(MemoryError::OutOfBounds(
+                address.saturating_add(bytes.len() as u32),
+            ))?;
+        self.memory.buffer[address as usize..end as usize].copy_from_slice(bytes);
+        Ok(())
+    }
+
+    /// Map `len` bytes from `address` to `device`, in front of RAM. Device mappings can't overlap
+    pub fn map_device(
+        &mut self,
+        address: u32,
+        len: u32,
+        device: impl Device + 'static,
+    ) -> Result<(), MemoryError> {
+        self.devices.map(address, len, Box::new(device))
+    }
+
+    /// Tick the devices, once per instruction
+    pub fn tick_devices(&mut self) -> DeviceEvent {
+        self.devices.tick()
+    }
+
     pub fn read<T>(&self, address: u32) -> Result<T, MemoryError>
     where
-        T: Copy,
+        T: Copy + Into<u32> + TryFrom<u32>,
         LinearMemory: ReadWrite<T>,
     {
-        let real_addr = self.validate(address, std::mem::size_of::<T>(), Permission::R)?;
+        let size = std::mem::size_of::<T>();
+        let value = match self.devices.read(address, size as u8) {
+            Some(value) => T::try_from(value?)
+                .ok()
+                .expect("the bus truncates to the access size"),
+            None => {
+                let real_addr = self.validate(address, size, Permission::R)?;
+                self.memory.read(real_addr)?
+            }
+        };
+        self.watch(address, size, Permission::R);
+        self.log(address, size, value.into(), Permission::R);
+        Ok(value)
+    }
+
+    /// Read the instruction at `address`, which must be in an executable region
+    pub fn fetch(&self, address: u32) -> Result<u32, MemoryError> {
+        let real_addr = self.validate(address, std::mem::size_of::<u32>(), Permission::X)?;
         self.memory.read(real_addr)
     }
 
     pub fn write<T>(&mut self, address: u32, value: T) -> Result<(), MemoryError>
     where
-        T: Copy,
+        T: Copy + Into<u32>,
         LinearMemory: ReadWrite<T>,
     {
-        let real_addr = self.validate(address, std::mem::size_of::<T>(), Permission::W)?;
-        self.memory.write(real_addr, value)
+        let size = std::mem::size_of::<T>();
+        match self.devices.write(address, size as u8, value.into()) {
+            Some(written) => written?,
+            None => {
+                let real_addr = self.validate(address, size, Permission::W)?;
+                self.memory.write(real_addr, value)?;
+            }
+        }
+        self.watch(address, size, Permission::W);
+        self.log(address, size, value.into(), Permission::W);
+        Ok(())
     }
 
     pub fn reset(&mut self) {
         self.memory.zero_all();
-        self.regions.reset();
+        self.map = self.initial_map.clone();
+        self.heap_start = 0;
+        self.program_break = 0;
+        self.watch_hit.set(None);
+        if let Some(accesses) = &self.accesses {
+            accesses.take();
+        }
     }
 }
 
-#[derive(Debug, Clone, Copy, EnumCount, PartialEq)]
-enum Permission {
+#[derive(Debug, Clone, Copy, EnumCount, PartialEq, Eq)]
+pub enum Permission {
     R,
     W,
     X,
@@ -331,249 +649,227 @@ impl Display for Permission {
     }
 }
 
-#[derive(Debug)]
-struct Permissions([bool; Permission::VARIANT_COUNT]);
+/// What the guest may do in a region
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Permissions([bool; Permission::VARIANT_COUNT]);
 
 impl Permissions {
-    fn new(permissions: &[Permission]) -> Permissions {
-        let mut perm = Self::default();
-        for p in permissions {
-            perm[*p] = true
+    pub fn new(permissions: &[Permission]) -> Permissions {
+        let mut allowed = Permissions::default();
+        for permission in permissions {
+            allowed.enable(*permission);
         }
-
-        Permissions(perm.into())
+        allowed
     }
 
-    fn enable(&mut self, permission: Permission) {
+    pub fn enable(&mut self, permission: Permission) {
         self.0[permission as usize] = true;
     }
 
-    fn disable(&mut self, permission: Permission) {
-        self.0[permission as usize] = false;
-    }
-
-    fn status(&self, permission: Permission) -> bool {
+    pub fn allows(&self, permission: Permission) -> bool {
         self.0[permission as usize]
     }
 }
 
-impl From<Permissions> for [bool; Permission::VARIANT_COUNT] {
-    fn from(value: Permissions) -> Self {
-        // TODO: Fishy. If the Permission::VARIANT_COUNT changes it won't be good
-        [value.0[0], value.0[1], value.0[2]]
-    }
-}
-
-impl Default for Permissions {
-    fn default() -> Self {
-        Self([true, false, false])
-    }
-}
-
-impl Index<Permission> for Permissions {
-    type Output = bool;
-
-    fn index(&self, index: Permission) -> &Self::Output {
-        &self.0[index as usize]
-    }
-}
-
-impl IndexMut<Permission> for Permissions {
-    fn index_mut(&mut self, index: Permission) -> &mut Self::Output {
-        &mut self.0[index as usize]
-    }
-}
-
-#[derive(Default, Debug)]
-/// (start, end) inclusive
-struct RegionBounds(u32, u32);
-impl RegionBounds {
-    fn new(start: u32, end: u32) -> RegionBounds {
-        RegionBounds(start, end)
-    }
-
-    fn start(&self) -> u32 {
-        self.0
-    }
-
-    fn start_mut(&mut self, value: u32) {
-        self.0 = value;
-    }
-
-    fn end(&self) -> u32 {
-        self.1
-    }
-
-    fn end_mut(&mut self, value: u32) {
-        self.1 = value;
-    }
-}
-
-impl From<&RegionBounds> for Range<usize> {
-    fn from(value: &RegionBounds) -> Self {
-        Self {
-            start: value.0 as usize,
-            end: value.1 as usize,
+/// `rwx`, with `-` for what isn't allowed
+impl Display for Permissions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (permission, letter) in [
+            (Permission::R, 'r'),
+            (Permission::W, 'w'),
+            (Permission::X, 'x'),
+        ] {
+            write!(f, "{}", if self.allows(permission) { letter } else { '-' })?;
         }
+        Ok(())
     }
 }
 
-// THE ORDER IS IMPORTANT CAUSE IT WILL BE USED EVERYWHERE ELSE
-#[derive(Debug, EnumCount)]
-enum RegionType {
-    Code,
-    Data,
-    Heap,
-    Stack,
-}
-
-impl RegionType {
-    const fn default_stack_size() -> u32 {
-        2048
-    }
-}
-
-#[derive(Debug)]
+/// `size` bytes from `base`, named so that they can be looked up and replaced
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Region {
-    permissions: Permissions,
-    bounds: RegionBounds,
-    ty: RegionType,
-    // size: usize
+    pub name: String,
+    pub base: u32,
+    pub size: u32,
+    pub permissions: Permissions,
 }
 
 impl Region {
-    fn new(permissions: Permissions, bounds: RegionBounds, ty: RegionType) -> Region {
+    /// Executable segments, or the whole flat binary
+    pub const CODE: &'static str = "code";
+    /// Read only segments
+    pub const RODATA: &'static str = "rodata";
+    /// Writable segments
+    pub const DATA: &'static str = "data";
+    /// From the end of the program to the program break
+    pub const HEAP: &'static str = "heap";
+    /// Ends memory, `sp` starts at its last byte
+    pub const STACK: &'static str = "stack";
+
+    /// Regions the VM lays out itself, they can't be declared
+    const RESERVED: [&'static str; 5] = [
+        Region::CODE,
+        Region::RODATA,
+        Region::DATA,
+        Region::HEAP,
+        Region::STACK,
+    ];
+
+    pub fn new(name: impl Into<String>, base: u32, size: u32, permissions: Permissions) -> Region {
         Region {
+            name: name.into(),
+            base,
+            size,
             permissions,
-            bounds,
-            ty,
-            // size,
         }
     }
 
-    fn grow(&mut self, offset: u32) {
-        self.bounds.end_mut(offset);
-    }
-
-    fn is_valid_address(&self, address: u32) -> bool {
-        address >= self.bounds.start() && address <= self.bounds.end()
+    /// First address after the region, a `u64` so that a region can end the address space
+    pub fn end(&self) -> u64 {
+        self.base as u64 + self.size as u64
     }
 
-    fn set_bounds(&mut self, start: u32, end: u32) {
-        self.bounds = RegionBounds::new(start, end);
+    pub fn contains(&self, address: u32) -> bool {
+        self.base <= address && (address as u64) < self.end()
     }
 
-    fn reset(&mut self) {
-        self.bounds.start_mut(0);
-        self.bounds.end_mut(0);
+    fn overlaps(&self, other: &Region) -> bool {
+        (self.base as u64) < other.end() && (other.base as u64) < self.end()
     }
+}
 
-    fn allowed_to(&self, permission: Permission) -> bool {
-        self.permissions.status(permission)
-    }
-    // pub fn offset(&self, address: usize) -> usize {
-    //     address - self.start
-    // }
+/// Regions that don't overlap, sorted by address so that lookups are a binary search
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct MemoryMap {
+    regions: Vec<Region>,
 }
 
-// code (0x0000_0000, 0x0000_1000)
-// data (0x0000_1000, 0x4000_0000)
-// heap (0x4000_0000, 0x8000_0000)
-// stack  (0x8000_0000, 0xFFFF_FFFF)
-pub struct Regions([Region; RegionType::VARIANT_COUNT]);
-
-impl Regions {
-    fn get(&self, address: u32) -> Option<&Region> {
-        let mut res: Option<&Region> = None;
-
-        for region in self.0.iter() {
-            println!("Region {:?} bounds: {:?}", region.ty, region.bounds);
-            if region.is_valid_address(address) {
-                res = Some(region);
-                break;
-            }
+impl MemoryMap {
+    /// Add `region` in place of the one with the same name. An empty region only removes it
+    pub fn insert(&mut self, region: Region) -> Result<(), MemoryError> {
+        if region.end() > 1 << 32 {
+            return Err(MemoryError::OutOfBounds(region.base));
+        }
+        let overlap = self
+            .regions
+            .iter()
+            .find(|other| other.name != region.name && other.overlaps(&region));
+        if let Some(other) = overlap {
+            return Err(MemoryError::RegionOverlap(region.name, other.name.clone()));
         }
 
-        res
-    }
-
-    fn reset(&mut self) {
-        for region in &mut self.0 {
-            region.reset();
+        self.remove(&region.name);
+        if region.size != 0 {
+            let index = self
+                .regions
+                .partition_point(|other| other.base < region.base);
+            self.regions.insert(index, region);
         }
+        Ok(())
     }
 
-    // fn valid(&self) -> i32 {
-    //     let f = 0xFFFFF000;
-    //     // 4294963200
-    //     // 4294967295
-    //     1
-    // }
-}
-
-impl Index<RegionType> for Regions {
-    type Output = Region;
+    pub fn remove(&mut self, name: &str) -> Option<Region> {
+        let index = self.regions.iter().position(|region| region.name == name)?;
+        Some(self.regions.remove(index))
+    }
 
-    fn index(&self, t: RegionType) -> &Self::Output {
-        &self.0[t as usize]
+    /// The region `address` falls in
+    pub fn get(&self, address: u32) -> Option<&Region> {
+        let after = self
+            .regions
+            .partition_point(|region| region.base <= address);
+        self.regions[..after]
+            .last()
+            .filter(|region| region.contains(address))
     }
-}
 
-impl IndexMut<RegionType> for Regions {
-    fn index_mut(&mut self, t: RegionType) -> &mut Self::Output {
-        &mut self.0[t as usize]
+    pub fn region(&self, name: &str) -> Option<&Region> {
+        self.regions.iter().find(|region| region.name == name)
     }
-}
 
-impl Default for Regions {
-    fn default() -> Self {
-        Self([
-            Region::new(
-                Permissions::default(),
-                RegionBounds::default(),
-                RegionType::Code,
-            ), //(0x0000_0000, 0x0000_1000)
-            Region::new(
-                Permissions::default(),
-                RegionBounds::default(),
-                RegionType::Data,
-            ), //(0x0000_1000, 0x4000_0000)
-            Region::new(
-                Permissions::new(&[Permission::R, Permission::W]),
-                RegionBounds::default(),
-                RegionType::Heap,
-            ), //(0x4000_0000, 0x8000_0000)
-            Region::new(
-                Permissions::new(&[Permission::R, Permission::W]),
-                RegionBounds::default(),
-                RegionType::Stack,
-            ), //(0x8000_0000, 0xFFFF_FFFF)
-        ])
+    /// By address
+    pub fn regions(&self) -> &[Region] {
+        &self.regions
     }
 }
 
+/// Size of the memory and its layout. The loaders add the `code`, `rodata` and `data` regions, the
+/// heap follows them and the stack ends the memory. More regions can be declared with
+/// [`MemoryConfiguration::region`], e.g. a scratchpad above the heap's reach
+#[derive(Debug, Clone)]
 pub struct MemoryConfiguration {
     allocated_memory: u32,
-    stack_size: u32,
+    map: MemoryMap,
 }
 
 impl MemoryConfiguration {
-    pub fn new(allocated_memory: u32) -> MemoryConfiguration {
-        let stack_size = RegionType::default_stack_size();
-        assert!(
-            stack_size <= allocated_memory,
-            "Stack size {} can't be more than the memory size and vice versa",
-            stack_size
-        );
+    pub const DEFAULT_STACK_SIZE: u32 = 2048;
+
+    /// `allocated_memory` bytes ending with a stack of [`MemoryConfiguration::DEFAULT_STACK_SIZE`]
+    pub fn new(allocated_memory: u32) -> Result<MemoryConfiguration, MemoryError> {
+        Self::with_stack_size(allocated_memory, Self::DEFAULT_STACK_SIZE)
+    }
 
-        MemoryConfiguration {
+    /// `allocated_memory` bytes ending with a stack of `stack_size`, which must fit. The memory
+    /// can't be empty, `sp` starts at its last byte
+    pub fn with_stack_size(
+        allocated_memory: u32,
+        stack_size: u32,
+    ) -> Result<MemoryConfiguration, MemoryError> {
+        if allocated_memory == 0 {
+            return Err(MemoryError::OutOfMemory(0));
+        }
+
+        let mut configuration = MemoryConfiguration {
             allocated_memory,
-            stack_size,
+            map: MemoryMap::default(),
+        };
+        configuration.set_stack_size(stack_size)?;
+        Ok(configuration)
+    }
+
+    /// The stack can't overlap the declared regions
+    pub fn set_stack_size(&mut self, size: u32) -> Result<&mut Self, MemoryError> {
+        let base = self
+            .allocated_memory
+            .checked_sub(size)
+            .ok_or(MemoryError::OutOfMemory(self.allocated_memory))?;
+        let stack = Region::new(
+            Region::STACK,
+            base,
+            size,
+            Permissions::new(&[Permission::R, Permission::W]),
+        );
+        self.map.insert(stack)?;
+        Ok(self)
+    }
+
+    /// Declare the region `name`. It must fit in memory and can't overlap the other regions
+    pub fn region(
+        &mut self,
+        name: impl Into<String>,
+        base: u32,
+        size: u32,
+        permissions: Permissions,
+    ) -> Result<&mut Self, MemoryError> {
+        let region = Region::new(name, base, size, permissions);
+        if Region::RESERVED.contains(&region.name.as_str()) {
+            return Err(MemoryError::ReservedRegion(region.name));
+        }
+        if region.end() > self.allocated_memory as u64 {
+            return Err(MemoryError::OutOfMemory(self.allocated_memory));
         }
+        self.map.insert(region)?;
+        Ok(self)
     }
 
-    pub fn set_stack_size(mut self, size: u32) {
-        self.stack_size = size;
+    pub fn allocated_memory(&self) -> u32 {
+        self.allocated_memory
+    }
+
+    /// The stack and the declared regions
+    pub fn memory_map(&self) -> &MemoryMap {
+        &self.map
     }
 }
 
@@ -605,7 +901,7 @@ mod tests {
 
     #[test]
     fn t_memory_error() {
-        let mut memnager = MemoryManager::new(&MemoryConfiguration::new(1024 * 1024));
+        let mut memnager = MemoryManager::new(&MemoryConfiguration::new(1024 * 1024).unwrap());
         let mut err = memnager.write(0x8000, 44u32).err();
         assert_eq!(Some(MemoryError::InvalidAddress(0x8000)), err);
 
@@ -615,9 +911,206 @@ mod tests {
         #[rustfmt::skip]
         assert_eq!(Some(MemoryError::UnalignedAccess(0x7FFF, std::mem::size_of::<u32>())), err);
 
+        memnager.load_program(&[0; 4]).unwrap();
         err = memnager.write(0x0, 44u32).err();
         assert_eq!(Some(MemoryError::PermissionDenied(Permission::W, 0x0)), err);
 
         println!("{:?}", err);
     }
+
+    #[test]
+    fn t_load_segments() {
+        let mut memory = MemoryManager::new(&MemoryConfiguration::new(1024 * 1024).unwrap());
+        let segment = |address, data: &[u8], permissions: &[Permission]| Segment {
+            address,
+            size: data.len() as u32,
+            data: data.to_vec(),
+            permissions: permissions.to_vec(),
+        };
+        // A linker script layout: code and constants in ROM at 0x100, data in RAM at 0x8000
+        memory
+            .load_segments(&[
+                segment(0x100, &[1, 0, 0, 0], &[Permission::R, Permission::X]),
+                segment(0x110, &[2, 0, 0, 0], &[Permission::R]),
+                segment(0x8000, &[3, 0, 0, 0], &[Permission::R, Permission::W]),
+            ])
+            .unwrap();
+
+        assert_eq!(memory.fetch(0x100), Ok(1));
+        assert_eq!(memory.read::<u32>(0x110), Ok(2));
+        assert_eq!(
+            memory.write(0x110, 0u32),
+            Err(MemoryError::PermissionDenied(Permission::W, 0x110))
+        );
+        assert_eq!(
+            memory.fetch(0x110),
+            Err(MemoryError::PermissionDenied(Permission::X, 0x110))
+        );
+        assert_eq!(memory.write(0x8000, 4u32), Ok(()));
+        assert_eq!(memory.read::<u32>(0x8000), Ok(4));
+        // Nothing is mapped between ROM and RAM
+        assert_eq!(
+            memory.read::<u32>(0x200),
+            Err(MemoryError::InvalidAddress(0x200))
+        );
+        assert_eq!(memory.program_break(), 0x8004);
+
+        // Empty segments take no room, wherever they are
+        let empty = Segment {
+            address: 0xffff_0000,
+            size: 0,
+            data: Vec::new(),
+            permissions: vec![Permission::R],
+        };
+        let code = segment(0, &[5, 0, 0, 0], &[Permission::R, Permission::X]);
+        memory.load_segments(&[code.clone(), empty]).unwrap();
+        assert_eq!(memory.fetch(0), Ok(5));
+
+        // A program that doesn't fit leaves the previous one in place
+        let past_end = segment(0x10_0000, &[6, 0, 0, 0], &[Permission::R, Permission::W]);
+        assert_eq!(
+            memory.load_segments(&[segment(0x100, &[7, 0, 0, 0], &[Permission::R]), past_end]),
+            Err(MemoryError::OutOfMemory(1024 * 1024))
+        );
+        assert_eq!(memory.fetch(0), Ok(5));
+        assert_eq!(
+            memory.read::<u32>(0x100),
+            Err(MemoryError::InvalidAddress(0x100))
+        );
+    }
+
+    #[test]
+    fn t_memory_below_default_stack() {
+        assert_eq!(
+            MemoryConfiguration::new(1024).unwrap_err(),
+            MemoryError::OutOfMemory(1024)
+        );
+        assert_eq!(
+            MemoryConfiguration::with_stack_size(1024, 2048).unwrap_err(),
+            MemoryError::OutOfMemory(1024)
+        );
+
+        assert_eq!(
+            MemoryConfiguration::with_stack_size(0, 0).unwrap_err(),
+            MemoryError::OutOfMemory(0)
+        );
+
+        let configuration = MemoryConfiguration::with_stack_size(1024, 512).unwrap();
+        let memory = MemoryManager::new(&configuration);
+        assert_eq!(memory.stack_start(), 1023);
+        assert_eq!(
+            memory
+                .memory_map()
+                .region(Region::STACK)
+                .map(|stack| stack.base),
+            Some(512)
+        );
+    }
+
+    #[test]
+    fn t_memory_map() {
+        let rw = Permissions::new(&[Permission::R, Permission::W]);
+        let mut configuration = MemoryConfiguration::new(1024 * 1024).unwrap();
+        configuration
+            .set_stack_size(0x1000)
+            .unwrap()
+            .region("scratch", 0x8_0000, 0x100, rw)
+            .unwrap()
+            .region("rom", 0x9_0000, 0x100, Permissions::new(&[Permission::R]))
+            .unwrap();
+        assert_eq!(
+            configuration
+                .region("overlap", 0x8_00f0, 0x20, rw)
+                .unwrap_err(),
+            MemoryError::RegionOverlap("overlap".into(), "scratch".into())
+        );
+        assert_eq!(
+            configuration.region("past", 0xf_ff00, 0x200, rw).unwrap_err(),
+            MemoryError::OutOfMemory(1024 * 1024)
+        );
+        assert_eq!(
+            configuration.region(Region::HEAP, 0, 4, rw).unwrap_err(),
+            MemoryError::ReservedRegion("heap".into())
+        );
+        assert!(configuration.set_stack_size(0x8_0000).is_err());
+
+        let mut memory = MemoryManager::new(&configuration);
+        memory.load_program(&[1, 0, 0, 0]).unwrap();
+        let names: Vec<_> = memory
+            .memory_map()
+            .regions()
+            .iter()
+            .map(|region| (region.name.as_str(), region.permissions.to_string()))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                ("code", "r-x".to_owned()),
+                ("scratch", "rw-".to_owned()),
+                ("rom", "r--".to_owned()),
+                ("stack", "rw-".to_owned())
+            ]
+        );
+        assert_eq!(memory.stack_start(), 1024 * 1024 - 1);
+        assert_eq!(
+            memory.memory_map().get(0x8_00ff).map(|region| &*region.name),
+            Some("scratch")
+        );
+        assert_eq!(memory.memory_map().get(0x8_0100), None);
+
+        assert_eq!(memory.write(0x8_0000, 7u32), Ok(()));
+        assert_eq!(
+            memory.write(0x9_0000, 7u32),
+            Err(MemoryError::PermissionDenied(Permission::W, 0x9_0000))
+        );
+        // The heap grows up to the next region
+        assert_eq!(memory.set_program_break(0x8_0000), Ok(()));
+        assert_eq!(memory.write(0x7_fffc, 1u32), Ok(()));
+        assert!(matches!(
+            memory.set_program_break(0x8_0004),
+            Err(MemoryError::RegionOverlap(..))
+        ));
+
+        memory.reset();
+        assert_eq!(memory.memory_map(), configuration.memory_map());
+    }
+
+    #[test]
+    fn t_watchpoints() {
+        let mut memory = MemoryManager::new(&MemoryConfiguration::new(1024 * 1024).unwrap());
+        let stack = memory.stack_start() - 15;
+        memory.add_watchpoint(stack + 4, 2, WatchKind::Write);
+        memory.add_watchpoint(stack + 8, 4, WatchKind::Read);
+
+        memory.write(stack, 1u32).unwrap();
+        memory.read::<u32>(stack + 4).unwrap();
+        assert_eq!(memory.take_watch_hit(), None);
+
+        // The word overlaps the watched half word
+        memory.write(stack + 2, 0x0102_0304u32).unwrap();
+        let hit = memory.take_watch_hit().unwrap();
+        assert_eq!((hit.address, hit.access), (stack + 2, Permission::W));
+        assert_eq!(hit.watchpoint.address, stack + 4);
+        assert_eq!(memory.take_watch_hit(), None);
+
+        memory.read::<u8>(stack + 11).unwrap();
+        assert_eq!(
+            memory.take_watch_hit().map(|hit| hit.access),
+            Some(Permission::R)
+        );
+
+        // Debugger reads go around watchpoints and permissions
+        assert_eq!(memory.inspect(stack + 2, 4), Ok(&[4, 3, 2, 1][..]));
+        assert_eq!(memory.take_watch_hit(), None);
+        assert_eq!(memory.inspect(0, 4), Ok(&[0, 0, 0, 0][..]));
+        memory.patch(0, &[1, 2]).unwrap();
+        assert_eq!(memory.inspect(0, 4), Ok(&[1, 2, 0, 0][..]));
+        assert_eq!(memory.take_watch_hit(), None);
+        assert!(memory.patch(1024 * 1024 - 1, &[0, 0]).is_err());
+
+        assert!(memory.remove_watchpoint(stack + 4));
+        assert!(!memory.remove_watchpoint(stack + 4));
+        memory.write(stack + 4, 0u8).unwrap();
+        assert_eq!(memory.take_watch_hit(), None);
+    }
 }
@@ -0,0 +1,529 @@
+use std::{
+    collections::BTreeMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use isa::Register;
+use thiserror::Error;
+
+use crate::{
+    cpu::{TrapState, register::Registers},
+    io::HostIo,
+    memory::{MemoryError, MemoryManager, Permission},
+};
+
+/// Holds the syscall number
+pub const NUMBER_REGISTER: Register = Register::X20;
+/// `a0..a7`
+pub const ARGUMENT_REGISTERS: [Register; 8] = [
+    Register::X10,
+    Register::X11,
+    Register::X12,
+    Register::X13,
+    Register::X14,
+    Register::X15,
+    Register::X16,
+    Register::X17,
+];
+/// `a0`
+pub const RETURN_REGISTER: Register = Register::X10;
+
+/// Value returned in `a0` when a syscall fails
+pub const FAILURE: u32 = -1i32 as u32;
+
+#[derive(Debug, Error)]
+pub enum SyscallError {
+    #[error("Unknown syscall: `{0}`")]
+    UnknownSyscall(u32),
+    /// The `access` of guest memory failed
+    #[error("{error}")]
+    MemoryAccess {
+        access: Permission,
+        error: MemoryError,
+    },
+}
+
+/// Built-in syscalls
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyscallNumber {
+    /// `exit(code)`
+    Exit = 0,
+    /// `write(fd, buf, len) -> written`
+    Write = 1,
+    /// `read(fd, buf, len) -> read`
+    Read = 2,
+    /// `brk(address) -> break`. `address == 0` queries the current break
+    Brk = 3,
+    /// `sbrk(increment) -> old break`
+    Sbrk = 4,
+    /// `time() -> (a0: low, a1: high)` milliseconds since the unix epoch
+    Time = 5,
+    /// `trap_vector(address) -> previous` sends traps to the handler at `address`, 0 lets them
+    /// stop the VM
+    TrapVector = 6,
+    /// `trap_info() -> (a0: cause, a1: value, a2: pc)` describes the last trap taken
+    TrapInfo = 7,
+}
+
+impl From<SyscallNumber> for u32 {
+    fn from(value: SyscallNumber) -> Self {
+        value as u32
+    }
+}
+
+/// What the VM does once the syscall is handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyscallOutcome {
+    /// Write the value into `a0` and continue
+    Return(u32),
+    /// Halt the VM with the exit code
+    Exit(u32),
+}
+
+/// The machine state a syscall is allowed to touch
+pub struct SyscallContext<'a> {
+    pub registers: &'a mut Registers,
+    pub memory: &'a mut MemoryManager,
+    pub io: &'a mut dyn HostIo,
+    pub trap: &'a mut TrapState,
+}
+
+impl SyscallContext<'_> {
+    /// The value of `a{index}`
+    pub fn arg(&self, index: usize) -> u32 {
+        self.registers.get(ARGUMENT_REGISTERS[index])
+    }
+
+    /// Copy `len` bytes of guest memory starting at `address`
+    pub fn read_bytes(&self, address: u32, len: u32) -> Result<Vec<u8>, SyscallError> {
+        (0..len)
+            .map(|i| self.memory.read::<u8>(address.wrapping_add(i)))
+            .collect::<Result<_, _>>()
+            .map_err(|error| SyscallError::MemoryAccess {
+                access: Permission::R,
+                error,
+            })
+    }
+
+    /// Copy `bytes` into guest memory starting at `address`
+    pub fn write_bytes(&mut self, address: u32, bytes: &[u8]) -> Result<(), SyscallError> {
+        for (i, byte) in bytes.iter().enumerate() {
+            self.memory
+                .write(address.wrapping_add(i as u32), *byte)
+                .map_err(|error| SyscallError::MemoryAccess {
+                    access: Permission::W,
+                    error,
+                })?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A host function callable from the guest through `syscall`
+pub trait SyscallHandler {
+    fn handle(&mut self, context: &mut SyscallContext) -> Result<SyscallOutcome, SyscallError>;
+}
+
+impl<F> SyscallHandler for F
+where
+    F: FnMut(&mut SyscallContext) -> Result<SyscallOutcome, SyscallError>,
+{
+    fn handle(&mut self, context: &mut SyscallContext) -> Result<SyscallOutcome, SyscallError> {
+        self(context)
+    }
+}
+
+/// Syscall table keyed by the syscall number
+pub struct Syscalls {
+    handlers: BTreeMap<u32, Box<dyn SyscallHandler>>,
+}
+
+impl Syscalls {
+    /// Table without any handler
+    pub fn empty() -> Syscalls {
+        Syscalls {
+            handlers: BTreeMap::new(),
+        }
+    }
+
+    /// Register `handler` under `number`. Replaces the previous handler, built-ins included
+    pub fn register(&mut self, number: impl Into<u32>, handler: impl SyscallHandler + 'static) {
+        self.handlers.insert(number.into(), Box::new(handler));
+    }
+
+    pub fn dispatch(
+        &mut self,
+        number: u32,
+        context: &mut SyscallContext,
+    ) -> Result<SyscallOutcome, SyscallError> {
+        let Some(handler) = self.handlers.get_mut(&number) else {
+            return Err(SyscallError::UnknownSyscall(number));
+        };
+
+        handler.handle(context)
+    }
+}
+
+impl Default for Syscalls {
+    fn default() -> Self {
+        let mut syscalls = Self::empty();
+        syscalls.register(SyscallNumber::Exit, Exit);
+        syscalls.register(SyscallNumber::Write, Write);
+        syscalls.register(SyscallNumber::Read, Read);
+        syscalls.register(SyscallNumber::Brk, Brk);
+        syscalls.register(SyscallNumber::Sbrk, Sbrk);
+        syscalls.register(SyscallNumber::Time, Time);
+        syscalls.register(SyscallNumber::TrapVector, TrapVector);
+        syscalls.register(SyscallNumber::TrapInfo, TrapInfo);
+        syscalls
+    }
+}
+
+pub struct Exit;
+
+impl SyscallHandler for Exit {
+    fn handle(&mut self, context: &mut SyscallContext) -> Result<SyscallOutcome, SyscallError> {
+        Ok(SyscallOutcome::Exit(context.arg(0)))
+    }
+}
+
+pub struct Write;
+
+impl SyscallHandler for Write {
+    fn handle(&mut self, context: &mut SyscallContext) -> Result<SyscallOutcome, SyscallError> {
+        let (fd, buf, len) = (context.arg(0), context.arg(1), context.arg(2));
+        let bytes = context.read_bytes(buf, len)?;
+
+        let written = match fd {
+            1 => context.io.stdout().write(&bytes),
+            2 => context.io.stderr().write(&bytes),
+            _ => return Ok(SyscallOutcome::Return(FAILURE)),
+        };
+
+        Ok(SyscallOutcome::Return(
+            written.map_or(FAILURE, |written| written as u32),
+        ))
+    }
+}
+
+pub struct Read;
+
+impl Read {
+    /// Bytes read from the host at once. `len` comes from the guest, it can't size the buffer
+    const CHUNK: usize = 4096;
+}
+
+impl SyscallHandler for Read {
+    fn handle(&mut self, context: &mut SyscallContext) -> Result<SyscallOutcome, SyscallError> {
+        let (fd, buf, len) = (context.arg(0), context.arg(1), context.arg(2));
+        if fd != 0 {
+            return Ok(SyscallOutcome::Return(FAILURE));
+        }
+
+        let mut chunk = [0; Self::CHUNK];
+        let mut total = 0;
+        while total < len {
+            let wanted = Self::CHUNK.min((len - total) as usize);
+            let read = match context.io.stdin().read(&mut chunk[..wanted]) {
+                Ok(read) => read,
+                // What was already copied stays read
+                Err(_) if total > 0 => break,
+                Err(_) => return Ok(SyscallOutcome::Return(FAILURE)),
+            };
+            context.write_bytes(buf.wrapping_add(total), &chunk[..read])?;
+            total += read as u32;
+            // Don't block for more input than the host had ready
+            if read < wanted {
+                break;
+            }
+        }
+
+        Ok(SyscallOutcome::Return(total))
+    }
+}
+
+pub struct Brk;
+
+impl SyscallHandler for Brk {
+    fn handle(&mut self, context: &mut SyscallContext) -> Result<SyscallOutcome, SyscallError> {
+        let address = context.arg(0);
+        if address != 0 {
+            // On failure the break stays where it was, same as linux
+            let _ = context.memory.set_program_break(address);
+        }
+
+        Ok(SyscallOutcome::Return(context.memory.program_break()))
+    }
+}
+
+pub struct Sbrk;
+
+impl SyscallHandler for Sbrk {
+    fn handle(&mut self, context: &mut SyscallContext) -> Result<SyscallOutcome, SyscallError> {
+        let increment = context.arg(0) as i32;
+        let old_break = context.memory.program_break();
+
+        let Some(new_break) = old_break.checked_add_signed(increment) else {
+            return Ok(SyscallOutcome::Return(FAILURE));
+        };
+
+        match context.memory.set_program_break(new_break) {
+            Ok(_) => Ok(SyscallOutcome::Return(old_break)),
+            Err(_) => Ok(SyscallOutcome::Return(FAILURE)),
+        }
+    }
+}
+
+pub struct Time;
+
+impl SyscallHandler for Time {
+    fn handle(&mut self, context: &mut SyscallContext) -> Result<SyscallOutcome, SyscallError> {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_millis() as u64);
+
+        context
+            .registers
+            .set(ARGUMENT_REGISTERS[1], (millis >> 32) as u32);
+        Ok(SyscallOutcome::Return(millis as u32))
+    }
+}
+
+pub struct TrapVector;
+
+impl SyscallHandler for TrapVector {
+    fn handle(&mut self, context: &mut SyscallContext) -> Result<SyscallOutcome, SyscallError> {
+        let address = context.arg(0);
+        let previous = std::mem::replace(&mut context.trap.vector, address);
+        Ok(SyscallOutcome::Return(previous))
+    }
+}
+
+pub struct TrapInfo;
+
+impl SyscallHandler for TrapInfo {
+    fn handle(&mut self, context: &mut SyscallContext) -> Result<SyscallOutcome, SyscallError> {
+        let trap = *context.trap;
+        context.registers.set(ARGUMENT_REGISTERS[1], trap.value);
+        context.registers.set(ARGUMENT_REGISTERS[2], trap.pc);
+        Ok(SyscallOutcome::Return(trap.cause))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use isa::{
+        Instruction::*,
+        operand::{Immediate14, Immediate19},
+    };
+
+    use super::*;
+    use crate::{
+        VM,
+        io::BufferIo,
+        memory::{MemoryConfiguration, Permission},
+        trap::Trap,
+    };
+
+    fn syscall() -> isa::Instruction {
+        Syscall {
+            src1: Register::X0,
+            src2: Register::X0,
+            src3: Register::X0,
+        }
+    }
+
+    fn li(dest: Register, value: i32) -> isa::Instruction {
+        AddI {
+            dest,
+            src: Register::X0,
+            value: Immediate14::new(value),
+        }
+    }
+
+    #[test]
+    fn t_exit_code() {
+        let mut vm = VM::new(MemoryConfiguration::new(1024 * 1024).unwrap());
+        let program = &[
+            li(Register::X10, 7),
+            li(NUMBER_REGISTER, SyscallNumber::Exit as i32),
+            syscall(),
+            li(Register::X10, 8),
+        ];
+
+        vm.test_run(program).unwrap();
+        assert_eq!(vm.exit_code(), Some(7));
+    }
+
+    #[test]
+    fn t_brk_sbrk() {
+        let mut vm = VM::new(MemoryConfiguration::new(1024 * 1024).unwrap());
+        let program = &[
+            // X5 = brk(0)
+            li(Register::X10, 0),
+            li(NUMBER_REGISTER, SyscallNumber::Brk as i32),
+            syscall(),
+            Add {
+                dest: Register::X5,
+                src1: Register::X10,
+                src2: Register::X0,
+            },
+            // X6 = sbrk(64)
+            li(Register::X10, 64),
+            li(NUMBER_REGISTER, SyscallNumber::Sbrk as i32),
+            syscall(),
+            Add {
+                dest: Register::X6,
+                src1: Register::X10,
+                src2: Register::X0,
+            },
+            // the heap is now usable
+            li(Register::X7, 99),
+            Sw {
+                src: Register::X7,
+                dest: Register::X6,
+                offset: Immediate14::new(60),
+            },
+            Lw {
+                dest: Register::X28,
+                src: Register::X6,
+                offset: Immediate14::new(60),
+            },
+            // X29 = brk(0)
+            li(Register::X10, 0),
+            li(NUMBER_REGISTER, SyscallNumber::Brk as i32),
+            syscall(),
+            Add {
+                dest: Register::X29,
+                src1: Register::X10,
+                src2: Register::X0,
+            },
+            li(Register::X10, 0),
+            li(NUMBER_REGISTER, SyscallNumber::Exit as i32),
+            syscall(),
+        ];
+
+        vm.test_run(program).unwrap();
+
+        let code_end = (program.len() * 4) as u32;
+        assert_eq!(vm.registers().get(Register::X5), code_end);
+        assert_eq!(vm.registers().get(Register::X6), code_end);
+        assert_eq!(vm.registers().get(Register::X28), 99);
+        assert_eq!(vm.registers().get(Register::X29), code_end + 64);
+    }
+
+    #[test]
+    fn t_write_bad_fd() {
+        let mut vm = VM::new(MemoryConfiguration::new(1024 * 1024).unwrap());
+        let program = &[
+            li(Register::X10, 9),
+            li(Register::X11, 0),
+            li(Register::X12, 4),
+            li(NUMBER_REGISTER, SyscallNumber::Write as i32),
+            syscall(),
+            Add {
+                dest: Register::X5,
+                src1: Register::X10,
+                src2: Register::X0,
+            },
+            li(NUMBER_REGISTER, SyscallNumber::Exit as i32),
+            syscall(),
+        ];
+
+        vm.test_run(program).unwrap();
+        assert_eq!(vm.registers().get(Register::X5), FAILURE);
+    }
+
+    #[test]
+    fn t_custom_handler() {
+        let mut vm = VM::new(MemoryConfiguration::new(1024 * 1024).unwrap());
+        vm.register_syscall(100u32, |context: &mut SyscallContext| {
+            Ok(SyscallOutcome::Return(context.arg(0) + context.arg(1)))
+        });
+
+        let program = &[
+            li(Register::X10, 40),
+            li(Register::X11, 2),
+            li(NUMBER_REGISTER, 100),
+            syscall(),
+            li(NUMBER_REGISTER, SyscallNumber::Exit as i32),
+            syscall(),
+        ];
+
+        vm.test_run(program).unwrap();
+        assert_eq!(vm.exit_code(), Some(42));
+    }
+
+    #[test]
+    fn t_read_len_from_guest() {
+        let read = |buf| {
+            [
+                li(Register::X10, 0),
+                buf,
+                // The whole address space
+                li(Register::X12, -1),
+                li(NUMBER_REGISTER, SyscallNumber::Read as i32),
+                syscall(),
+                li(NUMBER_REGISTER, SyscallNumber::Exit as i32),
+                syscall(),
+            ]
+        };
+
+        // Only what the host has is read
+        let mut vm = VM::new(MemoryConfiguration::new(1024 * 1024).unwrap());
+        vm.set_io(BufferIo::new("ping"));
+        let sp = AddI {
+            dest: Register::X11,
+            src: Register::X2,
+            value: Immediate14::new(-32),
+        };
+        vm.test_run(&read(sp)).unwrap();
+        assert_eq!(vm.exit_code(), Some(4));
+
+        // The code can't be written
+        let mut vm = VM::new(MemoryConfiguration::new(1024 * 1024).unwrap());
+        vm.set_io(BufferIo::new("ping"));
+        let err = vm.test_run(&read(li(Register::X11, 0))).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<Trap>(),
+            Some(&Trap::AccessFault {
+                pc: 16,
+                address: 0,
+                access: Permission::W
+            })
+        );
+
+        // Nothing is mapped past the memory, the failed access is still the syscall's store
+        let mut vm = VM::new(MemoryConfiguration::new(0x8_0000).unwrap());
+        vm.set_io(BufferIo::new("ping"));
+        let past = Lui {
+            dest: Register::X11,
+            value: Immediate19::new(0x8_0000 >> 13),
+        };
+        let err = vm.test_run(&read(past)).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<Trap>(),
+            Some(&Trap::AccessFault {
+                pc: 16,
+                address: 0x8_0000,
+                access: Permission::W
+            })
+        );
+    }
+
+    #[test]
+    fn t_unknown_syscall() {
+        let mut vm = VM::new(MemoryConfiguration::new(1024 * 1024).unwrap());
+        let program = &[li(NUMBER_REGISTER, 1000), syscall()];
+
+        let err = vm.test_run(program).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<Trap>(),
+            Some(&Trap::Ecall {
+                pc: 4,
+                number: 1000
+            })
+        );
+    }
+}
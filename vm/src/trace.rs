@@ -0,0 +1,437 @@
+//! Per-instruction execution traces. A [`TraceSink`] handed to [`crate::VM::set_trace`] receives a
+//! [`TraceRecord`] after every executed instruction. Without a sink the VM doesn't build records
+//! nor log memory accesses
+use std::io::{self, Write};
+
+use isa::{Instruction, Register};
+
+use crate::memory::{MemoryAccess, Permission};
+
+/// A register changed by an instruction, with its new value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegisterWrite {
+    pub register: Register,
+    pub value: u32,
+}
+
+/// Everything one instruction did
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceRecord {
+    /// Address of the instruction
+    pub pc: u32,
+    /// The instruction as fetched
+    pub word: u32,
+    pub instruction: Instruction,
+    /// Registers whose value changed, in register order
+    pub registers: Vec<RegisterWrite>,
+    /// Loads and stores, in program order. Includes the ones made by syscalls
+    pub memory: Vec<MemoryAccess>,
+}
+
+/// Destination of the trace records
+pub trait TraceSink {
+    fn record(&mut self, record: &TraceRecord) -> io::Result<()>;
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn access_name(access: Permission) -> &'static str {
+    match access {
+        Permission::R => "read",
+        Permission::W => "write",
+        Permission::X => "execute",
+    }
+}
+
+/// One human readable line per instruction:
+///
+/// `00000104  0000a50b  sw x10, 0(x2)        [0x000ffff0] <- 0x0000002a`
+pub struct TextTrace<W: Write> {
+    output: W,
+}
+
+impl<W: Write> TextTrace<W> {
+    pub fn new(output: W) -> Self {
+        Self { output }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.output
+    }
+}
+
+impl<W: Write> TraceSink for TextTrace<W> {
+    fn record(&mut self, record: &TraceRecord) -> io::Result<()> {
+        let mut line = format!(
+            "{:08x}  {:08x}  {:<20}",
+            record.pc,
+            record.word,
+            record.instruction.to_string()
+        );
+        for write in &record.registers {
+            line += &format!(" x{}={:#010x}", write.register as u8, write.value);
+        }
+        for access in &record.memory {
+            let arrow = match access.access {
+                Permission::W => "<-",
+                _ => "->",
+            };
+            line += &format!(
+                " [{:#010x}] {arrow} {:#0width$x}",
+                access.address,
+                access.value,
+                width = 2 + 2 * access.size as usize
+            );
+        }
+        writeln!(self.output, "{}", line.trim_end())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
+
+/// One JSON object per line:
+///
+/// `{"pc":260,"word":42251,"instruction":"sw x10, 0(x2)","registers":[],"memory":[{"access":"write","address":1048560,"size":4,"value":42}]}`
+pub struct JsonTrace<W: Write> {
+    output: W,
+}
+
+impl<W: Write> JsonTrace<W> {
+    pub fn new(output: W) -> Self {
+        Self { output }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.output
+    }
+}
+
+/// `text` as a JSON string
+fn json_string(text: &str) -> String {
+    let mut json = String::from('"');
+    for c in text.chars() {
+        match c {
+            '"' => json += "\\\"",
+            '\\' => json += "\\\\",
+            c if c.is_control() => json += &format!("\\u{:04x}", c as u32),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+impl<W: Write> TraceSink for JsonTrace<W> {
+    fn record(&mut self, record: &TraceRecord) -> io::Result<()> {
+        let registers: Vec<_> = record
+            .registers
+            .iter()
+            .map(|write| {
+                format!(
+                    r#"{{"register":"x{}","value":{}}}"#,
+                    write.register as u8, write.value
+                )
+            })
+            .collect();
+        let memory: Vec<_> = record
+            .memory
+            .iter()
+            .map(|access| {
+                format!(
+                    r#"{{"access":"{}","address":{},"size":{},"value":{}}}"#,
+                    access_name(access.access),
+                    access.address,
+                    access.size,
+                    access.value
+                )
+            })
+            .collect();
+        writeln!(
+            self.output,
+            r#"{{"pc":{},"word":{},"instruction":{},"registers":[{}],"memory":[{}]}}"#,
+            record.pc,
+            record.word,
+            json_string(&record.instruction.to_string()),
+            registers.join(","),
+            memory.join(",")
+        )
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
+
+/// Compact little-endian records, the instruction is left to be decoded from `word`:
+///
+/// - `pc: u32`, `word: u32`, register writes `: u32`, memory accesses `: u32`
+/// - per register write: `register: u8`, `value: u32`
+/// - per memory access: `access: u8` (0 read, 1 write), `size: u8`, `address: u32`, `value: u32`
+pub struct BinaryTrace<W: Write> {
+    output: W,
+}
+
+impl<W: Write> BinaryTrace<W> {
+    pub fn new(output: W) -> Self {
+        Self { output }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.output
+    }
+}
+
+impl<W: Write> TraceSink for BinaryTrace<W> {
+    fn record(&mut self, record: &TraceRecord) -> io::Result<()> {
+        let mut bytes =
+            Vec::with_capacity(16 + 5 * record.registers.len() + 10 * record.memory.len());
+        bytes.extend(record.pc.to_le_bytes());
+        bytes.extend(record.word.to_le_bytes());
+        // Syscalls moving buffers make an access per byte, more than a `u16` counts
+        bytes.extend((record.registers.len() as u32).to_le_bytes());
+        bytes.extend((record.memory.len() as u32).to_le_bytes());
+        for write in &record.registers {
+            bytes.push(write.register as u8);
+            bytes.extend(write.value.to_le_bytes());
+        }
+        for access in &record.memory {
+            bytes.push((access.access == Permission::W) as u8);
+            bytes.push(access.size);
+            bytes.extend(access.address.to_le_bytes());
+            bytes.extend(access.value.to_le_bytes());
+        }
+        self.output.write_all(&bytes)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{cell::RefCell, rc::Rc};
+
+    use isa::{
+        Instruction::*,
+        operand::{Immediate14, Immediate19},
+    };
+
+    use super::*;
+    use crate::{
+        VM,
+        io::BufferIo,
+        memory::{MemoryConfiguration, Permissions},
+        syscall::NUMBER_REGISTER,
+    };
+
+    #[derive(Default, Clone)]
+    struct Records(Rc<RefCell<Vec<TraceRecord>>>);
+
+    impl TraceSink for Records {
+        fn record(&mut self, record: &TraceRecord) -> io::Result<()> {
+            self.0.borrow_mut().push(record.clone());
+            Ok(())
+        }
+    }
+
+    fn li(dest: Register, value: i32) -> Instruction {
+        AddI {
+            dest,
+            src: Register::X0,
+            value: Immediate14::new(value),
+        }
+    }
+
+    #[test]
+    fn t_trace() {
+        let mut vm = VM::new(MemoryConfiguration::new(1024 * 1024).unwrap());
+        let records = Records::default();
+        vm.set_trace(records.clone());
+        let program = [
+            li(Register::X10, 0x2a),
+            // `sp` points at the last byte of memory
+            Sw {
+                dest: Register::X2,
+                src: Register::X10,
+                offset: Immediate14::new(-7),
+            },
+            Lb {
+                dest: Register::X11,
+                src: Register::X2,
+                offset: Immediate14::new(-7),
+            },
+            // Jumps over itself
+            Jal {
+                dest: Register::X0,
+                offset: Immediate19::new(4),
+            },
+            li(NUMBER_REGISTER, 0),
+            Syscall {
+                src1: Register::X0,
+                src2: Register::X0,
+                src3: Register::X0,
+            },
+        ];
+        vm.test_run(&program).unwrap();
+        assert_eq!(vm.exit_code(), Some(0x2a));
+
+        let records = records.0.take();
+        let sp = vm.memory().stack_start();
+        assert_eq!(records.len(), program.len());
+        assert_eq!(
+            records.iter().map(|record| record.pc).collect::<Vec<_>>(),
+            [0, 4, 8, 12, 16, 20]
+        );
+        assert_eq!(records[0].word, u32::from(&program[0]));
+        assert_eq!(records[0].instruction, program[0]);
+        assert_eq!(
+            records[0].registers,
+            [RegisterWrite {
+                register: Register::X10,
+                value: 0x2a
+            }]
+        );
+        let store = MemoryAccess {
+            address: sp - 7,
+            size: 4,
+            value: 0x2a,
+            access: Permission::W,
+        };
+        assert_eq!(
+            (
+                records[1].registers.as_slice(),
+                records[1].memory.as_slice()
+            ),
+            (&[][..], &[store][..])
+        );
+        let load = MemoryAccess {
+            size: 1,
+            access: Permission::R,
+            ..store
+        };
+        assert_eq!(records[2].memory, [load]);
+        assert!(records[3].registers.is_empty() && records[3].memory.is_empty());
+
+        let record = TraceRecord {
+            registers: vec![RegisterWrite {
+                register: Register::X11,
+                value: 0x2a,
+            }],
+            ..records[2].clone()
+        };
+
+        let mut text = TextTrace::new(Vec::new());
+        text.record(&record).unwrap();
+        assert_eq!(
+            String::from_utf8(text.into_inner()).unwrap(),
+            format!(
+                "00000008  {:08x}  lb x11, -7(x2)       x11=0x0000002a [{:#010x}] -> 0x2a\n",
+                record.word,
+                sp - 7
+            )
+        );
+
+        let mut json = JsonTrace::new(Vec::new());
+        json.record(&record).unwrap();
+        assert_eq!(
+            String::from_utf8(json.into_inner()).unwrap(),
+            format!(
+                r#"{{"pc":8,"word":{},"instruction":"lb x11, -7(x2)","registers":[{{"register":"x11","value":42}}],"memory":[{{"access":"read","address":{},"size":1,"value":42}}]}}"#,
+                record.word,
+                sp - 7
+            ) + "\n"
+        );
+
+        let mut binary = BinaryTrace::new(Vec::new());
+        binary.record(&record).unwrap();
+        let mut expected = vec![8, 0, 0, 0];
+        expected.extend(record.word.to_le_bytes());
+        expected.extend([1, 0, 0, 0, 1, 0, 0, 0, 11, 0x2a, 0, 0, 0, 0, 1]);
+        expected.extend((sp - 7).to_le_bytes());
+        expected.extend([0x2a, 0, 0, 0]);
+        assert_eq!(binary.into_inner(), expected);
+
+        // Once the sink is gone, nothing is recorded
+        assert!(vm.take_trace().is_some());
+        vm.memory().read::<u32>(sp - 7).unwrap();
+        assert!(vm.memory().take_accesses().is_empty());
+    }
+
+    /// Keeps what the sink writes once the VM owns it
+    #[derive(Default, Clone)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn t_binary_trace_large_syscall() {
+        let rw = Permissions::new(&[Permission::R, Permission::W]);
+        let mut configuration = MemoryConfiguration::new(1024 * 1024).unwrap();
+        configuration
+            .region("buffer", 0x8_0000, 0x2_0000, rw)
+            .unwrap();
+        let mut vm = VM::new(configuration);
+        vm.set_io(BufferIo::default());
+        let output = Shared::default();
+        vm.set_trace(BinaryTrace::new(output.clone()));
+
+        // write(1, buffer, 70000) reads a byte at a time
+        let len = 70_000;
+        let program = [
+            li(Register::X10, 1),
+            Lui {
+                dest: Register::X11,
+                value: Immediate19::new(0x8_0000 >> 13),
+            },
+            Lui {
+                dest: Register::X12,
+                value: Immediate19::new(len >> 13),
+            },
+            AddI {
+                dest: Register::X12,
+                src: Register::X12,
+                value: Immediate14::new(len & 0x1fff),
+            },
+            li(NUMBER_REGISTER, 1),
+            Syscall {
+                src1: Register::X0,
+                src2: Register::X0,
+                src3: Register::X0,
+            },
+            li(NUMBER_REGISTER, 0),
+            Syscall {
+                src1: Register::X0,
+                src2: Register::X0,
+                src3: Register::X0,
+            },
+        ];
+        vm.test_run(&program).unwrap();
+
+        // The sink kept up, the write's record holds every access
+        assert!(vm.take_trace().is_some());
+        let bytes = output.0.take();
+        let mut records = Vec::new();
+        let mut rest = &bytes[..];
+        while !rest.is_empty() {
+            let field = |at: usize| u32::from_le_bytes(rest[at..at + 4].try_into().unwrap());
+            let (pc, registers, memory) = (field(0), field(8), field(12));
+            records.push((pc, memory));
+            rest = &rest[16 + 5 * registers as usize + 10 * memory as usize..];
+        }
+        assert_eq!(records.len(), program.len());
+        assert_eq!(records[5], (20, len as u32));
+    }
+}
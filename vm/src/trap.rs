@@ -0,0 +1,232 @@
+//! Synchronous exceptions raised by the instruction at `pc`. A trap either transfers control to the
+//! guest's trap vector, the `tvec` CSR also set by [`crate::syscall::SyscallNumber::TrapVector`], or
+//! stops the VM and is reported by [`crate::VM::run`]. The handler finds the trap in the `cause`,
+//! `tval` and `epc` CSRs
+use thiserror::Error;
+
+use crate::memory::{MemoryError, Permission};
+
+/// What went wrong, where. The VM is left with `pc` at the faulting instruction
+#[derive(Debug, Error, Clone, Copy, PartialEq, Eq)]
+pub enum Trap {
+    /// The word at `pc` doesn't decode to an instruction
+    #[error("illegal instruction `{word:#010x}` at pc `{pc:#010x}`")]
+    IllegalInstruction { pc: u32, word: u32 },
+    /// A `access` of `address` that isn't aligned on the access size
+    #[error("misaligned `{access}` of `{address:#010x}` at pc `{pc:#010x}`")]
+    MisalignedAccess {
+        pc: u32,
+        address: u32,
+        access: Permission,
+    },
+    /// A `access` of `address` that isn't mapped or isn't allowed
+    #[error("`{access}` access fault on `{address:#010x}` at pc `{pc:#010x}`")]
+    AccessFault {
+        pc: u32,
+        address: u32,
+        access: Permission,
+    },
+    /// A syscall without a host handler, left to the guest
+    #[error("unhandled syscall `{number}` at pc `{pc:#010x}`")]
+    Ecall { pc: u32, number: u32 },
+    /// An `ebreak` instruction
+    #[error("breakpoint at pc `{pc:#010x}`")]
+    Breakpoint { pc: u32 },
+}
+
+impl Trap {
+    /// `error` raised by a `access` of the instruction at `pc`
+    pub fn memory(pc: u32, access: Permission, error: MemoryError) -> Trap {
+        match error {
+            MemoryError::UnalignedAccess(address, _) => Trap::MisalignedAccess {
+                pc,
+                address,
+                access,
+            },
+            MemoryError::PermissionDenied(access, address) => Trap::AccessFault {
+                pc,
+                address,
+                access,
+            },
+            MemoryError::InvalidAddress(address)
+            | MemoryError::OutOfBounds(address)
+            | MemoryError::AddressTranslation(address, _)
+            | MemoryError::NoMap(address)
+            | MemoryError::InvalidMap(address, _)
+            | MemoryError::InternalMapperError(address)
+            | MemoryError::InternalMapperWithMessage(address, _)
+            | MemoryError::DeviceOverlap(address) => Trap::AccessFault {
+                pc,
+                address,
+                access,
+            },
+            MemoryError::OutOfMemory(_)
+            | MemoryError::ReadOnly
+            | MemoryError::RegionOverlap(..)
+            | MemoryError::ReservedRegion(_) => Trap::AccessFault {
+                pc,
+                address: 0,
+                access,
+            },
+        }
+    }
+
+    /// Address of the faulting instruction
+    pub fn pc(&self) -> u32 {
+        match *self {
+            Trap::IllegalInstruction { pc, .. }
+            | Trap::MisalignedAccess { pc, .. }
+            | Trap::AccessFault { pc, .. }
+            | Trap::Ecall { pc, .. }
+            | Trap::Breakpoint { pc } => pc,
+        }
+    }
+
+    /// Exception code, numbered like RISC-V's `mcause`
+    pub fn cause(&self) -> u32 {
+        match *self {
+            Trap::MisalignedAccess { access, .. } => match access {
+                Permission::X => 0,
+                Permission::R => 4,
+                Permission::W => 6,
+            },
+            Trap::AccessFault { access, .. } => match access {
+                Permission::X => 1,
+                Permission::R => 5,
+                Permission::W => 7,
+            },
+            Trap::IllegalInstruction { .. } => 2,
+            Trap::Breakpoint { .. } => 3,
+            Trap::Ecall { .. } => 8,
+        }
+    }
+
+    /// The bad address, the illegal instruction or the syscall number, like RISC-V's `mtval`
+    pub fn value(&self) -> u32 {
+        match *self {
+            Trap::IllegalInstruction { word, .. } => word,
+            Trap::MisalignedAccess { address, .. } | Trap::AccessFault { address, .. } => address,
+            Trap::Ecall { number, .. } => number,
+            Trap::Breakpoint { pc } => pc,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use isa::{Instruction::*, Register, operand::Immediate14};
+
+    use super::*;
+    use crate::{
+        VM,
+        memory::MemoryConfiguration,
+        syscall::{NUMBER_REGISTER, SyscallNumber},
+    };
+
+    fn li(dest: Register, value: i32) -> isa::Instruction {
+        AddI {
+            dest,
+            src: Register::X0,
+            value: Immediate14::new(value),
+        }
+    }
+
+    fn syscall() -> isa::Instruction {
+        Syscall {
+            src1: Register::X0,
+            src2: Register::X0,
+            src3: Register::X0,
+        }
+    }
+
+    fn load(program: &[isa::Instruction]) -> VM {
+        let mut vm = VM::new(MemoryConfiguration::new(1024 * 1024).unwrap());
+        let bytes: Vec<u8> = program
+            .iter()
+            .flat_map(|instruction| u32::from(instruction).to_le_bytes())
+            .collect();
+        vm.load_program(&bytes).unwrap();
+        vm
+    }
+
+    #[test]
+    fn t_trap_handler() {
+        let mut vm = load(&[
+            li(Register::X10, 24),
+            li(NUMBER_REGISTER, SyscallNumber::TrapVector as i32),
+            syscall(),
+            Ebreak,
+            // exit(cause)
+            li(NUMBER_REGISTER, SyscallNumber::Exit as i32),
+            syscall(),
+            // Handler: skip the faulting instruction
+            li(NUMBER_REGISTER, SyscallNumber::TrapInfo as i32),
+            syscall(),
+            AddI {
+                dest: Register::X12,
+                src: Register::X12,
+                value: Immediate14::new(4),
+            },
+            Jalr {
+                dest: Register::X0,
+                src: Register::X12,
+                offset: Immediate14::new(0),
+            },
+        ]);
+
+        assert_eq!(vm.run(), Ok(3));
+        assert_eq!(vm.registers().get(Register::X11), 12);
+        assert_eq!(
+            vm.trap_state(),
+            crate::cpu::TrapState {
+                vector: 24,
+                cause: 3,
+                value: 12,
+                pc: 12
+            }
+        );
+    }
+
+    #[test]
+    fn t_unhandled_traps() {
+        let mut vm = load(&[li(Register::X5, 2), Ebreak]);
+        assert_eq!(vm.run(), Err(Trap::Breakpoint { pc: 4 }));
+        assert_eq!(vm.pc(), 4);
+        assert_eq!(vm.registers().get(Register::X5), 2);
+
+        let mut vm = load(&[Sw {
+            dest: Register::X0,
+            src: Register::X0,
+            offset: Immediate14::new(4096),
+        }]);
+        let trap = vm.run().unwrap_err();
+        assert_eq!(
+            trap,
+            Trap::AccessFault {
+                pc: 0,
+                address: 4096,
+                access: Permission::W
+            }
+        );
+        assert_eq!((trap.cause(), trap.value()), (7, 4096));
+
+        let mut vm = VM::new(MemoryConfiguration::new(1024 * 1024).unwrap());
+        vm.load_program(&0xffu32.to_le_bytes()).unwrap();
+        let trap = vm.run().unwrap_err();
+        assert_eq!(trap, Trap::IllegalInstruction { pc: 0, word: 0xff });
+        assert_eq!(trap.cause(), 2);
+
+        // A handler that can't run doesn't loop
+        let mut vm = load(&[Ebreak]);
+        vm.set_trap_vector(4096);
+        assert_eq!(
+            vm.run(),
+            Err(Trap::AccessFault {
+                pc: 4096,
+                address: 4096,
+                access: Permission::X
+            })
+        );
+        assert_eq!(vm.trap_state().pc, 0);
+    }
+}
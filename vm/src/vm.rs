@@ -1,14 +1,28 @@
-use isa::Instruction;
+use isa::{Csr, Instruction, Register, operand::Immediate14};
+use shared::EnumCount;
 
 use crate::{
-    cpu::{CPU, register::Registers},
-    memory::{MemoryConfiguration, MemoryManager},
+    cpu::{CPU, TrapState, csr::Csrs, register::Registers},
+    device::{Device, DeviceEvent},
+    interrupt::{self, Interrupt},
+    io::{HostIo, StdIo},
+    memory::{
+        LinearMemory, MemoryConfiguration, MemoryError, MemoryManager, Permission, ReadWrite,
+        Segment,
+    },
+    syscall::{self, SyscallContext, SyscallError, SyscallHandler, SyscallOutcome, Syscalls},
+    trace::{RegisterWrite, TraceRecord, TraceSink},
+    trap::Trap,
 };
 
 pub struct VM {
     pub(crate) cpu: CPU,
     memory: MemoryManager,
+    syscalls: Syscalls,
+    io: Box<dyn HostIo>,
+    trace: Option<Box<dyn TraceSink>>,
     halt: bool,
+    exit_code: Option<u32>,
 }
 
 impl VM {
@@ -16,7 +30,11 @@ impl VM {
         Self {
             cpu: CPU::new(),
             memory: MemoryManager::new(&configuration),
+            syscalls: Syscalls::default(),
+            io: Box::new(StdIo::default()),
+            trace: None,
             halt: false,
+            exit_code: None,
         }
     }
 
@@ -25,28 +43,154 @@ impl VM {
         // self.flags = 0;
         self.cpu.registers.reset();
         self.halt = false;
+        self.exit_code = None;
         self.cpu.pc.reset();
+        self.cpu.csrs.reset();
         self.memory.reset();
     }
 
-    pub fn run(&mut self) -> anyhow::Result<()> {
+    /// Register a host function callable with `syscall` when `X20 == number`. Replaces the built-in handler with the same number
+    pub fn register_syscall(
+        &mut self,
+        number: impl Into<u32>,
+        handler: impl SyscallHandler + 'static,
+    ) {
+        self.syscalls.register(number, handler);
+    }
+
+    /// Replace the streams the guest reads from and writes to through syscalls, the process' own by default
+    pub fn set_io(&mut self, io: impl HostIo + 'static) {
+        self.io = Box::new(io);
+    }
+
+    /// Send a record of every executed instruction to `sink`
+    pub fn set_trace(&mut self, sink: impl TraceSink + 'static) {
+        self.trace = Some(Box::new(sink));
+        self.memory.record_accesses(true);
+    }
+
+    /// Stop tracing, giving back the sink
+    pub fn take_trace(&mut self) -> Option<Box<dyn TraceSink>> {
+        self.memory.record_accesses(false);
+        self.trace.take()
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.halt
+    }
+
+    /// The code passed to the exit syscall, `None` if the program hasn't exited
+    pub fn exit_code(&self) -> Option<u32> {
+        self.exit_code
+    }
+
+    /// Execute until the program exits, returning the exit code, or until a trap the guest doesn't
+    /// handle
+    pub fn run(&mut self) -> Result<u32, Trap> {
         while !self.halt {
             self.step()?;
         }
 
+        Ok(self.exit_code.unwrap_or_default())
+    }
+
+    /// Execute one instruction. A pending interrupt is taken first, the instruction is then the
+    /// handler's. A trap goes to the guest's trap vector when there is one, otherwise it's returned
+    /// with `pc` left at the faulting instruction
+    pub fn step(&mut self) -> Result<(), Trap> {
+        if let Some(interrupt) = interrupt::poll(&mut self.cpu.csrs) {
+            let pc = self.cpu.pc.value();
+            let vector = self.cpu.csrs.enter_trap(interrupt.cause(), 0, pc);
+            self.cpu.pc.set(vector);
+        }
+
+        let result = match self.trace.is_some() {
+            true => self.traced_step(),
+            false => self
+                .fetch()
+                .and_then(|instruction| self.execute(instruction)),
+        };
+        let csrs = &mut self.cpu.csrs;
+        csrs.cycle = csrs.cycle.wrapping_add(1);
+        if result.is_ok() {
+            csrs.instret = csrs.instret.wrapping_add(1);
+        }
+        if let DeviceEvent::Exit(code) = self.memory.tick_devices() {
+            self.exit_code = Some(code);
+            self.halt = true;
+        }
+        let Err(trap) = result else {
+            return Ok(());
+        };
+
+        self.cpu.pc.set(trap.pc());
+        let vector = self.cpu.csrs.trap.vector;
+        // A handler that faults right away would trap forever
+        if vector == 0 || trap.pc() == vector {
+            return Err(trap);
+        }
+        self.cpu
+            .csrs
+            .enter_trap(trap.cause(), trap.value(), trap.pc());
+        self.cpu.pc.set(vector);
         Ok(())
     }
 
-    pub fn step(&mut self) -> anyhow::Result<()> {
-        let instruction = self.fetch()?;
-        self.cpu.pc.increment();
-        self.decode_execute(instruction)
+    /// Map `len` bytes from `address` to `device`, loads and stores there reach it instead of RAM
+    pub fn map_device(
+        &mut self,
+        address: u32,
+        len: u32,
+        device: impl Device + 'static,
+    ) -> Result<(), MemoryError> {
+        self.memory.map_device(address, len, device)
+    }
+
+    /// Make `interrupt` pending, it's taken once enabled. The timer's is recomputed every step
+    pub fn raise_interrupt(&mut self, interrupt: Interrupt) {
+        self.cpu.csrs.interrupts.raise(interrupt);
+    }
+
+    pub fn clear_interrupt(&mut self, interrupt: Interrupt) {
+        self.cpu.csrs.interrupts.clear(interrupt);
+    }
+
+    /// Where traps go and what the last one was
+    pub fn trap_state(&self) -> TrapState {
+        self.cpu.csrs.trap
+    }
+
+    /// Control and status registers, e.g. the `cycle` and `instret` counters
+    pub fn csrs(&self) -> &Csrs {
+        &self.cpu.csrs
+    }
+
+    /// Send traps to the handler at `address`, 0 lets them stop the VM
+    pub fn set_trap_vector(&mut self, address: u32) {
+        self.cpu.csrs.trap.vector = address;
+    }
+
+    /// Address of the instruction to be executed next
+    pub fn pc(&self) -> u32 {
+        self.cpu.pc.value()
+    }
+
+    pub fn set_pc(&mut self, address: u32) {
+        self.cpu.pc.set(address);
     }
 
     pub fn registers(&mut self) -> &mut Registers {
         &mut self.cpu.registers
     }
 
+    pub fn memory(&self) -> &MemoryManager {
+        &self.memory
+    }
+
+    pub fn memory_mut(&mut self) -> &mut MemoryManager {
+        &mut self.memory
+    }
+
     // #[cfg(test)]
     // pub fn load_asm(&self, path: &std::path::Path) -> i32 {
     //     use std::fs::File;
@@ -58,10 +202,28 @@ impl VM {
     //     1;
     // }
 
+    /// Copy `program` at address 0 and point the stack pointer at the top of the stack
+    pub fn load_program(&mut self, program: &[u8]) -> Result<(), MemoryError> {
+        self.memory.load_program(program)?;
+        self.cpu
+            .registers
+            .set(Register::X2, self.memory.stack_start());
+
+        Ok(())
+    }
+
+    /// Place every segment at its address and point the stack pointer at the top of the stack
+    pub fn load_segments(&mut self, segments: &[Segment]) -> Result<(), MemoryError> {
+        self.memory.load_segments(segments)?;
+        self.cpu
+            .registers
+            .set(Register::X2, self.memory.stack_start());
+
+        Ok(())
+    }
+
     #[cfg(test)]
     pub fn test_run(&mut self, program: &[Instruction]) -> anyhow::Result<()> {
-        use isa::Register;
-
         let program_words: Vec<u32> = program
             .iter()
             .map(|instruction| instruction.into())
@@ -69,17 +231,10 @@ impl VM {
 
         unsafe {
             let program_bytes = program_words.align_to::<u8>().1;
-            self.memory.load_program(program_bytes)?;
+            self.load_program(program_bytes)?;
             // .map_err(Box::new)?;
         }
 
-        println!("");
-        println!("Program is successfully loaded");
-        println!("");
-        self.cpu
-            .registers
-            .set(Register::X2, self.memory.stack_start());
-
         while !self.halt {
             self.step()?;
         }
@@ -93,15 +248,121 @@ impl VM {
 }
 
 impl VM {
-    fn fetch(&self) -> anyhow::Result<Instruction> {
-        let memory = self.memory.read::<u32>(self.cpu.pc.value())?;
+    /// [`VM::step`], reporting what the instruction did to the trace sink. The record is sent even
+    /// if the instruction faults
+    #[cold]
+    fn traced_step(&mut self) -> Result<(), Trap> {
+        let pc = self.cpu.pc.value();
+        let word = self.fetch_word()?;
+        let instruction =
+            Instruction::try_from(word).map_err(|_| Trap::IllegalInstruction { pc, word })?;
+        let before = self.cpu.registers.clone();
+        self.memory.take_accesses();
+
+        let result = self.execute(instruction);
+
+        let registers = (0..Register::VARIANT_COUNT as u32)
+            .map(Register::from)
+            .filter(|&register| self.cpu.registers[register] != before[register])
+            .map(|register| RegisterWrite {
+                register,
+                value: self.cpu.registers[register],
+            })
+            .collect();
+        let record = TraceRecord {
+            pc,
+            word,
+            instruction,
+            registers,
+            memory: self.memory.take_accesses(),
+        };
+        let failed = self
+            .trace
+            .as_mut()
+            .and_then(|trace| trace.record(&record).err());
+        // Losing the trace isn't the guest's fault, the program carries on without it
+        if let Some(err) = failed {
+            log::error!("tracing stopped: {err}");
+            self.take_trace();
+        }
+        result
+    }
+
+    /// The word at `pc`, which must be aligned and executable
+    fn fetch_word(&self) -> Result<u32, Trap> {
+        let pc = self.cpu.pc.value();
+        self.memory
+            .alignment_check(std::mem::size_of::<u32>(), pc)
+            .and_then(|_| self.memory.fetch(pc))
+            .map_err(|error| Trap::memory(pc, Permission::X, error))
+    }
+
+    fn fetch(&self) -> Result<Instruction, Trap> {
+        let word = self.fetch_word()?;
+        Instruction::try_from(word).map_err(|_| Trap::IllegalInstruction {
+            pc: self.cpu.pc.value(),
+            word,
+        })
+    }
+
+    fn execute(&mut self, instruction: Instruction) -> Result<(), Trap> {
+        self.cpu.pc.increment();
+        self.decode_execute(instruction)
+    }
+
+    /// Address of the instruction being executed, `pc` has already moved past it
+    #[inline(always)]
+    fn current_pc(&self) -> u32 {
+        self.cpu.pc.value().wrapping_sub(4)
+    }
+
+    /// Read a `T` at `address`, which must be aligned on the size of `T`
+    #[inline(always)]
+    fn load<T>(&self, address: u32) -> Result<T, Trap>
+    where
+        T: Copy + Into<u32> + TryFrom<u32>,
+        LinearMemory: ReadWrite<T>,
+    {
+        self.memory
+            .alignment_check(std::mem::size_of::<T>(), address)
+            .and_then(|_| self.memory.read(address))
+            .map_err(|error| Trap::memory(self.current_pc(), Permission::R, error))
+    }
 
-        Ok(Instruction::try_from(memory)?)
+    /// Write a `T` at `address`, which must be aligned on the size of `T`
+    #[inline(always)]
+    fn store<T>(&mut self, address: u32, value: T) -> Result<(), Trap>
+    where
+        T: Copy + Into<u32>,
+        LinearMemory: ReadWrite<T>,
+    {
+        let pc = self.current_pc();
+        self.memory
+            .alignment_check(std::mem::size_of::<T>(), address)
+            .and_then(|_| self.memory.write(address, value))
+            .map_err(|error| Trap::memory(pc, Permission::W, error))
+    }
+
+    /// `base` register + sign-extended `offset`
+    #[inline(always)]
+    fn effective_address(&self, base: Register, offset: Immediate14) -> u32 {
+        self.cpu
+            .registers
+            .get(base)
+            .wrapping_add_signed(offset.into())
+    }
+
+    /// Jump `offset` bytes away from the current instruction if the branch is `taken`
+    #[inline(always)]
+    fn branch(&mut self, taken: bool, offset: i32) {
+        if taken {
+            self.cpu.pc.offset(offset);
+        }
     }
 
     // TODO: Should it be inlined bcs of hot loop? (https://nnethercote.github.io/perf-book/inlining.html)
     // #[inline(always)]
-    fn decode_execute(&mut self, instruction: Instruction) -> anyhow::Result<()> {
+    fn decode_execute(&mut self, instruction: Instruction) -> Result<(), Trap> {
         // println!("Decoded Instruction: {:?}", instruction);
         match instruction {
             // Instruction::Li { dest, value } => {
@@ -134,6 +395,59 @@ impl VM {
 
                 Ok(())
             }
+            Instruction::Mulh { dest, src1, src2 } => {
+                let r0 = self.cpu.registers.get(src1) as i32 as i64;
+                let r1 = self.cpu.registers.get(src2) as i32 as i64;
+                self.registers().set(dest, ((r0 * r1) >> 32) as u32);
+                Ok(())
+            }
+            Instruction::Mulhu { dest, src1, src2 } => {
+                let r0 = self.cpu.registers.get(src1) as u64;
+                let r1 = self.cpu.registers.get(src2) as u64;
+                self.registers().set(dest, ((r0 * r1) >> 32) as u32);
+                Ok(())
+            }
+            Instruction::Mulhsu { dest, src1, src2 } => {
+                let r0 = self.cpu.registers.get(src1) as i32 as i64;
+                let r1 = self.cpu.registers.get(src2) as i64;
+                self.registers().set(dest, ((r0 * r1) >> 32) as u32);
+                Ok(())
+            }
+            // Division never traps. Division by zero and signed overflow have defined results (RISC-V M extension)
+            Instruction::Div { dest, src1, src2 } => {
+                let r0 = self.cpu.registers.get(src1) as i32;
+                let r1 = self.cpu.registers.get(src2) as i32;
+                let value = match r1 {
+                    0 => -1,
+                    _ => r0.wrapping_div(r1),
+                };
+                self.registers().set(dest, value as u32);
+                Ok(())
+            }
+            Instruction::Divu { dest, src1, src2 } => {
+                let r0 = self.cpu.registers.get(src1);
+                let r1 = self.cpu.registers.get(src2);
+                let value = r0.checked_div(r1).unwrap_or(u32::MAX);
+                self.registers().set(dest, value);
+                Ok(())
+            }
+            Instruction::Rem { dest, src1, src2 } => {
+                let r0 = self.cpu.registers.get(src1) as i32;
+                let r1 = self.cpu.registers.get(src2) as i32;
+                let value = match r1 {
+                    0 => r0,
+                    _ => r0.wrapping_rem(r1),
+                };
+                self.registers().set(dest, value as u32);
+                Ok(())
+            }
+            Instruction::Remu { dest, src1, src2 } => {
+                let r0 = self.cpu.registers.get(src1);
+                let r1 = self.cpu.registers.get(src2);
+                let value = r0.checked_rem(r1).unwrap_or(r0);
+                self.registers().set(dest, value);
+                Ok(())
+            }
             Instruction::And { dest, src1, src2 } => {
                 let r0 = self.cpu.registers.get(src1);
                 let r1 = self.cpu.registers.get(src2);
@@ -175,53 +489,268 @@ impl VM {
                 self.cpu.registers.set(dest, result);
                 Ok(())
             }
+            Instruction::Slt { dest, src1, src2 } => {
+                let r0 = self.cpu.registers.get(src1) as i32;
+                let r1 = self.cpu.registers.get(src2) as i32;
+                self.registers().set(dest, (r0 < r1) as u32);
+                Ok(())
+            }
+            Instruction::Sltu { dest, src1, src2 } => {
+                let r0 = self.cpu.registers.get(src1);
+                let r1 = self.cpu.registers.get(src2);
+                self.registers().set(dest, (r0 < r1) as u32);
+                Ok(())
+            }
+            Instruction::SltI { dest, src, value } => {
+                let src = self.cpu.registers.get(src) as i32;
+                self.cpu
+                    .registers
+                    .set(dest, (src < i32::from(value)) as u32);
+                Ok(())
+            }
+            Instruction::SltIU { dest, src, value } => {
+                let src = self.cpu.registers.get(src);
+                self.cpu
+                    .registers
+                    .set(dest, (src < u32::from(value)) as u32);
+                Ok(())
+            }
+            Instruction::AndI { dest, src, value } => {
+                let src = self.cpu.registers.get(src);
+                self.cpu.registers.set(dest, src & u32::from(value));
+                Ok(())
+            }
+            Instruction::OrI { dest, src, value } => {
+                let src = self.cpu.registers.get(src);
+                self.cpu.registers.set(dest, src | u32::from(value));
+                Ok(())
+            }
+            Instruction::XorI { dest, src, value } => {
+                let src = self.cpu.registers.get(src);
+                self.cpu.registers.set(dest, src ^ u32::from(value));
+                Ok(())
+            }
+            Instruction::SllI { dest, src, shift } => {
+                let base = self.cpu.registers.get(src);
+                self.cpu
+                    .registers
+                    .set(dest, base << (u32::from(shift) & 0x1F));
+                Ok(())
+            }
+            Instruction::SrlI { dest, src, shift } => {
+                let base = self.cpu.registers.get(src);
+                self.cpu
+                    .registers
+                    .set(dest, base >> (u32::from(shift) & 0x1F));
+                Ok(())
+            }
+            Instruction::SraI { dest, src, shift } => {
+                let base = self.cpu.registers.get(src) as i32;
+                self.cpu
+                    .registers
+                    .set(dest, (base >> (u32::from(shift) & 0x1F)) as u32);
+                Ok(())
+            }
             Instruction::Lui { dest, value } => {
-                /*
-                **U-type** example: `LUI x1, 0x12345`.
-                Opcode 0110111.
-                The immediate 0x12345 is shifted right by 12 bits to get the upper 20 bits.
-                The encoding places these 20 bits in the imm[31:12] field, followed by rd (x1) and opcode.
-                Decoding extracts the upper 20 bits and shifts left by 12 to reconstruct the immediate.
-                */
-
-                self.cpu.registers.set(dest, value.into());
+                // The 19-bit immediate is the upper part of the value, an `AddI` of the low 13 bits
+                // completes it
+                self.cpu
+                    .registers
+                    .set(dest, (i32::from(value) as u32) << 13);
                 Ok(())
             }
+            // RISC-V requires loads and stores to be aligned on their size
             Instruction::Lw { src, dest, offset } => {
-                let addr = u32::from(offset) + self.cpu.registers.get(src);
-                self.memory
-                    .alignment_check(std::mem::size_of::<u32>(), addr)?;
-                self.cpu.registers.set(dest, self.memory.read(addr)?);
+                let addr = self.effective_address(src, offset);
+                let value = self.load::<u32>(addr)?;
+                self.cpu.registers.set(dest, value);
                 Ok(())
             }
             Instruction::Sw { dest, src, offset } => {
-                // Alignment check (RISC-V requires alignment for LW/SW/LH/SH)
-
-                let dest = self.cpu.registers.get(dest);
-                let offset = u32::from(offset);
-                // TODO: dest and offset potential to be minus
-                let address = offset + dest;
-                self.memory
-                    .alignment_check(std::mem::size_of::<u32>(), address)?;
+                let address = self.effective_address(dest, offset);
                 let value = self.cpu.registers.get(src);
-                self.memory.write(address, value)?;
+                self.store(address, value)
+            }
+            Instruction::Lb { dest, src, offset } => {
+                let addr = self.effective_address(src, offset);
+                let value = self.load::<u8>(addr)? as i8;
+                self.cpu.registers.set(dest, value as i32 as u32);
+                Ok(())
+            }
+            Instruction::Lh { dest, src, offset } => {
+                let addr = self.effective_address(src, offset);
+                let value = self.load::<u16>(addr)? as i16;
+                self.cpu.registers.set(dest, value as i32 as u32);
+                Ok(())
+            }
+            Instruction::Lbu { dest, src, offset } => {
+                let addr = self.effective_address(src, offset);
+                let value = self.load::<u8>(addr)?;
+                self.cpu.registers.set(dest, value as u32);
+                Ok(())
+            }
+            Instruction::Lhu { dest, src, offset } => {
+                let addr = self.effective_address(src, offset);
+                let value = self.load::<u16>(addr)?;
+                self.cpu.registers.set(dest, value as u32);
+                Ok(())
+            }
+            Instruction::Sb { src, dest, offset } => {
+                let address = self.effective_address(dest, offset);
+                let value = self.cpu.registers.get(src) as u8;
+                self.store(address, value)
+            }
+            Instruction::Sh { src, dest, offset } => {
+                let address = self.effective_address(dest, offset);
+                let value = self.cpu.registers.get(src) as u16;
+                self.store(address, value)
+            }
+            Instruction::Beq { src1, src2, offset } => {
+                let taken = self.cpu.registers.get(src1) == self.cpu.registers.get(src2);
+                self.branch(taken, offset.into());
+                Ok(())
+            }
+            Instruction::Bne { src1, src2, offset } => {
+                let taken = self.cpu.registers.get(src1) != self.cpu.registers.get(src2);
+                self.branch(taken, offset.into());
+                Ok(())
+            }
+            Instruction::Blt { src1, src2, offset } => {
+                let taken =
+                    (self.cpu.registers.get(src1) as i32) < (self.cpu.registers.get(src2) as i32);
+                self.branch(taken, offset.into());
+                Ok(())
+            }
+            Instruction::Bge { src1, src2, offset } => {
+                let taken =
+                    (self.cpu.registers.get(src1) as i32) >= (self.cpu.registers.get(src2) as i32);
+                self.branch(taken, offset.into());
+                Ok(())
+            }
+            Instruction::Bltu { src1, src2, offset } => {
+                let taken = self.cpu.registers.get(src1) < self.cpu.registers.get(src2);
+                self.branch(taken, offset.into());
                 Ok(())
             }
-            Instruction::Syscall { src1, src2, src3 } => {
-                self.halt = true;
+            Instruction::Bgeu { src1, src2, offset } => {
+                let taken = self.cpu.registers.get(src1) >= self.cpu.registers.get(src2);
+                self.branch(taken, offset.into());
                 Ok(())
             }
+            Instruction::Jal { dest, offset } => {
+                let link = self.cpu.pc.value();
+                self.cpu.pc.offset(offset.into());
+                self.cpu.registers.set(dest, link);
+                Ok(())
+            }
+            Instruction::Jalr { dest, src, offset } => {
+                let link = self.cpu.pc.value();
+                let target = self
+                    .cpu
+                    .registers
+                    .get(src)
+                    .wrapping_add_signed(offset.into())
+                    & !1;
+                self.cpu.pc.set(target);
+                self.cpu.registers.set(dest, link);
+                Ok(())
+            }
+            Instruction::Syscall { .. } => {
+                let pc = self.current_pc();
+                let number = self.cpu.registers.get(syscall::NUMBER_REGISTER);
+                let mut context = SyscallContext {
+                    registers: &mut self.cpu.registers,
+                    memory: &mut self.memory,
+                    io: self.io.as_mut(),
+                    trap: &mut self.cpu.csrs.trap,
+                };
+
+                let outcome = self
+                    .syscalls
+                    .dispatch(number, &mut context)
+                    .map_err(|error| match error {
+                        SyscallError::UnknownSyscall(number) => Trap::Ecall { pc, number },
+                        SyscallError::MemoryAccess { access, error } => {
+                            Trap::memory(pc, access, error)
+                        }
+                    })?;
+                match outcome {
+                    SyscallOutcome::Return(value) => {
+                        self.cpu.registers.set(syscall::RETURN_REGISTER, value);
+                    }
+                    SyscallOutcome::Exit(code) => {
+                        self.exit_code = Some(code);
+                        self.halt = true;
+                    }
+                }
+                Ok(())
+            }
+            Instruction::Ebreak => Err(Trap::Breakpoint {
+                pc: self.current_pc(),
+            }),
+            Instruction::Mret => {
+                let resume = self.cpu.csrs.return_from_trap();
+                self.cpu.pc.set(resume);
+                Ok(())
+            }
+            Instruction::Csrrw { dest, src, csr } => {
+                let value = self.cpu.registers.get(src);
+                self.csr_access(&instruction, dest, csr, |_| Some(value))
+            }
+            Instruction::Csrrs { dest, src, csr } => {
+                let mask = self.cpu.registers.get(src);
+                let write = src != Register::X0;
+                self.csr_access(&instruction, dest, csr, |old| write.then_some(old | mask))
+            }
+            Instruction::Csrrc { dest, src, csr } => {
+                let mask = self.cpu.registers.get(src);
+                let write = src != Register::X0;
+                self.csr_access(&instruction, dest, csr, |old| write.then_some(old & !mask))
+            }
+            Instruction::Csrrwi { dest, value, csr } => {
+                self.csr_access(&instruction, dest, csr, |_| Some(value.into()))
+            }
+            Instruction::Csrrsi { dest, value, csr } => {
+                let mask = u32::from(value);
+                self.csr_access(&instruction, dest, csr, |old| {
+                    (mask != 0).then_some(old | mask)
+                })
+            }
+            Instruction::Csrrci { dest, value, csr } => {
+                let mask = u32::from(value);
+                self.csr_access(&instruction, dest, csr, |old| {
+                    (mask != 0).then_some(old & !mask)
+                })
+            }
         }
     }
+
+    /// Read `csr` into `dest` and write back what `update` makes of its old value, if anything.
+    /// Set and clear with `x0` or 0 only read, so they're allowed on read-only CSRs
+    fn csr_access(
+        &mut self,
+        instruction: &Instruction,
+        dest: Register,
+        csr: Csr,
+        update: impl FnOnce(u32) -> Option<u32>,
+    ) -> Result<(), Trap> {
+        let illegal = Trap::IllegalInstruction {
+            pc: self.current_pc(),
+            word: u32::from(instruction),
+        };
+        let old = self.cpu.csrs.read(csr).ok_or(illegal)?;
+        if update(old).is_some_and(|value| !self.cpu.csrs.write(csr, value)) {
+            return Err(illegal);
+        }
+        self.cpu.registers.set(dest, old);
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 mod test {
     use Instruction::*;
-    use isa::{
-        Register,
-        operand::{Immediate14, Immediate19},
-    };
+    use isa::operand::{Immediate19, UImmediate5};
 
     use super::*;
 
@@ -242,7 +771,7 @@ mod test {
     fn t_arith() {
         let size = 1024 * 1024;
 
-        let mut vm = VM::new(crate::memory::MemoryConfiguration::new(size));
+        let mut vm = VM::new(crate::memory::MemoryConfiguration::new(size).unwrap());
         for (a, b) in CASES {
             let program = &[
                 AddI {
@@ -276,11 +805,276 @@ mod test {
         }
     }
 
+    #[test]
+    fn t_div_rem_mulh() {
+        let size = 1024 * 1024;
+
+        let mut vm = VM::new(crate::memory::MemoryConfiguration::new(size).unwrap());
+
+        // (dividend, divisor) => [div, divu, rem, remu, mulh, mulhu, mulhsu]
+        let cases: [((i32, i32), [u32; 7]); 5] = [
+            ((7, 2), [3, 3, 1, 1, 0, 0, 0]),
+            (
+                (-7, 2),
+                [
+                    -3i32 as u32,
+                    0x7FFF_FFFC,
+                    -1i32 as u32,
+                    1,
+                    u32::MAX,
+                    1,
+                    u32::MAX,
+                ],
+            ),
+            ((7, 0), [u32::MAX, u32::MAX, 7, 7, 0, 0, 0]),
+            (
+                (i32::MIN, -1),
+                [
+                    i32::MIN as u32,
+                    0,
+                    0,
+                    i32::MIN as u32,
+                    0,
+                    0x7FFF_FFFF,
+                    0x8000_0000,
+                ],
+            ),
+            ((-1, -1), [1, 1, 0, 0, 0, 0xFFFF_FFFE, u32::MAX]),
+        ];
+
+        for ((a, b), expected) in cases {
+            vm.reset();
+            vm.cpu.registers.set(Register::X5, a as u32);
+            vm.cpu.registers.set(Register::X6, b as u32);
+
+            let (src1, src2) = (Register::X5, Register::X6);
+            let program = [
+                Div {
+                    dest: Register::X10,
+                    src1,
+                    src2,
+                },
+                Divu {
+                    dest: Register::X11,
+                    src1,
+                    src2,
+                },
+                Rem {
+                    dest: Register::X12,
+                    src1,
+                    src2,
+                },
+                Remu {
+                    dest: Register::X13,
+                    src1,
+                    src2,
+                },
+                Mulh {
+                    dest: Register::X14,
+                    src1,
+                    src2,
+                },
+                Mulhu {
+                    dest: Register::X15,
+                    src1,
+                    src2,
+                },
+                Mulhsu {
+                    dest: Register::X16,
+                    src1,
+                    src2,
+                },
+            ];
+
+            for ins in program {
+                vm.decode_execute(ins).unwrap();
+            }
+
+            let result = [
+                Register::X10,
+                Register::X11,
+                Register::X12,
+                Register::X13,
+                Register::X14,
+                Register::X15,
+                Register::X16,
+            ]
+            .map(|r| vm.cpu.registers.get(r));
+            assert_eq!(result, expected, "Variable: {a} and {b}");
+        }
+    }
+
+    #[test]
+    fn t_slt_and_imm() {
+        let size = 1024 * 1024;
+
+        let mut vm = VM::new(crate::memory::MemoryConfiguration::new(size).unwrap());
+        vm.cpu.registers.set(Register::X5, -8i32 as u32);
+        vm.cpu.registers.set(Register::X6, 3);
+
+        let (neg, pos) = (Register::X5, Register::X6);
+        let imm = Immediate14::new;
+        let cases = [
+            (
+                Slt {
+                    dest: Register::X10,
+                    src1: neg,
+                    src2: pos,
+                },
+                1,
+            ),
+            (
+                Sltu {
+                    dest: Register::X10,
+                    src1: neg,
+                    src2: pos,
+                },
+                0,
+            ),
+            (
+                SltI {
+                    dest: Register::X10,
+                    src: neg,
+                    value: imm(-7),
+                },
+                1,
+            ),
+            (
+                SltI {
+                    dest: Register::X10,
+                    src: pos,
+                    value: imm(3),
+                },
+                0,
+            ),
+            // -1 sign-extends to u32::MAX
+            (
+                SltIU {
+                    dest: Register::X10,
+                    src: neg,
+                    value: imm(-1),
+                },
+                1,
+            ),
+            (
+                SltIU {
+                    dest: Register::X10,
+                    src: Register::X0,
+                    value: imm(1),
+                },
+                1,
+            ),
+            (
+                AndI {
+                    dest: Register::X10,
+                    src: neg,
+                    value: imm(0xFF),
+                },
+                0xF8,
+            ),
+            (
+                OrI {
+                    dest: Register::X10,
+                    src: pos,
+                    value: imm(-16),
+                },
+                -13i32 as u32,
+            ),
+            (
+                XorI {
+                    dest: Register::X10,
+                    src: neg,
+                    value: imm(-1),
+                },
+                7,
+            ),
+            (
+                SllI {
+                    dest: Register::X10,
+                    src: pos,
+                    shift: imm(4),
+                },
+                48,
+            ),
+            (
+                SrlI {
+                    dest: Register::X10,
+                    src: neg,
+                    shift: imm(28),
+                },
+                0xF,
+            ),
+            (
+                SraI {
+                    dest: Register::X10,
+                    src: neg,
+                    shift: imm(1),
+                },
+                -4i32 as u32,
+            ),
+            // only the lower 5 bits of the shift amount are used
+            (
+                SllI {
+                    dest: Register::X10,
+                    src: pos,
+                    shift: imm(33),
+                },
+                6,
+            ),
+        ];
+
+        for (ins, expected) in cases {
+            let name = format!("{:?}", ins);
+            vm.decode_execute(ins).unwrap();
+            assert_eq!(vm.cpu.registers.get(Register::X10), expected, "{name}");
+        }
+    }
+
+    #[test]
+    fn t_lui() {
+        let size = 1024 * 1024;
+
+        let mut vm = VM::new(crate::memory::MemoryConfiguration::new(size).unwrap());
+
+        // `lui` fills bits 31..13 and clears the low 13 bits, the immediate isn't shifted by 12
+        let cases = [
+            (1, 0x2000),
+            (-1, 0xffff_e000),
+            (0x3_ffff, 0x7fff_e000),
+            (-0x4_0000, 0x8000_0000),
+        ];
+
+        for (value, expected) in cases {
+            vm.cpu.registers.set(Register::X5, 0x1fff);
+            vm.decode_execute(Lui {
+                dest: Register::X5,
+                value: Immediate19::new(value),
+            })
+            .unwrap();
+            assert_eq!(vm.cpu.registers.get(Register::X5), expected, "{value}");
+        }
+
+        // `lui` and an `addi` of the low 13 bits load any 32-bit value
+        let value = 0x1234_5678u32;
+        vm.decode_execute(Lui {
+            dest: Register::X5,
+            value: Immediate19::new(value as i32 >> 13),
+        })
+        .unwrap();
+        vm.decode_execute(AddI {
+            dest: Register::X5,
+            src: Register::X5,
+            value: Immediate14::new((value & 0x1fff) as i32),
+        })
+        .unwrap();
+        assert_eq!(vm.cpu.registers.get(Register::X5), value);
+    }
+
     #[test]
     fn t_load_store_on_the_stack() {
         let size = 1024 * 1024;
 
-        let mut vm = VM::new(crate::memory::MemoryConfiguration::new(size));
+        let mut vm = VM::new(crate::memory::MemoryConfiguration::new(size).unwrap());
 
         // # Function prologue - setup stack frame
         // function_start:
@@ -357,7 +1151,322 @@ mod test {
 
         assert_eq!(vm.cpu.registers.get(Register::X6), 5);
         assert_eq!(vm.cpu.registers.get(Register::X8), 13);
-        assert_eq!(vm.cpu.registers.get(Register::X5), 43);
+        assert_eq!(vm.cpu.registers.get(Register::X5), 43 << 13);
+        vm.reset();
+    }
+
+    #[test]
+    fn t_branch_loop() {
+        let size = 1024 * 1024;
+
+        let mut vm = VM::new(crate::memory::MemoryConfiguration::new(size).unwrap());
+
+        // sum = 0; for (i = 10; i != 0; i--) sum += i;
+        let program = &[
+            AddI {
+                dest: Register::X5,
+                src: Register::X0,
+                value: Immediate14::new(10),
+            },
+            AddI {
+                dest: Register::X6,
+                src: Register::X0,
+                value: Immediate14::new(0),
+            },
+            // loop:
+            Add {
+                dest: Register::X6,
+                src1: Register::X6,
+                src2: Register::X5,
+            },
+            AddI {
+                dest: Register::X5,
+                src: Register::X5,
+                value: Immediate14::new(-1),
+            },
+            Bne {
+                src1: Register::X5,
+                src2: Register::X0,
+                offset: Immediate14::new(-8), // back to `loop`
+            },
+            // skip the next instruction when -1 < 1 (signed)
+            AddI {
+                dest: Register::X7,
+                src: Register::X0,
+                value: Immediate14::new(-1),
+            },
+            Blt {
+                src1: Register::X7,
+                src2: Register::X0,
+                offset: Immediate14::new(8),
+            },
+            AddI {
+                dest: Register::X28,
+                src: Register::X0,
+                value: Immediate14::new(1),
+            },
+            // 0xFFFF_FFFF is not less than 0 (unsigned), falls through
+            Bltu {
+                src1: Register::X7,
+                src2: Register::X0,
+                offset: Immediate14::new(8),
+            },
+            AddI {
+                dest: Register::X29,
+                src: Register::X0,
+                value: Immediate14::new(1),
+            },
+            Syscall {
+                src1: Register::X0,
+                src2: Register::X0,
+                src3: Register::X0,
+            },
+        ];
+
+        match vm.test_run(program) {
+            Ok(_) => {}
+            Err(e) => println!("Test run went wrong {}", e),
+        }
+
+        assert_eq!(vm.cpu.registers.get(Register::X6), 55);
+        assert_eq!(vm.cpu.registers.get(Register::X28), 0);
+        assert_eq!(vm.cpu.registers.get(Register::X29), 1);
+        vm.reset();
+    }
+
+    #[test]
+    fn t_call_ret() {
+        let size = 1024 * 1024;
+
+        let mut vm = VM::new(crate::memory::MemoryConfiguration::new(size).unwrap());
+
+        let program = &[
+            // 0x0: call double
+            Jal {
+                dest: Register::X1,
+                offset: Immediate19::new(16),
+            },
+            // 0x4: j end
+            Jal {
+                dest: Register::X0,
+                offset: Immediate19::new(8),
+            },
+            // 0x8: skipped by `j end`
+            AddI {
+                dest: Register::X6,
+                src: Register::X0,
+                value: Immediate14::new(1),
+            },
+            // 0xc: end
+            Syscall {
+                src1: Register::X0,
+                src2: Register::X0,
+                src3: Register::X0,
+            },
+            // 0x10: double
+            AddI {
+                dest: Register::X10,
+                src: Register::X0,
+                value: Immediate14::new(21),
+            },
+            Add {
+                dest: Register::X10,
+                src1: Register::X10,
+                src2: Register::X10,
+            },
+            // ret
+            Jalr {
+                dest: Register::X0,
+                src: Register::X1,
+                offset: Immediate14::new(0),
+            },
+        ];
+
+        match vm.test_run(program) {
+            Ok(_) => {}
+            Err(e) => println!("Test run went wrong {}", e),
+        }
+
+        assert_eq!(vm.cpu.registers.get(Register::X10), 42);
+        assert_eq!(vm.cpu.registers.get(Register::X1), 0x4);
+        assert_eq!(vm.cpu.registers.get(Register::X6), 0);
+        assert_eq!(vm.cpu.registers.get(Register::X0), 0);
+        vm.reset();
+    }
+
+    #[test]
+    fn t_load_store_byte_half() {
+        let size = 1024 * 1024;
+
+        let mut vm = VM::new(crate::memory::MemoryConfiguration::new(size).unwrap());
+
+        let program = &[
+            AddI {
+                dest: Register::X2,
+                src: Register::X2,
+                value: Immediate14::new(-15),
+            },
+            // 0xFFFF_FF80
+            AddI {
+                dest: Register::X5,
+                src: Register::X0,
+                value: Immediate14::new(-128),
+            },
+            Sb {
+                src: Register::X5,
+                dest: Register::X2,
+                offset: Immediate14::new(1),
+            },
+            Sh {
+                src: Register::X5,
+                dest: Register::X2,
+                offset: Immediate14::new(2),
+            },
+            Lb {
+                dest: Register::X6,
+                src: Register::X2,
+                offset: Immediate14::new(1),
+            },
+            Lbu {
+                dest: Register::X7,
+                src: Register::X2,
+                offset: Immediate14::new(1),
+            },
+            Lh {
+                dest: Register::X28,
+                src: Register::X2,
+                offset: Immediate14::new(2),
+            },
+            Lhu {
+                dest: Register::X29,
+                src: Register::X2,
+                offset: Immediate14::new(2),
+            },
+            // [0x00, 0x80, 0x80, 0xFF]
+            Lw {
+                dest: Register::X30,
+                src: Register::X2,
+                offset: Immediate14::new(0),
+            },
+            Syscall {
+                src1: Register::X0,
+                src2: Register::X0,
+                src3: Register::X0,
+            },
+        ];
+
+        match vm.test_run(program) {
+            Ok(_) => {}
+            Err(e) => println!("Test run went wrong {}", e),
+        }
+
+        assert_eq!(vm.cpu.registers.get(Register::X6), 0xFFFF_FF80);
+        assert_eq!(vm.cpu.registers.get(Register::X7), 0x80);
+        assert_eq!(vm.cpu.registers.get(Register::X28), 0xFFFF_FF80);
+        assert_eq!(vm.cpu.registers.get(Register::X29), 0xFF80);
+        assert_eq!(vm.cpu.registers.get(Register::X30), 0xFF80_8000);
+        vm.reset();
+    }
+
+    #[test]
+    fn t_unaligned_half() {
+        let size = 1024 * 1024;
+
+        let mut vm = VM::new(crate::memory::MemoryConfiguration::new(size).unwrap());
+
+        let program = &[
+            AddI {
+                dest: Register::X2,
+                src: Register::X2,
+                value: Immediate14::new(-15),
+            },
+            Lh {
+                dest: Register::X6,
+                src: Register::X2,
+                offset: Immediate14::new(1),
+            },
+        ];
+
+        let err = vm.test_run(program).unwrap_err();
+        let stack = vm.memory.stack_start() - 15;
+        assert_eq!(
+            err.downcast_ref::<Trap>(),
+            Some(&Trap::MisalignedAccess {
+                pc: 4,
+                address: stack + 1,
+                access: Permission::R
+            })
+        );
+        assert_eq!(vm.pc(), 4);
+    }
+
+    #[test]
+    fn t_csr() {
+        let mut vm = VM::new(crate::memory::MemoryConfiguration::new(1024 * 1024).unwrap());
+
+        let program = &[
+            AddI {
+                dest: Register::X5,
+                src: Register::X0,
+                value: Immediate14::new(0b1100),
+            },
+            // csrw scratch, x5
+            Csrrw {
+                dest: Register::X0,
+                src: Register::X5,
+                csr: Csr::SCRATCH,
+            },
+            Csrrci {
+                dest: Register::X6,
+                value: UImmediate5::new(0b0100),
+                csr: Csr::SCRATCH,
+            },
+            Csrrsi {
+                dest: Register::X7,
+                value: UImmediate5::new(0b0001),
+                csr: Csr::SCRATCH,
+            },
+            // csrr x10, cycle
+            Csrrs {
+                dest: Register::X10,
+                src: Register::X0,
+                csr: Csr::CYCLE,
+            },
+            Csrrs {
+                dest: Register::X11,
+                src: Register::X0,
+                csr: Csr::INSTRET,
+            },
+            Syscall {
+                src1: Register::X0,
+                src2: Register::X0,
+                src3: Register::X0,
+            },
+        ];
+        vm.test_run(program).unwrap();
+        assert_eq!(vm.cpu.registers.get(Register::X6), 0b1100);
+        assert_eq!(vm.cpu.registers.get(Register::X7), 0b1000);
+        assert_eq!(vm.csrs().scratch, 0b1001);
+        // Counters read what came before the reading instruction
+        assert_eq!(vm.cpu.registers.get(Register::X10), 4);
+        assert_eq!(vm.cpu.registers.get(Register::X11), 5);
+        assert_eq!((vm.csrs().cycle, vm.csrs().instret), (7, 7));
+
         vm.reset();
+        let write = Csrrw {
+            dest: Register::X0,
+            src: Register::X0,
+            csr: Csr::CYCLE,
+        };
+        let err = vm.test_run(&[write]).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<Trap>(),
+            Some(&Trap::IllegalInstruction {
+                pc: 0,
+                word: u32::from(&write)
+            })
+        );
+        // Trapped instructions take a cycle but don't retire
+        assert_eq!((vm.csrs().cycle, vm.csrs().instret), (1, 0));
     }
 }